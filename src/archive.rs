@@ -0,0 +1,564 @@
+use crate::colors::*;
+use crate::utils::{
+    check_contained_rel_path, collect_process_output, run_process, shell_quote, strip_prefix,
+};
+use failure::{Error, ResultExt};
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_link, File};
+use std::io::Read;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use walkdir::WalkDir;
+
+/// Compression used for tar archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Compress with the `zstd` program, at the specified level.
+    Zstd {
+        level: u32,
+    },
+}
+
+/// Range of levels supported by `zstd` without `--ultra`.
+pub const MAX_ZSTD_LEVEL: u32 = 19;
+
+/// First bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    pub fn zstd(level: u32) -> Result<Compression, Error> {
+        if !(1..=MAX_ZSTD_LEVEL).contains(&level) {
+            bail!(
+                "Expected zstd compression level to be between 1 and {}, but got {}",
+                MAX_ZSTD_LEVEL,
+                level
+            );
+        }
+        Ok(Compression::Zstd { level })
+    }
+
+    /// Determines the compression of an archive by peeking at its magic bytes.
+    pub fn detect(archive: &Path) -> Result<Compression, Error> {
+        let mut magic = [0; 4];
+        let mut file = File::open(archive).context(format_err!(
+            "Failed to open archive {}",
+            color_file(&archive.display())
+        ))?;
+        let mut length = 0;
+        while length < magic.len() {
+            match file.read(&mut magic[length..])? {
+                0 => break,
+                count => length += count,
+            }
+        }
+        if length == magic.len() && magic == ZSTD_MAGIC {
+            // The level isn't needed for decompression.
+            Ok(Compression::Zstd { level: 0 })
+        } else {
+            Ok(Compression::None)
+        }
+    }
+
+    pub fn add_tar_args(self, cmd: &mut Command, decompress: bool) {
+        match self {
+            Compression::None => {}
+            Compression::Zstd { level } => {
+                cmd.arg("--use-compress-program");
+                if decompress {
+                    cmd.arg("zstd -d");
+                } else {
+                    cmd.arg(format!("zstd -{}", level));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a tar archive of the contents of a directory.
+pub fn create(dir: &Path, archive: &Path, compression: Compression) -> Result<(), Error> {
+    let mut cmd = Command::new("tar");
+    cmd.stdin(Stdio::null())
+        .arg("--create")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir);
+    compression.add_tar_args(&mut cmd, false);
+    // Archive the contents of the directory, rather than the directory itself.
+    cmd.arg(".");
+    run_process(&mut cmd)
+}
+
+/// Creates a tar archive of the contents of a directory, streaming it to the
+/// stdin of another process, such as `ssh`. Fails if either process fails.
+pub fn create_streamed(
+    dir: &Path,
+    sink: &mut Command,
+    compression: Compression,
+) -> Result<(), Error> {
+    let mut tar_cmd = Command::new("tar");
+    tar_cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .arg("--create")
+        .arg("--file")
+        .arg("-")
+        .arg("--directory")
+        .arg(dir);
+    compression.add_tar_args(&mut tar_cmd, false);
+    tar_cmd.arg(".");
+    let mut tar = tar_cmd.spawn().context(format_err!(
+        "Error encountered while running {:?}",
+        color_cmd(&tar_cmd)
+    ))?;
+    // Unwrap is safe because stdout is piped.
+    let tar_stdout = tar.stdout.take().unwrap();
+    let sink_result = run_process(sink.stdin(Stdio::from(tar_stdout)));
+    // Closes this process's copy of the pipe, which the command holds onto,
+    // so that tar can't block on writing after the sink exits.
+    sink.stdin(Stdio::null());
+    // When the sink fails, tar gets SIGPIPE, so its status isn't interesting.
+    let tar_status = tar.wait()?;
+    sink_result?;
+    if !tar_status.success() {
+        bail!(
+            "{:?} exited with failure status {}",
+            color_cmd(&tar_cmd),
+            color_err(&tar_status)
+        );
+    }
+    Ok(())
+}
+
+/// Destination on a remote host to write an archive to, via ssh.
+#[derive(Debug, Clone)]
+pub struct SshDestination {
+    /// Program and arguments used to connect, like `ssh -p 2222`.
+    pub ssh_command: Vec<String>,
+    pub host: String,
+    pub path: String,
+}
+
+impl SshDestination {
+    pub fn new(ssh_command: &str, host: &str, path: &str) -> Result<SshDestination, Error> {
+        let ssh_command: Vec<String> = ssh_command.split_whitespace().map(String::from).collect();
+        if ssh_command.is_empty() {
+            bail!("Expected a non-empty ssh command.");
+        }
+        // Hosts starting with '-' would be interpreted as options by ssh.
+        if host.is_empty() || host.starts_with('-') || host.chars().any(char::is_whitespace) {
+            bail!("Invalid ssh host {:?}", host);
+        }
+        if path.is_empty() || path.ends_with('/') {
+            bail!(
+                "Expected remote archive path to be the path of a file, but got {:?}",
+                path
+            );
+        }
+        if path.chars().any(|c| c == '\0' || c == '\n') {
+            bail!(
+                "Remote archive path {:?} contains invalid characters.",
+                path
+            );
+        }
+        Ok(SshDestination {
+            ssh_command,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Command which writes its stdin to the destination. The remote path is
+    /// quoted, since ssh passes the command to the remote user's shell.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.ssh_command[0]);
+        cmd.args(&self.ssh_command[1..])
+            .arg("--")
+            .arg(&self.host)
+            .arg(format!("cat > {}", shell_quote(&self.path)));
+        cmd
+    }
+}
+
+impl Display for SshDestination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}:{}", self.host, self.path)
+    }
+}
+
+/// Extracts a tar archive into a directory, detecting whether it is
+/// compressed. All the entries are checked before extracting anything, so
+/// that a malicious archive can't write outside of the directory. See
+/// `check_entries`.
+pub fn extract(archive: &Path, dir: &Path, allow_absolute_symlinks: bool) -> Result<(), Error> {
+    let compression = Compression::detect(archive)?;
+    let entries = list_entries(archive, compression)?;
+    check_entries(&entries, allow_absolute_symlinks).context(format_err!(
+        "Refusing to extract {}, due to invalid entry.",
+        color_file(&archive.display())
+    ))?;
+    let mut extract_cmd = Command::new("tar");
+    extract_cmd
+        .stdin(Stdio::null())
+        .arg("--extract")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir)
+        // Files should be owned by the user rather than whoever created the
+        // archive.
+        .arg("--no-same-owner");
+    compression.add_tar_args(&mut extract_cmd, true);
+    run_process(&mut extract_cmd)
+}
+
+/// Checks that copying a directory's contents into another directory is as
+/// safe as extracting an archive - that is, its symlinks satisfy the same
+/// checks as `extract` applies to archive entries. See `check_entries`.
+pub fn check_dir(dir: &Path, allow_absolute_symlinks: bool) -> Result<(), Error> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(dir)?.to_path_buf();
+        let kind = if entry.path_is_symlink() {
+            ArchiveEntryKind::Symlink(read_link(entry.path())?)
+        } else {
+            ArchiveEntryKind::Other
+        };
+        entries.push(ArchiveEntry { path, kind });
+    }
+    check_entries(&entries, allow_absolute_symlinks)
+}
+
+/// Entry of a tar archive, as listed by `tar --list --verbose`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ArchiveEntry {
+    /// Path of the entry, without `.` components.
+    path: PathBuf,
+    kind: ArchiveEntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArchiveEntryKind {
+    /// Symlink, with its target.
+    Symlink(PathBuf),
+    /// Hard link to the entry at the path, which is relative to the root of
+    /// the archive.
+    Hardlink(PathBuf),
+    Other,
+}
+
+fn list_entries(archive: &Path, compression: Compression) -> Result<Vec<ArchiveEntry>, Error> {
+    let mut list_cmd = Command::new("tar");
+    list_cmd
+        .stdin(Stdio::null())
+        // The listing is parsed, so it shouldn't be localized.
+        .env("LC_ALL", "C")
+        .arg("--list")
+        .arg("--verbose")
+        // Quotes names and escapes special characters in them, so that the
+        // listing can be parsed unambiguously.
+        .arg("--quoting-style=c")
+        .arg("--file")
+        .arg(archive);
+    compression.add_tar_args(&mut list_cmd, true);
+    let listing = collect_process_output(&mut list_cmd)?;
+    listing
+        .lines()
+        .map(|line| {
+            parse_listing_line(line).ok_or_else(|| {
+                format_err!(
+                    "Unexpected line in listing of {}: {:?}",
+                    color_file(&archive.display()),
+                    line
+                )
+            })
+        })
+        .collect()
+}
+
+/// Parses a line of `tar --list --verbose --quoting-style=c` output, like
+/// `lrwxrwxrwx user/group 0 2020-01-01 00:00 "name" -> "target"`.
+fn parse_listing_line(line: &str) -> Option<ArchiveEntry> {
+    let (name, rest) = parse_c_quoted(&line[line.find('"')?..])?;
+    let path = normalize_entry_path(&name);
+    let kind = match line.chars().next()? {
+        'l' => ArchiveEntryKind::Symlink(PathBuf::from(OsString::from_vec(
+            parse_c_quoted(strip_prefix(" -> ", rest)?.as_str())?.0,
+        ))),
+        'h' => ArchiveEntryKind::Hardlink(normalize_entry_path(
+            &parse_c_quoted(strip_prefix(" link to ", rest)?.as_str())?.0,
+        )),
+        _ => ArchiveEntryKind::Other,
+    };
+    Some(ArchiveEntry { path, kind })
+}
+
+fn normalize_entry_path(name: &[u8]) -> PathBuf {
+    Path::new(OsStr::from_bytes(name))
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// Parses a string in double quotes with C escapes, yielding its bytes and the
+/// input which follows it.
+fn parse_c_quoted(input: &str) -> Option<(Vec<u8>, &str)> {
+    let bytes = input.as_bytes();
+    if bytes.first() != Some(&b'"') {
+        return None;
+    }
+    let mut result = Vec::new();
+    let mut i = 1;
+    loop {
+        match *bytes.get(i)? {
+            b'"' => return Some((result, &input[i + 1..])),
+            b'\\' => {
+                let escaped = *bytes.get(i + 1)?;
+                i += 2;
+                result.push(match escaped {
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'f' => 0x0c,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'v' => 0x0b,
+                    b'0'..=b'7' => {
+                        // Up to three octal digits.
+                        let mut value = u32::from(escaped - b'0');
+                        for _ in 0..2 {
+                            match bytes.get(i) {
+                                Some(digit @ b'0'..=b'7') => {
+                                    value = value * 8 + u32::from(digit - b'0');
+                                    i += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        value as u8
+                    }
+                    // Such as quotes and backslashes.
+                    other => other,
+                });
+            }
+            byte => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Checks that extracting the entries into an empty directory only writes
+/// within that directory, and only creates symlinks which point within it:
+///
+/// * Paths must be relative and not contain `..`.
+///
+/// * No entry may be within an entry which is a symlink, since extracting it
+///   would write wherever the symlink points.
+///
+/// * Hard links must refer to paths within the archive.
+///
+/// * Symlink targets must be relative, and must not leave the directory when
+///   resolved from the symlink's location. This resolution must not pass
+///   through other symlinks, since where they point isn't lexically apparent.
+///   When `allow_absolute_symlinks` is set, absolute targets are allowed,
+///   since extracting them doesn't write outside of the directory.
+fn check_entries(entries: &[ArchiveEntry], allow_absolute_symlinks: bool) -> Result<(), Error> {
+    let symlinks: HashSet<&Path> = entries
+        .iter()
+        .filter_map(|entry| match entry.kind {
+            ArchiveEntryKind::Symlink(_) => Some(entry.path.as_path()),
+            _ => None,
+        })
+        .collect();
+    for entry in entries {
+        check_contained_rel_path(&entry.path)?;
+        if let Some(symlink) = entry
+            .path
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| symlinks.contains(ancestor))
+        {
+            bail!(
+                "{} is within symlink {}, so extracting it would write wherever the symlink \
+                 points.",
+                color_file(&entry.path.display()),
+                color_file(&symlink.display())
+            );
+        }
+        match &entry.kind {
+            ArchiveEntryKind::Symlink(target) if target.is_absolute() => {
+                if !allow_absolute_symlinks {
+                    bail!(
+                        "Symlink {} points to absolute path {}. Absolute symlinks could point \
+                         anywhere once extracted, so they are only allowed when importing \
+                         snapshots with {}.",
+                        color_file(&entry.path.display()),
+                        color_file(&target.display()),
+                        color_cmd(&"--allow-absolute-symlinks")
+                    );
+                }
+            }
+            ArchiveEntryKind::Symlink(target) => {
+                check_symlink_target(&entry.path, target, &symlinks)?
+            }
+            ArchiveEntryKind::Hardlink(target) => check_contained_rel_path(target)?,
+            ArchiveEntryKind::Other => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_symlink_target(
+    symlink: &Path,
+    target: &Path,
+    symlinks: &HashSet<&Path>,
+) -> Result<(), Error> {
+    let escapes = || {
+        format_err!(
+            "Symlink {} points to {}, which is outside of the directory being extracted to.",
+            color_file(&symlink.display()),
+            color_file(&target.display())
+        )
+    };
+    let mut resolved = symlink
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf();
+    for component in target.components() {
+        if symlinks.contains(resolved.as_path()) {
+            bail!(
+                "Symlink {} points to {}, which passes through symlink {}.",
+                color_file(&symlink.display()),
+                color_file(&target.display()),
+                color_file(&resolved.display())
+            );
+        }
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return Err(escapes());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: ArchiveEntryKind) -> ArchiveEntry {
+        ArchiveEntry {
+            path: PathBuf::from(path),
+            kind,
+        }
+    }
+
+    fn symlink(path: &str, target: &str) -> ArchiveEntry {
+        entry(path, ArchiveEntryKind::Symlink(PathBuf::from(target)))
+    }
+
+    #[test]
+    fn parses_c_quoted_strings() {
+        assert_eq!(
+            parse_c_quoted(r#""a\"b\\c\101\n\0" rest"#),
+            Some((b"a\"b\\cA\n\0".to_vec(), " rest"))
+        );
+        assert_eq!(
+            parse_c_quoted(r#""\303\251""#),
+            Some(("é".as_bytes().to_vec(), ""))
+        );
+        assert_eq!(parse_c_quoted(r#""unterminated"#), None);
+        assert_eq!(parse_c_quoted("unquoted"), None);
+    }
+
+    #[test]
+    fn parses_listing_lines() {
+        assert_eq!(
+            parse_listing_line(r#"lrwxrwxrwx u/g 0 2020-01-01 00:00 "./a b" -> "../c""#),
+            Some(symlink("a b", "../c"))
+        );
+        assert_eq!(
+            parse_listing_line(r#"hrw-r--r-- u/g 0 2020-01-01 00:00 "./x" link to "./d/y""#),
+            Some(entry("x", ArchiveEntryKind::Hardlink(PathBuf::from("d/y"))))
+        );
+        assert_eq!(
+            parse_listing_line(r#"-rw-r--r-- u/g 5 2020-01-01 00:00 "./d/f""#),
+            Some(entry("d/f", ArchiveEntryKind::Other))
+        );
+        assert_eq!(parse_listing_line("total 0"), None);
+    }
+
+    #[test]
+    fn checks_entries_stay_within_directory() {
+        let file = |path| entry(path, ArchiveEntryKind::Other);
+        let hardlink =
+            |path, target| entry(path, ArchiveEntryKind::Hardlink(PathBuf::from(target)));
+        let ok = |entries: &[ArchiveEntry]| check_entries(entries, false).is_ok();
+        assert!(ok(&[file("d"), file("d/f"), symlink("d/l", "../d/f")]));
+        assert!(ok(&[file("f"), hardlink("h", "f")]));
+        assert!(!ok(&[file("../f")]));
+        assert!(!ok(&[file("/f")]));
+        assert!(!ok(&[hardlink("h", "../f")]));
+        assert!(!ok(&[symlink("l", "..")]));
+        assert!(!ok(&[symlink("d/l", "../../f")]));
+        // Writing within a symlink, or resolving a target through one.
+        assert!(!ok(&[symlink("l", "d"), file("l/f")]));
+        assert!(!ok(&[symlink("l", "d"), symlink("m", "l/f")]));
+    }
+
+    #[test]
+    fn absolute_symlinks_need_to_be_allowed() {
+        let entries = [symlink("l", "/etc/passwd")];
+        assert!(check_entries(&entries, false).is_err());
+        assert!(check_entries(&entries, true).is_ok());
+        // Other checks still apply.
+        let entries = [symlink("l", "/etc"), entry("l/f", ArchiveEntryKind::Other)];
+        assert!(check_entries(&entries, true).is_err());
+    }
+
+    #[test]
+    fn ssh_destinations_are_validated() {
+        let destination =
+            SshDestination::new("ssh -p 2222", "backup@host", "/backups/a b.tar").unwrap();
+        let cmd = destination.command();
+        assert_eq!(cmd.get_program(), "ssh");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![
+                "-p",
+                "2222",
+                "--",
+                "backup@host",
+                &format!("cat > {}", shell_quote("/backups/a b.tar")),
+            ]
+        );
+        assert_eq!(destination.to_string(), "backup@host:/backups/a b.tar");
+        assert!(SshDestination::new(" ", "host", "/a.tar").is_err());
+        for host in &["", "-oProxyCommand=evil", "two hosts"] {
+            assert!(
+                SshDestination::new("ssh", host, "/a.tar").is_err(),
+                "{}",
+                host
+            );
+        }
+        for path in &["", "/backups/", "a\nb.tar", "a\0b.tar"] {
+            assert!(
+                SshDestination::new("ssh", "host", path).is_err(),
+                "{:?}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,987 @@
+use crate::archive::{self, Compression};
+use crate::colors::*;
+use crate::json::{self, Versioned};
+use crate::merge::copy_file;
+use crate::paths::{MzrDir, OvfsChangesDir, SnapName, TmpDir};
+use crate::utils::{check_contained_rel_path, ensure_dir, run_process};
+use crate::vfs::{EntryKind, Filesystem, RealFs};
+use failure::{Error, ResultExt};
+use nix::sys::stat::{mknod, Mode, SFlag};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::{CString, OsString};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, create_dir, create_dir_all, Metadata};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use walkdir::WalkDir;
+
+/// Classification of an entry within an overlayfs changes directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// A file, symlink, or other non-directory entry which was added or
+    /// modified.
+    File,
+    /// A directory which merges with the corresponding snapshot directory.
+    Dir,
+    /// A directory which hides the contents of the corresponding snapshot
+    /// directory.
+    OpaqueDir,
+    /// A marker indicating that the path was deleted.
+    Whiteout,
+}
+
+impl ChangeKind {
+    /// Classifies the entry at the specified path, yielding `None` if it does
+    /// not exist.
+    pub fn of(path: &Path) -> Result<Option<ChangeKind>, Error> {
+        ChangeKind::of_in(&RealFs, path)
+    }
+
+    pub fn of_in(fs: &dyn Filesystem, path: &Path) -> Result<Option<ChangeKind>, Error> {
+        // Note that symlinks are not followed, since they could point anywhere.
+        let stat = match fs.stat(path)? {
+            None => return Ok(None),
+            Some(stat) => stat,
+        };
+        Ok(Some(if stat.is_whiteout() {
+            ChangeKind::Whiteout
+        } else if stat.kind == EntryKind::Dir {
+            if is_opaque_dir_in(fs, path)? {
+                ChangeKind::OpaqueDir
+            } else {
+                ChangeKind::Dir
+            }
+        } else {
+            ChangeKind::File
+        }))
+    }
+
+    pub fn is_dir(self) -> bool {
+        self == ChangeKind::Dir || self == ChangeKind::OpaqueDir
+    }
+}
+
+/// overlayfs represents deletions as character devices with device number 0/0.
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// overlayfs marks directories as opaque via an extended attribute. When the
+/// mount is within a user namespace, the `user.` prefix is used instead of
+/// `trusted.`.
+pub fn is_opaque_dir(path: &Path) -> Result<bool, Error> {
+    is_opaque_dir_in(&RealFs, path)
+}
+
+fn is_opaque_dir_in(fs: &dyn Filesystem, path: &Path) -> Result<bool, Error> {
+    for attr in ["trusted.overlay.opaque", "user.overlay.opaque"].iter() {
+        if fs.get_xattr(path, attr)?.as_deref() == Some(b"y") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Reads an extended attribute without following symlinks, yielding `None` if
+/// it is not set or extended attributes are not supported.
+pub fn get_xattr(path: &Path, attr: &str) -> Result<Option<Vec<u8>>, Error> {
+    let path_cstring = CString::new(path.as_os_str().as_bytes())?;
+    let attr_cstring = CString::new(attr)?;
+    let mut buffer = vec![0u8; 256];
+    let len = unsafe {
+        libc::lgetxattr(
+            path_cstring.as_ptr(),
+            attr_cstring.as_ptr(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    if len < 0 {
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(err).context(format_err!(
+                "Failed to read {} extended attribute of {}",
+                attr,
+                color_file(&path.display())
+            ))?,
+        }
+    } else {
+        buffer.truncate(len as usize);
+        Ok(Some(buffer))
+    }
+}
+
+/*
+ * Listing whiteouts
+ */
+
+/// Deletion recorded in a changes directory.
+#[derive(Debug, Clone)]
+pub struct Whiteout {
+    /// Path which is deleted, relative to the changes directory.
+    pub rel_path: PathBuf,
+    pub kind: WhiteoutKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteoutKind {
+    /// The path was deleted.
+    Deleted,
+    /// The directory at the path hides the contents of the corresponding
+    /// snapshot directory.
+    Opaque,
+}
+
+/// Prefix of whiteout files, used instead of character devices by aufs and by
+/// overlayfs when xattr based whiteouts are used.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the file which marks its directory as opaque, when whiteout files
+/// are used.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Lists the whiteouts in a changes directory, mapped to the paths that they
+/// delete. This includes both character device and `.wh.` file style
+/// whiteouts, as well as opaque directories.
+pub fn list_whiteouts(changes_dir: &OvfsChangesDir) -> Result<Vec<Whiteout>, Error> {
+    let mut result = Vec::new();
+    for walk_result in WalkDir::new(changes_dir)
+        .min_depth(1)
+        .same_file_system(true)
+    {
+        let entry = walk_result?;
+        let rel_path = changes_dir.work_relative_path(entry.path())?;
+        let name = entry.file_name().to_string_lossy();
+        if name == OPAQUE_MARKER {
+            result.push(Whiteout {
+                rel_path: rel_path.parent().map(PathBuf::from).unwrap_or_default(),
+                kind: WhiteoutKind::Opaque,
+            });
+        } else if let Some(deleted_name) = name.strip_prefix(WHITEOUT_PREFIX) {
+            result.push(Whiteout {
+                rel_path: rel_path.with_file_name(deleted_name),
+                kind: WhiteoutKind::Deleted,
+            });
+        } else {
+            match ChangeKind::of(entry.path())? {
+                Some(ChangeKind::Whiteout) => result.push(Whiteout {
+                    rel_path,
+                    kind: WhiteoutKind::Deleted,
+                }),
+                Some(ChangeKind::OpaqueDir) => result.push(Whiteout {
+                    rel_path,
+                    kind: WhiteoutKind::Opaque,
+                }),
+                _ => {}
+            }
+        }
+    }
+    Ok(result)
+}
+
+/*
+ * Querying the state of a path
+ */
+
+/// How a path in a zone differs from the zone's snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathChange {
+    /// Neither the zone nor the snapshot has the path.
+    Absent,
+    /// The zone has the path unmodified from the snapshot.
+    Unchanged,
+    /// The zone has the path, but the snapshot doesn't.
+    Added,
+    /// The path was replaced or modified in the zone.
+    Modified,
+    /// The path is a directory from the snapshot, which has changes within it.
+    ContainsChanges,
+    /// The path was deleted in the zone, or it's hidden by a deleted or
+    /// opaque parent directory.
+    Deleted,
+}
+
+impl Display for PathChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        let description = match self {
+            PathChange::Absent => "not present",
+            PathChange::Unchanged => "unchanged",
+            PathChange::Added => "added",
+            PathChange::Modified => "modified",
+            PathChange::ContainsChanges => "a directory containing changes",
+            PathChange::Deleted => "deleted",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// State of a path in a zone, along with the entry in the changes directory
+/// which determines it, if any.
+#[derive(Debug, Clone)]
+pub struct PathState {
+    pub change: PathChange,
+    pub changes_entry: Option<PathBuf>,
+}
+
+/// Determines how a path, relative to the zone's working directory, differs
+/// between the zone's changes and its snapshot.
+pub fn path_state(
+    changes_dir: &OvfsChangesDir,
+    snap_dir: &Path,
+    rel_path: &Path,
+) -> Result<PathState, Error> {
+    check_contained_rel_path(rel_path)?;
+    let in_snapshot = fs::symlink_metadata(snap_dir.join(rel_path)).is_ok();
+    let state = |change, changes_entry: Option<&Path>| PathState {
+        change,
+        changes_entry: changes_entry.map(PathBuf::from),
+    };
+    let deleted_or_absent = if in_snapshot {
+        PathChange::Deleted
+    } else {
+        PathChange::Absent
+    };
+    // Parent directories which were deleted or replaced by files hide the
+    // path entirely, while opaque directories only hide the snapshot's
+    // version of it.
+    let mut hiding_dir = None;
+    let mut ancestor = PathBuf::new();
+    for component in rel_path.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        let entry = changes_dir.join(&ancestor);
+        if let Some(whiteout_file) = whiteout_file(&entry) {
+            return Ok(state(deleted_or_absent, Some(&whiteout_file)));
+        }
+        match ChangeKind::of(&entry)? {
+            None => {}
+            Some(ChangeKind::Whiteout) | Some(ChangeKind::File) => {
+                return Ok(state(deleted_or_absent, Some(&entry)));
+            }
+            Some(ChangeKind::OpaqueDir) => hiding_dir = Some(entry),
+            Some(ChangeKind::Dir) if entry.join(OPAQUE_MARKER).exists() => hiding_dir = Some(entry),
+            Some(ChangeKind::Dir) => {}
+        }
+    }
+    let entry = changes_dir.join(rel_path);
+    if let Some(whiteout_file) = whiteout_file(&entry) {
+        return Ok(state(deleted_or_absent, Some(&whiteout_file)));
+    }
+    Ok(match ChangeKind::of(&entry)? {
+        None => match hiding_dir {
+            Some(hiding_dir) => state(deleted_or_absent, Some(&hiding_dir)),
+            None if in_snapshot => state(PathChange::Unchanged, None),
+            None => state(PathChange::Absent, None),
+        },
+        Some(ChangeKind::Whiteout) => state(deleted_or_absent, Some(&entry)),
+        Some(_) if !in_snapshot || hiding_dir.is_some() => state(PathChange::Added, Some(&entry)),
+        Some(ChangeKind::Dir) => state(PathChange::ContainsChanges, Some(&entry)),
+        Some(ChangeKind::File) | Some(ChangeKind::OpaqueDir) => {
+            state(PathChange::Modified, Some(&entry))
+        }
+    })
+}
+
+/// Finds the `.wh.` file style whiteout for the path, if there is one.
+fn whiteout_file(path: &Path) -> Option<PathBuf> {
+    let mut whiteout_name = OsString::from(WHITEOUT_PREFIX);
+    whiteout_name.push(path.file_name()?);
+    let whiteout_file = path.parent()?.join(whiteout_name);
+    if fs::symlink_metadata(&whiteout_file).is_ok() {
+        Some(whiteout_file)
+    } else {
+        None
+    }
+}
+
+/*
+ * Applying changes to a directory tree
+ */
+
+/// Applies the changes to a directory tree, such as a copy of the zone's
+/// snapshot, so that the tree matches the zone's merged view. Whiteouts are
+/// interpreted as deletions, and opaque directories replace the directories
+/// they hide.
+pub fn apply_to_tree(changes_dir: &OvfsChangesDir, tree: &Path) -> Result<(), Error> {
+    apply_to_tree_in(&RealFs, changes_dir, tree)
+}
+
+/// Like `apply_to_tree`, but operating on the specified filesystem.
+pub fn apply_to_tree_in(fs: &dyn Filesystem, changes_dir: &Path, tree: &Path) -> Result<(), Error> {
+    for name in fs.read_dir(changes_dir)? {
+        let source = changes_dir.join(&name);
+        let target = tree.join(&name);
+        match ChangeKind::of_in(fs, &source)? {
+            // Entry was removed while walking.
+            None => {}
+            Some(ChangeKind::Whiteout) => remove_path(fs, &target)?,
+            Some(ChangeKind::File) => {
+                remove_path(fs, &target)?;
+                fs.copy_file(&source, &target)?;
+            }
+            Some(kind) => {
+                let target_is_dir = match fs.stat(&target)? {
+                    Some(stat) => stat.kind == EntryKind::Dir,
+                    None => false,
+                };
+                if kind == ChangeKind::OpaqueDir || !target_is_dir {
+                    remove_path(fs, &target)?;
+                    let mode = match fs.stat(&source)? {
+                        Some(stat) => stat.mode,
+                        // Removed while walking.
+                        None => continue,
+                    };
+                    fs.create_dir(&target, mode)?;
+                }
+                apply_to_tree_in(fs, &source, &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file or directory, doing nothing if it doesn't exist.
+fn remove_path(fs: &dyn Filesystem, path: &Path) -> Result<(), Error> {
+    match fs.stat(path)? {
+        None => Ok(()),
+        Some(stat) => {
+            if stat.kind == EntryKind::Dir {
+                fs.remove_dir_all(path)
+            } else {
+                fs.remove_file(path)
+            }
+        }
+    }
+}
+
+/*
+ * Seeding changes directories
+ */
+
+/// Populates a new changes directory with the contents of a directory or tar
+/// archive of modifications. This allows distributing changes which can be
+/// applied atop a shared snapshot.
+pub fn seed(changes_dir: &OvfsChangesDir, seed: &Path) -> Result<(), Error> {
+    if seed.is_dir() {
+        seed_from_dir(changes_dir, seed)
+    } else if seed.is_file() {
+        seed_from_tar(changes_dir, seed)
+    } else {
+        bail!(
+            "Expected {} to be a directory or tar archive of changes.",
+            color_file(&seed.display())
+        )
+    }
+}
+
+fn seed_from_dir(changes_dir: &OvfsChangesDir, seed_dir: &Path) -> Result<(), Error> {
+    archive::check_dir(seed_dir, false).context(format_err!(
+        "Refusing to use {} as zone changes, due to invalid entry.",
+        color_file(&seed_dir.display())
+    ))?;
+    run_process(
+        Command::new("cp")
+            .stdin(Stdio::null())
+            // Preserve all file properties, and preserve symlinks.
+            .arg("--archive")
+            .arg("--reflink=auto")
+            .arg("--no-dereference")
+            // Copy the contents of the seed dir into the changes dir, rather
+            // than copying the seed dir into the changes dir.
+            .arg("--no-target-directory")
+            .arg(seed_dir)
+            .arg(changes_dir.as_path()),
+    )
+}
+
+fn seed_from_tar(changes_dir: &OvfsChangesDir, tar_file: &Path) -> Result<(), Error> {
+    archive::extract(tar_file, changes_dir, false).context(format_err!(
+        "Failed to use {} as zone changes.",
+        color_file(&tar_file.display())
+    ))?;
+    Ok(())
+}
+
+/*
+ * Copying changes between zones
+ */
+
+/// Plan for copying the changes from one changes directory into another.
+pub struct CopyChangesPlan {
+    /// Paths which will be copied, relative to the changes directories. When
+    /// the path is a directory, its contents are copied along with it.
+    pub copies: Vec<PathBuf>,
+    /// Paths which have been changed in both the source and target, relative to
+    /// the changes directories.
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl CopyChangesPlan {
+    /// Copies the changes. overlayfs doesn't support modification of the upper
+    /// dir while it is mounted, so the target zone must not be mounted.
+    pub fn apply(&self, source: &OvfsChangesDir, target: &OvfsChangesDir) -> Result<(), Error> {
+        for rel_path in &self.copies {
+            let target_path = target.join(rel_path);
+            if let Some(parent) = target_path.parent() {
+                create_dir_all(parent).context(format_err!(
+                    "Unexpected error while creating directory {}",
+                    color_dir(&parent.display())
+                ))?;
+            }
+            copy_file(&source.join(rel_path), &target_path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Enumerates the changes in `source`, including whiteouts, and determines
+/// which can be copied to `target` without clobbering any of its changes.
+pub fn plan_copy_changes(
+    source: &OvfsChangesDir,
+    target: &OvfsChangesDir,
+) -> Result<CopyChangesPlan, Error> {
+    let mut copies = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut walker = WalkDir::new(source)
+        .min_depth(1)
+        .same_file_system(true)
+        .into_iter();
+    while let Some(walk_result) = walker.next() {
+        let entry = walk_result?;
+        let rel_path = source.work_relative_path(entry.path())?;
+        let source_kind = match ChangeKind::of(entry.path())? {
+            // Entry was removed while walking.
+            None => continue,
+            Some(kind) => kind,
+        };
+        match ChangeKind::of(&target.join(&rel_path))? {
+            None => {
+                copies.push(rel_path);
+                // Directory gets copied along with its contents.
+                if source_kind.is_dir() {
+                    walker.skip_current_dir();
+                }
+            }
+            // Non-opaque directories in both are merged, so the contents are
+            // compared.
+            Some(ChangeKind::Dir) if source_kind == ChangeKind::Dir => {}
+            Some(_) => {
+                conflicts.push(rel_path);
+                if source_kind.is_dir() {
+                    walker.skip_current_dir();
+                }
+            }
+        }
+    }
+    Ok(CopyChangesPlan { copies, conflicts })
+}
+
+/*
+ * Changes archives
+ */
+
+/// Manifest stored in changes archives, alongside the added and modified files.
+/// Deletions are listed here rather than archived as whiteouts, since
+/// whiteouts are device files and extended attributes, which don't survive
+/// being archived and extracted by an unprivileged user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangesManifest {
+    /// Version of the format of this manifest, see `json::Versioned`.
+    pub version: u32,
+    /// Snapshot which the changes were made atop.
+    pub base_snapshot: SnapName,
+    /// Paths which were deleted.
+    pub deleted: Vec<PathBuf>,
+    /// Directories which hide the contents of the corresponding snapshot
+    /// directory.
+    pub opaque: Vec<PathBuf>,
+}
+
+impl Versioned for ChangesManifest {
+    const VERSION: u32 = 1;
+
+    fn migrate(version: u32, _contents: Value) -> Result<Value, Error> {
+        bail!(
+            "Unexpected error: no migration from changes manifest version {}",
+            version
+        )
+    }
+}
+
+/// Name of the manifest within changes archives.
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Name of the directory containing added and modified files within changes
+/// archives.
+const ARCHIVE_FILES_DIR: &str = "files";
+
+/// Writes the changes of a zone to an archive, which can be applied to another
+/// zone based on the same snapshot, even on another machine.
+pub fn export_archive(
+    mzr_dir: &MzrDir,
+    changes_dir: &OvfsChangesDir,
+    base_snapshot: &SnapName,
+    archive_path: &Path,
+    compression: Compression,
+) -> Result<(), Error> {
+    let mut files = Vec::new();
+    let mut manifest = ChangesManifest {
+        version: ChangesManifest::VERSION,
+        base_snapshot: base_snapshot.clone(),
+        deleted: Vec::new(),
+        opaque: Vec::new(),
+    };
+    for whiteout in list_whiteouts(changes_dir)? {
+        match whiteout.kind {
+            WhiteoutKind::Deleted => manifest.deleted.push(whiteout.rel_path),
+            WhiteoutKind::Opaque => manifest.opaque.push(whiteout.rel_path),
+        }
+    }
+    for walk_result in WalkDir::new(changes_dir)
+        .min_depth(1)
+        .same_file_system(true)
+    {
+        let entry = walk_result?;
+        let name = entry.file_name().to_string_lossy();
+        if name.starts_with(WHITEOUT_PREFIX) || is_whiteout(&entry.metadata()?) {
+            continue;
+        }
+        files.push(changes_dir.work_relative_path(entry.path())?);
+    }
+    with_staging_dir(mzr_dir, "changes-export", |stage_dir| {
+        json::write(&stage_dir.join(MANIFEST_NAME), &manifest)?;
+        // Names are NUL separated, since paths can contain newlines.
+        let mut file_list = Vec::new();
+        for rel_path in &files {
+            file_list.extend_from_slice(Path::new(".").join(rel_path).as_os_str().as_bytes());
+            file_list.push(0);
+        }
+        let file_list_path = stage_dir.join("files.list");
+        fs::write(&file_list_path, file_list)?;
+        let mut cmd = Command::new("tar");
+        cmd.stdin(Stdio::null())
+            .arg("--create")
+            .arg("--file")
+            .arg(archive_path)
+            .arg("--no-recursion")
+            // Files from the changes dir are put in a subdirectory. The "S"
+            // flag causes symlink targets to be left alone.
+            .arg(format!("--transform=s,^\\./,{}/,S", ARCHIVE_FILES_DIR))
+            .arg("--directory")
+            .arg(changes_dir.as_path())
+            .arg("--null")
+            .arg("--files-from")
+            .arg(&file_list_path)
+            .arg("--directory")
+            .arg(stage_dir)
+            .arg(MANIFEST_NAME);
+        compression.add_tar_args(&mut cmd, false);
+        run_process(&mut cmd)
+    })
+}
+
+/// Applies a changes archive to a zone's changes directory. The zone must be
+/// based on the same snapshot as the zone the changes were exported from, and
+/// the changes must not conflict with the zone's changes.
+pub fn apply_archive(
+    mzr_dir: &MzrDir,
+    changes_dir: &OvfsChangesDir,
+    base_snapshot: &SnapName,
+    archive_path: &Path,
+) -> Result<CopyChangesPlan, Error> {
+    with_staging_dir(mzr_dir, "changes-apply", |stage_dir| {
+        archive::extract(archive_path, stage_dir, false)?;
+        let manifest_path = stage_dir.join(MANIFEST_NAME);
+        if !manifest_path.exists() {
+            bail!(
+                "{} is not a changes archive, since it has no {}.",
+                color_file(&archive_path.display()),
+                MANIFEST_NAME
+            );
+        }
+        let manifest: ChangesManifest = json::read_versioned(&manifest_path)?;
+        if &manifest.base_snapshot != base_snapshot {
+            bail!(
+                "Changes in {} were made atop the {} snapshot, but the zone is based on {}.",
+                color_file(&archive_path.display()),
+                manifest.base_snapshot,
+                base_snapshot
+            );
+        }
+        // Recreate the deletions within the extracted files, so that it's
+        // the same as the original changes directory.
+        let staged = OvfsChangesDir::staged(&stage_dir.join(ARCHIVE_FILES_DIR));
+        ensure_dir(&staged)?;
+        for rel_path in &manifest.deleted {
+            check_contained_rel_path(rel_path)?;
+            create_whiteout(&staged.join(rel_path))?;
+        }
+        for rel_path in &manifest.opaque {
+            check_contained_rel_path(rel_path)?;
+            set_opaque(&staged.join(rel_path))?;
+        }
+        let plan = plan_copy_changes(&staged, changes_dir)?;
+        if !plan.conflicts.is_empty() {
+            return Ok(plan);
+        }
+        plan.apply(&staged, changes_dir)?;
+        Ok(plan)
+    })
+}
+
+/// Runs the function with a fresh temporary directory within the mzr
+/// directory, removing it afterwards.
+pub fn with_staging_dir<T, F>(mzr_dir: &MzrDir, name: &str, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&Path) -> Result<T, Error>,
+{
+    let stage_dir = TmpDir::new(mzr_dir).join(format!("{}-{}", name, process::id()));
+    if stage_dir.exists() {
+        fs::remove_dir_all(&stage_dir)?;
+    }
+    ensure_dir(&stage_dir)?;
+    let result = f(&stage_dir);
+    let remove_result = fs::remove_dir_all(&stage_dir).context(format_err!(
+        "Failed to remove temporary directory {}",
+        color_dir(&stage_dir.display())
+    ));
+    let value = result?;
+    remove_result?;
+    Ok(value)
+}
+
+/// Creates an overlayfs whiteout, which is a character device with device
+/// number 0/0.
+pub fn create_whiteout(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        ensure_dir(parent)?;
+    }
+    mknod(path, SFlag::S_IFCHR, Mode::empty(), 0).context(format_err!(
+        "Failed to create whiteout {}",
+        color_file(&path.display())
+    ))?;
+    Ok(())
+}
+
+/// Marks a directory as opaque. The `trusted.` attribute requires privileges,
+/// so when it can't be set the `user.` attribute used by overlayfs mounts
+/// within user namespaces is set instead.
+fn set_opaque(path: &Path) -> Result<(), Error> {
+    ensure_dir(path)?;
+    let result = set_xattr(path, "trusted.overlay.opaque", b"y");
+    match result {
+        Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
+            set_xattr(path, "user.overlay.opaque", b"y")
+        }
+        _ => result,
+    }
+    .context(format_err!(
+        "Failed to mark directory {} as opaque",
+        color_dir(&path.display())
+    ))?;
+    Ok(())
+}
+
+fn set_xattr(path: &Path, attr: &str, value: &[u8]) -> Result<(), std::io::Error> {
+    let path_cstring = CString::new(path.as_os_str().as_bytes())?;
+    let attr_cstring = CString::new(attr)?;
+    let result = unsafe {
+        libc::lsetxattr(
+            path_cstring.as_ptr(),
+            attr_cstring.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::SnapDir;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, TestDir};
+    use std::os::unix::fs::symlink;
+
+    fn sorted(paths: &[PathBuf]) -> Vec<String> {
+        let mut result: Vec<String> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn copy_changes_applies_cleanly() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[("deleted", "")]);
+        let source = test_zone(
+            &mzr_dir,
+            "source",
+            &snap_name,
+            &[("added", "new"), ("dir/nested", "nested")],
+        );
+        create_whiteout(&source.ovfs_changes_dir.join("deleted")).unwrap();
+        let target = test_zone(&mzr_dir, "target", &snap_name, &[("other", "kept")]);
+        let plan = plan_copy_changes(&source.ovfs_changes_dir, &target.ovfs_changes_dir).unwrap();
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(sorted(&plan.copies), vec!["added", "deleted", "dir"]);
+        plan.apply(&source.ovfs_changes_dir, &target.ovfs_changes_dir)
+            .unwrap();
+        let target_changes = &target.ovfs_changes_dir;
+        let read = |path: &str| fs::read_to_string(target_changes.join(path)).unwrap();
+        assert_eq!(read("added"), "new");
+        assert_eq!(read("dir/nested"), "nested");
+        assert_eq!(read("other"), "kept");
+        assert_eq!(
+            ChangeKind::of(&target_changes.join("deleted")).unwrap(),
+            Some(ChangeKind::Whiteout)
+        );
+    }
+
+    #[test]
+    fn copy_changes_detects_conflicts() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let source = test_zone(
+            &mzr_dir,
+            "source",
+            &snap_name,
+            &[
+                ("merged/added", "source"),
+                ("modified", "source"),
+                ("replaced/file", "source"),
+            ],
+        );
+        create_whiteout(&source.ovfs_changes_dir.join("deleted")).unwrap();
+        let target = test_zone(
+            &mzr_dir,
+            "target",
+            &snap_name,
+            &[
+                ("merged/other", "target"),
+                ("modified", "target"),
+                ("deleted", "target"),
+            ],
+        );
+        create_whiteout(&target.ovfs_changes_dir.join("replaced")).unwrap();
+        let plan = plan_copy_changes(&source.ovfs_changes_dir, &target.ovfs_changes_dir).unwrap();
+        assert_eq!(sorted(&plan.copies), vec!["merged/added"]);
+        assert_eq!(
+            sorted(&plan.conflicts),
+            vec!["deleted", "modified", "replaced"]
+        );
+    }
+
+    #[test]
+    fn changes_archives_round_trip() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[("deleted", ""), ("opaque/hidden", "")]);
+        let source = test_zone(
+            &mzr_dir,
+            "source",
+            &snap_name,
+            &[
+                ("added", "new"),
+                ("dir/nested", "nested"),
+                ("opaque/shown", ""),
+            ],
+        );
+        let source_changes = &source.ovfs_changes_dir;
+        create_whiteout(&source_changes.join("deleted")).unwrap();
+        set_opaque(&source_changes.join("opaque")).unwrap();
+        symlink("added", source_changes.join("link")).unwrap();
+        let archive_path = dir.join("changes.tar");
+        export_archive(
+            &mzr_dir,
+            source_changes,
+            &snap_name,
+            &archive_path,
+            Compression::None,
+        )
+        .unwrap();
+        let target = test_zone(&mzr_dir, "target", &snap_name, &[("other", "kept")]);
+        let target_changes = &target.ovfs_changes_dir;
+        let plan = apply_archive(&mzr_dir, target_changes, &snap_name, &archive_path).unwrap();
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(
+            sorted(&plan.copies),
+            vec!["added", "deleted", "dir", "link", "opaque"]
+        );
+        let read = |path: &str| fs::read_to_string(target_changes.join(path)).unwrap();
+        assert_eq!(read("added"), "new");
+        assert_eq!(read("dir/nested"), "nested");
+        assert_eq!(read("other"), "kept");
+        assert_eq!(
+            fs::read_link(target_changes.join("link")).unwrap(),
+            PathBuf::from("added")
+        );
+        assert_eq!(
+            ChangeKind::of(&target_changes.join("deleted")).unwrap(),
+            Some(ChangeKind::Whiteout)
+        );
+        assert!(is_opaque_dir(&target_changes.join("opaque")).unwrap());
+        assert!(target_changes.join("opaque/shown").is_file());
+        // Applying the same changes again conflicts, and changes nothing.
+        let plan = apply_archive(&mzr_dir, target_changes, &snap_name, &archive_path).unwrap();
+        assert!(!plan.conflicts.is_empty());
+        assert_eq!(read("added"), "new");
+        // The changes only apply to zones based on the same snapshot.
+        let other_snap_name = test_snapshot(&mzr_dir, "s2", &[]);
+        let other = test_zone(&mzr_dir, "other", &other_snap_name, &[]);
+        assert!(apply_archive(
+            &mzr_dir,
+            &other.ovfs_changes_dir,
+            &other_snap_name,
+            &archive_path
+        )
+        .is_err());
+        assert!(fs::read_dir(&other.ovfs_changes_dir)
+            .unwrap()
+            .next()
+            .is_none());
+        let not_changes_path = dir.join("snapshot.tar");
+        archive::create(
+            &SnapDir::new(&mzr_dir, &snap_name),
+            &not_changes_path,
+            Compression::None,
+        )
+        .unwrap();
+        let err = apply_archive(&mzr_dir, target_changes, &snap_name, &not_changes_path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not a changes archive"));
+    }
+
+    #[test]
+    fn path_states_follow_changes() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(
+            &mzr_dir,
+            "s1",
+            &[
+                ("unchanged", ""),
+                ("modified", ""),
+                ("deleted", ""),
+                ("file_deleted", ""),
+                ("dir/kept", ""),
+                ("opaque/hidden", ""),
+                ("replaced/inner", ""),
+            ],
+        );
+        let zone = test_zone(
+            &mzr_dir,
+            "mz",
+            &snap_name,
+            &[
+                ("modified", "changed"),
+                ("added", "new"),
+                ("dir/added", "new"),
+                ("opaque/shown", "new"),
+                ("replaced", "file"),
+            ],
+        );
+        let changes_dir = &zone.ovfs_changes_dir;
+        create_whiteout(&changes_dir.join("deleted")).unwrap();
+        fs::write(changes_dir.join(".wh.file_deleted"), "").unwrap();
+        set_opaque(&changes_dir.join("opaque")).unwrap();
+        let query = |rel_path: &str| {
+            let state = path_state(changes_dir, &zone.snap_dir, Path::new(rel_path)).unwrap();
+            let entry = state.changes_entry.map(|entry| {
+                changes_dir
+                    .work_relative_path(&entry)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            });
+            (state.change, entry)
+        };
+        let entry = |rel_path: &str| Some(rel_path.to_string());
+        assert_eq!(query("missing"), (PathChange::Absent, None));
+        assert_eq!(query("unchanged"), (PathChange::Unchanged, None));
+        assert_eq!(query("added"), (PathChange::Added, entry("added")));
+        assert_eq!(query("modified"), (PathChange::Modified, entry("modified")));
+        assert_eq!(query("dir"), (PathChange::ContainsChanges, entry("dir")));
+        assert_eq!(query("dir/kept"), (PathChange::Unchanged, None));
+        assert_eq!(query("dir/added"), (PathChange::Added, entry("dir/added")));
+        assert_eq!(query("deleted"), (PathChange::Deleted, entry("deleted")));
+        assert_eq!(
+            query("file_deleted"),
+            (PathChange::Deleted, entry(".wh.file_deleted"))
+        );
+        assert_eq!(query("opaque"), (PathChange::Modified, entry("opaque")));
+        assert_eq!(
+            query("opaque/hidden"),
+            (PathChange::Deleted, entry("opaque"))
+        );
+        assert_eq!(
+            query("opaque/shown"),
+            (PathChange::Added, entry("opaque/shown"))
+        );
+        assert_eq!(
+            query("replaced/inner"),
+            (PathChange::Deleted, entry("replaced"))
+        );
+        assert_eq!(query("added/inner"), (PathChange::Absent, entry("added")));
+        assert!(path_state(changes_dir, &zone.snap_dir, Path::new("../escape")).is_err());
+        assert_eq!(
+            PathChange::ContainsChanges.to_string(),
+            "a directory containing changes"
+        );
+    }
+
+    #[test]
+    fn whiteouts_of_both_kinds_are_listed() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let zone = test_zone(
+            &mzr_dir,
+            "mz",
+            &snap_name,
+            &[
+                ("kept", ""),
+                ("dir/.wh.file_deleted", ""),
+                ("marked/.wh..wh..opq", ""),
+                ("marked/added", ""),
+            ],
+        );
+        let changes_dir = &zone.ovfs_changes_dir;
+        create_whiteout(&changes_dir.join("deleted")).unwrap();
+        create_whiteout(&changes_dir.join("dir/nested/deleted")).unwrap();
+        set_opaque(&changes_dir.join("opaque")).unwrap();
+        let mut whiteouts: Vec<(String, WhiteoutKind)> = list_whiteouts(changes_dir)
+            .unwrap()
+            .into_iter()
+            .map(|whiteout| (whiteout.rel_path.display().to_string(), whiteout.kind))
+            .collect();
+        whiteouts.sort_by(|x, y| x.0.cmp(&y.0));
+        let expected = |path: &str, kind| (path.to_string(), kind);
+        assert_eq!(
+            whiteouts,
+            vec![
+                expected("deleted", WhiteoutKind::Deleted),
+                expected("dir/file_deleted", WhiteoutKind::Deleted),
+                expected("dir/nested/deleted", WhiteoutKind::Deleted),
+                expected("marked", WhiteoutKind::Opaque),
+                expected("opaque", WhiteoutKind::Opaque),
+            ]
+        );
+        let empty = test_zone(&mzr_dir, "empty", &snap_name, &[("file", "")]);
+        assert!(list_whiteouts(&empty.ovfs_changes_dir).unwrap().is_empty());
+    }
+}
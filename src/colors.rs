@@ -1,39 +1,137 @@
+use failure::Error;
+use std::cell::Cell;
+use std::env;
 use yansi::Paint;
 
+/// When this environment variable is set, producing data output fails if the
+/// color functions were used while producing it. Since the `Display` impls of
+/// many types include colors, this helps catch colors leaking into output such
+/// as JSON.
+const STRICT_PLAIN_VAR: &str = "MZR_STRICT_PLAIN";
+
+thread_local! {
+    static IN_DATA_OUTPUT: Cell<bool> = const { Cell::new(false) };
+    static COLOR_IN_DATA_OUTPUT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs a function which produces data output, such as JSON, which must not
+/// contain colors. See `STRICT_PLAIN_VAR`.
+pub fn data_output<F, T, E>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, E>,
+    Error: From<E>,
+{
+    data_output_impl(env::var_os(STRICT_PLAIN_VAR).is_some(), f)
+}
+
+fn data_output_impl<F, T, E>(strict: bool, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, E>,
+    Error: From<E>,
+{
+    if !strict {
+        return Ok(f()?);
+    }
+    let outer = IN_DATA_OUTPUT.with(|flag| flag.replace(true));
+    let outer_colored = COLOR_IN_DATA_OUTPUT.with(|flag| flag.replace(false));
+    let result = f();
+    IN_DATA_OUTPUT.with(|flag| flag.set(outer));
+    let colored = COLOR_IN_DATA_OUTPUT.with(Cell::get);
+    // Colors within nested data output also taint the outer data output.
+    COLOR_IN_DATA_OUTPUT.with(|flag| flag.set(outer_colored || (outer && colored)));
+    if colored {
+        bail!(
+            "Color was used while producing data output ({} is set).",
+            STRICT_PLAIN_VAR
+        );
+    }
+    Ok(result?)
+}
+
+fn check_plain() {
+    if IN_DATA_OUTPUT.with(Cell::get) {
+        COLOR_IN_DATA_OUTPUT.with(|flag| flag.set(true));
+    }
+}
+
 pub fn color_dir<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::blue(x).bold()
 }
 
 pub fn color_file<T>(x: &T) -> Paint<&T> {
+    check_plain();
     // TODO(cleanup): different color than color_dir?
     Paint::blue(x).bold()
 }
 
 pub fn color_zone_pid<T>(x: &T) -> Paint<&T> {
+    check_plain();
     // TODO(cleanup): different color than color_zone_name?
     Paint::yellow(x).bold()
 }
 
 pub fn color_zone_name<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::yellow(x).bold()
 }
 
 pub fn color_snap_name<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::cyan(x).bold()
 }
 
 pub fn color_err<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::red(x).bold()
 }
 
 pub fn color_warn<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::yellow(x).bold()
 }
 
 pub fn color_success<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::green(x).bold()
 }
 
 pub fn color_cmd<T>(x: &T) -> Paint<&T> {
+    check_plain();
     Paint::purple(x).bold()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_json(value: &str) -> Result<String, serde_json::Error> {
+        serde_json::to_string(value)
+    }
+
+    #[test]
+    fn strict_plain_rejects_colors_in_data_output() {
+        let colored = || to_json(&color_dir(&"dir").to_string());
+        assert!(data_output_impl(true, colored).is_err());
+        assert!(data_output_impl(false, colored).is_ok());
+        assert_eq!(
+            data_output_impl(true, || to_json("dir")).unwrap(),
+            "\"dir\""
+        );
+        // Colors outside of data output are fine, and don't taint later data
+        // output.
+        color_dir(&"dir");
+        assert!(data_output_impl(true, || to_json("dir")).is_ok());
+    }
+
+    #[test]
+    fn strict_plain_rejects_colors_in_nested_data_output() {
+        let result = data_output_impl(true, || {
+            let inner = data_output_impl(true, || to_json(&color_zone_name(&"mz").to_string()));
+            assert!(inner.is_err());
+            to_json("plain")
+        });
+        assert!(result.is_err());
+        assert!(data_output_impl(true, || to_json("plain")).is_ok());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::mount::{MountOptions, RetryPolicy, SelinuxContext, OVERLAY_MAX_STACK};
+use crate::paths::{ConfigFile, SnapName, UserWorkDir};
+use failure::{Error, ResultExt};
+use nix::sched::CloneFlags;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use yansi::Paint;
+
+/// Settings loaded from the optional `mzr.toml` file in the user's work
+/// directory. Every setting is optional, and a missing file is the same as an
+/// empty one.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Snapshot to use when none is specified, instead of one named after
+    /// the current git branch.
+    pub default_snapshot: Option<SnapName>,
+    /// Absolute path of a directory to store snapshots in, such as one on a
+    /// larger disk. The mzr directory's `snap` directory is made a symlink to
+    /// it. When unset, snapshots are stored within the mzr directory.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Whether to use colors in output.
+    pub color: ColorChoice,
+    /// Additional namespaces for the daemon to unshare, beyond the user and
+    /// mount namespaces. Zone processes inherit these.
+    pub unshare: Vec<Namespace>,
+    /// Mode for the mzr, zone, and snapshot directories that mzr creates, as an
+    /// octal string like `"0775"`. When unset, the umask determines their mode,
+    /// except that snapshots keep the mode of the directory they're taken of.
+    pub dir_mode: Option<DirMode>,
+    /// What to do when a zone target directory is not empty, since mounting
+    /// the zone shadows its contents. Defaults to `"warn"`.
+    pub non_empty_target: NonEmptyTargetPolicy,
+    /// SELinux context for zone overlay mounts, such as
+    /// `"system_u:object_r:container_file_t:s0"`. Needed on systems with
+    /// SELinux enforcing for processes to be able to access zone files.
+    pub selinux_context: Option<SelinuxContext>,
+    /// Whether to remove stale files from a zone's overlayfs work directory
+    /// before mounting it. Defaults to `true`.
+    pub clean_work_dir: Option<bool>,
+    /// Whether to make the daemon's mounts private before mounting zones, so
+    /// that zone mounts can't leak to the host. Defaults to `true`.
+    pub private_propagation: Option<bool>,
+    /// Whether `mzr unmount-all` re-checks the mount table after unmounting
+    /// each zone, to report zones which are still listed as mounted. Defaults
+    /// to `true`.
+    pub verify_unmount: Option<bool>,
+    /// Template for the names of snapshots taken without specifying a name,
+    /// like `"{zone}-{date}-{seq}"`. See `snapshot::expand_name_template`.
+    pub snap_name_template: Option<String>,
+    /// Maximum number of lowerdirs that a zone's overlay may stack. Defaults
+    /// to the kernel's limit of 500.
+    pub max_lowerdirs: Option<usize>,
+    /// Number of attempts at mounting a zone's overlay, when mounting fails
+    /// with a transient error such as `EAGAIN`. Defaults to 5.
+    pub mount_attempts: Option<u32>,
+    /// Delay in milliseconds before retrying a mount. This doubles for each
+    /// subsequent retry, up to a second. Defaults to 10.
+    pub mount_retry_backoff_ms: Option<u64>,
+    /// Whether mounting a zone records the time, kernel release and overlay
+    /// options in the zone's info file, for debugging. Defaults to `false`.
+    pub record_mounts: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirMode(pub u32);
+
+impl<'de> Deserialize<'de> for DirMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        match u32::from_str_radix(&input, 8) {
+            Ok(mode) if mode > 0 && mode <= 0o7777 => Ok(DirMode(mode)),
+            _ => Err(de::Error::custom(format!(
+                "expected an octal directory mode like \"0775\", but got {:?}",
+                input
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonEmptyTargetPolicy {
+    /// Ask whether to continue.
+    Prompt,
+    /// Continue after printing a warning.
+    #[default]
+    Warn,
+    /// Refuse to mount the zone.
+    Error,
+    /// Continue silently.
+    Proceed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    Ipc,
+    Net,
+    Uts,
+}
+
+impl Namespace {
+    fn clone_flag(self) -> CloneFlags {
+        match self {
+            Namespace::Ipc => CloneFlags::CLONE_NEWIPC,
+            Namespace::Net => CloneFlags::CLONE_NEWNET,
+            Namespace::Uts => CloneFlags::CLONE_NEWUTS,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(user_work_dir: &UserWorkDir) -> Result<Config, Error> {
+        let config_file = ConfigFile::new(user_work_dir);
+        if !config_file.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(&config_file)
+            .context(format_err!("Failed to read config file {}", config_file))?;
+        Ok(toml::from_str(&contents)
+            .context(format_err!("Failed to parse config file {}", config_file))?)
+    }
+
+    /// Applies the color setting to all subsequent output.
+    pub fn apply_color(&self) {
+        match self.color {
+            ColorChoice::Auto => {}
+            ColorChoice::Always => Paint::enable(),
+            ColorChoice::Never => Paint::disable(),
+        }
+    }
+
+    /// Options for mounting zone overlays.
+    pub fn mount_options(&self) -> MountOptions {
+        let default_retry = RetryPolicy::default();
+        MountOptions {
+            selinux_context: self.selinux_context.clone(),
+            clean_work_dir: self.clean_work_dir.unwrap_or(true),
+            private_propagation: self.private_propagation.unwrap_or(true),
+            max_lowerdirs: self.max_lowerdirs.unwrap_or(OVERLAY_MAX_STACK),
+            retry: RetryPolicy {
+                max_attempts: self.mount_attempts.unwrap_or(default_retry.max_attempts),
+                initial_backoff: self
+                    .mount_retry_backoff_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default_retry.initial_backoff),
+                ..default_retry
+            },
+            record_mount: self.record_mounts.unwrap_or(false),
+            ..MountOptions::default()
+        }
+    }
+
+    pub fn clone_flags(&self) -> CloneFlags {
+        self.unshare
+            .iter()
+            .fold(CloneFlags::empty(), |flags, ns| flags | ns.clone_flag())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sample_config() {
+        let config: Config = toml::from_str(
+            r#"
+            default_snapshot = "base"
+            snapshot_dir = "/big/disk/snaps"
+            color = "never"
+            unshare = ["ipc", "uts"]
+            dir_mode = "0775"
+            non_empty_target = "error"
+            clean_work_dir = false
+            max_lowerdirs = 10
+            mount_attempts = 3
+            mount_retry_backoff_ms = 50
+            record_mounts = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.default_snapshot,
+            Some(SnapName::new("base".to_string()).unwrap())
+        );
+        assert_eq!(config.snapshot_dir, Some(PathBuf::from("/big/disk/snaps")));
+        assert_eq!(config.color, ColorChoice::Never);
+        assert_eq!(
+            config.clone_flags(),
+            CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWUTS
+        );
+        assert_eq!(config.dir_mode, Some(DirMode(0o775)));
+        assert_eq!(config.non_empty_target, NonEmptyTargetPolicy::Error);
+        let options = config.mount_options();
+        assert!(!options.clean_work_dir);
+        assert!(options.private_propagation);
+        assert_eq!(options.max_lowerdirs, 10);
+        assert_eq!(options.retry.max_attempts, 3);
+        assert_eq!(options.retry.initial_backoff, Duration::from_millis(50));
+        assert!(options.record_mount);
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.default_snapshot, None);
+        assert_eq!(config.snapshot_dir, None);
+        assert_eq!(config.color, ColorChoice::Auto);
+        assert_eq!(config.clone_flags(), CloneFlags::empty());
+        assert_eq!(config.dir_mode, None);
+        assert_eq!(config.non_empty_target, NonEmptyTargetPolicy::Warn);
+        let options = config.mount_options();
+        assert!(options.clean_work_dir);
+        assert!(options.private_propagation);
+        assert_eq!(options.max_lowerdirs, OVERLAY_MAX_STACK);
+        assert!(!options.record_mount);
+        assert_eq!(
+            options.retry.max_attempts,
+            RetryPolicy::default().max_attempts
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(toml::from_str::<Config>("dir_mode = \"999\"").is_err());
+        assert!(toml::from_str::<Config>("unknown_setting = true").is_err());
+    }
+}
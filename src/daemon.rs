@@ -1,21 +1,30 @@
 use crate::colors::*;
 use crate::git::{get_git_dir, symlink_git_repo};
-use crate::namespaces;
+use crate::json;
+use crate::mount::{
+    self, is_mounted, make_mounts_private, mount_binds, unmount, unmount_binds, BindSpec,
+    SyscallBackend, Unmounted, ZoneUnmountResults,
+};
+use crate::namespaces::{self, UserMapping};
 use crate::paths::*;
 use crate::top_dirs::TopDirs;
-use crate::zone::Zone;
+use crate::utils::{confirm, is_process_alive, parse_pid_file, Confirmed};
+use crate::zone::{self, did_you_mean_zone, Zone};
 use daemonize::Daemonize;
-use failure::{Error, ResultExt};
+use failure::{err_msg, Error, ResultExt};
 use libc::pid_t;
 use libmount::BindMount;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
 use nix::unistd::{Gid, Pid, Uid};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
-use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::fs::{self, create_dir_all, read_dir, read_link, remove_file, File};
 use std::io::{BufRead, BufReader, Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time;
 use yansi::Paint;
@@ -42,62 +51,98 @@ impl Display for ZonePid {
     }
 }
 
-type ProcessMap = HashMap<ZoneName, ZonePid>;
+/// Zone processes, keyed by zone name and the directory the zone is bound to.
+/// `None` indicates that it is bound to the user's work directory.
+type ProcessMap = HashMap<(ZoneName, Option<ZoneTargetDir>), ZoneProcess>;
 
-pub fn run(top_dirs: &TopDirs) -> Result<(), Error> {
+/// A forked zone process, along with the extra bind mounts made within it.
+#[derive(Clone, Debug)]
+struct ZoneProcess {
+    pid: ZonePid,
+    binds: Vec<BindSpec>,
+}
+
+/// What the daemon has set up, which it tracks so that it can be reused and
+/// cleaned up.
+#[derive(Default)]
+struct DaemonState {
+    /// Zones whose overlays are mounted in the daemon's mount namespace. These
+    /// stay mounted after their zone processes exit.
+    mounted_zones: Vec<ZoneName>,
+    processes: ProcessMap,
+}
+
+/// How long the daemon may take to start, which includes setting up its
+/// mounts, before giving up on waiting for it.
+const DAEMON_START_TIMEOUT: time::Duration = time::Duration::from_secs(120);
+
+pub fn run(top_dirs: &TopDirs, mapping: UserMapping) -> Result<(), Error> {
     let user = Uid::current();
     let group = Gid::current();
-    let _pid = namespaces::with_unshared_user_and_mount(
-        |child_process| namespaces::map_user_to_root(child_process, user, group),
-        || {
-            let daemon_dir = DaemonDir::new(&top_dirs.mzr_dir);
-            create_dir_all(&daemon_dir)?;
-            let git_info = bind_git_repo(top_dirs)?;
-            // TODO(cleanup): Don't truncate old daemon logs?
-            let log_stdout_file = File::create(DaemonLogStdoutFile::new(&daemon_dir))?;
-            let log_stderr_file = File::create(DaemonLogStderrFile::new(&daemon_dir))?;
-            Daemonize::new()
-                .pid_file(DaemonPidFile::new(&daemon_dir))
-                // TODO(friendliness): Would be nice to merge
-                // these. Is stderr ever even used?
-                .stdout(log_stdout_file)
-                .stderr(log_stderr_file)
-                .start()?;
-            // Disable ANSI codes in output, since it's sent to a log
-            // rather than terminal.
-            Paint::disable();
-            // Listen for client connections.
-            let socket_path = DaemonSocketFile::new(&daemon_dir);
-            if socket_path.exists() {
-                remove_file(&socket_path).context(format_err!(
-                    "Failed to remove daemon socket file {}",
-                    socket_path
-                ))?;
-            }
-            // Mutable hashmap to track which child processes have been
-            // created.
-            let mut processes = HashMap::new();
-            // Listen for client connections. In the future, perhaps tokio
-            // or mio will be used, but for now using the lower level APIs
-            // because they are simpler and have better documentation.
-            let listener = UnixListener::bind(socket_path)?;
-            for stream_or_err in listener.incoming() {
-                let stream = stream_or_err?;
-                match handle_client(&top_dirs, &git_info, user, group, stream, &mut processes) {
-                    Ok(()) => (),
-                    Err(err) => {
-                        println!("");
-                        println!("Error while handling client.");
-                        println!("Debug info for exception: {:?}", err);
-                        println!("Display info for exception: {}", err);
-                        println!("Ignoring this and continuing daemon execution...");
-                        println!("");
+    reconcile_stale_mounts(&top_dirs.mzr_dir)?;
+    let pid = namespaces::UnsharedUserAndMount::new()
+        .extra_clone_flags(top_dirs.config.clone_flags())
+        .spawn(
+            |child_process| namespaces::map_user_into(child_process, user, group, mapping),
+            || {
+                // Isolate the daemon's mounts from the host before mounting
+                // anything.
+                if top_dirs.config.mount_options().private_propagation {
+                    make_mounts_private(&SyscallBackend)?;
+                }
+                let daemon_dir = DaemonDir::new(&top_dirs.mzr_dir);
+                create_dir_all(&daemon_dir)?;
+                let git_info = bind_git_repo(top_dirs)?;
+                // TODO(cleanup): Don't truncate old daemon logs?
+                let log_stdout_file = File::create(DaemonLogStdoutFile::new(&daemon_dir))?;
+                let log_stderr_file = File::create(DaemonLogStderrFile::new(&daemon_dir))?;
+                Daemonize::new()
+                    .pid_file(DaemonPidFile::new(&daemon_dir))
+                    // TODO(friendliness): Would be nice to merge
+                    // these. Is stderr ever even used?
+                    .stdout(log_stdout_file)
+                    .stderr(log_stderr_file)
+                    .start()?;
+                // Disable ANSI codes in output, since it's sent to a log
+                // rather than terminal.
+                Paint::disable();
+                // Listen for client connections.
+                let socket_path = DaemonSocketFile::new(&daemon_dir);
+                if socket_path.exists() {
+                    remove_file(&socket_path).context(format_err!(
+                        "Failed to remove daemon socket file {}",
+                        socket_path
+                    ))?;
+                }
+                // Tracks which zones have been mounted and which child
+                // processes have been created.
+                let mut state = DaemonState::default();
+                // Listen for client connections. In the future, perhaps tokio
+                // or mio will be used, but for now using the lower level APIs
+                // because they are simpler and have better documentation.
+                let listener = UnixListener::bind(socket_path)?;
+                for stream_or_err in listener.incoming() {
+                    let stream = stream_or_err?;
+                    match handle_client(
+                        top_dirs, &git_info, user, group, mapping, stream, &mut state,
+                    ) {
+                        Ok(()) => (),
+                        Err(err) => {
+                            println!();
+                            println!("Error while handling client.");
+                            println!("Debug info for exception: {:?}", err);
+                            println!("Display info for exception: {}", err);
+                            println!("Ignoring this and continuing daemon execution...");
+                            println!();
+                        }
                     }
                 }
-            }
-            Ok(())
-        },
-    )?;
+                Ok(())
+            },
+        )?;
+    // The child process exits once the daemon has forked into the background,
+    // so this detects failures which happen before that.
+    namespaces::wait_for_child_timeout(pid, DAEMON_START_TIMEOUT)?;
     // TODO(friendliness): Include this output, but only do it when
     // the daemon has actually started. Currently if you start the
     // daemon while another is running, and this line is uncommented,
@@ -107,6 +152,185 @@ pub fn run(top_dirs: &TopDirs) -> Result<(), Error> {
     Ok(())
 }
 
+/*
+ * Tracking of mounted zones
+ */
+
+/// Zone which was mounted by a daemon that is no longer running.
+#[derive(Debug)]
+struct StaleMount {
+    zone_name: ZoneName,
+    /// Processes which still have the zone mounted. The daemon's mounts are
+    /// only visible within its mount namespace, so they only outlive it when
+    /// other processes in that namespace, such as zone shells, keep running.
+    holders: Vec<Pid>,
+}
+
+/// If a previous daemon died without cleaning up, then processes which it
+/// started may still have its zones mounted. When the pid file refers to a
+/// process that is no longer running, this offers to kill them, so that the
+/// zones get unmounted.
+fn reconcile_stale_mounts(mzr_dir: &MzrDir) -> Result<(), Error> {
+    let (pid, stale_mounts) = match find_stale_mounts(mzr_dir, Path::new("/proc"))? {
+        None => return Ok(()),
+        Some(found) => found,
+    };
+    let lingering: Vec<&StaleMount> = stale_mounts
+        .iter()
+        .filter(|stale_mount| !stale_mount.holders.is_empty())
+        .collect();
+    if !lingering.is_empty() {
+        println!(
+            "{} The previous {} (PID {}) is no longer running, \
+             but processes it started still have the following zones mounted:",
+            color_warn(&"Warning:"),
+            color_cmd(&"mzr daemon"),
+            pid
+        );
+        for stale_mount in &lingering {
+            let holders: Vec<String> = stale_mount
+                .holders
+                .iter()
+                .map(|holder| holder.to_string())
+                .collect();
+            println!("* {} by PIDs {}", stale_mount.zone_name, holders.join(", "));
+        }
+        match confirm("Kill these processes, so that the zones get unmounted")? {
+            Confirmed::Yes => {
+                for stale_mount in &lingering {
+                    for holder in &stale_mount.holders {
+                        match kill(*holder, Signal::SIGKILL) {
+                            Ok(()) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+                            Err(e) => {
+                                Err(e).context(format_err!("Failed to kill process {}", holder))?
+                            }
+                        }
+                    }
+                }
+            }
+            Confirmed::No => {}
+        }
+    }
+    let mounts_file = DaemonMountsFile::new(&DaemonDir::new(mzr_dir));
+    remove_file(&mounts_file).context(format_err!(
+        "Failed to remove stale daemon mounts file {}",
+        mounts_file
+    ))?;
+    Ok(())
+}
+
+/// Finds the zones recorded as mounted by a daemon which is no longer
+/// running, along with the daemon's pid. Yields `None` if the daemon is
+/// running, or there is no record of its mounts. Processes are found via the
+/// procfs mounted at `proc_dir`, and only ones in a different mount namespace
+/// from the current process count as holding the old daemon's mounts.
+fn find_stale_mounts(
+    mzr_dir: &MzrDir,
+    proc_dir: &Path,
+) -> Result<Option<(Pid, Vec<StaleMount>)>, Error> {
+    let daemon_dir = DaemonDir::new(mzr_dir);
+    let pid_file = DaemonPidFile::new(&daemon_dir);
+    let mounts_file = DaemonMountsFile::new(&daemon_dir);
+    if !pid_file.exists() || !mounts_file.exists() {
+        return Ok(None);
+    }
+    let pid = parse_pid_file(&pid_file)?;
+    if is_process_alive(pid) {
+        return Ok(None);
+    }
+    let zone_names: Vec<ZoneName> = json::read(&mounts_file)?.contents;
+    let processes = overlays_in_other_namespaces(proc_dir)?;
+    let stale_mounts = zone_names
+        .into_iter()
+        .map(|zone_name| {
+            let mount_dir = ZoneDir::new(mzr_dir, &zone_name).ovfs_mount_dir();
+            let holders = processes
+                .iter()
+                .filter(|(_, mount_points)| mount_points.contains(&mount_dir))
+                .map(|(holder, _)| *holder)
+                .collect();
+            StaleMount { zone_name, holders }
+        })
+        .collect();
+    Ok(Some((pid, stale_mounts)))
+}
+
+/// Lists the overlay mount points of each process whose mount namespace
+/// differs from the current process's. Processes which exit or can't be
+/// inspected while doing this are skipped.
+fn overlays_in_other_namespaces(proc_dir: &Path) -> Result<Vec<(Pid, Vec<PathBuf>)>, Error> {
+    let own_namespace_file = proc_dir.join("self/ns/mnt");
+    let own_namespace = read_link(&own_namespace_file).context(format_err!(
+        "Failed to read mount namespace of current process from {}",
+        color_file(&own_namespace_file.display())
+    ))?;
+    let mut result = Vec::new();
+    for entry in read_dir(proc_dir)? {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => Pid::from_raw(pid),
+            None => continue,
+        };
+        match read_link(entry.path().join("ns/mnt")) {
+            Ok(namespace) if namespace != own_namespace => {}
+            _ => continue,
+        }
+        let mounts = match fs::read_to_string(entry.path().join("mountinfo"))
+            .map_err(Error::from)
+            .and_then(|contents| mount::parse_mountinfo(&contents))
+        {
+            Ok(mounts) => mounts,
+            Err(_) => continue,
+        };
+        let overlays = mounts
+            .into_iter()
+            .filter(|mount| mount.fstype == "overlay")
+            .map(|mount| mount.mount_point)
+            .collect();
+        result.push((pid, overlays));
+    }
+    Ok(result)
+}
+
+/// Checks whether the project's daemon is running.
+pub fn is_running(mzr_dir: &MzrDir) -> Result<bool, Error> {
+    let pid_file = DaemonPidFile::new(&DaemonDir::new(mzr_dir));
+    Ok(pid_file.exists() && is_process_alive(parse_pid_file(&pid_file)?))
+}
+
+/// Checks whether the zone is mounted, either by a running daemon or within the
+/// current mount namespace.
+pub fn is_zone_mounted(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<bool, Error> {
+    let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+    if is_mounted(&zone_dir.ovfs_mount_dir())? {
+        return Ok(true);
+    }
+    let daemon_dir = DaemonDir::new(mzr_dir);
+    let pid_file = DaemonPidFile::new(&daemon_dir);
+    let mounts_file = DaemonMountsFile::new(&daemon_dir);
+    if !pid_file.exists() || !mounts_file.exists() {
+        return Ok(false);
+    }
+    if !is_process_alive(parse_pid_file(&pid_file)?) {
+        return Ok(false);
+    }
+    let zone_names: Vec<ZoneName> = json::read(&mounts_file)?.contents;
+    Ok(zone_names.contains(zone_name))
+}
+
+/// Records the zones which have been mounted by the daemon, so that they can
+/// be cleaned up if the daemon dies.
+fn record_mounted_zones(mzr_dir: &MzrDir, state: &DaemonState) -> Result<(), Error> {
+    json::write(
+        &DaemonMountsFile::new(&DaemonDir::new(mzr_dir)),
+        &state.mounted_zones,
+    )
+}
+
 // If there is a top level git repository, bind mount it, so that the
 // repo can be shared by the zones.
 //
@@ -142,12 +366,18 @@ fn bind_git_repo(
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Request {
-    ZoneProcess(ZoneName),
+    ZoneProcess(ZoneName, Option<ZoneTargetDir>, Vec<BindSpec>),
+    MountZone(ZoneName),
+    UnmountZone(ZoneName),
+    DeleteZone(ZoneName),
+    UnmountAll { lazy: bool, verify: bool },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Response {
     ZoneProcess(ZonePid),
+    Done,
+    Unmounted(Vec<(ZoneName, Result<Unmounted, String>)>),
     Error(String),
 }
 
@@ -160,37 +390,95 @@ fn handle_client(
     git_info: &Option<(BoundGitRepoDir, RelativeGitRepoDir)>,
     user: Uid,
     group: Gid,
+    mapping: UserMapping,
     stream: UnixStream,
-    processes: &mut ProcessMap,
+    state: &mut DaemonState,
 ) -> Result<(), Error> {
+    reap_zone_processes(&mut state.processes);
     let result: Result<Response, Error> = try {
         match recv_request(&stream)? {
-            Request::ZoneProcess(zone_name) => match processes.get(&zone_name) {
-                None => match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
-                    None => Response::Error(String::from("Zone does not exist")),
-                    Some(zone) => {
-                        match git_info {
-                            None => {}
-                            Some((source_git_dir, rel_git_dir)) => {
-                                let target_git_dir = zone.ovfs_changes_dir.join(rel_git_dir);
-                                symlink_git_repo(&source_git_dir, &target_git_dir)?;
-                            }
+            Request::ZoneProcess(zone_name, target_dir, binds) => {
+                let key = (zone_name.clone(), target_dir.clone());
+                match state.processes.get(&key) {
+                    None => match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                        None => zone_not_found(&top_dirs.mzr_dir, &zone_name)?,
+                        Some(zone) => {
+                            mount_zone_overlay(top_dirs, git_info, state, &zone)?;
+                            // Fork a zone process which bind-mounts the
+                            // zone to the user's working directory, or to
+                            // the requested target directory.
+                            let pid = match &target_dir {
+                                None => fork_zone_process(
+                                    &top_dirs.user_work_dir,
+                                    &binds,
+                                    user,
+                                    group,
+                                    mapping,
+                                    &zone,
+                                )?,
+                                Some(target_dir) => fork_zone_process(
+                                    target_dir, &binds, user, group, mapping, &zone,
+                                )?,
+                            };
+                            state.processes.insert(
+                                key,
+                                ZoneProcess {
+                                    pid: pid.clone(),
+                                    binds,
+                                },
+                            );
+                            Response::ZoneProcess(pid)
                         }
-                        // Mount the zone's overlayfs in the daemon's namespace.
-                        //
-                        // TODO: Looks like this does not yet
-                        // propagate to the mount namespaces of the
-                        // existing zone processes, but it needs to.
-                        zone.mount()?;
-                        // Fork a zone process which bind-mounts the
-                        // zone to the user's working directory.
-                        let pid = fork_zone_process(&top_dirs.user_work_dir, user, group, &zone)?;
-                        processes.insert(zone_name, pid.clone());
-                        Response::ZoneProcess(pid)
+                    },
+                    // The bind mounts are made within the zone process, so
+                    // different ones can't be used while it's running.
+                    Some(process) if process.binds != binds => Response::Error(format!(
+                        "Zone is already entered with different bind mounts, \
+                         so zone process {} needs to be killed before they can be changed.",
+                        process.pid
+                    )),
+                    Some(process) => Response::ZoneProcess(process.pid.clone()),
+                }
+            }
+            Request::MountZone(zone_name) => {
+                match Zone::load_if_exists(&top_dirs.mzr_dir, &zone_name)? {
+                    None => zone_not_found(&top_dirs.mzr_dir, &zone_name)?,
+                    Some(zone) => {
+                        mount_zone_overlay(top_dirs, git_info, state, &zone)?;
+                        Response::Done
                     }
-                },
-                Some(pid) => Response::ZoneProcess(pid.clone()),
-            },
+                }
+            }
+            Request::UnmountZone(zone_name) => {
+                kill_zone_processes(&mut state.processes, |name| name == &zone_name)?;
+                let mount_dir = ZoneDir::new(&top_dirs.mzr_dir, &zone_name).ovfs_mount_dir();
+                if is_mounted(&mount_dir)? {
+                    unmount(&mount_dir)?;
+                }
+                forget_unmounted_zones(&top_dirs.mzr_dir, state)?;
+                Response::Done
+            }
+            Request::DeleteZone(zone_name) => {
+                kill_zone_processes(&mut state.processes, |name| name == &zone_name)?;
+                let mount_dir = ZoneDir::new(&top_dirs.mzr_dir, &zone_name).ovfs_mount_dir();
+                if is_mounted(&mount_dir)? {
+                    unmount(&mount_dir)?;
+                }
+                forget_unmounted_zones(&top_dirs.mzr_dir, state)?;
+                zone::delete(&top_dirs.mzr_dir, &zone_name, true)?;
+                Response::Done
+            }
+            Request::UnmountAll { lazy, verify } => {
+                kill_zone_processes(&mut state.processes, |_| true)?;
+                let results = mount::unmount_all(&top_dirs.mzr_dir, lazy, verify)?;
+                forget_unmounted_zones(&top_dirs.mzr_dir, state)?;
+                Response::Unmounted(
+                    results
+                        .into_iter()
+                        .map(|(zone_name, result)| (zone_name, result.map_err(|e| error_chain(&e))))
+                        .collect(),
+                )
+            }
         }
     };
     send_response(
@@ -202,45 +490,160 @@ fn handle_client(
     )
 }
 
+fn zone_not_found(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Response, Error> {
+    Ok(Response::Error(format!(
+        "Zone does not exist.{}",
+        did_you_mean_zone(mzr_dir, zone_name)?
+    )))
+}
+
+/// Mounts the zone's overlay in the daemon's namespace, unless it already is.
+/// The overlay only needs to be mounted once, even if the zone is bound to
+/// multiple targets.
+fn mount_zone_overlay(
+    top_dirs: &TopDirs,
+    git_info: &Option<(BoundGitRepoDir, RelativeGitRepoDir)>,
+    state: &mut DaemonState,
+    zone: &Zone,
+) -> Result<(), Error> {
+    if state.mounted_zones.contains(&zone.name) {
+        return Ok(());
+    }
+    if !is_mounted(&zone.ovfs_mount_dir)? {
+        match git_info {
+            None => {}
+            Some((source_git_dir, rel_git_dir)) => {
+                let target_git_dir = zone.ovfs_changes_dir.join(rel_git_dir);
+                symlink_git_repo(source_git_dir, &target_git_dir)?;
+            }
+        }
+        // TODO: Looks like this does not yet propagate to the mount namespaces
+        // of the existing zone processes, but it needs to.
+        zone.mount(&top_dirs.config.mount_options())?;
+    }
+    state.mounted_zones.push(zone.name.clone());
+    record_mounted_zones(&top_dirs.mzr_dir, state)
+}
+
+/// Stops tracking zones whose overlays are no longer mounted in the daemon's
+/// namespace, such as after unmounting them.
+fn forget_unmounted_zones(mzr_dir: &MzrDir, state: &mut DaemonState) -> Result<(), Error> {
+    let mut still_mounted = Vec::new();
+    for zone_name in &state.mounted_zones {
+        if is_mounted(&ZoneDir::new(mzr_dir, zone_name).ovfs_mount_dir())? {
+            still_mounted.push(zone_name.clone());
+        }
+    }
+    state.mounted_zones = still_mounted;
+    record_mounted_zones(mzr_dir, state)
+}
+
+/// Kills and reaps the processes of the zones for which `matches` is true.
+/// This needs to happen before unmounting the zones, since the namespaces of
+/// zone processes hold their own copies of the overlays, and zone processes
+/// block until killed.
+fn kill_zone_processes(
+    processes: &mut ProcessMap,
+    matches: impl Fn(&ZoneName) -> bool,
+) -> Result<(), Error> {
+    let keys: Vec<_> = processes
+        .keys()
+        .filter(|(zone_name, _)| matches(zone_name))
+        .cloned()
+        .collect();
+    for key in keys {
+        let pid = processes[&key].pid.clone();
+        match kill(pid.to_pid(), Signal::SIGKILL) {
+            // The process may have already exited, and just not been reaped.
+            Ok(()) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+            Err(e) => Err(e).context(format_err!("Failed to kill zone process {}", pid))?,
+        }
+        // Being killed makes this yield an error, which isn't of interest.
+        let _ = namespaces::wait_for_child(pid.to_pid());
+        println!("Killed zone process {} for zone {}.", pid, key.0);
+        processes.remove(&key);
+    }
+    Ok(())
+}
+
+/// Removes zone processes which have exited, such as by being killed, so that
+/// they get forked again when next requested.
+fn reap_zone_processes(processes: &mut ProcessMap) {
+    let mut exited = Vec::new();
+    for (key, ZoneProcess { pid, .. }) in processes.iter() {
+        if let Some(result) = namespaces::try_wait_for_child(pid.to_pid()) {
+            let (zone_name, _) = key;
+            match result {
+                Ok(()) => println!("Zone process {} for zone {} exited.", pid, zone_name),
+                Err(err) => println!(
+                    "Zone process {} for zone {} failed: {}",
+                    pid, zone_name, err
+                ),
+            }
+            exited.push(key.clone());
+        }
+    }
+    for key in &exited {
+        processes.remove(key);
+    }
+}
+
 const READY_MSG: &[u8; 6] = b"ready\n";
 
-fn fork_zone_process(
-    work_dir: &UserWorkDir,
+fn fork_zone_process<T: AsRef<Path>>(
+    target_dir: &T,
+    binds: &[BindSpec],
     user: Uid,
     group: Gid,
+    mapping: UserMapping,
     zone: &Zone,
 ) -> Result<ZonePid, Error> {
     // TODO(cleanup): mzr now has a few different takes on IPC, should
     // use a consistent style.
     let (server_stream, mut client_stream) = UnixStream::pair()?;
-    let pid = namespaces::with_unshared_user_and_mount(
-        |child_process| namespaces::map_root_to_user(child_process, user, group),
-        || {
-            // TODO(cleanup): When the parent process exits, it should
-            // close the pipe, which should cause the read to
-            // exit. However, for some reason that didn't work. Setting
-            // PDEATHSIG seems to work, though. It would be nicer to avoid
-            // this, though.
-            unsafe {
-                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) != 0 {
-                    bail!("Failed to set PDEATHSIG");
-                }
+    // Only bind mounts which were made get unmounted, since binding the zone
+    // may fail before they are.
+    let binds_mounted = Cell::new(false);
+    let pid = namespaces::UnsharedUserAndMount::new()
+        .teardown(|| {
+            if binds_mounted.get() {
+                unmount_binds(&SyscallBackend, binds)
+            } else {
+                Ok(())
             }
-            // Bind mount zone over the user's work-dir.
-            zone.bind_to(work_dir)?;
-            // Indicate to parent process that the zone is ready.
-            client_stream.write_all(READY_MSG)?;
-            let mut data = Vec::new();
-            // This should just block forever, since server_stream never
-            // gets written to.
-            let result = client_stream.read_to_end(&mut data);
-            println!(
-                "mzr zone process unexpectedly done blocking, result was {:?}",
-                result
-            );
-            Ok(())
-        },
-    )?;
+        })
+        .spawn(
+            |child_process| namespaces::map_back_to_user(child_process, user, group, mapping),
+            || {
+                // TODO(cleanup): When the parent process exits, it should
+                // close the pipe, which should cause the read to
+                // exit. However, for some reason that didn't work. Setting
+                // PDEATHSIG seems to work, though. It would be nicer to avoid
+                // this, though.
+                unsafe {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) != 0 {
+                        bail!("Failed to set PDEATHSIG");
+                    }
+                }
+                // Bind mount zone over the user's work-dir or target dir.
+                zone.bind_to(target_dir)?;
+                // Extra bind mounts happen after the zone is bound, so that they
+                // can be bound within it.
+                mount_binds(&SyscallBackend, binds)?;
+                binds_mounted.set(true);
+                // Indicate to parent process that the zone is ready.
+                client_stream.write_all(READY_MSG)?;
+                let mut data = Vec::new();
+                // This should just block forever, since server_stream never
+                // gets written to.
+                let result = client_stream.read_to_end(&mut data);
+                println!(
+                    "mzr zone process unexpectedly done blocking, result was {:?}",
+                    result
+                );
+                Ok(())
+            },
+        )?;
     let mut data = Vec::new();
     let mut reader = BufReader::new(server_stream);
     reader.read_until(b'\n', &mut data)?;
@@ -268,6 +671,15 @@ fn recv_request(stream: &UnixStream) -> Result<Request, Error> {
     Ok(request)
 }
 
+/// Errors are sent to clients as strings, so the whole chain of causes is
+/// included in the message.
+fn error_chain(err: &Error) -> String {
+    err.iter_chain()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
 fn send_response(stream: &UnixStream, response: &Response) -> Result<(), Error> {
     serde_json::to_writer(stream, &response)?;
     println!("<== {:?}", response);
@@ -289,7 +701,7 @@ fn recv_response(stream: &UnixStream) -> Result<Response, Error> {
 }
 
 fn connect_to_daemon(mzr_dir: &MzrDir) -> Result<UnixStream, Error> {
-    let daemon_dir = DaemonDir::new(&mzr_dir);
+    let daemon_dir = DaemonDir::new(mzr_dir);
     let socket_path = DaemonSocketFile::new(&daemon_dir);
     if !socket_path.exists() {
         bail!(
@@ -310,8 +722,13 @@ fn run_daemon_command(mzr_dir: &MzrDir, request: &Request) -> Result<Response, E
     recv_response(&stream)
 }
 
-pub fn get_zone_process(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<ZonePid, Error> {
-    let request = Request::ZoneProcess(zone_name.clone());
+pub fn get_zone_process(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    target_dir: Option<&ZoneTargetDir>,
+    binds: &[BindSpec],
+) -> Result<ZonePid, Error> {
+    let request = Request::ZoneProcess(zone_name.clone(), target_dir.cloned(), binds.to_vec());
     // TODO(hack): Sending the request twice is an ugly hack. For some
     // reason, on initial forking of the daemon's zone process, the
     // response never makes it back to the client. I suspect this is
@@ -328,6 +745,55 @@ pub fn get_zone_process(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<ZonePi
     match run_daemon_command(mzr_dir, &request)? {
         Response::ZoneProcess(p) => Ok(p),
         Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Asks the daemon to mount the zone's overlay within its mount namespace,
+/// without binding it anywhere.
+pub fn mount_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    match run_daemon_command(mzr_dir, &Request::MountZone(zone_name.clone()))? {
+        Response::Done => Ok(()),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Asks the daemon to unmount the zone's overlay from its mount namespace,
+/// after killing the zone's processes.
+pub fn unmount_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    match run_daemon_command(mzr_dir, &Request::UnmountZone(zone_name.clone()))? {
+        Response::Done => Ok(()),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Asks the daemon to permanently delete the zone, after killing its processes
+/// and unmounting it. Unlike deleting it directly, this works from within the
+/// zone's namespaces, where its overlay can't be unmounted.
+pub fn delete_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    match run_daemon_command(mzr_dir, &Request::DeleteZone(zone_name.clone()))? {
+        Response::Done => Ok(()),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+/// Asks the daemon to kill all zone processes and unmount all zone overlays
+/// from its mount namespace - see `mount::unmount_all`.
+pub fn unmount_all(
+    mzr_dir: &MzrDir,
+    lazy: bool,
+    verify: bool,
+) -> Result<ZoneUnmountResults, Error> {
+    match run_daemon_command(mzr_dir, &Request::UnmountAll { lazy, verify })? {
+        Response::Unmounted(results) => Ok(results
+            .into_iter()
+            .map(|(zone_name, result)| (zone_name, result.map_err(err_msg)))
+            .collect()),
+        Response::Error(e) => bail!("Response from daemon was {:?}", e),
+        response => bail!("Unexpected response from daemon: {:?}", response),
     }
 }
 
@@ -342,3 +808,63 @@ pub fn enter_zone_process_mount(zone_pid: &ZonePid) -> Result<(), Error> {
 pub fn enter_zone_process_user_and_mount(zone_pid: &ZonePid) -> Result<(), Error> {
     namespaces::enter_user_and_mount(zone_pid.to_pid())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_mzr_dir, TestDir};
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn stale_mounts_are_held_by_processes_in_other_namespaces() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let daemon_dir = DaemonDir::new(&mzr_dir);
+        create_dir_all(&daemon_dir).unwrap();
+        let zone_name = |name: &str| ZoneName::new(name.to_string()).unwrap();
+        json::write(
+            &DaemonMountsFile::new(&daemon_dir),
+            &vec![zone_name("held"), zone_name("gone")],
+        )
+        .unwrap();
+        let proc_dir = dir.join("proc");
+        let held_mount_dir = ZoneDir::new(&mzr_dir, &zone_name("held")).ovfs_mount_dir();
+        let fake_process = |pid: &str, namespace: &str, overlays: &[&Path]| {
+            let process_dir = proc_dir.join(pid);
+            create_dir_all(process_dir.join("ns")).unwrap();
+            symlink(namespace, process_dir.join("ns/mnt")).unwrap();
+            let mut mountinfo = String::from("20 1 0:20 / / rw - ext4 /dev/sda1 rw\n");
+            for overlay in overlays {
+                mountinfo.push_str(&format!(
+                    "30 20 0:30 / {} rw - overlay overlay rw\n",
+                    overlay.display()
+                ));
+            }
+            fs::write(process_dir.join("mountinfo"), mountinfo).unwrap();
+        };
+        fake_process("self", "mnt:[1]", &[&held_mount_dir]);
+        fake_process("10", "mnt:[1]", &[&held_mount_dir]);
+        fake_process("20", "mnt:[2]", &[&held_mount_dir]);
+        fake_process("30", "mnt:[3]", &[]);
+        let pid_file = DaemonPidFile::new(&daemon_dir);
+        // While the daemon is running, its mounts aren't stale.
+        fs::write(&pid_file, format!("{}\n", process::id())).unwrap();
+        assert!(find_stale_mounts(&mzr_dir, &proc_dir).unwrap().is_none());
+        // Pids are limited to less than 2^22, so this process doesn't exist.
+        fs::write(&pid_file, format!("{}\n", i32::MAX)).unwrap();
+        let (pid, stale_mounts) = find_stale_mounts(&mzr_dir, &proc_dir).unwrap().unwrap();
+        assert_eq!(pid, Pid::from_raw(i32::MAX));
+        let summary: Vec<(String, Vec<Pid>)> = stale_mounts
+            .into_iter()
+            .map(|stale_mount| ((*stale_mount.zone_name).clone(), stale_mount.holders))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("held".to_string(), vec![Pid::from_raw(20)]),
+                ("gone".to_string(), vec![]),
+            ]
+        );
+    }
+}
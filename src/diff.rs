@@ -0,0 +1,510 @@
+use crate::changes::{self, ChangeKind, WhiteoutKind, WHITEOUT_PREFIX};
+use crate::colors::*;
+use crate::merge::{get_metadata, metadata_matches};
+use crate::paths::{MzrDir, OvfsChangesDir, SnapDir, ZoneName};
+use crate::zone::Zone;
+use failure::{Error, ResultExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Differences between two directory trees. Paths are relative to the roots of
+/// the trees. When a directory is added or removed, only the directory is
+/// listed, not its contents.
+#[derive(Debug, Default, Serialize)]
+pub struct TreeDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+}
+
+/// How files are compared to determine whether they've been modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    /// Compare size, modification time, and permissions. This relies on
+    /// snapshotting preserving timestamps.
+    Metadata,
+    /// Compare file contents when the sizes match, ignoring timestamps.
+    Contents,
+}
+
+pub fn diff_snapshots(
+    old: &SnapDir,
+    new: &SnapDir,
+    comparison: Comparison,
+) -> Result<TreeDiff, Error> {
+    diff_trees(old, new, comparison)
+}
+
+pub fn diff_trees<P: AsRef<Path>, Q: AsRef<Path>>(
+    old: P,
+    new: Q,
+    comparison: Comparison,
+) -> Result<TreeDiff, Error> {
+    let old = old.as_ref();
+    let new = new.as_ref();
+    let mut diff = TreeDiff::default();
+    // Find removed and modified paths.
+    let mut walker = WalkDir::new(old)
+        .min_depth(1)
+        .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+        .into_iter();
+    while let Some(walk_result) = walker.next() {
+        let entry = walk_result?;
+        let rel_path = entry.path().strip_prefix(old)?.to_path_buf();
+        let old_metadata = entry.metadata()?;
+        match get_metadata_in_tree(&new.join(&rel_path))? {
+            None => {
+                if old_metadata.is_dir() {
+                    walker.skip_current_dir();
+                }
+                diff.removed.push(rel_path);
+            }
+            Some(new_metadata) => {
+                let both_dirs = old_metadata.is_dir() && new_metadata.is_dir();
+                if !both_dirs {
+                    if old_metadata.is_dir() {
+                        walker.skip_current_dir();
+                    }
+                    let old_path = entry.path();
+                    let new_path = new.join(&rel_path);
+                    if is_modified(
+                        old_path,
+                        &old_metadata,
+                        &new_path,
+                        &new_metadata,
+                        comparison,
+                    )? {
+                        diff.modified.push(rel_path);
+                    }
+                }
+            }
+        }
+    }
+    // Find added paths.
+    let mut walker = WalkDir::new(new)
+        .min_depth(1)
+        .sort_by(|x, y| x.file_name().cmp(y.file_name()))
+        .into_iter();
+    while let Some(walk_result) = walker.next() {
+        let entry = walk_result?;
+        let rel_path = entry.path().strip_prefix(new)?.to_path_buf();
+        match get_metadata_in_tree(&old.join(&rel_path))? {
+            None => {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                diff.added.push(rel_path);
+            }
+            Some(old_metadata) => {
+                // Directories replacing files are already listed as
+                // modified.
+                if !old_metadata.is_dir() && entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+            }
+        }
+    }
+    Ok(diff)
+}
+
+/// Like `get_metadata`, but also treats a path as nonexistent when one of its
+/// parents is not a directory.
+fn get_metadata_in_tree(path: &PathBuf) -> Result<Option<Metadata>, Error> {
+    match path.parent().map(|parent| parent.is_dir()) {
+        Some(false) => Ok(None),
+        _ => get_metadata(path),
+    }
+}
+
+fn is_modified(
+    old_path: &Path,
+    old_metadata: &Metadata,
+    new_path: &Path,
+    new_metadata: &Metadata,
+    comparison: Comparison,
+) -> Result<bool, Error> {
+    let old_type = old_metadata.file_type();
+    let new_type = new_metadata.file_type();
+    if old_type.is_dir() != new_type.is_dir()
+        || old_type.is_file() != new_type.is_file()
+        || old_type.is_symlink() != new_type.is_symlink()
+    {
+        return Ok(true);
+    }
+    if old_type.is_symlink() {
+        return Ok(fs::read_link(old_path)? != fs::read_link(new_path)?);
+    }
+    match comparison {
+        Comparison::Metadata => Ok(!metadata_matches(old_metadata, new_metadata)),
+        Comparison::Contents => {
+            if old_metadata.len() != new_metadata.len()
+                || old_metadata.permissions() != new_metadata.permissions()
+            {
+                return Ok(true);
+            }
+            Ok(old_type.is_file() && !contents_match(old_path, new_path)?)
+        }
+    }
+}
+
+fn contents_match(x: &Path, y: &Path) -> Result<bool, Error> {
+    let open = |path: &Path| -> Result<BufReader<File>, Error> {
+        Ok(BufReader::new(File::open(path).context(format_err!(
+            "Failed to open {} for comparison",
+            color_file(&path.display())
+        ))?))
+    };
+    let mut x_reader = open(x)?;
+    let mut y_reader = open(y)?;
+    let mut x_buf = [0; 8192];
+    let mut y_buf = [0; 8192];
+    loop {
+        let x_count = read_full(&mut x_reader, &mut x_buf)?;
+        let y_count = read_full(&mut y_reader, &mut y_buf)?;
+        if x_buf[..x_count] != y_buf[..y_count] {
+            return Ok(false);
+        }
+        if x_count == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads until the buffer is full or the end of the input is reached.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut count = 0;
+    while count < buf.len() {
+        match reader.read(&mut buf[count..])? {
+            0 => break,
+            n => count += n,
+        }
+    }
+    Ok(count)
+}
+
+/// Prints a diff in a style similar to `git status --short`.
+pub fn print_diff(diff: &TreeDiff) {
+    for path in &diff.added {
+        println!("{} {}", color_success(&"A"), path.display());
+    }
+    for path in &diff.removed {
+        println!("{} {}", color_err(&"D"), path.display());
+    }
+    for path in &diff.modified {
+        println!("{} {}", color_warn(&"M"), path.display());
+    }
+}
+
+/*
+ * Comparing zones
+ */
+
+/// Comparison of the changes of two zones. Paths are relative to the work
+/// directory.
+#[derive(Debug, Default, Serialize)]
+pub struct ZonesDiff {
+    /// Whether both zones are based on the same snapshot. When they aren't,
+    /// their changes are still compared, but the same change may have a
+    /// different effect on each zone.
+    pub same_snapshot: bool,
+    /// Paths changed only by the first zone.
+    pub first_only: Vec<PathBuf>,
+    /// Paths changed only by the second zone.
+    pub second_only: Vec<PathBuf>,
+    /// Paths which both zones changed in the same way.
+    pub both_same: Vec<PathBuf>,
+    /// Paths which both zones changed differently, or which one zone changed
+    /// within a directory that the other deleted or replaced.
+    pub conflicts: Vec<PathBuf>,
+}
+
+/// Change to a path recorded in a changes directory. Directories which merge
+/// with the snapshot's are not changes in themselves.
+#[derive(Debug, Clone)]
+enum ZoneChange {
+    /// Added or modified file, with its path in the changes directory.
+    File(PathBuf),
+    Deleted,
+    /// Directory which replaces the snapshot's directory.
+    OpaqueDir,
+}
+
+/// Compares the changes of two zones, to find which paths each zone changed,
+/// and which changes conflict.
+pub fn diff_zones(
+    mzr_dir: &MzrDir,
+    first: &ZoneName,
+    second: &ZoneName,
+) -> Result<ZonesDiff, Error> {
+    let first = Zone::load(mzr_dir, first)?;
+    let second = Zone::load(mzr_dir, second)?;
+    let first_changes = zone_changes(&first.ovfs_changes_dir)?;
+    let second_changes = zone_changes(&second.ovfs_changes_dir)?;
+    let mut diff = ZonesDiff {
+        same_snapshot: first.info.snapshot == second.info.snapshot,
+        ..ZonesDiff::default()
+    };
+    // A path changed by only one zone conflicts with the other zone deleting
+    // or replacing a directory containing it.
+    let hidden_by = |changes: &BTreeMap<PathBuf, ZoneChange>, path: &Path| {
+        path.ancestors()
+            .skip(1)
+            .any(|ancestor| changes.contains_key(ancestor))
+    };
+    for (path, first_change) in &first_changes {
+        match second_changes.get(path) {
+            None => {
+                if hidden_by(&second_changes, path) {
+                    diff.conflicts.push(path.clone());
+                } else {
+                    diff.first_only.push(path.clone());
+                }
+            }
+            Some(second_change) => {
+                if changes_match(first_change, second_change)? {
+                    diff.both_same.push(path.clone());
+                } else {
+                    diff.conflicts.push(path.clone());
+                }
+            }
+        }
+    }
+    for path in second_changes.keys() {
+        if !first_changes.contains_key(path) {
+            if hidden_by(&first_changes, path) {
+                diff.conflicts.push(path.clone());
+            } else {
+                diff.second_only.push(path.clone());
+            }
+        }
+    }
+    diff.conflicts.sort();
+    Ok(diff)
+}
+
+/// Lists the changes in a changes directory, by path relative to the work
+/// directory.
+fn zone_changes(changes_dir: &OvfsChangesDir) -> Result<BTreeMap<PathBuf, ZoneChange>, Error> {
+    let mut result = BTreeMap::new();
+    for whiteout in changes::list_whiteouts(changes_dir)? {
+        let change = match whiteout.kind {
+            WhiteoutKind::Deleted => ZoneChange::Deleted,
+            WhiteoutKind::Opaque => ZoneChange::OpaqueDir,
+        };
+        result.insert(whiteout.rel_path, change);
+    }
+    for walk_result in WalkDir::new(changes_dir)
+        .min_depth(1)
+        .same_file_system(true)
+    {
+        let entry = walk_result?;
+        // Whiteout files were already listed as deletions.
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(WHITEOUT_PREFIX)
+        {
+            continue;
+        }
+        if ChangeKind::of(entry.path())? == Some(ChangeKind::File) {
+            result.insert(
+                changes_dir.work_relative_path(entry.path())?,
+                ZoneChange::File(entry.path().to_path_buf()),
+            );
+        }
+    }
+    Ok(result)
+}
+
+/// Whether two changes to the same path have the same result. Opaque
+/// directories match, since their contents are compared separately.
+fn changes_match(x: &ZoneChange, y: &ZoneChange) -> Result<bool, Error> {
+    Ok(match (x, y) {
+        (ZoneChange::Deleted, ZoneChange::Deleted) => true,
+        (ZoneChange::OpaqueDir, ZoneChange::OpaqueDir) => true,
+        (ZoneChange::File(x), ZoneChange::File(y)) => {
+            let x_metadata = fs::symlink_metadata(x)?;
+            let y_metadata = fs::symlink_metadata(y)?;
+            !is_modified(x, &x_metadata, y, &y_metadata, Comparison::Contents)?
+        }
+        _ => false,
+    })
+}
+
+/// Prints a comparison of zones, marking paths changed by the first zone with
+/// `<`, by the second zone with `>`, by both with `=`, and conflicts with `!`.
+pub fn print_zones_diff(diff: &ZonesDiff) {
+    for path in &diff.first_only {
+        println!("{} {}", color_success(&"<"), path.display());
+    }
+    for path in &diff.second_only {
+        println!("{} {}", color_success(&">"), path.display());
+    }
+    for path in &diff.both_same {
+        println!("= {}", path.display());
+    }
+    for path in &diff.conflicts {
+        println!("{} {}", color_err(&"!"), path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changes::create_whiteout;
+    use crate::paths::{SnapName, UserWorkDir};
+    use crate::test_utils::{test_mzr_dir, test_snapshot, write_files, TestDir};
+    use crate::utils::ensure_dir;
+    use std::os::unix::fs::symlink;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn diff_zones_finds_conflicts() {
+        let dir = TestDir::new();
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("proj")));
+        let snap_name = SnapName::new("s1".to_string()).unwrap();
+        ensure_dir(SnapDir::new(&mzr_dir, &snap_name)).unwrap();
+        let zone_with = |name: &str, files: &[(&str, &str)]| {
+            let zone_name = ZoneName::new(name.to_string()).unwrap();
+            let zone = Zone::create(&mzr_dir, &zone_name, &snap_name, None).unwrap();
+            for (path, contents) in files {
+                let path = zone.ovfs_changes_dir.join(path);
+                ensure_dir(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            zone_name
+        };
+        let first = zone_with(
+            "first",
+            &[
+                ("same", "x"),
+                ("differs", "1"),
+                ("first-only", ""),
+                ("dir/file", ""),
+                (".wh.gone", ""),
+            ],
+        );
+        let second = zone_with(
+            "second",
+            &[
+                ("same", "x"),
+                ("differs", "2"),
+                ("dir/.wh..wh..opq", ""),
+                (".wh.gone", ""),
+                (".wh.second-only", ""),
+            ],
+        );
+        let diff = diff_zones(&mzr_dir, &first, &second).unwrap();
+        assert!(diff.same_snapshot);
+        assert_eq!(strings(&diff.first_only), vec!["first-only"]);
+        assert_eq!(strings(&diff.second_only), vec!["dir", "second-only"]);
+        assert_eq!(strings(&diff.both_same), vec!["gone", "same"]);
+        assert_eq!(strings(&diff.conflicts), vec!["differs", "dir/file"]);
+    }
+
+    fn strings(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn diff_snapshots_finds_added_removed_and_modified_paths() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let old_name = test_snapshot(
+            &mzr_dir,
+            "old",
+            &[
+                ("same", "x"),
+                ("changed", "1"),
+                ("resized", "1"),
+                ("removed", ""),
+                ("removed-dir/a", ""),
+                ("removed-dir/b", ""),
+                ("becomes-dir", ""),
+                ("kept-dir/removed", ""),
+            ],
+        );
+        let new_name = test_snapshot(
+            &mzr_dir,
+            "new",
+            &[
+                ("same", "x"),
+                ("changed", "2"),
+                ("resized", "22"),
+                ("added", ""),
+                ("added-dir/a", ""),
+                ("becomes-dir/a", ""),
+                ("kept-dir/added", ""),
+            ],
+        );
+        let old = SnapDir::new(&mzr_dir, &old_name);
+        let new = SnapDir::new(&mzr_dir, &new_name);
+        symlink("same", old.join("link")).unwrap();
+        symlink("changed", new.join("link")).unwrap();
+        let diff = diff_snapshots(&old, &new, Comparison::Contents).unwrap();
+        assert_eq!(
+            strings(&diff.added),
+            vec!["added", "added-dir", "kept-dir/added"]
+        );
+        assert_eq!(
+            strings(&diff.removed),
+            vec!["kept-dir/removed", "removed", "removed-dir"]
+        );
+        assert_eq!(
+            strings(&diff.modified),
+            vec!["becomes-dir", "changed", "link", "resized"]
+        );
+        let reversed = diff_snapshots(&new, &old, Comparison::Contents).unwrap();
+        assert_eq!(reversed.added, diff.removed);
+        assert_eq!(reversed.removed, diff.added);
+    }
+
+    #[test]
+    fn diff_trees_compares_metadata() {
+        let dir = TestDir::new();
+        let files = &[("same", "x"), ("touched", "x")];
+        write_files(&dir.join("old"), files);
+        write_files(&dir.join("new"), files);
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for tree in &["old", "new"] {
+            File::open(dir.join(tree).join("same"))
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+        File::open(dir.join("new/touched"))
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+        let diff = diff_trees(dir.join("old"), dir.join("new"), Comparison::Metadata).unwrap();
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(strings(&diff.modified), vec!["touched"]);
+        let diff = diff_trees(dir.join("old"), dir.join("new"), Comparison::Contents).unwrap();
+        assert!(diff.modified.is_empty());
+    }
+
+    #[test]
+    fn diff_trees_finds_whiteouts() {
+        let dir = TestDir::new();
+        let old = dir.join("old");
+        let new = dir.join("new");
+        write_files(&old, &[("deleted", "x"), ("kept", "")]);
+        write_files(&new, &[("kept", "")]);
+        create_whiteout(&old.join("both")).unwrap();
+        create_whiteout(&new.join("both")).unwrap();
+        create_whiteout(&new.join("deleted")).unwrap();
+        create_whiteout(&new.join("new")).unwrap();
+        let diff = diff_trees(&old, &new, Comparison::Contents).unwrap();
+        assert_eq!(strings(&diff.added), vec!["new"]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(strings(&diff.modified), vec!["deleted"]);
+    }
+}
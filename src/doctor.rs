@@ -0,0 +1,317 @@
+use crate::mount::{probe_redirect_dir, selinux_enforcing, RedirectDirSupport};
+use nix::sys::utsname::uname;
+use nix::unistd::{self, Uid};
+use serde::Serialize;
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::path::Path;
+
+/// Summary of the mzr version and the kernel capabilities that it relies on,
+/// for diagnosing issues.
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemInfo {
+    pub version: String,
+    pub kernel_release: String,
+    /// Whether overlayfs is listed in `/proc/filesystems`.
+    pub overlayfs_available: bool,
+    pub redirect_dir: RedirectDirSupport,
+    /// Whether unprivileged users can create user namespaces, or `None` if
+    /// this can't be determined.
+    pub unprivileged_userns: Option<bool>,
+    /// Subordinate uid ranges of the current user, from `/etc/subuid`.
+    pub subuid_ranges: Vec<SubIdRange>,
+    /// Subordinate gid ranges of the current user, from `/etc/subgid`.
+    pub subgid_ranges: Vec<SubIdRange>,
+    pub selinux_enforcing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SubIdRange {
+    pub start: u64,
+    pub count: u64,
+}
+
+/// Raw results of probing the system, which `SystemInfo::assemble`
+/// interprets. Files which couldn't be read are `None`.
+#[derive(Debug, Clone)]
+pub struct Probes {
+    pub kernel_release: String,
+    pub filesystems: Option<String>,
+    pub redirect_dir: RedirectDirSupport,
+    /// Contents of `/proc/sys/kernel/unprivileged_userns_clone`, which only
+    /// exists on some distributions' kernels.
+    pub unprivileged_userns_clone: Option<String>,
+    pub max_user_namespaces: Option<String>,
+    pub uid: Uid,
+    pub user_name: Option<String>,
+    pub subuid: Option<String>,
+    pub subgid: Option<String>,
+    pub selinux_enforcing: bool,
+}
+
+impl Probes {
+    pub fn run() -> Probes {
+        let read = |path: &str| fs::read_to_string(Path::new(path)).ok();
+        Probes {
+            kernel_release: uname().release().to_string(),
+            filesystems: read("/proc/filesystems"),
+            redirect_dir: probe_redirect_dir(),
+            unprivileged_userns_clone: read("/proc/sys/kernel/unprivileged_userns_clone"),
+            max_user_namespaces: read("/proc/sys/user/max_user_namespaces"),
+            uid: unistd::getuid(),
+            user_name: user_name(unistd::getuid()),
+            subuid: read("/etc/subuid"),
+            subgid: read("/etc/subgid"),
+            selinux_enforcing: selinux_enforcing(),
+        }
+    }
+}
+
+impl SystemInfo {
+    pub fn gather() -> SystemInfo {
+        SystemInfo::assemble(&Probes::run())
+    }
+
+    pub fn assemble(probes: &Probes) -> SystemInfo {
+        let overlayfs_available = probes.filesystems.as_ref().is_some_and(|contents| {
+            contents
+                .lines()
+                .any(|line| line.split_whitespace().last() == Some("overlay"))
+        });
+        let setting = |contents: &Option<String>| {
+            contents
+                .as_ref()
+                .and_then(|contents| contents.trim().parse::<u64>().ok())
+        };
+        let unprivileged_userns = match (
+            setting(&probes.unprivileged_userns_clone),
+            setting(&probes.max_user_namespaces),
+        ) {
+            (Some(0), _) | (_, Some(0)) => Some(false),
+            (_, Some(_)) => Some(true),
+            (Some(_), None) | (None, None) => None,
+        };
+        let ranges = |contents: &Option<String>| match contents {
+            Some(contents) => parse_sub_id_ranges(contents, probes.uid, &probes.user_name),
+            None => Vec::new(),
+        };
+        SystemInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            kernel_release: probes.kernel_release.clone(),
+            overlayfs_available,
+            redirect_dir: probes.redirect_dir,
+            unprivileged_userns,
+            subuid_ranges: ranges(&probes.subuid),
+            subgid_ranges: ranges(&probes.subgid),
+            selinux_enforcing: probes.selinux_enforcing,
+        }
+    }
+
+    pub fn print(&self) {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        println!("mzr version:          {}", self.version);
+        println!("kernel release:       {}", self.kernel_release);
+        println!("overlayfs available:  {}", yes_no(self.overlayfs_available));
+        println!(
+            "overlay redirect_dir: {}",
+            match self.redirect_dir {
+                RedirectDirSupport::Unknown => "unknown (overlay module not loaded)",
+                RedirectDirSupport::Unsupported => "unsupported",
+                RedirectDirSupport::DefaultOff => "supported, off by default",
+                RedirectDirSupport::DefaultOn => "supported, on by default",
+            }
+        );
+        println!(
+            "unprivileged userns:  {}",
+            match self.unprivileged_userns {
+                Some(enabled) => yes_no(enabled),
+                None => "unknown",
+            }
+        );
+        println!(
+            "subuid ranges:        {}",
+            format_ranges(&self.subuid_ranges)
+        );
+        println!(
+            "subgid ranges:        {}",
+            format_ranges(&self.subgid_ranges)
+        );
+        println!("SELinux enforcing:    {}", yes_no(self.selinux_enforcing));
+    }
+}
+
+/// Parses the ranges of a user from a file in the format of `/etc/subuid`,
+/// where each line is `USER:START:COUNT`, and the user may be a name or uid.
+pub fn parse_sub_id_ranges(
+    contents: &str,
+    uid: Uid,
+    user_name: &Option<String>,
+) -> Vec<SubIdRange> {
+    let uid_string = uid.to_string();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.trim().split(':').collect();
+            match fields.as_slice() {
+                [user, start, count]
+                    if *user == uid_string
+                        || Some(*user) == user_name.as_ref().map(|x| x.as_str()) =>
+                {
+                    Some(SubIdRange {
+                        start: start.parse().ok()?,
+                        count: count.parse().ok()?,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn format_ranges(ranges: &[SubIdRange]) -> String {
+    if ranges.is_empty() {
+        return String::from("none");
+    }
+    ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "{}-{}",
+                range.start,
+                range.start + range.count.saturating_sub(1)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Name of the user, from the password database, falling back on `$USER`.
+fn user_name(uid: Uid) -> Option<String> {
+    let passwd = unsafe { libc::getpwuid(libc::uid_t::from(uid)) };
+    if passwd.is_null() {
+        return env::var("USER").ok();
+    }
+    let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probes() -> Probes {
+        Probes {
+            kernel_release: "5.4.0-test".to_string(),
+            filesystems: Some("nodev\tsysfs\nnodev\toverlay\n\text4\n".to_string()),
+            redirect_dir: RedirectDirSupport::DefaultOff,
+            unprivileged_userns_clone: None,
+            max_user_namespaces: Some("63000\n".to_string()),
+            uid: Uid::from_raw(1000),
+            user_name: Some("alice".to_string()),
+            subuid: Some("alice:100000:65536\nbob:165536:65536\n".to_string()),
+            subgid: Some("1000:200000:65536\n".to_string()),
+            selinux_enforcing: false,
+        }
+    }
+
+    #[test]
+    fn assembles_system_info_from_probes() {
+        let info = SystemInfo::assemble(&probes());
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.kernel_release, "5.4.0-test");
+        assert!(info.overlayfs_available);
+        assert_eq!(info.redirect_dir, RedirectDirSupport::DefaultOff);
+        assert_eq!(info.unprivileged_userns, Some(true));
+        assert_eq!(
+            info.subuid_ranges,
+            vec![SubIdRange {
+                start: 100_000,
+                count: 65536
+            }]
+        );
+        assert_eq!(
+            info.subgid_ranges,
+            vec![SubIdRange {
+                start: 200_000,
+                count: 65536
+            }]
+        );
+        assert!(!info.selinux_enforcing);
+    }
+
+    #[test]
+    fn assembles_system_info_from_missing_probes() {
+        let info = SystemInfo::assemble(&Probes {
+            filesystems: None,
+            max_user_namespaces: None,
+            subuid: None,
+            subgid: None,
+            ..probes()
+        });
+        assert!(!info.overlayfs_available);
+        assert_eq!(info.unprivileged_userns, None);
+        assert!(info.subuid_ranges.is_empty());
+        assert!(info.subgid_ranges.is_empty());
+    }
+
+    #[test]
+    fn either_userns_setting_can_disable_it() {
+        let userns = |clone: Option<&str>, max: Option<&str>| {
+            SystemInfo::assemble(&Probes {
+                unprivileged_userns_clone: clone.map(String::from),
+                max_user_namespaces: max.map(String::from),
+                ..probes()
+            })
+            .unprivileged_userns
+        };
+        assert_eq!(userns(Some("0\n"), Some("63000\n")), Some(false));
+        assert_eq!(userns(Some("1\n"), Some("0\n")), Some(false));
+        assert_eq!(userns(Some("1\n"), Some("63000\n")), Some(true));
+        assert_eq!(userns(Some("1\n"), None), None);
+        assert_eq!(userns(Some("garbage"), Some("63000")), Some(true));
+    }
+
+    #[test]
+    fn parses_sub_id_ranges_of_user() {
+        let user_name = Some("alice".to_string());
+        let uid = Uid::from_raw(1000);
+        assert_eq!(
+            parse_sub_id_ranges(
+                "alice:100000:65536\n  1000:300000:10  \nbob:165536:65536\n",
+                uid,
+                &user_name
+            ),
+            vec![
+                SubIdRange {
+                    start: 100_000,
+                    count: 65536
+                },
+                SubIdRange {
+                    start: 300_000,
+                    count: 10
+                }
+            ]
+        );
+        assert!(parse_sub_id_ranges("", uid, &user_name).is_empty());
+        assert!(parse_sub_id_ranges("alice:100000:65536", uid, &None).is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_sub_id_lines() {
+        let user_name = Some("alice".to_string());
+        let ranges = parse_sub_id_ranges(
+            "alice\nalice:100000\nalice:x:65536\nalice:100000:-1\n\
+             alice:1:2:3\n# alice:1:2\nalice:100000:65536\n",
+            Uid::from_raw(1000),
+            &user_name,
+        );
+        assert_eq!(
+            ranges,
+            vec![SubIdRange {
+                start: 100_000,
+                count: 65536
+            }]
+        );
+    }
+}
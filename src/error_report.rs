@@ -0,0 +1,155 @@
+use crate::git::GitError;
+use crate::namespaces::{ChildDiedBeforeInit, ChildExited, ChildSignaled, HandshakeTimeout};
+use crate::paths::NotMzrDir;
+use crate::top_dirs::MzrDirNotFound;
+use crate::utils::Timeout;
+use failure::{Error, Fail};
+use serde::Serialize;
+use std::io;
+use structopt::clap;
+
+/// Machine readable form of an error, printed instead of the usual colored
+/// output when `--json-errors` is passed.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// Category of the error, determined by the first recognized error type
+    /// in the chain of causes.
+    pub kind: &'static str,
+    /// The error's message, which is the outermost context.
+    pub message: String,
+    /// Messages of the chain of errors which caused this one, outermost
+    /// first, not including the error's message.
+    pub causes: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(err: &Error) -> Self {
+        ErrorReport {
+            kind: error_kind(err),
+            message: plain_message(err.as_fail()),
+            causes: err.iter_chain().skip(1).map(plain_message).collect(),
+        }
+    }
+}
+
+fn error_kind(err: &Error) -> &'static str {
+    for cause in err.iter_chain() {
+        if cause.downcast_ref::<clap::Error>().is_some() {
+            return "usage";
+        } else if cause.downcast_ref::<MzrDirNotFound>().is_some() {
+            return "mzr_dir_not_found";
+        } else if cause.downcast_ref::<NotMzrDir>().is_some() {
+            return "not_mzr_dir";
+        } else if cause.downcast_ref::<GitError>().is_some() {
+            return "git";
+        } else if cause.downcast_ref::<Timeout>().is_some()
+            || cause.downcast_ref::<HandshakeTimeout>().is_some()
+        {
+            return "timeout";
+        } else if cause.downcast_ref::<ChildDiedBeforeInit>().is_some()
+            || cause.downcast_ref::<ChildExited>().is_some()
+            || cause.downcast_ref::<ChildSignaled>().is_some()
+        {
+            return "child_process";
+        } else if cause.downcast_ref::<nix::Error>().is_some() {
+            return "os";
+        } else if cause.downcast_ref::<io::Error>().is_some() {
+            return "io";
+        }
+    }
+    "other"
+}
+
+/// Many error messages include colored paths and names, which don't belong
+/// in JSON.
+fn plain_message(fail: &dyn Fail) -> String {
+    strip_ansi_escapes(&fail.to_string())
+}
+
+pub fn strip_ansi_escapes(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip the rest of the escape sequence, up to its final letter.
+            for c in &mut chars {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::ResultExt;
+    use nix::errno::Errno;
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+    use std::time::Duration;
+
+    fn kind<F: Fail>(cause: F) -> &'static str {
+        let result: Result<(), F> = Err(cause);
+        let err: Error = result
+            .context(format_err!("Outer context"))
+            .unwrap_err()
+            .into();
+        error_kind(&err)
+    }
+
+    #[test]
+    fn error_kind_finds_first_recognized_cause() {
+        let pid = Pid::from_raw(1234);
+        assert_eq!(kind(MzrDirNotFound), "mzr_dir_not_found");
+        assert_eq!(kind(NotMzrDir("/tmp".into())), "not_mzr_dir");
+        assert_eq!(kind(GitError::NotFound), "git");
+        assert_eq!(
+            kind(Timeout(
+                "unmounting /tmp".to_string(),
+                Duration::from_secs(1)
+            )),
+            "timeout"
+        );
+        assert_eq!(kind(HandshakeTimeout(pid)), "timeout");
+        assert_eq!(kind(ChildDiedBeforeInit(pid)), "child_process");
+        assert_eq!(kind(ChildExited(pid, 1)), "child_process");
+        assert_eq!(kind(ChildSignaled(pid, Signal::SIGKILL)), "child_process");
+        assert_eq!(kind(nix::Error::Sys(Errno::EPERM)), "os");
+        assert_eq!(kind(io::Error::from(io::ErrorKind::NotFound)), "io");
+        assert_eq!(error_kind(&format_err!("plain")), "other");
+    }
+
+    #[test]
+    fn strips_ansi_escapes() {
+        assert_eq!(strip_ansi_escapes("plain"), "plain");
+        assert_eq!(strip_ansi_escapes(""), "");
+        assert_eq!(
+            strip_ansi_escapes("\u{1b}[1;34mzone\u{1b}[0m zone does not exist."),
+            "zone zone does not exist."
+        );
+        assert_eq!(strip_ansi_escapes("a\u{1b}[38;5;12mb\u{1b}[0mc"), "abc");
+        // An unterminated escape swallows the rest of the input.
+        assert_eq!(strip_ansi_escapes("a\u{1b}[1;3"), "a");
+        assert_eq!(
+            strip_ansi_escapes("non-ascii é \u{1b}[1m✓\u{1b}[0m"),
+            "non-ascii é ✓"
+        );
+    }
+
+    #[test]
+    fn report_messages_are_plain() {
+        let err: Error = Err::<(), _>(format_err!("\u{1b}[1mcause\u{1b}[0m"))
+            .context(format_err!("\u{1b}[1mouter\u{1b}[0m"))
+            .unwrap_err()
+            .into();
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.kind, "other");
+        assert_eq!(report.message, "outer");
+        assert_eq!(report.causes, vec!["cause".to_string()]);
+    }
+}
@@ -8,7 +8,7 @@ use std::fmt;
 use std::fs::{create_dir_all, read_link};
 use std::io::ErrorKind;
 use std::os::unix::fs::symlink;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
 
 // This implements something very similar to git's old "workdir"
@@ -17,7 +17,7 @@ use std::process::{Command, ExitStatus, Stdio};
 //
 // Unlike the script there, this is idempotent, but only if the
 // symlinks are correct.
-pub fn symlink_git_repo(source_git_dir: &PathBuf, target_git_dir: &PathBuf) -> Result<(), Error> {
+pub fn symlink_git_repo(source_git_dir: &Path, target_git_dir: &Path) -> Result<(), Error> {
     // Based on list / code at
     // https://github.com/git/git/blob/e32afab7b0376a7b07601a87cd5c6841ff2a811a/contrib/workdir/git-new-workdir#L82
     for shared_path in [
@@ -67,7 +67,7 @@ pub fn symlink_git_repo(source_git_dir: &PathBuf, target_git_dir: &PathBuf) -> R
 }
 
 pub fn default_snap_name(work_dir: &UserWorkDir) -> Result<SnapName, Error> {
-    match current_ref_or_short_sha(&work_dir) {
+    match current_ref_or_short_sha(work_dir) {
         Err(e) => Err(format_err!(
             "Since no snapshot was specified, attempted to query git for \
              current ref or sha info. Encountered an error:\n{}",
@@ -0,0 +1,179 @@
+use crate::daemon;
+use crate::error_report::strip_ansi_escapes;
+use crate::json;
+use crate::paths::*;
+use crate::snapshot::{self, SnapInfo};
+use crate::zone::{self, ZoneInfo};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/*
+ * Inventory of a mzr directory
+ *
+ * Describes the metadata of all zones and snapshots, for auditing. Building
+ * it never reads file contents, but it does walk every snapshot and changes
+ * directory to total up file sizes, so it takes a while for large projects.
+ */
+
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    pub mzr_dir: PathBuf,
+    pub time: DateTime<Utc>,
+    pub snapshots: Vec<SnapInventory>,
+    pub zones: Vec<ZoneInventory>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapInventory {
+    pub name: SnapName,
+    /// `None` if the snapshot has no info file, or it couldn't be read.
+    pub info: Option<SnapInfo>,
+    /// Why the info couldn't be read.
+    pub info_error: Option<String>,
+    /// Total size of the files in the snapshot, in bytes.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ZoneInventory {
+    pub name: ZoneName,
+    /// `None` if the zone's info couldn't be read.
+    pub info: Option<ZoneInfo>,
+    /// Why the info couldn't be read.
+    pub info_error: Option<String>,
+    /// Total size of the files in the zone's changes directory, in bytes.
+    pub changes_size: u64,
+    /// Whether the zone's overlay is mounted, either by the running daemon or
+    /// within the current mount namespace. `None` if this couldn't be
+    /// determined.
+    pub mounted: Option<bool>,
+    /// Why it couldn't be determined whether the zone is mounted.
+    pub mounted_error: Option<String>,
+}
+
+impl Inventory {
+    /// Gathers the inventory. Problems with individual zones or snapshots,
+    /// such as invalid info files, are recorded rather than causing failure.
+    pub fn gather(mzr_dir: &MzrDir) -> Result<Inventory, Error> {
+        let mut snapshots = Vec::new();
+        for snap_name in snapshot::list_snaps(mzr_dir)? {
+            let (info, info_error) = split_result(snapshot::read_info(mzr_dir, &snap_name));
+            snapshots.push(SnapInventory {
+                size: tree_size(&SnapDir::new(mzr_dir, &snap_name)),
+                name: snap_name,
+                info: info.and_then(|info| info),
+                info_error,
+            });
+        }
+        let mut zones = Vec::new();
+        for zone_name in zone::list_zones(mzr_dir)? {
+            let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
+            let (info, info_error) =
+                split_result(json::read_versioned::<ZoneInfo>(&zone_dir.info_file()));
+            let (mounted, mounted_error) =
+                split_result(daemon::is_zone_mounted(mzr_dir, &zone_name));
+            zones.push(ZoneInventory {
+                changes_size: tree_size(&zone_dir.changes_dir()),
+                name: zone_name,
+                info,
+                info_error,
+                mounted,
+                mounted_error,
+            });
+        }
+        Ok(Inventory {
+            mzr_dir: mzr_dir.to_path_buf(),
+            time: Utc::now(),
+            snapshots,
+            zones,
+        })
+    }
+}
+
+fn split_result<T>(result: Result<T, Error>) -> (Option<T>, Option<String>) {
+    match result {
+        Ok(value) => (Some(value), None),
+        Err(e) => (None, Some(strip_ansi_escapes(&e.to_string()))),
+    }
+}
+
+/// Sum of the sizes of the files within a directory, from their metadata.
+/// Entries which can't be read are skipped, since the size is informational.
+fn tree_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, TestDir};
+    use std::fs;
+
+    #[test]
+    fn inventory_includes_all_zones_and_snapshots() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let s1 = test_snapshot(&mzr_dir, "s1", &[("file", "abc"), ("dir/file", "de")]);
+        let s2 = test_snapshot(&mzr_dir, "s2", &[]);
+        let s2_info_file = SnapInfoFile::new(&mzr_dir, &s2);
+        fs::create_dir_all(s2_info_file.parent().unwrap()).unwrap();
+        fs::write(&s2_info_file, "{").unwrap();
+        test_zone(&mzr_dir, "z1", &s1, &[("changed", "12345")]);
+        let z2 = test_zone(&mzr_dir, "z2", &s2, &[]);
+        fs::write(z2.zone_dir.info_file(), "{").unwrap();
+        let inventory = Inventory::gather(&mzr_dir).unwrap();
+        let snapshots: Vec<(String, u64, bool)> = inventory
+            .snapshots
+            .iter()
+            .map(|snap| ((*snap.name).clone(), snap.size, snap.info_error.is_some()))
+            .collect();
+        assert_eq!(
+            snapshots,
+            vec![("s1".to_string(), 5, false), ("s2".to_string(), 0, true)]
+        );
+        let zones: Vec<(String, u64, bool, Option<bool>)> = inventory
+            .zones
+            .iter()
+            .map(|zone| {
+                (
+                    (*zone.name).clone(),
+                    zone.changes_size,
+                    zone.info.is_some(),
+                    zone.mounted,
+                )
+            })
+            .collect();
+        assert_eq!(
+            zones,
+            vec![
+                ("z1".to_string(), 5, true, Some(false)),
+                ("z2".to_string(), 0, false, Some(false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn inventory_records_mount_state_errors() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let s1 = test_snapshot(&mzr_dir, "s1", &[]);
+        test_zone(&mzr_dir, "z1", &s1, &[]);
+        let daemon_dir = DaemonDir::new(&mzr_dir);
+        fs::create_dir_all(&daemon_dir).unwrap();
+        fs::write(DaemonPidFile::new(&daemon_dir), "not a pid").unwrap();
+        fs::write(DaemonMountsFile::new(&daemon_dir), "[]").unwrap();
+        let inventory = Inventory::gather(&mzr_dir).unwrap();
+        assert_eq!(inventory.zones.len(), 1);
+        assert_eq!(inventory.zones[0].mounted, None);
+        assert!(inventory.zones[0].mounted_error.is_some());
+    }
+}
@@ -1,11 +1,13 @@
 use chrono::{DateTime, Utc};
-use failure::Error;
+use failure::{Error, ResultExt};
 use semver::Version;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::fs::File;
+use serde_json::Value;
+use std::ffi::OsString;
+use std::fs::{remove_file, rename, File};
 use std::path::PathBuf;
+use std::process;
 
 const VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
 
@@ -22,9 +24,36 @@ pub struct WriterInfo {
     pub update_time: DateTime<Utc>,
 }
 
+/// Writes the json file atomically, by writing to a temporary file in the same
+/// directory, syncing it, and then renaming it over the target. This way, a
+/// crash while writing never leaves a partially written file.
 pub fn write<T: Serialize>(path: &PathBuf, value: &T) -> Result<(), Error> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("Unexpected error: {:?} has no file name.", path))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".tmp-{}", process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = write_and_sync(&tmp_path, value).and_then(|()| {
+        rename(&tmp_path, path).context(format_err!(
+            "Failed to rename {:?} to {:?}",
+            tmp_path,
+            path
+        ))?;
+        Ok(())
+    });
+    if result.is_err() {
+        // Ignore failure to remove, since the original error is more relevant.
+        let _ = remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_and_sync<T: Serialize>(path: &PathBuf, value: &T) -> Result<(), Error> {
+    let file = File::create(path)?;
     serde_json::to_writer_pretty(
-        File::create(path)?,
+        &file,
         &JsonFile {
             contents: value,
             writer: WriterInfo {
@@ -34,6 +63,7 @@ pub fn write<T: Serialize>(path: &PathBuf, value: &T) -> Result<(), Error> {
             },
         },
     )?;
+    file.sync_all()?;
     Ok(())
 }
 
@@ -43,3 +73,170 @@ where
 {
     Ok(serde_json::from_reader(File::open(path)?)?)
 }
+
+/// Types stored in json files which have a version number, so that files
+/// written by other versions of mzr are not misread.
+pub trait Versioned: DeserializeOwned {
+    /// Version written by this version of mzr.
+    const VERSION: u32;
+
+    /// Converts contents with the specified version to the next version.
+    fn migrate(version: u32, contents: Value) -> Result<Value, Error>;
+}
+
+/// Reads a file containing a `Versioned` type, migrating it if it has an
+/// older version. Files from before versioning was added, which lack a
+/// `version` field, are treated as version 0. Files with a newer version than
+/// is known by this mzr are rejected.
+pub fn read_versioned<T: Versioned>(path: &PathBuf) -> Result<T, Error> {
+    let file: JsonFile<Value> = read(path)?;
+    let mut contents = file.contents;
+    let mut version = match contents.get("version") {
+        None => 0,
+        Some(Value::Number(number)) => match number.as_u64() {
+            Some(version) if version <= u64::from(u32::MAX) => version as u32,
+            _ => bail!("Invalid version number {} in {:?}", number, path),
+        },
+        Some(other) => bail!("Expected a version number in {:?}, but got {}", path, other),
+    };
+    if version > T::VERSION {
+        bail!(
+            "{:?} has version {}, but this version of mzr only understands versions up to {}. \
+             It was written by mzr {}, so perhaps mzr needs to be upgraded.",
+            path,
+            version,
+            T::VERSION,
+            file.writer.mzr_version
+        );
+    }
+    while version < T::VERSION {
+        contents = T::migrate(version, contents)?;
+        version += 1;
+    }
+    if let Value::Object(fields) = &mut contents {
+        fields.insert(String::from("version"), Value::from(version));
+    }
+    Ok(serde_json::from_value(contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Format where version 1 renamed `name` to `title`, and version 2 added
+    /// `tags`.
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Note {
+        version: u32,
+        title: String,
+        tags: Vec<String>,
+    }
+
+    impl Versioned for Note {
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, mut contents: Value) -> Result<Value, Error> {
+            let fields = contents.as_object_mut().unwrap();
+            match version {
+                0 => {
+                    let name = fields.remove("name").unwrap();
+                    fields.insert(String::from("title"), name);
+                }
+                1 => {
+                    fields.insert(String::from("tags"), json!([]));
+                }
+                _ => bail!("no migration from version {}", version),
+            }
+            Ok(contents)
+        }
+    }
+
+    #[test]
+    fn older_versions_are_migrated() {
+        let dir = TestDir::new();
+        let path = dir.join("note.json");
+        let note = |title: &str, tags: &[&str]| Note {
+            version: 2,
+            title: title.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        write(&path, &json!({ "name": "unversioned" })).unwrap();
+        assert_eq!(
+            read_versioned::<Note>(&path).unwrap(),
+            note("unversioned", &[])
+        );
+        write(&path, &json!({ "version": 1, "title": "old" })).unwrap();
+        assert_eq!(read_versioned::<Note>(&path).unwrap(), note("old", &[]));
+        write(
+            &path,
+            &json!({ "version": 2, "title": "new", "tags": ["a"] }),
+        )
+        .unwrap();
+        assert_eq!(read_versioned::<Note>(&path).unwrap(), note("new", &["a"]));
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let dir = TestDir::new();
+        let path = dir.join("note.json");
+        write(
+            &path,
+            &json!({ "version": 3, "title": "future", "tags": [] }),
+        )
+        .unwrap();
+        let err = read_versioned::<Note>(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only understands versions up to 2"));
+        for version in &[json!(-1), json!(1.5), json!("2"), json!(u64::MAX)] {
+            write(&path, &json!({ "version": version, "title": "bad" })).unwrap();
+            assert!(read_versioned::<Note>(&path).is_err(), "{}", version);
+        }
+    }
+
+    #[test]
+    fn writes_replace_files_completely_or_not_at_all() {
+        let dir = TestDir::new();
+        let path = dir.join("info.json");
+        write(&path, &json!({ "value": "old" })).unwrap();
+        // Serialization fails partway through writing, since json object keys
+        // must be strings.
+        let mut unserializable = BTreeMap::new();
+        unserializable.insert(vec![1u8], "new");
+        assert!(write(&path, &unserializable).is_err());
+        let file: JsonFile<Value> = read(&path).unwrap();
+        assert_eq!(file.contents, json!({ "value": "old" }));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        // Readers only ever see complete files, while they're being replaced.
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let path = path.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) || reads == 0 {
+                    let file: JsonFile<Value> = read(&path).unwrap();
+                    let value = file.contents["value"].as_str().unwrap().to_string();
+                    assert!(value == "old" || value.starts_with("new"), "{}", value);
+                    reads += 1;
+                }
+            })
+        };
+        for i in 0..200 {
+            write(
+                &path,
+                &json!({ "value": format!("new {}", "x".repeat(i * 50)) }),
+            )
+            .unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+    }
+}
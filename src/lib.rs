@@ -1,39 +1,65 @@
 #![feature(try_blocks)]
-#![feature(const_vec_new)]
 #![warn(rust_2018_idioms)]
 // Allowing these makes prototyping convenient.
 //
 // TODO(cleanup): remove once that phase is done.
 #![allow(dead_code)]
 #![allow(unused_imports)]
+// The derives in failure_derive 0.1 define their impls within a constant.
+#![allow(non_local_definitions)]
 
 // TODO(cleanup): figure out how to remove this
 #[macro_use]
 extern crate failure;
 
+mod archive;
+mod changes;
 pub mod colors;
+mod config;
 mod daemon;
+mod diff;
+mod doctor;
+pub mod error_report;
 mod git;
+mod inventory;
 mod json;
 mod merge;
+mod mount;
 mod namespaces;
 mod paths;
+mod registry;
 mod snapshot;
+#[cfg(test)]
+mod test_utils;
 mod top_dirs;
 mod utils;
+mod vfs;
 mod zone;
 
-use crate::colors::color_dir;
+use crate::archive::{Compression, SshDestination};
+use crate::colors::{color_dir, color_err};
+use crate::config::NonEmptyTargetPolicy;
+use crate::diff::Comparison;
 use crate::merge::{interactive_merge, Mode};
-use crate::paths::{SnapName, ZoneName};
+use crate::mount::{BindSpec, MountOptions, OverlayMount};
+use crate::namespaces::UserMapping;
+use crate::paths::{ConfigFile, SnapDir, SnapName, UserWorkDir, ZoneDir, ZoneName, ZoneTargetDir};
+use crate::snapshot::{CopyOptions, CopyProgress, XattrPolicy};
 use crate::top_dirs::TopDirs;
-use crate::utils::{execvp, exit_with_status, find_existent_parent_dir, maybe_strip_prefix};
+use crate::utils::{
+    check_contained_rel_path, confirm, execvp, exit_with_status, find_existent_parent_dir,
+    format_bytes, is_empty_dir, maybe_strip_prefix, run_process, Confirmed,
+};
 use crate::zone::Zone;
-use failure::Error;
-use nix::unistd::Pid;
+use chrono::Utc;
+use failure::{Error, ResultExt};
+use nix::unistd::{Gid, Pid, Uid};
 use std::env;
-use std::path::PathBuf;
-use std::process::Command;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 use void::unreachable;
 
@@ -43,9 +69,23 @@ use void::unreachable;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "mzr", author = "Michael Sloan <mgsloan@gmail.com>")]
+pub struct Opts {
+    #[structopt(
+        long = "json-errors",
+        help = "Print errors to stderr as JSON, for use by other tools."
+    )]
+    pub json_errors: bool,
+    #[structopt(subcommand)]
+    pub cmd: Cmd,
+}
+
+#[derive(StructOpt, Debug)]
 pub enum Cmd {
     #[structopt(name = "daemon", about = "Run mzr daemon")]
-    Daemon {},
+    Daemon {
+        #[structopt(flatten)]
+        opts: DaemonOpts,
+    },
     #[structopt(name = "shell", about = "Enter a mzr shell")]
     Shell {
         #[structopt(flatten)]
@@ -59,11 +99,269 @@ pub enum Cmd {
         #[structopt(flatten)]
         opts: RunOpts,
     },
+    #[structopt(name = "create", about = "Create a zone based on a snapshot")]
+    Create {
+        #[structopt(flatten)]
+        opts: CreateOpts,
+    },
+    #[structopt(
+        name = "compare",
+        about = "Enter a shell with several zones mounted at separate directories"
+    )]
+    Compare {
+        #[structopt(flatten)]
+        opts: CompareOpts,
+    },
     #[structopt(name = "snap", about = "Create mzr snapshot of working directory")]
     Snap {
         #[structopt(flatten)]
         opts: SnapOpts,
     },
+    #[structopt(
+        name = "tag-snap",
+        about = "Set or remove key / value tags of a snapshot"
+    )]
+    TagSnap {
+        #[structopt(flatten)]
+        opts: TagSnapOpts,
+    },
+    #[structopt(
+        name = "list-snaps",
+        about = "List snapshots and their tags, optionally only those with a tag"
+    )]
+    ListSnaps {
+        #[structopt(flatten)]
+        opts: ListSnapsOpts,
+    },
+    #[structopt(name = "dup-snap", about = "Create a copy of a snapshot")]
+    DupSnap {
+        #[structopt(flatten)]
+        opts: DupSnapOpts,
+    },
+    #[structopt(name = "export-snap", about = "Export a snapshot as a tar archive")]
+    ExportSnap {
+        #[structopt(flatten)]
+        opts: ExportSnapOpts,
+    },
+    #[structopt(
+        name = "import-snap",
+        about = "Create a snapshot from a tar archive, which may be zstd compressed"
+    )]
+    ImportSnap {
+        #[structopt(flatten)]
+        opts: ImportSnapOpts,
+    },
+    #[structopt(
+        name = "diff-snaps",
+        about = "Show the differences between two snapshots"
+    )]
+    DiffSnaps {
+        #[structopt(flatten)]
+        opts: DiffSnapsOpts,
+    },
+    #[structopt(
+        name = "diff-zones",
+        about = "Compare the changes of two zones, showing which paths each changed and \
+                 which changes conflict"
+    )]
+    DiffZones {
+        #[structopt(flatten)]
+        opts: DiffZonesOpts,
+    },
+    #[structopt(
+        name = "copy-changes",
+        about = "Copy the changes made in one zone into another zone"
+    )]
+    CopyChanges {
+        #[structopt(flatten)]
+        opts: CopyChangesOpts,
+    },
+    #[structopt(
+        name = "export-changes",
+        about = "Export the changes made in a zone as an archive"
+    )]
+    ExportChanges {
+        #[structopt(flatten)]
+        opts: ExportChangesOpts,
+    },
+    #[structopt(
+        name = "apply-changes",
+        about = "Apply an archive of changes to a zone based on the same snapshot"
+    )]
+    ApplyChanges {
+        #[structopt(flatten)]
+        opts: ApplyChangesOpts,
+    },
+    #[structopt(
+        name = "mount-plan",
+        about = "Print the mount command equivalent to how a zone gets mounted"
+    )]
+    MountPlan {
+        #[structopt(flatten)]
+        opts: MountPlanOpts,
+    },
+    #[structopt(
+        name = "inspect-mount",
+        about = "Print the mount options that the kernel recorded for a mounted zone"
+    )]
+    InspectMount {
+        #[structopt(flatten)]
+        opts: InspectMountOpts,
+    },
+    #[structopt(
+        name = "reset",
+        about = "Discard all of a zone's changes, so that it matches its snapshot"
+    )]
+    Reset {
+        #[structopt(flatten)]
+        opts: ResetOpts,
+    },
+    #[structopt(
+        name = "compact",
+        about = "Snapshot a zone's current state and base the zone on it, clearing its changes."
+    )]
+    Compact {
+        #[structopt(flatten)]
+        opts: CompactOpts,
+    },
+    #[structopt(
+        name = "rebase",
+        about = "Base a zone on a different snapshot, keeping its changes"
+    )]
+    Rebase {
+        #[structopt(flatten)]
+        opts: RebaseOpts,
+    },
+    #[structopt(
+        name = "rm",
+        about = "Delete a zone, by moving it to the trash unless --permanent is specified"
+    )]
+    Rm {
+        #[structopt(flatten)]
+        opts: RmOpts,
+    },
+    #[structopt(name = "pin", about = "Pin a zone, protecting it from deletion")]
+    Pin {
+        #[structopt(flatten)]
+        opts: PinOpts,
+    },
+    #[structopt(name = "unpin", about = "Unpin a zone, allowing it to be deleted")]
+    Unpin {
+        #[structopt(flatten)]
+        opts: PinOpts,
+    },
+    #[structopt(
+        name = "describe",
+        about = "Show information about a zone, such as its snapshot and description"
+    )]
+    Describe {
+        #[structopt(flatten)]
+        opts: DescribeOpts,
+    },
+    #[structopt(
+        name = "set-description",
+        about = "Set or clear a zone's description, as a reminder of what it's for"
+    )]
+    SetDescription {
+        #[structopt(flatten)]
+        opts: SetDescriptionOpts,
+    },
+    #[structopt(
+        name = "list-zones",
+        about = "List the zones, along with their snapshots and whether they're pinned"
+    )]
+    ListZones {
+        #[structopt(flatten)]
+        opts: ListZonesOpts,
+    },
+    #[structopt(
+        name = "list-orphans",
+        about = "List zone directories left over without valid zone info, such as from an \
+                 interrupted create"
+    )]
+    ListOrphans {},
+    #[structopt(
+        name = "inventory",
+        about = "Print the metadata of all zones and snapshots as JSON, without reading file \
+                 contents"
+    )]
+    Inventory {},
+    #[structopt(
+        name = "empty-trash",
+        about = "Permanently delete the zones in the trash"
+    )]
+    EmptyTrash {},
+    #[structopt(
+        name = "snap-tree",
+        about = "Show the tree of snapshots and the snapshots derived from them"
+    )]
+    SnapTree {
+        #[structopt(flatten)]
+        opts: SnapTreeOpts,
+    },
+    #[structopt(
+        name = "snap-chain",
+        about = "Show the snapshot a zone is based on, and the snapshots it was derived from"
+    )]
+    SnapChain {
+        #[structopt(flatten)]
+        opts: SnapChainOpts,
+    },
+    #[structopt(
+        name = "ls-snap",
+        about = "List the files within a snapshot, without mounting it"
+    )]
+    LsSnap {
+        #[structopt(flatten)]
+        opts: LsSnapOpts,
+    },
+    #[structopt(
+        name = "list-ops",
+        about = "List operations of mzr processes, for any project, that hold locks"
+    )]
+    ListOps {},
+    #[structopt(
+        name = "list-mounted",
+        about = "List the zones of any project which are mounted in this mount namespace"
+    )]
+    ListMounted {},
+    #[structopt(
+        name = "unmount-all",
+        about = "Unmount all of the project's zones, such as before deleting the project"
+    )]
+    UnmountAll {
+        #[structopt(flatten)]
+        opts: UnmountAllOpts,
+    },
+    #[structopt(
+        name = "doctor",
+        about = "Print the mzr version and a summary of the kernel features it relies on"
+    )]
+    Doctor {
+        #[structopt(flatten)]
+        opts: DoctorOpts,
+    },
+    #[structopt(
+        name = "path-status",
+        about = "Show whether a path was added, modified, or deleted in a zone"
+    )]
+    PathStatus {
+        #[structopt(flatten)]
+        opts: PathStatusOpts,
+    },
+    #[structopt(
+        name = "which-zone",
+        about = "Print the name of the zone mounted at a work directory, failing if there is none"
+    )]
+    WhichZone {
+        #[structopt(flatten)]
+        opts: WhichZoneOpts,
+    },
+    #[structopt(name = "paths", about = "Print the paths used by a zone")]
+    Paths {
+        #[structopt(flatten)]
+        opts: PathsOpts,
+    },
     /*
     #[structopt(
         name = "go",
@@ -78,10 +376,51 @@ pub enum Cmd {
 
 pub fn run_cmd(cmd: &Cmd) -> Result<(), Error> {
     match cmd {
-        Cmd::Daemon {} => daemon(),
-        Cmd::Shell { opts } => shell(&opts),
-        Cmd::Run { opts } => run(&opts),
-        Cmd::Snap { opts } => snap(&opts),
+        // The doctor command reports on the system even when /proc is missing.
+        Cmd::Doctor { .. } => {}
+        _ => utils::check_proc_mounted()?,
+    }
+    match cmd {
+        Cmd::Daemon { opts } => daemon(opts),
+        Cmd::Shell { opts } => shell(opts),
+        Cmd::Run { opts } => run(opts),
+        Cmd::Create { opts } => create(opts),
+        Cmd::Compare { opts } => compare(opts),
+        Cmd::Snap { opts } => snap(opts),
+        Cmd::TagSnap { opts } => tag_snap(opts),
+        Cmd::ListSnaps { opts } => list_snaps(opts),
+        Cmd::DupSnap { opts } => dup_snap(opts),
+        Cmd::ExportSnap { opts } => export_snap(opts),
+        Cmd::ImportSnap { opts } => import_snap(opts),
+        Cmd::DiffSnaps { opts } => diff_snaps(opts),
+        Cmd::DiffZones { opts } => diff_zones(opts),
+        Cmd::CopyChanges { opts } => copy_changes(opts),
+        Cmd::ExportChanges { opts } => export_changes(opts),
+        Cmd::ApplyChanges { opts } => apply_changes(opts),
+        Cmd::MountPlan { opts } => mount_plan(opts),
+        Cmd::InspectMount { opts } => inspect_mount(opts),
+        Cmd::Reset { opts } => reset(opts),
+        Cmd::Compact { opts } => compact(opts),
+        Cmd::Rebase { opts } => rebase(opts),
+        Cmd::Rm { opts } => rm(opts),
+        Cmd::Pin { opts } => pin(opts, true),
+        Cmd::Unpin { opts } => pin(opts, false),
+        Cmd::Describe { opts } => describe(opts),
+        Cmd::SetDescription { opts } => set_description(opts),
+        Cmd::ListZones { opts } => list_zones(opts),
+        Cmd::ListOrphans {} => list_orphans(),
+        Cmd::Inventory {} => inventory(),
+        Cmd::EmptyTrash {} => empty_trash(),
+        Cmd::SnapTree { opts } => snap_tree(opts),
+        Cmd::SnapChain { opts } => snap_chain(opts),
+        Cmd::LsSnap { opts } => ls_snap(opts),
+        Cmd::ListOps {} => list_ops(),
+        Cmd::ListMounted {} => list_mounted(),
+        Cmd::UnmountAll { opts } => unmount_all(opts),
+        Cmd::Doctor { opts } => doctor(opts),
+        Cmd::PathStatus { opts } => path_status(opts),
+        Cmd::WhichZone { opts } => which_zone(opts),
+        Cmd::Paths { opts } => paths(opts),
         // Cmd::Go { opts } => go(&opts),
     }
 }
@@ -96,9 +435,24 @@ pub fn run_cmd(cmd: &Cmd) -> Result<(), Error> {
 // one. It may also be helpful in the future if a root daemon is
 // supported (instead of using user namespaces).
 
-fn daemon() -> Result<(), Error> {
+#[derive(StructOpt, Debug)]
+pub struct DaemonOpts {
+    #[structopt(
+        long = "keep-user",
+        help = "Keep the current user and group within the daemon's namespace, \
+                instead of mapping them to root."
+    )]
+    keep_user: bool,
+}
+
+fn daemon(opts: &DaemonOpts) -> Result<(), Error> {
     let top_dirs = TopDirs::find_or_prompt_create("start mzr daemon")?;
-    daemon::run(&top_dirs)
+    let mapping = if opts.keep_user {
+        UserMapping::Current
+    } else {
+        UserMapping::Root
+    };
+    daemon::run(&top_dirs, mapping)
 }
 
 /*
@@ -115,31 +469,312 @@ pub struct ShellOpts {
                 If creating a new zone and this is unspecified, a new snapshot will be taken."
     )]
     snap_name: Option<SnapName>,
+    #[structopt(
+        long = "target",
+        parse(from_os_str),
+        help = "Absolute path of an existing directory to mount the zone at, \
+                instead of the work directory."
+    )]
+    target_dir: Option<PathBuf>,
+    #[structopt(
+        long = "seed-changes",
+        parse(from_os_str),
+        help = "Directory or tar archive of changes to populate the zone with, \
+                when creating a new zone."
+    )]
+    changes_seed: Option<PathBuf>,
+    #[structopt(
+        long = "bind",
+        help = "Extra directory to bind-mount within the zone, \
+                in the form SOURCE:TARGET[:ro|:rw]. May be specified multiple times."
+    )]
+    binds: Vec<BindSpec>,
+    #[structopt(
+        long = "create-work-dir",
+        help = "Create the work directory that the zone gets mounted at, if it doesn't exist."
+    )]
+    create_work_dir: bool,
 }
 
 fn shell(opts: &ShellOpts) -> Result<(), Error> {
     let top_dirs = TopDirs::find_or_prompt_create("enter mzr shell")?;
+    let target_dir = match &opts.target_dir {
+        None => {
+            check_user_work_dir(&top_dirs.user_work_dir, opts.create_work_dir)?;
+            None
+        }
+        Some(path) => Some(confirm_zone_target_dir(&top_dirs, path)?),
+    };
+    for bind in &opts.binds {
+        bind.validate()?;
+    }
     if !Zone::exists(&top_dirs.mzr_dir, &opts.zone_name) {
-        let snap_name = default_git_snap_name(&top_dirs, &opts.snap_name)?;
+        let snap_name = default_base_snap_name(&top_dirs, &opts.snap_name)?;
         /* TODO(friendliness): What should the snapshot creation logic be?
         println!("Taking a snapshot named {}", snap_name);
         snapshot::create(&top_dirs.user_work_dir, &top_dirs.mzr_dir, &snap_name)?;
         println!("Finished taking snapshot.");
         */
         println!("Requested zone does not yet exist, so attempting to create it.");
-        Zone::create(&top_dirs.mzr_dir, &opts.zone_name, &snap_name)?;
+        match &opts.changes_seed {
+            None => Zone::create(
+                &top_dirs.mzr_dir,
+                &opts.zone_name,
+                &snap_name,
+                top_dirs.config.dir_mode,
+            )?,
+            Some(seed) => Zone::create_seeded(
+                &top_dirs.mzr_dir,
+                &opts.zone_name,
+                &snap_name,
+                seed,
+                top_dirs.config.dir_mode,
+            )?,
+        };
+    } else if opts.changes_seed.is_some() {
+        bail!(
+            "Can't seed changes of {} zone, because it already exists.",
+            opts.zone_name
+        );
+    };
+    enter_zone(&top_dirs, &opts.zone_name, target_dir.as_ref(), &opts.binds)?;
+    let void = execvp("/bin/bash")?;
+    unreachable(void)
+}
+
+/*
+ * "mzr create"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct CreateOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to create.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "SNAP_NAME",
+        help = "Name of the snapshot to base the zone on. \
+                If unspecified, the current git ref or sha is used."
+    )]
+    snap_name: Option<SnapName>,
+    #[structopt(
+        long = "idempotent",
+        help = "Succeed without changes if the zone already exists with the same snapshot."
+    )]
+    idempotent: bool,
+}
+
+fn create(opts: &CreateOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("create zone")?;
+    let snap_name = default_base_snap_name(&top_dirs, &opts.snap_name)?;
+    if opts.idempotent {
+        Zone::create_idempotent(
+            &top_dirs.mzr_dir,
+            &opts.zone_name,
+            &snap_name,
+            top_dirs.config.dir_mode,
+        )?;
+    } else {
+        Zone::create(
+            &top_dirs.mzr_dir,
+            &opts.zone_name,
+            &snap_name,
+            top_dirs.config.dir_mode,
+        )?;
+    }
+    println!(
+        "{} {} zone is based on snapshot {}.",
+        colors::color_success(&"Success:"),
+        opts.zone_name,
+        snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr compare"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct CompareOpts {
+    #[structopt(
+        name = "ZONE:TARGET",
+        required = true,
+        help = "Zones to mount, each followed by the absolute path of an existing directory \
+                to mount it at."
+    )]
+    mounts: Vec<ZoneMountSpec>,
+}
+
+/// Zone to mount at a particular directory, parsed from `ZONE:TARGET`.
+#[derive(Debug)]
+struct ZoneMountSpec {
+    zone_name: ZoneName,
+    target_dir: PathBuf,
+}
+
+impl FromStr for ZoneMountSpec {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.find(':') {
+            Some(index) if index > 0 && index + 1 < spec.len() => Ok(ZoneMountSpec {
+                zone_name: ZoneName::new(spec[..index].to_string())?,
+                target_dir: PathBuf::from(&spec[index + 1..]),
+            }),
+            _ => bail!(
+                "Expected zone mount in the form ZONE:TARGET, but got {:?}",
+                spec
+            ),
+        }
+    }
+}
+
+fn compare(opts: &CompareOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("compare zones")?;
+    let mzr_dir = &top_dirs.mzr_dir;
+    let user = Uid::current();
+    let group = Gid::current();
+    let mut zones = Vec::new();
+    for spec in &opts.mounts {
+        let target_dir = confirm_zone_target_dir(&top_dirs, &spec.target_dir)?;
+        let zone = Zone::load(mzr_dir, &spec.zone_name)?;
+        zones.push((zone, target_dir));
+    }
+    // The zones are read through their overlay mounts, so this doesn't bind
+    // them over the work directory.
+    let zone_names: Vec<&ZoneName> = zones.iter().map(|(zone, _)| &zone.name).collect();
+    let newly_mounted = mount_zones_or_unmount(
+        &zone_names,
+        |zone_name| {
+            let was_mounted = daemon::is_zone_mounted(mzr_dir, zone_name)?;
+            daemon::mount_zone(mzr_dir, zone_name)?;
+            Ok(!was_mounted)
+        },
+        |zone_name| daemon::unmount_zone(mzr_dir, zone_name),
+    )?;
+    let unmount_newly_mounted = || {
+        unmount_zones(&newly_mounted, |zone_name| {
+            daemon::unmount_zone(mzr_dir, zone_name)
+        })
     };
-    enter_zone(&top_dirs, &opts.zone_name)?;
+    // Bind the zones within a private mount namespace nested in the daemon's,
+    // so that they get unmounted when the shell exits.
+    let result: Result<(), Error> = try {
+        namespaces::enter_daemon_space(mzr_dir)?;
+        for (zone, _) in &zones {
+            mount::wait_until_mounted(zone, Duration::from_secs(5))?;
+        }
+        namespaces::unshare_mount()?;
+    };
+    if let Err(e) = result {
+        unmount_newly_mounted();
+        return Err(e);
+    }
+    let mut bound: Vec<&ZoneTargetDir> = Vec::new();
+    for (zone, target_dir) in &zones {
+        match zone.bind_to(target_dir) {
+            Ok(()) => {
+                println!("Mounted {} zone at {}", zone.name, target_dir);
+                bound.push(target_dir);
+            }
+            Err(e) => {
+                println!(
+                    "{} Failed to mount {} zone at {}: {}",
+                    color_err(&"Error:"),
+                    zone.name,
+                    target_dir,
+                    e
+                );
+                for target_dir in bound.iter().rev() {
+                    mount::unmount(target_dir)?;
+                }
+                unmount_newly_mounted();
+                bail!("Not all zones could be mounted, so none were left mounted.");
+            }
+        }
+    }
+    namespaces::unshare_user_as(user, group)?;
+    println!("Zones will be unmounted when this shell exits.");
     let void = execvp("/bin/bash")?;
     unreachable(void)
 }
 
+/// Mounts each of the zones in order, via `mount`, which yields whether the
+/// zone was newly mounted rather than already mounted. Yields the zones which
+/// were newly mounted. If a zone fails to mount, then the zones which were
+/// newly mounted before it get unmounted, so that they aren't left mounted.
+fn mount_zones_or_unmount<'a, M, U>(
+    zone_names: &[&'a ZoneName],
+    mut mount: M,
+    unmount: U,
+) -> Result<Vec<&'a ZoneName>, Error>
+where
+    M: FnMut(&ZoneName) -> Result<bool, Error>,
+    U: FnMut(&ZoneName) -> Result<(), Error>,
+{
+    let mut newly_mounted = Vec::new();
+    for zone_name in zone_names {
+        match mount(zone_name) {
+            Ok(true) => newly_mounted.push(*zone_name),
+            Ok(false) => {}
+            Err(e) => {
+                println!(
+                    "{} Failed to mount {} zone: {}",
+                    color_err(&"Error:"),
+                    zone_name,
+                    e
+                );
+                unmount_zones(&newly_mounted, unmount);
+                bail!("Not all zones could be mounted, so none were left mounted.");
+            }
+        }
+    }
+    Ok(newly_mounted)
+}
+
+/// Unmounts zones in the reverse of the order they were mounted in. Failures
+/// are only warnings, so that the rest still get unmounted.
+fn unmount_zones<U>(zone_names: &[&ZoneName], mut unmount: U)
+where
+    U: FnMut(&ZoneName) -> Result<(), Error>,
+{
+    for zone_name in zone_names.iter().rev() {
+        if let Err(e) = unmount(zone_name) {
+            println!(
+                "{} Failed to unmount {} zone: {}",
+                colors::color_warn(&"Warning:"),
+                zone_name,
+                e
+            );
+        }
+    }
+}
+
 /*
  * "mzr run"
  */
 
 #[derive(StructOpt, Debug)]
 pub struct RunOpts {
+    #[structopt(
+        long = "env",
+        short = "e",
+        parse(try_from_str = "parse_env_var"),
+        help = "Environment variable to set for the command, in the form KEY=VALUE. \
+                Can be specified multiple times."
+    )]
+    env: Vec<(String, String)>,
+    #[structopt(
+        long = "clear-env",
+        help = "Run the command with only the variables specified via --env, \
+                rather than adding them to the inherited environment."
+    )]
+    clear_env: bool,
+    #[structopt(
+        long = "setup",
+        help = "Shell command to run within the zone before running CMD, such as to add \
+                mounts. If it fails, then CMD is not run."
+    )]
+    setup: Option<String>,
     #[structopt(name = "CMD")]
     cmd: String,
     #[structopt(name = "ARGS")]
@@ -160,15 +795,30 @@ fn run(opts: &RunOpts) -> Result<(), Error> {
     let snap_name = SnapName::new(tmp_name.clone())?;
     let zone_name = ZoneName::new(tmp_name.clone())?;
     println!("Taking temporary snapshot named {}", snap_name);
-    snapshot::of_workdir(&top_dirs, &snap_name)?;
-    let zone = Zone::create(&top_dirs.mzr_dir, &zone_name, &snap_name)?;
+    let copy_options = CopyOptions {
+        dir_mode: top_dirs.config.dir_mode,
+        ..CopyOptions::default()
+    };
+    snapshot::of_workdir(&top_dirs, &snap_name, &copy_options)?;
+    let cleanup = TempRunCleanup {
+        top_dirs: &top_dirs,
+        zone_name: &zone_name,
+        snap_name: &snap_name,
+        keep: false,
+    };
+    let zone = Zone::create(
+        &top_dirs.mzr_dir,
+        &zone_name,
+        &snap_name,
+        top_dirs.config.dir_mode,
+    )?;
     println!(
         "Running {} inside temporary zone named {}\n",
         opts.cmd, zone_name
     );
     // Run process within the temporary zone, inheriting stdio.
-    enter_zone(&top_dirs, &zone_name)?;
-    let mut child = Command::new(&opts.cmd).args(&opts.args).spawn()?;
+    enter_zone(&top_dirs, &zone_name, None, &[])?;
+    let mut child = spawn_run_command(opts, cleanup)?;
     let status = child.wait()?;
     // TODO: I suppose the next steps here are:
     //
@@ -186,8 +836,97 @@ fn run(opts: &RunOpts) -> Result<(), Error> {
         top_dirs.user_work_dir.as_ref(),
         Mode::AutoApplyUpdates,
     )?;
-    let _void = exit_with_status(status);
-    unreachable(_void)
+    exit_with_status(status)
+}
+
+/// Runs the setup command, if any, and then spawns the command. If either
+/// fails, then the temporary zone and snapshot are removed. Otherwise they're
+/// kept, since the command may make changes which need merging.
+fn spawn_run_command(opts: &RunOpts, cleanup: TempRunCleanup<'_>) -> Result<Child, Error> {
+    let set_env = |cmd: &mut Command| {
+        if opts.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(opts.env.iter().cloned());
+    };
+    if let Some(setup) = &opts.setup {
+        let mut setup_cmd = Command::new("sh");
+        setup_cmd.arg("-c").arg(setup);
+        set_env(&mut setup_cmd);
+        run_process(&mut setup_cmd).context(format_err!(
+            "Setup command {:?} failed, so {} was not run.",
+            setup,
+            opts.cmd
+        ))?;
+    }
+    let mut cmd = Command::new(&opts.cmd);
+    cmd.args(&opts.args);
+    set_env(&mut cmd);
+    let child = cmd.spawn()?;
+    cleanup.keep();
+    Ok(child)
+}
+
+/// Removes the temporary zone and snapshot of `mzr run` when dropped, unless
+/// `keep` is called, so that they don't leak when the command can't be run,
+/// such as due to the setup command failing.
+struct TempRunCleanup<'a> {
+    top_dirs: &'a TopDirs,
+    zone_name: &'a ZoneName,
+    snap_name: &'a SnapName,
+    keep: bool,
+}
+
+impl<'a> TempRunCleanup<'a> {
+    fn keep(mut self) {
+        self.keep = true;
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        let mzr_dir = &self.top_dirs.mzr_dir;
+        // The zone gets mounted by the daemon, which also needs to kill the
+        // zone's process. Its overlay can't be unmounted from within the
+        // zone's namespaces, so the daemon deletes the zone.
+        if daemon::is_running(mzr_dir)? {
+            daemon::delete_zone(mzr_dir, self.zone_name)?;
+        } else if Zone::exists(mzr_dir, self.zone_name) {
+            zone::delete(mzr_dir, self.zone_name, true)?;
+        }
+        snapshot::delete(mzr_dir, self.snap_name)
+    }
+}
+
+impl<'a> Drop for TempRunCleanup<'a> {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        println!(
+            "Removing temporary zone {} and snapshot {}",
+            self.zone_name, self.snap_name
+        );
+        if let Err(e) = self.remove() {
+            println!(
+                "{} Failed to remove temporary zone {} and snapshot {}: {}",
+                colors::color_warn(&"Warning:"),
+                self.zone_name,
+                self.snap_name,
+                e
+            );
+        }
+    }
+}
+
+fn parse_env_var(input: &str) -> Result<(String, String), Error> {
+    match input.find('=') {
+        Some(index) if index > 0 => {
+            Ok((input[..index].to_string(), input[index + 1..].to_string()))
+        }
+        _ => bail!(
+            "Expected environment variable in the form KEY=VALUE, but got {:?}",
+            input
+        ),
+    }
 }
 
 /*
@@ -202,18 +941,1477 @@ pub struct SnapOpts {
                 If unspecified, a name will be generated based on the current git branch name."
     )]
     snap_name: Option<SnapName>,
-}
-
-fn snap(opts: &SnapOpts) -> Result<(), Error> {
-    let top_dirs = TopDirs::find_or_prompt_create("take mzr snapshot")?;
-    let snap_name = default_git_snap_name(&top_dirs, &opts.snap_name)?;
-    println!("Taking a snapshot named {}", snap_name);
-    let _snap_dir = snapshot::of_workdir(&top_dirs, &snap_name)?;
-    println!(
-        "{} snapshot named {} taken.",
-        colors::color_success(&"Success:"),
-        snap_name
+    #[structopt(
+        long = "xattrs",
+        default_value = "preserve-if-possible",
+        help = "How to handle extended attributes: \
+                preserve-if-possible, preserve, or strip."
+    )]
+    xattrs: XattrPolicy,
+    #[structopt(
+        long = "parallel",
+        help = "Copy files using multiple threads, \
+                which can be faster for large trees on fast storage."
+    )]
+    parallel: bool,
+    #[structopt(
+        long = "jobs",
+        help = "Number of threads to use with --parallel. \
+                Defaults to the number of CPUs."
+    )]
+    jobs: Option<usize>,
+    #[structopt(
+        long = "strict-special-files",
+        help = "Fail if a special file, such as a socket or device node, can't be copied, \
+                instead of skipping it with a warning."
+    )]
+    strict_special_files: bool,
+    #[structopt(
+        long = "progress",
+        help = "Report progress while copying, with an estimate of the time remaining. \
+                First measures the size of every file, unless --no-measure is used."
+    )]
+    progress: bool,
+    #[structopt(
+        long = "no-measure",
+        help = "With --progress, skip measuring file sizes, which can take a while for huge \
+                trees. Progress is then estimated by the number of files copied."
+    )]
+    no_measure: bool,
+    #[structopt(
+        long = "zone",
+        help = "Zone to take a snapshot of, instead of the work directory."
+    )]
+    zone: Option<ZoneName>,
+    #[structopt(
+        long = "live",
+        help = "With --zone, take the snapshot even if the zone is mounted, by copying \
+                its merged view. Files written while the snapshot is being taken may be \
+                captured partially written, or not at all."
+    )]
+    live: bool,
+    #[structopt(
+        long = "name-template",
+        help = "Template for the snapshot name, used when SNAP_NAME isn't specified. \
+                {zone} expands to the zone name, {date} to the current date, and {seq} \
+                to the lowest number which doesn't collide with an existing snapshot. \
+                Defaults to snap_name_template from the config."
+    )]
+    name_template: Option<String>,
+    #[structopt(
+        long = "subdir",
+        parse(from_os_str),
+        help = "With --zone, only snapshot this directory within the zone, relative to the \
+                work directory. The snapshot is rooted at the directory."
+    )]
+    subdir: Option<PathBuf>,
+}
+
+fn snap(opts: &SnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("take mzr snapshot")?;
+    let template = opts
+        .name_template
+        .as_ref()
+        .or(top_dirs.config.snap_name_template.as_ref());
+    let snap_name = match (&opts.snap_name, template) {
+        (None, Some(template)) => {
+            snapshot::expand_name_template(&top_dirs.mzr_dir, template, opts.zone.as_ref())?
+        }
+        _ => default_git_snap_name(&top_dirs, &opts.snap_name)?,
+    };
+    println!("Taking a snapshot named {}", snap_name);
+    let copy_options = CopyOptions {
+        xattrs: opts.xattrs,
+        jobs: if opts.parallel {
+            Some(opts.jobs.unwrap_or_else(snapshot::default_jobs))
+        } else {
+            None
+        },
+        strict_special_files: opts.strict_special_files,
+        progress: if opts.progress {
+            Some(print_copy_progress)
+        } else {
+            None
+        },
+        measure_sizes: opts.progress && !opts.no_measure,
+        dir_mode: top_dirs.config.dir_mode,
+    };
+    let _snap_dir = match &opts.zone {
+        None => {
+            if opts.live {
+                bail!("--live can only be used along with --zone.");
+            }
+            if opts.subdir.is_some() {
+                bail!("--subdir can only be used along with --zone.");
+            }
+            snapshot::of_workdir(&top_dirs, &snap_name, &copy_options)?
+        }
+        Some(zone_name) => {
+            let zone = Zone::load(&top_dirs.mzr_dir, zone_name)?;
+            let subdir = match &opts.subdir {
+                None => None,
+                Some(subdir) => Some(work_relative_subdir(&top_dirs, subdir)?),
+            };
+            if !daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)? {
+                match &subdir {
+                    None => snapshot::of_zone(
+                        &top_dirs.mzr_dir,
+                        &zone,
+                        &snap_name,
+                        top_dirs.config.dir_mode,
+                    )?,
+                    Some(subdir) => snapshot::of_zone_subdir(
+                        &top_dirs.mzr_dir,
+                        &zone,
+                        subdir,
+                        &snap_name,
+                        top_dirs.config.dir_mode,
+                    )?,
+                }
+            } else if opts.live {
+                println!(
+                    "{} {} zone is mounted, so files written while the snapshot is \
+                     being taken may be captured partially written.",
+                    colors::color_warn(&"Warning:"),
+                    zone.name
+                );
+                // The zone's overlay is only visible within the daemon's
+                // namespaces.
+                if daemon::is_running(&top_dirs.mzr_dir)? {
+                    namespaces::enter_daemon_space(&top_dirs.mzr_dir)?;
+                }
+                snapshot::of_mounted_zone(
+                    &top_dirs.mzr_dir,
+                    &zone,
+                    subdir.as_deref(),
+                    &snap_name,
+                    &copy_options,
+                )?
+            } else {
+                bail!(
+                    "{} zone is mounted. Use --live to take a snapshot of it anyway, \
+                     or unmount it first.",
+                    zone.name
+                );
+            }
+        }
+    };
+    println!(
+        "{} snapshot named {} taken.",
+        colors::color_success(&"Success:"),
+        snap_name
+    );
+    Ok(())
+}
+
+fn print_copy_progress(progress: &CopyProgress) {
+    println!("{}", progress);
+}
+
+/*
+ * "mzr tag-snap"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct TagSnapOpts {
+    #[structopt(name = "SNAP_NAME", help = "Name of the snapshot to tag.")]
+    snap_name: SnapName,
+    #[structopt(
+        name = "TAGS",
+        parse(try_from_str = "parse_tag"),
+        help = "Tags to set, in the form KEY=VALUE."
+    )]
+    tags: Vec<(String, String)>,
+    #[structopt(
+        long = "remove",
+        help = "Key of a tag to remove. Can be specified multiple times."
+    )]
+    remove: Vec<String>,
+}
+
+fn tag_snap(opts: &TagSnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("tag snapshot")?;
+    for key in &opts.remove {
+        if !snapshot::remove_tag(&top_dirs.mzr_dir, &opts.snap_name, key)? {
+            println!(
+                "{} {} snapshot has no {:?} tag to remove.",
+                colors::color_warn(&"Warning:"),
+                opts.snap_name,
+                key
+            );
+        }
+    }
+    for (key, value) in &opts.tags {
+        snapshot::set_tag(&top_dirs.mzr_dir, &opts.snap_name, key, value)?;
+    }
+    for (key, value) in snapshot::get_tags(&top_dirs.mzr_dir, &opts.snap_name)? {
+        println!("{}={}", key, value);
+    }
+    Ok(())
+}
+
+fn parse_tag(input: &str) -> Result<(String, String), Error> {
+    match input.find('=') {
+        Some(index) => {
+            let key = &input[..index];
+            snapshot::validate_tag_key(key)?;
+            Ok((key.to_string(), input[index + 1..].to_string()))
+        }
+        None => bail!("Expected tag in the form KEY=VALUE, but got {:?}", input),
+    }
+}
+
+/*
+ * "mzr list-snaps"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ListSnapsOpts {
+    #[structopt(
+        long = "tag",
+        help = "Only list snapshots with this tag, specified as KEY or KEY=VALUE."
+    )]
+    tag: Option<String>,
+}
+
+fn list_snaps(opts: &ListSnapsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list snapshots")?;
+    let snap_names = match &opts.tag {
+        None => snapshot::list_snaps(&top_dirs.mzr_dir)?,
+        Some(tag) => {
+            let (key, value) = match tag.find('=') {
+                Some(index) => (&tag[..index], Some(&tag[index + 1..])),
+                None => (&tag[..], None),
+            };
+            snapshot::list_snaps_with_tag(&top_dirs.mzr_dir, key, value)?
+        }
+    };
+    for snap_name in &snap_names {
+        let tags = snapshot::get_tags(&top_dirs.mzr_dir, snap_name)?
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>();
+        if tags.is_empty() {
+            println!("{}", snap_name);
+        } else {
+            println!("{} {}", snap_name, tags.join(" "));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr dup-snap"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct DupSnapOpts {
+    #[structopt(name = "SOURCE_SNAP", help = "Name of the snapshot to copy.")]
+    source_snap_name: SnapName,
+    #[structopt(name = "TARGET_SNAP", help = "Name of the snapshot to create.")]
+    target_snap_name: SnapName,
+}
+
+fn dup_snap(opts: &DupSnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("duplicate snapshot")?;
+    snapshot::duplicate(
+        &top_dirs.mzr_dir,
+        &opts.source_snap_name,
+        &opts.target_snap_name,
+        top_dirs.config.dir_mode,
+    )?;
+    println!(
+        "{} {} snapshot copied to {}.",
+        colors::color_success(&"Success:"),
+        opts.source_snap_name,
+        opts.target_snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr export-snap"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ExportSnapOpts {
+    #[structopt(name = "SNAP_NAME", help = "Name of the snapshot to export.")]
+    snap_name: SnapName,
+    #[structopt(
+        name = "ARCHIVE",
+        parse(from_os_str),
+        help = "Path of the tar archive to write. With --ssh-host, this is a path on the remote host."
+    )]
+    archive_path: PathBuf,
+    #[structopt(long = "zstd", help = "Compress the archive with zstd.")]
+    zstd: bool,
+    #[structopt(
+        long = "zstd-level",
+        default_value = "3",
+        help = "Compression level to use with --zstd, from 1 to 19."
+    )]
+    zstd_level: u32,
+    #[structopt(
+        long = "ssh-host",
+        help = "Write the archive to a remote host, by streaming it via ssh."
+    )]
+    ssh_host: Option<String>,
+    #[structopt(
+        long = "ssh-command",
+        default_value = "ssh",
+        help = "Command used to connect to the --ssh-host, such as \"ssh -p 2222\"."
+    )]
+    ssh_command: String,
+}
+
+fn export_snap(opts: &ExportSnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("export snapshot")?;
+    let compression = if opts.zstd {
+        Compression::zstd(opts.zstd_level)?
+    } else {
+        Compression::None
+    };
+    match &opts.ssh_host {
+        None => {
+            snapshot::export(
+                &top_dirs.mzr_dir,
+                &opts.snap_name,
+                &opts.archive_path,
+                compression,
+            )?;
+            println!(
+                "{} {} snapshot exported to {}",
+                colors::color_success(&"Success:"),
+                opts.snap_name,
+                colors::color_file(&opts.archive_path.display())
+            );
+        }
+        Some(host) => {
+            let destination = SshDestination::new(
+                &opts.ssh_command,
+                host,
+                &opts.archive_path.to_string_lossy(),
+            )?;
+            snapshot::export_ssh(
+                &top_dirs.mzr_dir,
+                &opts.snap_name,
+                &destination,
+                compression,
+            )?;
+            println!(
+                "{} {} snapshot exported to {}",
+                colors::color_success(&"Success:"),
+                opts.snap_name,
+                colors::color_file(&destination)
+            );
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr import-snap"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ImportSnapOpts {
+    #[structopt(name = "SNAP_NAME", help = "Name of the snapshot to create.")]
+    snap_name: SnapName,
+    #[structopt(
+        name = "ARCHIVE",
+        parse(from_os_str),
+        help = "Path of the tar archive to import."
+    )]
+    archive_path: PathBuf,
+    #[structopt(
+        long = "allow-absolute-symlinks",
+        help = "Import symlinks with absolute targets, which are refused by default \
+                since they could point anywhere."
+    )]
+    allow_absolute_symlinks: bool,
+}
+
+fn import_snap(opts: &ImportSnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find_or_prompt_create("import snapshot")?;
+    snapshot::import(
+        &top_dirs.mzr_dir,
+        &opts.snap_name,
+        &opts.archive_path,
+        opts.allow_absolute_symlinks,
+        top_dirs.config.dir_mode,
+    )?;
+    println!(
+        "{} {} snapshot imported.",
+        colors::color_success(&"Success:"),
+        opts.snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr diff-snaps"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct DiffSnapsOpts {
+    #[structopt(name = "OLD_SNAP", help = "Name of the snapshot to compare from.")]
+    old_snap_name: SnapName,
+    #[structopt(name = "NEW_SNAP", help = "Name of the snapshot to compare to.")]
+    new_snap_name: SnapName,
+    #[structopt(
+        long = "contents",
+        help = "Compare file contents, instead of sizes and modification times."
+    )]
+    contents: bool,
+    #[structopt(long = "json", help = "Output the differences as JSON.")]
+    json: bool,
+}
+
+fn diff_snaps(opts: &DiffSnapsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("diff snapshots")?;
+    let mut snap_dirs = Vec::new();
+    for snap_name in &[&opts.old_snap_name, &opts.new_snap_name] {
+        let snap_dir = SnapDir::new(&top_dirs.mzr_dir, snap_name);
+        if !snap_dir.is_dir() {
+            bail!(
+                "{} snapshot does not exist.{}",
+                snap_name,
+                snapshot::did_you_mean_snap(&top_dirs.mzr_dir, snap_name)?
+            );
+        }
+        snap_dirs.push(snap_dir);
+    }
+    let comparison = if opts.contents {
+        Comparison::Contents
+    } else {
+        Comparison::Metadata
+    };
+    let diff = diff::diff_snapshots(&snap_dirs[0], &snap_dirs[1], comparison)?;
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&diff))?
+        );
+    } else {
+        diff::print_diff(&diff);
+    }
+    Ok(())
+}
+
+/*
+ * "mzr diff-zones"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct DiffZonesOpts {
+    #[structopt(name = "FIRST_ZONE", help = "Name of the first zone to compare.")]
+    first_zone_name: ZoneName,
+    #[structopt(name = "SECOND_ZONE", help = "Name of the second zone to compare.")]
+    second_zone_name: ZoneName,
+    #[structopt(long = "json", help = "Output the comparison as JSON.")]
+    json: bool,
+}
+
+fn diff_zones(opts: &DiffZonesOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("diff zones")?;
+    let diff = diff::diff_zones(
+        &top_dirs.mzr_dir,
+        &opts.first_zone_name,
+        &opts.second_zone_name,
+    )?;
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&diff))?
+        );
+    } else {
+        if !diff.same_snapshot {
+            println!(
+                "{} {} and {} zones are based on different snapshots, so the same change \
+                 may have a different effect in each.",
+                colors::color_warn(&"Warning:"),
+                opts.first_zone_name,
+                opts.second_zone_name
+            );
+        }
+        diff::print_zones_diff(&diff);
+    }
+    Ok(())
+}
+
+/*
+ * "mzr copy-changes"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct CopyChangesOpts {
+    #[structopt(name = "SOURCE_ZONE", help = "Name of the zone to copy changes from.")]
+    source_zone_name: ZoneName,
+    #[structopt(name = "TARGET_ZONE", help = "Name of the zone to copy changes into.")]
+    target_zone_name: ZoneName,
+}
+
+fn copy_changes(opts: &CopyChangesOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("copy zone changes")?;
+    let source = Zone::load(&top_dirs.mzr_dir, &opts.source_zone_name)?;
+    let target = Zone::load(&top_dirs.mzr_dir, &opts.target_zone_name)?;
+    if daemon::is_zone_mounted(&top_dirs.mzr_dir, &target.name)? {
+        bail!(
+            "{} zone is mounted, and must be unmounted before changes can be copied into it.",
+            target.name
+        );
+    }
+    let plan = changes::plan_copy_changes(&source.ovfs_changes_dir, &target.ovfs_changes_dir)?;
+    if !plan.conflicts.is_empty() {
+        println!(
+            "The following paths have been changed in both {} and {}:",
+            source.name, target.name
+        );
+        for conflict in &plan.conflicts {
+            println!("* {:?}", conflict);
+        }
+        bail!(
+            "Not copying changes, since doing so would overwrite changes in {}.",
+            target.name
+        );
+    }
+    plan.apply(&source.ovfs_changes_dir, &target.ovfs_changes_dir)?;
+    println!(
+        "{} copied {} change(s) from {} into {}.",
+        colors::color_success(&"Success:"),
+        plan.copies.len(),
+        source.name,
+        target.name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr export-changes"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ExportChangesOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to export changes from.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "ARCHIVE",
+        parse(from_os_str),
+        help = "Path of the tar archive to write."
+    )]
+    archive_path: PathBuf,
+    #[structopt(long = "zstd", help = "Compress the archive with zstd.")]
+    zstd: bool,
+    #[structopt(
+        long = "zstd-level",
+        default_value = "3",
+        help = "Compression level to use with --zstd, from 1 to 19."
+    )]
+    zstd_level: u32,
+}
+
+fn export_changes(opts: &ExportChangesOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("export zone changes")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let compression = if opts.zstd {
+        Compression::zstd(opts.zstd_level)?
+    } else {
+        Compression::None
+    };
+    changes::export_archive(
+        &top_dirs.mzr_dir,
+        &zone.ovfs_changes_dir,
+        &zone.info.snapshot,
+        &opts.archive_path,
+        compression,
+    )?;
+    println!(
+        "{} changes of {} zone exported to {}",
+        colors::color_success(&"Success:"),
+        zone.name,
+        colors::color_file(&opts.archive_path.display())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr apply-changes"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ApplyChangesOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to apply changes to.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "ARCHIVE",
+        parse(from_os_str),
+        help = "Path of the changes archive, as written by export-changes."
+    )]
+    archive_path: PathBuf,
+}
+
+fn apply_changes(opts: &ApplyChangesOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("apply zone changes")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)? {
+        bail!(
+            "{} zone is mounted, and must be unmounted before changes can be applied to it.",
+            zone.name
+        );
+    }
+    let plan = changes::apply_archive(
+        &top_dirs.mzr_dir,
+        &zone.ovfs_changes_dir,
+        &zone.info.snapshot,
+        &opts.archive_path,
+    )?;
+    if !plan.conflicts.is_empty() {
+        println!(
+            "The following paths have been changed in both {} and {}:",
+            colors::color_file(&opts.archive_path.display()),
+            zone.name
+        );
+        for conflict in &plan.conflicts {
+            println!("* {:?}", conflict);
+        }
+        bail!(
+            "Not applying changes, since doing so would overwrite changes in {}.",
+            zone.name
+        );
+    }
+    println!(
+        "{} applied {} change(s) to {}.",
+        colors::color_success(&"Success:"),
+        plan.copies.len(),
+        zone.name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr mount-plan"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct MountPlanOpts {
+    #[structopt(
+        name = "ZONE_NAME",
+        help = "Name of the zone to print the mount command for."
+    )]
+    zone_name: ZoneName,
+}
+
+fn mount_plan(opts: &MountPlanOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("print zone mount command")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    println!(
+        "{}",
+        OverlayMount::for_zone(&zone, &top_dirs.config.mount_options())
+    );
+    Ok(())
+}
+
+/*
+ * "mzr inspect-mount"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct InspectMountOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the mounted zone to inspect.")]
+    zone_name: ZoneName,
+}
+
+fn inspect_mount(opts: &InspectMountOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("inspect zone mount")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    // Zones are mounted within the daemon's mount namespace.
+    namespaces::enter_daemon_space(&top_dirs.mzr_dir)?;
+    match mount::find_mountinfo(&zone.ovfs_mount_dir)? {
+        None => bail!("{} zone is not mounted.", zone.name),
+        Some(entry) => {
+            println!("Mount point: {}", color_dir(&entry.mount_point.display()));
+            println!("Filesystem type: {}", entry.fstype);
+            println!("Mount options: {}", entry.mount_options);
+            println!("Superblock options: {}", entry.super_options);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr reset"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ResetOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to reset.")]
+    zone_name: ZoneName,
+    #[structopt(
+        long = "force",
+        help = "Required, since the zone's changes are permanently discarded."
+    )]
+    force: bool,
+}
+
+fn reset(opts: &ResetOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("reset zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)? {
+        bail!(
+            "{} zone is mounted, and must be unmounted before it can be reset.",
+            zone.name
+        );
+    }
+    if !opts.force {
+        bail!(
+            "Resetting permanently discards all of the changes in the {} zone, so it requires {}. \
+             Use {} to keep them in a snapshot first.",
+            zone.name,
+            colors::color_cmd(&"--force"),
+            colors::color_cmd(&format!("mzr snap --zone {}", *zone.name))
+        );
+    }
+    zone::reset_zone(&top_dirs.mzr_dir, &zone.name)?;
+    println!(
+        "{} {} zone now matches snapshot {}, and has no changes.",
+        colors::color_success(&"Success:"),
+        zone.name,
+        zone.info.snapshot
+    );
+    Ok(())
+}
+
+/*
+ * "mzr compact"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct CompactOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to compact.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "SNAP_NAME",
+        help = "Name of the snapshot to create. \
+                If unspecified, a name will be generated based on the zone name and current time."
+    )]
+    snap_name: Option<SnapName>,
+}
+
+fn compact(opts: &CompactOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("compact zone")?;
+    let mut zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)? {
+        bail!(
+            "{} zone is mounted, and must be unmounted before it can be compacted.",
+            zone.name
+        );
+    }
+    let snap_name = match &opts.snap_name {
+        Some(name) => name.clone(),
+        None => SnapName::new(format!(
+            "{}-compacted-{}",
+            *zone.name,
+            Utc::now().format("%Y%m%d-%H%M%S")
+        ))?,
+    };
+    let estimate = snapshot::estimate_shared_bytes(&zone)?;
+    println!(
+        "{} of the new snapshot will be unchanged from snapshot {} ({:.0}%), \
+         and {} will be new or modified.",
+        format_bytes(estimate.shared),
+        zone.info.snapshot,
+        estimate.shared_fraction() * 100.0,
+        format_bytes(estimate.changed)
+    );
+    println!("Taking a snapshot named {}", snap_name);
+    zone.compact(&top_dirs.mzr_dir, &snap_name, top_dirs.config.dir_mode)?;
+    println!(
+        "{} {} zone is now based on snapshot {}, and has no changes.",
+        colors::color_success(&"Success:"),
+        zone.name,
+        snap_name
+    );
+    Ok(())
+}
+
+/*
+ * "mzr rebase"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct RebaseOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to rebase.")]
+    zone_name: ZoneName,
+    #[structopt(name = "SNAP_NAME", help = "Name of the snapshot to base the zone on.")]
+    snap_name: SnapName,
+    #[structopt(
+        long = "remount",
+        help = "If the zone is mounted, unmount it and mount it again with the new snapshot."
+    )]
+    remount: bool,
+}
+
+fn rebase(opts: &RebaseOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("rebase zone")?;
+    let mut zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let mounted = daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)?;
+    let daemon_running = mounted && daemon::is_running(&top_dirs.mzr_dir)?;
+    let remount = rebase_remount(&zone.name, mounted, opts.remount, daemon_running)?;
+    let conflicts = zone.rebase_conflicts(&top_dirs.mzr_dir, &opts.snap_name)?;
+    if !conflicts.is_empty() {
+        println!(
+            "{} The following changes are to paths which differ between snapshots {} and {}, \
+             so they may conflict with the new snapshot:",
+            colors::color_warn(&"Warning:"),
+            zone.info.snapshot,
+            opts.snap_name
+        );
+        for path in &conflicts {
+            println!("* {}", path.display());
+        }
+    }
+    match remount {
+        RebaseRemount::Daemon => {
+            // The daemon kills the zone's processes before unmounting it, since
+            // their namespaces also have the old overlay mounted.
+            daemon::unmount_zone(&top_dirs.mzr_dir, &zone.name)?;
+            zone.rebase(&top_dirs.mzr_dir, &opts.snap_name)?;
+            daemon::mount_zone(&top_dirs.mzr_dir, &zone.name)?;
+            println!(
+                "{} The zone's processes were killed, but shells which were already in the \
+                 zone still see its old overlay, so they should be exited.",
+                colors::color_warn(&"Warning:")
+            );
+        }
+        RebaseRemount::Direct => {
+            mount::unmount(&zone.ovfs_mount_dir)?;
+            zone.rebase(&top_dirs.mzr_dir, &opts.snap_name)?;
+            zone.mount(&top_dirs.config.mount_options())?;
+        }
+        RebaseRemount::NotMounted => zone.rebase(&top_dirs.mzr_dir, &opts.snap_name)?,
+    }
+    println!(
+        "{} {} zone is now based on snapshot {}.",
+        colors::color_success(&"Success:"),
+        zone.name,
+        opts.snap_name
+    );
+    Ok(())
+}
+
+/// How a zone gets remounted when it is rebased.
+#[derive(Debug, PartialEq)]
+enum RebaseRemount {
+    NotMounted,
+    /// The daemon unmounts the zone, which kills its processes, and then
+    /// mounts it again.
+    Daemon,
+    /// The zone is unmounted and mounted again in the current namespace.
+    Direct,
+}
+
+/// Decides how to remount a zone which is being rebased. Rebasing a mounted
+/// zone requires `--remount`, since the zone's processes get disrupted.
+fn rebase_remount(
+    zone_name: &ZoneName,
+    mounted: bool,
+    remount: bool,
+    daemon_running: bool,
+) -> Result<RebaseRemount, Error> {
+    if !mounted {
+        Ok(RebaseRemount::NotMounted)
+    } else if !remount {
+        bail!(
+            "{} zone is mounted, so it must be unmounted before it can be rebased, \
+             or --remount must be used.",
+            zone_name
+        )
+    } else if daemon_running {
+        Ok(RebaseRemount::Daemon)
+    } else {
+        Ok(RebaseRemount::Direct)
+    }
+}
+
+/*
+ * "mzr rm"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct RmOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to delete.")]
+    zone_name: ZoneName,
+    #[structopt(
+        long = "permanent",
+        help = "Delete the zone immediately, instead of moving it to the trash."
+    )]
+    permanent: bool,
+    #[structopt(long = "force", help = "Delete the zone even if it is pinned.")]
+    force: bool,
+}
+
+fn rm(opts: &RmOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("delete zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if daemon::is_zone_mounted(&top_dirs.mzr_dir, &zone.name)? {
+        bail!(
+            "{} zone is mounted, and must be unmounted before it can be deleted.",
+            zone.name
+        );
+    }
+    // Checked before confirming, as well as when deleting.
+    zone::check_not_pinned(&top_dirs.mzr_dir, &zone.name, opts.force)?;
+    if opts.permanent {
+        match confirm(&format!(
+            "Permanently delete {} zone, including its changes",
+            zone.name
+        ))? {
+            Confirmed::Yes => zone::delete(&top_dirs.mzr_dir, &zone.name, opts.force)?,
+            Confirmed::No => bail!("Zone not deleted."),
+        }
+        println!(
+            "{} {} zone deleted.",
+            colors::color_success(&"Success:"),
+            zone.name
+        );
+    } else {
+        let trashed_dir = zone::move_to_trash(&top_dirs.mzr_dir, &zone.name, opts.force)?;
+        println!(
+            "{} {} zone moved to {}. Use {} to permanently delete it.",
+            colors::color_success(&"Success:"),
+            zone.name,
+            color_dir(&trashed_dir.display()),
+            colors::color_cmd(&"mzr empty-trash")
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr pin" and "mzr unpin"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct PinOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone.")]
+    zone_name: ZoneName,
+}
+
+fn pin(opts: &PinOpts, pinned: bool) -> Result<(), Error> {
+    let top_dirs = TopDirs::find(if pinned { "pin zone" } else { "unpin zone" })?;
+    if pinned {
+        zone::pin_zone(&top_dirs.mzr_dir, &opts.zone_name)?;
+    } else {
+        zone::unpin_zone(&top_dirs.mzr_dir, &opts.zone_name)?;
+    }
+    println!(
+        "{} {} zone {}.",
+        colors::color_success(&"Success:"),
+        opts.zone_name,
+        if pinned { "pinned" } else { "unpinned" }
+    );
+    Ok(())
+}
+
+/*
+ * "mzr describe"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct DescribeOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone.")]
+    zone_name: ZoneName,
+}
+
+fn describe(opts: &DescribeOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("describe zone")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    println!("Zone:         {}", zone.name);
+    println!(
+        "Description:  {}",
+        zone.info
+            .description
+            .as_ref()
+            .map_or("none", |description| description.as_str())
+    );
+    println!("Snapshot:     {}", zone.info.snapshot);
+    println!("Created:      {}", zone.info.creation_time);
+    println!(
+        "Pinned:       {}",
+        if zone.info.pinned { "yes" } else { "no" }
     );
+    match &zone.info.last_mount {
+        Some(record) => println!(
+            "Last mounted: {} (kernel {})",
+            record.time, record.kernel_release
+        ),
+        None => println!("Last mounted: never"),
+    }
+    Ok(())
+}
+
+/*
+ * "mzr set-description"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct SetDescriptionOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "DESCRIPTION",
+        required_unless = "clear",
+        help = "Description of the zone. Control characters are removed."
+    )]
+    description: Option<String>,
+    #[structopt(
+        long = "clear",
+        conflicts_with = "DESCRIPTION",
+        help = "Remove the zone's description."
+    )]
+    clear: bool,
+}
+
+fn set_description(opts: &SetDescriptionOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("set zone description")?;
+    match &opts.description {
+        Some(description) => {
+            let description =
+                zone::set_description(&top_dirs.mzr_dir, &opts.zone_name, description)?;
+            println!(
+                "{} {} zone is described as: {}",
+                colors::color_success(&"Success:"),
+                opts.zone_name,
+                description
+            );
+        }
+        None => {
+            zone::clear_description(&top_dirs.mzr_dir, &opts.zone_name)?;
+            println!(
+                "{} Cleared description of {} zone.",
+                colors::color_success(&"Success:"),
+                opts.zone_name
+            );
+        }
+    }
+    Ok(())
+}
+
+/*
+ * "mzr list-zones"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct ListZonesOpts {
+    #[structopt(
+        long = "snapshot",
+        help = "Only list zones based on this snapshot, such as to check whether it's in use."
+    )]
+    snap_name: Option<SnapName>,
+    #[structopt(
+        long = "by-creation",
+        help = "Sort zones by when they were created, oldest first, rather than by name."
+    )]
+    by_creation: bool,
+}
+
+fn list_zones(opts: &ListZonesOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list zones")?;
+    let zone_names = match &opts.snap_name {
+        None => zone::list_zones(&top_dirs.mzr_dir)?,
+        Some(snap_name) => zone::zones_based_on(&top_dirs.mzr_dir, snap_name)?,
+    };
+    let mut zones = Vec::new();
+    for zone_name in &zone_names {
+        zones.push(Zone::load(&top_dirs.mzr_dir, zone_name)?);
+    }
+    if opts.by_creation {
+        // Sorting is stable, so zones created at the same time stay sorted by
+        // name.
+        zones.sort_by_key(|zone| zone.info.creation_time);
+    }
+    if zones.is_empty() {
+        match &opts.snap_name {
+            None => println!("No zones exist."),
+            Some(snap_name) => println!("No zones are based on the {} snapshot.", snap_name),
+        }
+    }
+    for zone in &zones {
+        println!(
+            "{} (snapshot {}){}{}",
+            zone.name,
+            zone.info.snapshot,
+            if zone.info.pinned { ", pinned" } else { "" },
+            match &zone.info.description {
+                Some(description) => format!(" - {}", description),
+                None => String::new(),
+            }
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr list-orphans"
+ */
+
+fn list_orphans() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list orphaned zone directories")?;
+    let orphans = zone::find_orphaned_dirs(&top_dirs.mzr_dir)?;
+    if orphans.is_empty() {
+        println!("No orphaned zone directories found.");
+    }
+    for orphan in &orphans {
+        println!(
+            "* {}{}\n  {}",
+            orphan.zone_dir,
+            if orphan.in_progress {
+                " (recently modified, so may still be being created)"
+            } else {
+                ""
+            },
+            orphan.reason
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr inventory"
+ */
+
+fn inventory() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("take inventory")?;
+    let inventory = inventory::Inventory::gather(&top_dirs.mzr_dir)?;
+    println!(
+        "{}",
+        colors::data_output(|| serde_json::to_string_pretty(&inventory))?
+    );
+    Ok(())
+}
+
+/*
+ * "mzr empty-trash"
+ */
+
+fn empty_trash() -> Result<(), Error> {
+    let top_dirs = TopDirs::find("empty trash")?;
+    let trashed_dirs = zone::list_trash(&top_dirs.mzr_dir)?;
+    if trashed_dirs.is_empty() {
+        println!("Trash is already empty.");
+        return Ok(());
+    }
+    println!("The following deleted zones are in the trash:");
+    for trashed_dir in &trashed_dirs {
+        println!("* {}", color_dir(&trashed_dir.display()));
+    }
+    match confirm("Permanently delete these zones")? {
+        Confirmed::Yes => {
+            zone::empty_trash(&top_dirs.mzr_dir)?;
+            println!("{} Trash emptied.", colors::color_success(&"Success:"));
+        }
+        Confirmed::No => {}
+    }
+    Ok(())
+}
+
+/*
+ * "mzr snap-tree"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct SnapTreeOpts {
+    #[structopt(long = "json", help = "Output the tree as JSON.")]
+    json: bool,
+}
+
+fn snap_tree(opts: &SnapTreeOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show snapshot tree")?;
+    let roots = snapshot::lineage(&top_dirs.mzr_dir)?;
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&roots))?
+        );
+    } else {
+        print!("{}", snapshot::render_lineage(&roots));
+    }
+    Ok(())
+}
+
+/*
+ * "mzr snap-chain"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct SnapChainOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone.")]
+    zone_name: ZoneName,
+}
+
+fn snap_chain(opts: &SnapChainOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("show snapshot chain")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let chain = snapshot::snapshot_chain(&top_dirs.mzr_dir, &zone)?;
+    for snap_name in &chain.snaps {
+        println!("{}", snap_name);
+    }
+    match (&chain.missing, chain.snaps.last()) {
+        (None, _) => {}
+        (Some(missing), None) => bail!(
+            "{} zone is based on snapshot {}, which no longer exists, so the zone can't be mounted.",
+            zone.name,
+            missing
+        ),
+        (Some(missing), Some(child)) => println!(
+            "{} {} snapshot was derived from snapshot {}, which no longer exists.",
+            colors::color_warn(&"Warning:"),
+            child,
+            missing
+        ),
+    }
+    Ok(())
+}
+
+/*
+ * "mzr ls-snap"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct LsSnapOpts {
+    #[structopt(name = "SNAP_NAME", help = "Name of the snapshot to list.")]
+    snap_name: SnapName,
+    #[structopt(
+        long = "depth",
+        help = "Maximum depth of entries to list, where 1 lists only the snapshot's root."
+    )]
+    depth: Option<usize>,
+    #[structopt(long = "sizes", help = "Show the sizes of files.")]
+    sizes: bool,
+    #[structopt(long = "json", help = "Output the tree as JSON.")]
+    json: bool,
+}
+
+fn ls_snap(opts: &LsSnapOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("list snapshot")?;
+    let nodes = snapshot::list_snapshot_tree(&top_dirs.mzr_dir, &opts.snap_name, opts.depth)?;
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&nodes))?
+        );
+    } else {
+        println!("{}", opts.snap_name);
+        print!("{}", snapshot::render_file_tree(&nodes, opts.sizes));
+    }
+    Ok(())
+}
+
+/*
+ * "mzr list-ops"
+ */
+
+fn list_ops() -> Result<(), Error> {
+    let operations = registry::active_operations()?;
+    if operations.is_empty() {
+        println!("No mzr operations are in progress.");
+    }
+    for operation in &operations {
+        println!("PID {}: {}", operation.pid, operation.description);
+    }
+    Ok(())
+}
+
+/*
+ * "mzr list-mounted"
+ */
+
+fn list_mounted() -> Result<(), Error> {
+    let zone_mounts = mount::find_zone_mounts(&mount::read_mounts()?);
+    if zone_mounts.is_empty() {
+        println!("No zones are mounted.");
+    }
+    for (mzr_dir, zone_name) in &zone_mounts {
+        println!("{} in {}", zone_name, mzr_dir);
+    }
+    Ok(())
+}
+
+/*
+ * "mzr unmount-all"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct UnmountAllOpts {
+    #[structopt(
+        long = "lazy",
+        help = "Detach zones which are busy, rather than failing to unmount them. \
+                Processes using them can continue to do so."
+    )]
+    lazy: bool,
+}
+
+fn unmount_all(opts: &UnmountAllOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("unmount zones")?;
+    let verify = top_dirs.config.verify_unmount.unwrap_or(true);
+    // Zones mounted by the daemon are only visible within its namespaces, and
+    // it needs to kill the zone processes which use them.
+    let results = if daemon::is_running(&top_dirs.mzr_dir)? {
+        daemon::unmount_all(&top_dirs.mzr_dir, opts.lazy, verify)?
+    } else {
+        mount::unmount_all(&top_dirs.mzr_dir, opts.lazy, verify)?
+    };
+    if results.is_empty() {
+        println!("No zones are mounted.");
+    }
+    let mut failures = 0;
+    for (zone_name, result) in &results {
+        match result {
+            Ok(mount::Unmounted::Unmounted) => println!("Unmounted {} zone.", zone_name),
+            Ok(mount::Unmounted::Detached) => println!(
+                "Detached {} zone, since it was busy. It will be fully unmounted once the \
+                 processes using it exit.",
+                zone_name
+            ),
+            Err(e) => {
+                println!("{} {}", color_err(&"Error:"), e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        bail!(
+            "Failed to unmount {} of {} mounted zones.{}",
+            failures,
+            results.len(),
+            if opts.lazy {
+                ""
+            } else {
+                " Busy zones can be detached with --lazy."
+            }
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr doctor"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct DoctorOpts {
+    #[structopt(long = "json", help = "Output the summary as JSON.")]
+    json: bool,
+}
+
+fn doctor(opts: &DoctorOpts) -> Result<(), Error> {
+    let info = doctor::SystemInfo::gather();
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&info))?
+        );
+    } else {
+        info.print();
+    }
+    Ok(())
+}
+
+/*
+ * "mzr path-status"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct PathStatusOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone.")]
+    zone_name: ZoneName,
+    #[structopt(
+        name = "PATH",
+        parse(from_os_str),
+        help = "Path to query, relative to the working directory."
+    )]
+    path: PathBuf,
+}
+
+fn path_status(opts: &PathStatusOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("query path status")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    let state = changes::path_state(&zone.ovfs_changes_dir, &zone.snap_dir, &opts.path)?;
+    println!(
+        "{} is {} in {} zone.",
+        colors::color_file(&opts.path.display()),
+        state.change,
+        zone.name
+    );
+    if let Some(changes_entry) = &state.changes_entry {
+        println!(
+            "Changes directory entry: {}",
+            colors::color_file(&changes_entry.display())
+        );
+    }
+    Ok(())
+}
+
+/*
+ * "mzr which-zone"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct WhichZoneOpts {
+    #[structopt(
+        name = "DIR",
+        parse(from_os_str),
+        help = "Work directory to check. Defaults to the current directory."
+    )]
+    dir: Option<PathBuf>,
+}
+
+fn which_zone(opts: &WhichZoneOpts) -> Result<(), Error> {
+    let dir = match &opts.dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir()?,
+    };
+    let dir = dir.canonicalize().context(format_err!(
+        "Failed to resolve directory {}",
+        color_dir(&dir.display())
+    ))?;
+    match mount::zone_at_work_dir(&UserWorkDir::new(&dir))? {
+        Some(zone_name) => println!("{}", *zone_name),
+        None => bail!(
+            "{} is not the work directory of a mounted zone.",
+            color_dir(&dir.display())
+        ),
+    }
+    Ok(())
+}
+
+/*
+ * "mzr paths"
+ */
+
+#[derive(StructOpt, Debug)]
+pub struct PathsOpts {
+    #[structopt(name = "ZONE_NAME", help = "Name of the zone to print paths for.")]
+    zone_name: ZoneName,
+    #[structopt(long = "json", help = "Output the paths as JSON.")]
+    json: bool,
+}
+
+fn paths(opts: &PathsOpts) -> Result<(), Error> {
+    let top_dirs = TopDirs::find("print zone paths")?;
+    let zone = Zone::load(&top_dirs.mzr_dir, &opts.zone_name)?;
+    if opts.json {
+        println!(
+            "{}",
+            colors::data_output(|| serde_json::to_string_pretty(&zone.paths()))?
+        );
+    } else {
+        println!("zone dir:      {}", zone.zone_dir);
+        println!("changes dir:   {}", zone.ovfs_changes_dir);
+        println!("ovfs work dir: {}", zone.ovfs_work_dir);
+        println!("info file:     {}", zone.zone_dir.info_file());
+        println!("snapshot dir:  {}", zone.snap_dir);
+    }
     Ok(())
 }
 
@@ -251,6 +2449,44 @@ fn go(opts: &GoOpts) -> Result<(), Error> {
  * belong in main.rs
  */
 
+/// Like `default_git_snap_name`, but for snapshots used as the base of a zone,
+/// which can also be specified via `default_snapshot` in the config.
+fn default_base_snap_name(
+    top_dirs: &TopDirs,
+    snap_name: &Option<SnapName>,
+) -> Result<SnapName, Error> {
+    match (snap_name, &top_dirs.config.default_snapshot) {
+        (None, Some(name)) => {
+            println!(
+                "Since no snapshot was specified, using the default snapshot from {}: {}",
+                ConfigFile::new(&top_dirs.user_work_dir),
+                name
+            );
+            Ok(name.clone())
+        }
+        _ => default_git_snap_name(top_dirs, snap_name),
+    }
+}
+
+/// Converts a directory specified for `--subdir` to be relative to the work
+/// directory, refusing absolute paths outside of it.
+fn work_relative_subdir(top_dirs: &TopDirs, subdir: &Path) -> Result<PathBuf, Error> {
+    let rel_path = if subdir.is_absolute() {
+        match subdir.strip_prefix(&*top_dirs.user_work_dir) {
+            Ok(rel_path) => rel_path.to_path_buf(),
+            Err(_) => bail!(
+                "{} is not within the work directory {}.",
+                color_dir(&subdir.display()),
+                color_dir(&top_dirs.user_work_dir.display())
+            ),
+        }
+    } else {
+        subdir.to_path_buf()
+    };
+    check_contained_rel_path(&rel_path)?;
+    Ok(rel_path)
+}
+
 fn default_git_snap_name(
     top_dirs: &TopDirs,
     snap_name: &Option<SnapName>,
@@ -271,15 +2507,100 @@ fn default_git_snap_name(
     }
 }
 
-fn enter_zone(top_dirs: &TopDirs, zone_name: &ZoneName) -> Result<(), Error> {
-    let current_directory = env::current_dir()?;
-    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, &zone_name)?;
+fn enter_zone(
+    top_dirs: &TopDirs,
+    zone_name: &ZoneName,
+    target_dir: Option<&ZoneTargetDir>,
+    binds: &[BindSpec],
+) -> Result<(), Error> {
+    let current_directory =
+        zone_current_dir(&top_dirs.user_work_dir, target_dir, &env::current_dir()?);
+    let zone_pid = daemon::get_zone_process(&top_dirs.mzr_dir, zone_name, target_dir, binds)?;
     daemon::enter_zone_process_user_and_mount(&zone_pid)?;
     change_dir_fallback_parent(&top_dirs.user_work_dir, &current_directory)?;
     env::set_var("MZR_DIR", &top_dirs.mzr_dir);
     Ok(())
 }
 
+/// Directory to switch to after entering a zone. When the zone is mounted at a
+/// target directory, a current directory within the work directory is moved
+/// to the corresponding location within the target.
+fn zone_current_dir(
+    work_dir: &UserWorkDir,
+    target_dir: Option<&ZoneTargetDir>,
+    current_dir: &Path,
+) -> PathBuf {
+    match target_dir {
+        Some(target_dir) => match current_dir.strip_prefix(&**work_dir) {
+            Ok(rel_path) => target_dir.join(rel_path),
+            Err(_) => current_dir.to_path_buf(),
+        },
+        None => current_dir.to_path_buf(),
+    }
+}
+
+/// Validates a directory to mount a zone at. If it isn't empty, then the user
+/// is asked to confirm that it's fine for its contents to be shadowed.
+fn confirm_zone_target_dir(top_dirs: &TopDirs, path: &Path) -> Result<ZoneTargetDir, Error> {
+    let target_dir = ZoneTargetDir::new(path)?;
+    let policy = top_dirs.config.non_empty_target;
+    if policy == NonEmptyTargetPolicy::Proceed || is_empty_dir(&target_dir)? {
+        return Ok(target_dir);
+    }
+    match policy {
+        NonEmptyTargetPolicy::Prompt => {
+            let query = format!(
+                "{} is not empty, and its contents will be shadowed by the zone. Continue",
+                target_dir
+            );
+            match confirm(&query)? {
+                Confirmed::Yes => {}
+                Confirmed::No => bail!("Not mounting zone over non-empty {}", target_dir),
+            }
+        }
+        NonEmptyTargetPolicy::Warn => println!(
+            "{} {} is not empty, and its contents will be shadowed by the zone.",
+            colors::color_warn(&"Warning:"),
+            target_dir
+        ),
+        NonEmptyTargetPolicy::Error => bail!(
+            "Not mounting zone over non-empty {}, since non_empty_target is \"error\" in {}",
+            target_dir,
+            ConfigFile::new(&top_dirs.user_work_dir)
+        ),
+        NonEmptyTargetPolicy::Proceed => {}
+    }
+    Ok(target_dir)
+}
+
+/// Checks that the user's work directory exists, since otherwise the zone can't
+/// be mounted at it. If `create` is set, then it gets created instead.
+fn check_user_work_dir(work_dir: &paths::UserWorkDir, create: bool) -> Result<(), Error> {
+    if work_dir.is_dir() {
+        return Ok(());
+    }
+    if work_dir.exists() {
+        bail!(
+            "Can't mount zone at {}, because it is not a directory.",
+            work_dir
+        );
+    }
+    if !create {
+        bail!(
+            "Can't mount zone at {}, because it does not exist. \
+             Create it, or pass {} to create it.",
+            work_dir,
+            colors::color_cmd(&"--create-work-dir")
+        );
+    }
+    create_dir_all(&**work_dir).context(format_err!(
+        "Unexpected error while creating work directory {}",
+        work_dir
+    ))?;
+    println!("Created work directory {}", work_dir);
+    Ok(())
+}
+
 fn change_dir_fallback_parent(
     work_dir: &paths::UserWorkDir,
     start_dir: &PathBuf,
@@ -289,7 +2610,7 @@ fn change_dir_fallback_parent(
             if &existent_dir != start_dir {
                 println!(
                     "Couldn't find {:?} in zone, so instead setting current directory to {:?}",
-                    maybe_strip_prefix(&work_dir, &existent_dir),
+                    maybe_strip_prefix(work_dir, &existent_dir),
                     existent_dir
                 );
             }
@@ -301,3 +2622,262 @@ fn change_dir_fallback_parent(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::error_report::strip_ansi_escapes;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, write_files, TestDir};
+    use std::cell::RefCell;
+
+    #[test]
+    fn zone_current_dir_moves_into_target() {
+        let work_dir = UserWorkDir::new(&PathBuf::from("/home/user/proj"));
+        let target_dir = ZoneTargetDir::new(&env::temp_dir()).unwrap();
+        assert_eq!(
+            zone_current_dir(
+                &work_dir,
+                Some(&target_dir),
+                Path::new("/home/user/proj/src")
+            ),
+            env::temp_dir().join("src")
+        );
+        assert_eq!(
+            zone_current_dir(&work_dir, Some(&target_dir), Path::new("/home/user/proj")),
+            env::temp_dir()
+        );
+    }
+
+    #[test]
+    fn zone_current_dir_outside_work_dir_unchanged() {
+        let work_dir = UserWorkDir::new(&PathBuf::from("/home/user/proj"));
+        let target_dir = ZoneTargetDir::new(&env::temp_dir()).unwrap();
+        assert_eq!(
+            zone_current_dir(&work_dir, Some(&target_dir), Path::new("/home/user/other")),
+            PathBuf::from("/home/user/other")
+        );
+        assert_eq!(
+            zone_current_dir(&work_dir, None, Path::new("/home/user/proj/src")),
+            PathBuf::from("/home/user/proj/src")
+        );
+    }
+
+    #[test]
+    fn failing_to_mount_a_zone_unmounts_newly_mounted_zones() {
+        let names: Vec<ZoneName> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| ZoneName::new(name.to_string()).unwrap())
+            .collect();
+        let zone_names: Vec<&ZoneName> = names.iter().collect();
+        let unmounted = RefCell::new(Vec::new());
+        // "b" is already mounted, so only "a" gets unmounted when "c" fails.
+        let result = mount_zones_or_unmount(
+            &zone_names,
+            |zone_name| match zone_name.as_str() {
+                "b" => Ok(false),
+                "c" => bail!("mount failed"),
+                "d" => panic!("mounted zone after failure"),
+                _ => Ok(true),
+            },
+            |zone_name| {
+                unmounted.borrow_mut().push((*zone_name).clone());
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(unmounted.into_inner(), vec![names[0].clone()]);
+    }
+
+    #[test]
+    fn mounting_zones_yields_newly_mounted_zones() {
+        let names: Vec<ZoneName> = ["a", "b", "c"]
+            .iter()
+            .map(|name| ZoneName::new(name.to_string()).unwrap())
+            .collect();
+        let zone_names: Vec<&ZoneName> = names.iter().collect();
+        let newly_mounted = mount_zones_or_unmount(
+            &zone_names,
+            |zone_name| Ok(zone_name.as_str() != "b"),
+            |_| panic!("unmounted zone without failure"),
+        )
+        .unwrap();
+        assert_eq!(newly_mounted, vec![&names[0], &names[2]]);
+    }
+
+    fn run_opts(setup: &str, env: &[(&str, &Path)], cmd: &str) -> RunOpts {
+        RunOpts {
+            env: env
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.display().to_string()))
+                .collect(),
+            clear_env: false,
+            setup: Some(setup.to_string()),
+            cmd: "sh".to_string(),
+            args: vec!["-c".to_string(), cmd.to_string()],
+        }
+    }
+
+    /// Creates a snapshot and zone like those of `mzr run`, yielding the
+    /// directories and their names.
+    fn temp_run_zone(dir: &TestDir) -> (TopDirs, ZoneName, SnapName) {
+        let mzr_dir = test_mzr_dir(dir);
+        let snap_name = test_snapshot(&mzr_dir, "run-snap", &[]);
+        let zone = test_zone(&mzr_dir, "run-zone", &snap_name, &[]);
+        let top_dirs = TopDirs {
+            mzr_dir,
+            user_work_dir: UserWorkDir::new(&dir.join("proj")),
+            config: Config::default(),
+        };
+        (top_dirs, zone.name, snap_name)
+    }
+
+    #[test]
+    fn run_setup_happens_before_command() {
+        let dir = TestDir::new();
+        let (top_dirs, zone_name, snap_name) = temp_run_zone(&dir);
+        let cleanup = TempRunCleanup {
+            top_dirs: &top_dirs,
+            zone_name: &zone_name,
+            snap_name: &snap_name,
+            keep: false,
+        };
+        let marker = dir.join("marker");
+        let opts = run_opts(
+            "echo setup > \"$MARKER\"",
+            &[("MARKER", &marker)],
+            "test \"$(cat \"$MARKER\")\" = setup",
+        );
+        let status = spawn_run_command(&opts, cleanup).unwrap().wait().unwrap();
+        assert!(status.success());
+        assert!(Zone::exists(&top_dirs.mzr_dir, &zone_name));
+        assert!(SnapDir::new(&top_dirs.mzr_dir, &snap_name).is_dir());
+    }
+
+    #[test]
+    fn failing_run_setup_removes_temp_zone() {
+        let dir = TestDir::new();
+        let (top_dirs, zone_name, snap_name) = temp_run_zone(&dir);
+        let cleanup = TempRunCleanup {
+            top_dirs: &top_dirs,
+            zone_name: &zone_name,
+            snap_name: &snap_name,
+            keep: false,
+        };
+        let marker = dir.join("marker");
+        let opts = run_opts("exit 3", &[("MARKER", &marker)], "touch \"$MARKER\"");
+        assert!(spawn_run_command(&opts, cleanup).is_err());
+        assert!(!marker.exists());
+        assert!(!Zone::exists(&top_dirs.mzr_dir, &zone_name));
+        assert!(!SnapDir::new(&top_dirs.mzr_dir, &snap_name).exists());
+    }
+
+    #[test]
+    fn run_env_vars_are_visible_to_command() {
+        let dir = TestDir::new();
+        let (top_dirs, zone_name, snap_name) = temp_run_zone(&dir);
+        // Cargo sets this when running tests, so it stands in for the
+        // environment inherited from the user's shell.
+        assert!(env::var_os("CARGO_MANIFEST_DIR").is_some());
+        for &(clear_env, inherited_check) in &[(false, "-n"), (true, "-z")] {
+            let cleanup = TempRunCleanup {
+                top_dirs: &top_dirs,
+                zone_name: &zone_name,
+                snap_name: &snap_name,
+                keep: false,
+            };
+            let opts = RunOpts {
+                env: vec![("MZR_INJECTED".to_string(), "a=b c".to_string())],
+                clear_env,
+                setup: None,
+                cmd: "/bin/sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    format!(
+                        "test \"$MZR_INJECTED\" = 'a=b c' && test {} \"$CARGO_MANIFEST_DIR\"",
+                        inherited_check
+                    ),
+                ],
+            };
+            let status = spawn_run_command(&opts, cleanup).unwrap().wait().unwrap();
+            assert!(status.success(), "clear_env: {}", clear_env);
+        }
+    }
+
+    #[test]
+    fn missing_work_dir_is_only_created_when_asked() {
+        let dir = TestDir::new();
+        let work_dir = UserWorkDir::new(&dir.join("missing/proj"));
+        let err = check_user_work_dir(&work_dir, false).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        assert!(!work_dir.exists());
+        check_user_work_dir(&work_dir, true).unwrap();
+        assert!(work_dir.is_dir());
+        check_user_work_dir(&work_dir, false).unwrap();
+        let file = UserWorkDir::new(&dir.join("file"));
+        std::fs::write(&*file, "").unwrap();
+        for &create in &[false, true] {
+            let err = check_user_work_dir(&file, create).unwrap_err();
+            assert!(err.to_string().contains("not a directory"));
+        }
+    }
+
+    #[test]
+    fn rebase_remounts_mounted_zones_only_when_asked() {
+        let zone_name = ZoneName::new("mz".to_string()).unwrap();
+        for &(remount, daemon_running) in &[(false, false), (true, false), (true, true)] {
+            assert_eq!(
+                rebase_remount(&zone_name, false, remount, daemon_running).unwrap(),
+                RebaseRemount::NotMounted
+            );
+        }
+        assert!(rebase_remount(&zone_name, true, false, true).is_err());
+        assert!(rebase_remount(&zone_name, true, false, false).is_err());
+        assert_eq!(
+            rebase_remount(&zone_name, true, true, true).unwrap(),
+            RebaseRemount::Daemon
+        );
+        assert_eq!(
+            rebase_remount(&zone_name, true, true, false).unwrap(),
+            RebaseRemount::Direct
+        );
+    }
+
+    #[test]
+    fn non_empty_target_policies() {
+        let dir = TestDir::new();
+        let mut top_dirs = TopDirs {
+            mzr_dir: test_mzr_dir(&dir),
+            user_work_dir: UserWorkDir::new(&dir.join("proj")),
+            config: Config::default(),
+        };
+        let empty_dir = dir.join("empty");
+        let non_empty_dir = dir.join("non-empty");
+        write_files(&non_empty_dir, &[("file", "")]);
+        create_dir_all(&empty_dir).unwrap();
+        let confirmed = |top_dirs: &TopDirs, path: &PathBuf| {
+            confirm_zone_target_dir(top_dirs, path).map(|target_dir| (*target_dir).clone())
+        };
+        assert_eq!(top_dirs.config.non_empty_target, NonEmptyTargetPolicy::Warn);
+        for policy in &[
+            NonEmptyTargetPolicy::Warn,
+            NonEmptyTargetPolicy::Error,
+            NonEmptyTargetPolicy::Proceed,
+        ] {
+            top_dirs.config.non_empty_target = *policy;
+            assert_eq!(confirmed(&top_dirs, &empty_dir).unwrap(), empty_dir);
+            let result = confirmed(&top_dirs, &non_empty_dir);
+            if *policy == NonEmptyTargetPolicy::Error {
+                let message = strip_ansi_escapes(&result.unwrap_err().to_string());
+                assert!(
+                    message.contains("non_empty_target is \"error\""),
+                    "{}",
+                    message
+                );
+            } else {
+                assert_eq!(result.unwrap(), non_empty_dir);
+            }
+            assert!(confirmed(&top_dirs, &dir.join("missing")).is_err());
+        }
+    }
+}
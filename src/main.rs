@@ -1,19 +1,49 @@
-#![feature(try_blocks)]
-#![feature(const_vec_new)]
 #![warn(rust_2018_idioms)]
 
+use failure::Error;
 use mzr::colors::color_err;
+use mzr::error_report::ErrorReport;
 use mzr::*;
+use std::env;
 use std::process::exit;
+use structopt::clap::ErrorKind;
 use structopt::StructOpt;
 
 pub fn main() {
-    match run_cmd(&Cmd::from_args()) {
+    let opts = match Opts::from_iter_safe(env::args_os()) {
+        Ok(opts) => opts,
+        // Since parsing failed, whether JSON errors were requested is
+        // determined by looking for the flag.
+        Err(err) => match err.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => err.exit(),
+            _ if env::args_os().any(|arg| arg == "--json-errors") => {
+                print_json_error(&Error::from(err))
+            }
+            _ => err.exit(),
+        },
+    };
+    match run_cmd(&opts.cmd) {
         Ok(()) => {}
         Err(err) => {
+            if opts.json_errors {
+                print_json_error(&err);
+            }
             println!();
             println!("{} {}", color_err(&"mzr error:"), err);
+            // Errors are wrapped with context, so the underlying causes
+            // explain what actually went wrong.
+            for cause in err.iter_chain().skip(1) {
+                println!("  caused by: {}", cause);
+            }
             exit(1);
         }
     }
 }
+
+fn print_json_error(err: &Error) -> ! {
+    match serde_json::to_string(&ErrorReport::new(err)) {
+        Ok(json) => eprintln!("{}", json),
+        Err(json_err) => eprintln!("Failed to serialize error {:?}: {}", err, json_err),
+    }
+    exit(1)
+}
@@ -6,7 +6,7 @@ use failure::Error;
 use std::fs;
 use std::fs::Metadata;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use walkdir::WalkDir;
 
@@ -16,9 +16,9 @@ pub enum Mode {
     AutoApplyConflicts,
 }
 
-pub fn interactive_merge(zone: &Zone, target_dir: &PathBuf, mode: Mode) -> Result<(), Error> {
-    let plan = plan_merging_zone_changes(zone, &target_dir);
-    if plan.skips.len() > 0 {
+pub fn interactive_merge(zone: &Zone, target_dir: &Path, _mode: Mode) -> Result<(), Error> {
+    let plan = plan_merging_zone_changes(zone, target_dir);
+    if !plan.skips.is_empty() {
         println!("Skipping merging the following paths:");
         for skip in plan.skips {
             // TODO(cleanliness): use option combinator
@@ -111,30 +111,30 @@ pub struct Skip {
 }
 
 impl Update {
-    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &PathBuf) -> Result<(), Error> {
+    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &Path) -> Result<(), Error> {
         copy_from_changes_dir(&self.rel_path, changes_dir, target_dir)
     }
 }
 
 impl Conflict {
-    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &PathBuf) -> Result<(), Error> {
+    fn apply(&self, changes_dir: &OvfsChangesDir, target_dir: &Path) -> Result<(), Error> {
         copy_from_changes_dir(&self.rel_path, changes_dir, target_dir)
     }
 }
 
 // TODO(correctness): Check expected metadata
 fn copy_from_changes_dir(
-    rel_path: &PathBuf,
+    rel_path: &Path,
     changes_dir: &OvfsChangesDir,
-    target_dir: &PathBuf,
+    target_dir: &Path,
 ) -> Result<(), Error> {
-    let source = changes_dir.join(rel_path.clone());
-    let target = target_dir.join(rel_path.clone());
+    let source = changes_dir.join(rel_path);
+    let target = target_dir.join(rel_path);
     copy_file(&source, &target)
 }
 
 /// Copies a file from source path to target path, using cp in order to support reflinks.
-fn copy_file(source: &PathBuf, target: &PathBuf) -> Result<(), Error> {
+pub fn copy_file(source: &PathBuf, target: &PathBuf) -> Result<(), Error> {
     let mut cmd_base = Command::new("cp");
     let cmd = cmd_base
         .stdin(Stdio::null())
@@ -158,7 +158,7 @@ fn copy_file(source: &PathBuf, target: &PathBuf) -> Result<(), Error> {
 /// This plan will turn these changed files into updates if the file has not been changed in the
 /// target dir. Whether the file has been changed in the target dir is determined by comparing its
 /// metadata to the metadata of the corresponding file in the snapshot.
-fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf) -> Plan {
+fn plan_merging_zone_changes(zone: &Zone, target_dir: &Path) -> Plan {
     let source_dir = zone.ovfs_changes_dir.clone();
     let mut updates = Vec::new();
     let mut conflicts = Vec::new();
@@ -172,10 +172,11 @@ fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf) -> Plan {
             Ok(entry) => {
                 let source = PathBuf::from(entry.path());
                 let result: Result<(), Error> = try {
-                    let source_metadata = entry.metadata()?;
+                    let source_metadata = entry.metadata().map_err(Error::from)?;
                     // For now, emulating git's precedent of ignoring dirs.
                     if !source_metadata.is_dir() {
-                        let rel_path = PathBuf::from(source.strip_prefix(&source_dir)?);
+                        let rel_path =
+                            PathBuf::from(source.strip_prefix(&source_dir).map_err(Error::from)?);
                         let target = target_dir.join(&rel_path);
                         match get_metadata(&target)? {
                             None => updates.push(Update {
@@ -216,12 +217,12 @@ fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf) -> Plan {
                         }
                     }
                 };
-                result.err().map(|reason| {
+                if let Some(reason) = result.err() {
                     skips.push(Skip {
                         source: Some(source),
                         reason,
                     })
-                });
+                }
             }
         }
     }
@@ -232,7 +233,7 @@ fn plan_merging_zone_changes(zone: &Zone, target_dir: &PathBuf) -> Plan {
     }
 }
 
-fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
+pub fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
     // Note that this function gets metadata without looking through symlinks.  We really don't want
     // to try to look through symlinks, since relative symlinks won't resolve correctly anyway.
     match fs::symlink_metadata(path) {
@@ -244,7 +245,7 @@ fn get_metadata(path: &PathBuf) -> Result<Option<Metadata>, Error> {
     }
 }
 
-fn metadata_matches(x: &Metadata, y: &Metadata) -> bool {
+pub fn metadata_matches(x: &Metadata, y: &Metadata) -> bool {
     // Check things that are most likely to differ first.
     if x.len() != y.len() {
         return false;
@@ -0,0 +1,2006 @@
+use crate::colors::*;
+use crate::paths::{
+    MzrDir, NotMzrDir, OvfsChangesDir, OvfsWorkDir, UserWorkDir, ZoneDir, ZoneName,
+};
+use crate::utils::{list_dir_names, shell_quote, strip_prefix, with_timeout};
+use crate::zone::Zone;
+use failure::{Error, Fail, ResultExt};
+use libmount::BindMount;
+use nix::errno::Errno;
+use nix::mount::{mount, umount, umount2, MntFlags, MsFlags};
+use nix::sys::statfs::statfs;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Options for how zones get mounted, which correspond to mount flags.
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// Mount as read-only (`MS_RDONLY`).
+    pub read_only: bool,
+    /// Disallow access to device files (`MS_NODEV`).
+    pub nodev: bool,
+    /// Ignore set-user-ID and set-group-ID bits (`MS_NOSUID`).
+    pub nosuid: bool,
+    /// Enable overlayfs's `redirect_dir` feature, when the kernel supports it
+    /// but doesn't enable it by default.
+    pub redirect_dir: bool,
+    /// SELinux context to label all files in the zone with, via the
+    /// `context=` mount option.
+    pub selinux_context: Option<SelinuxContext>,
+    /// Remove stale files from the overlayfs work directory before mounting.
+    /// See `clean_work_dir`.
+    pub clean_work_dir: bool,
+    /// Make all mounts in the daemon's mount namespace private before mounting
+    /// zones, so that mount events don't propagate to or from the host. See
+    /// `make_mounts_private`.
+    pub private_propagation: bool,
+    /// Maximum number of lowerdirs that an overlay may stack. See
+    /// `check_lowerdir_count`.
+    pub max_lowerdirs: usize,
+    /// How mounting the overlay gets retried when it fails with a transient
+    /// error.
+    pub retry: RetryPolicy,
+    /// Record the mount in the zone's info file. See `Zone::record_mount`.
+    pub record_mount: bool,
+}
+
+impl Default for MountOptions {
+    /// By default, zones are writable, but hardened against device files and
+    /// set-user-ID programs.
+    fn default() -> Self {
+        MountOptions {
+            read_only: false,
+            nodev: true,
+            nosuid: true,
+            redirect_dir: true,
+            selinux_context: None,
+            clean_work_dir: true,
+            private_propagation: true,
+            max_lowerdirs: OVERLAY_MAX_STACK,
+            retry: RetryPolicy::default(),
+            record_mount: false,
+        }
+    }
+}
+
+/// Limit on the number of lowerdirs in the kernel's overlayfs (`OVL_MAX_STACK`).
+pub const OVERLAY_MAX_STACK: usize = 500;
+
+impl MountOptions {
+    pub fn to_flags(&self) -> MsFlags {
+        let mut flags = MsFlags::empty();
+        flags.set(MsFlags::MS_RDONLY, self.read_only);
+        flags.set(MsFlags::MS_NODEV, self.nodev);
+        flags.set(MsFlags::MS_NOSUID, self.nosuid);
+        flags
+    }
+}
+
+/// SELinux security context, such as
+/// `system_u:object_r:container_file_t:s0:c1,c2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelinuxContext(String);
+
+impl SelinuxContext {
+    /// Appends the `context=` mount option. The context is quoted, since MLS
+    /// category lists contain commas, which would otherwise separate options.
+    fn append_option(&self, dest: &mut Vec<u8>) {
+        dest.extend(b"context=\"");
+        dest.extend(self.0.as_bytes());
+        dest.extend(b"\"");
+    }
+}
+
+impl FromStr for SelinuxContext {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input.is_empty() {
+            bail!("SELinux context must not be empty");
+        }
+        // There's no way to escape quotes within the quoted option value.
+        if let Some(c) = input
+            .chars()
+            .find(|c| *c == '"' || c.is_whitespace() || c.is_control())
+        {
+            bail!(
+                "SELinux context {:?} must not contain the character {:?}",
+                input,
+                c
+            );
+        }
+        Ok(SelinuxContext(input.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for SelinuxContext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Whether SELinux is loaded and in enforcing mode.
+pub fn selinux_enforcing() -> bool {
+    match fs::read_to_string("/sys/fs/selinux/enforce") {
+        Ok(contents) => contents.trim() == "1",
+        Err(_) => false,
+    }
+}
+
+/// Arguments to the `mount` syscall for an overlayfs mount. This is
+/// constructed before mounting so that it can be displayed as an equivalent
+/// `mount -t overlay` command, which is useful for debugging escaping and
+/// option issues.
+#[derive(Debug, Clone)]
+pub struct OverlayMount {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+    pub flags: MsFlags,
+    pub data: Vec<u8>,
+}
+
+impl OverlayMount {
+    pub fn for_zone(zone: &Zone, options: &MountOptions) -> OverlayMount {
+        let mut data = Vec::new();
+        data.extend(b"lowerdir=");
+        append_escaped(&mut data, zone.snap_dir.as_ref());
+        data.extend(b",upperdir=");
+        append_escaped(&mut data, zone.ovfs_changes_dir.as_ref());
+        data.extend(b",workdir=");
+        append_escaped(&mut data, zone.ovfs_work_dir.as_ref());
+        if options.redirect_dir {
+            let support = probe_redirect_dir();
+            if let RedirectDirSupport::Unsupported = support {
+                println!(
+                    "{} This kernel's overlayfs does not support redirect_dir, so renaming \
+                     directories from the snapshot will fail with EXDEV. Most tools fall back \
+                     on copying.",
+                    color_warn(&"Warning:")
+                );
+            }
+            if let Some(option) = redirect_dir_option(support) {
+                data.extend(b",");
+                data.extend(option.as_bytes());
+            }
+        }
+        if let Some(context) = &options.selinux_context {
+            data.extend(b",");
+            context.append_option(&mut data);
+        }
+        OverlayMount {
+            source: String::from("overlay"),
+            target: zone.ovfs_mount_dir.to_path_buf(),
+            fstype: String::from("overlay"),
+            flags: options.to_flags(),
+            data,
+        }
+    }
+
+    /// Number of directories in the `lowerdir` option, which are separated by
+    /// unescaped colons.
+    pub fn lowerdir_count(&self) -> usize {
+        let mut count = 0;
+        let mut in_lowerdir = false;
+        let mut escaped = false;
+        let mut option_start = true;
+        for (index, byte) in self.data.iter().enumerate() {
+            if option_start {
+                in_lowerdir = self.data[index..].starts_with(b"lowerdir=");
+                if in_lowerdir {
+                    count = 1;
+                }
+                option_start = false;
+            }
+            match (escaped, byte) {
+                (true, _) => escaped = false,
+                (false, b'\\') => escaped = true,
+                (false, b',') => option_start = true,
+                (false, b':') if in_lowerdir => count += 1,
+                _ => {}
+            }
+        }
+        count
+    }
+
+    pub fn enables_redirect_dir(&self) -> bool {
+        self.data
+            .split(|byte| *byte == b',')
+            .any(|option| option == b"redirect_dir=on")
+    }
+
+    pub fn mount(&self, policy: &RetryPolicy) -> Result<(), Error> {
+        self.mount_with_timeout(SyscallBackend, policy, MOUNT_TIMEOUT)
+    }
+
+    /// Like `mount_with_retry`, but yields a `Timeout` error if mounting,
+    /// including retries, takes longer than the timeout.
+    fn mount_with_timeout<B: MountBackend + Send + 'static>(
+        &self,
+        backend: B,
+        policy: &RetryPolicy,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let overlay = self.clone();
+        let policy = policy.clone();
+        with_timeout(
+            &format!("mounting overlay at {}", self.target.display()),
+            timeout,
+            move || overlay.mount_with_retry(&backend, &policy),
+        )
+    }
+
+    /// Mounts the overlay, retrying with exponential backoff when the mount
+    /// fails with a transient error. Other errors are not retried.
+    pub fn mount_with_retry(
+        &self,
+        backend: &dyn MountBackend,
+        policy: &RetryPolicy,
+    ) -> Result<(), Error> {
+        let mut attempt = 1;
+        let mut backoff = policy.initial_backoff;
+        loop {
+            match self.mount_once(backend) {
+                Err(ref err) if attempt < policy.max_attempts && is_transient_mount_error(err) => {
+                    println!(
+                        "{} Mount attempt {} failed with {}, retrying in {:?}.",
+                        color_warn(&"Warning:"),
+                        attempt,
+                        err,
+                        backoff
+                    );
+                    thread::sleep(backoff);
+                    attempt += 1;
+                    backoff = min(backoff * 2, policy.max_backoff);
+                }
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    let explanation = match explain_overlay_mount_error(&err) {
+                        Some(explanation) => format!("\n{}", explanation),
+                        None => String::new(),
+                    };
+                    let message = format_err!(
+                        "Failed to mount overlay.{}\nEquivalent command is:\n  {}",
+                        explanation,
+                        self
+                    );
+                    return Err(err.context(message).into());
+                }
+            }
+        }
+    }
+
+    fn mount_once(&self, backend: &dyn MountBackend) -> Result<(), nix::Error> {
+        backend.mount(
+            &self.source,
+            &self.target,
+            &self.fstype,
+            self.flags,
+            &self.data,
+        )
+    }
+
+    /// Options passed via `-o` to the `mount` command - the options
+    /// corresponding to flags, followed by the data string.
+    pub fn options(&self) -> String {
+        let mut options: Vec<String> = flag_options(self.flags)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        options.push(String::from_utf8_lossy(&self.data).into_owned());
+        options.join(",")
+    }
+}
+
+impl Display for OverlayMount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{}",
+            color_cmd(&format!(
+                "mount -t {} -o {} {} {}",
+                self.fstype,
+                shell_quote(&self.options()),
+                shell_quote(&self.source),
+                shell_quote(&self.target.to_string_lossy())
+            ))
+        )
+    }
+}
+
+/*
+ * Mount backends
+ */
+
+/// Performs the mount operations used by zones. Functions which mount take a
+/// backend so that the sequence of operations can be recorded rather than
+/// performed, which doesn't require privileges.
+pub trait MountBackend {
+    fn mount(
+        &self,
+        source: &str,
+        target: &Path,
+        fstype: &str,
+        flags: MsFlags,
+        data: &[u8],
+    ) -> Result<(), nix::Error>;
+
+    fn bind(&self, source: &Path, target: &Path, read_only: bool) -> Result<(), Error>;
+
+    fn unmount(&self, target: &Path, flags: MntFlags) -> Result<(), nix::Error>;
+
+    /// Recursively sets the propagation type of the mount at the target, and
+    /// the mounts beneath it, to private (`MS_PRIVATE | MS_REC`).
+    fn make_private(&self, target: &Path) -> Result<(), nix::Error>;
+}
+
+/// Backend which performs mounts via syscalls.
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallBackend;
+
+impl MountBackend for SyscallBackend {
+    fn mount(
+        &self,
+        source: &str,
+        target: &Path,
+        fstype: &str,
+        flags: MsFlags,
+        data: &[u8],
+    ) -> Result<(), nix::Error> {
+        mount(Some(source), target, Some(fstype), flags, Some(data))
+    }
+
+    fn bind(&self, source: &Path, target: &Path, read_only: bool) -> Result<(), Error> {
+        BindMount::new(source, target)
+            .readonly(read_only)
+            .mount()
+            .map_err(|e| format_err!("{}", e))
+    }
+
+    fn unmount(&self, target: &Path, flags: MntFlags) -> Result<(), nix::Error> {
+        umount2(target, flags)
+    }
+
+    fn make_private(&self, target: &Path) -> Result<(), nix::Error> {
+        mount(
+            None::<&str>,
+            target,
+            None::<&str>,
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            None::<&str>,
+        )
+    }
+}
+
+/// Backend which records the operations it's asked to perform, without
+/// performing them. Every operation succeeds.
+#[derive(Debug, Default)]
+pub struct RecordingBackend {
+    calls: RefCell<Vec<MountCall>>,
+}
+
+/// Operation recorded by `RecordingBackend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountCall {
+    Mount {
+        source: String,
+        target: PathBuf,
+        fstype: String,
+        flags: MsFlags,
+        data: Vec<u8>,
+    },
+    Bind {
+        source: PathBuf,
+        target: PathBuf,
+        read_only: bool,
+    },
+    Unmount {
+        target: PathBuf,
+        flags: MntFlags,
+    },
+    MakePrivate {
+        target: PathBuf,
+    },
+}
+
+impl RecordingBackend {
+    /// Operations recorded so far, in the order they were performed.
+    pub fn calls(&self) -> Vec<MountCall> {
+        self.calls.borrow().clone()
+    }
+}
+
+impl MountBackend for RecordingBackend {
+    fn mount(
+        &self,
+        source: &str,
+        target: &Path,
+        fstype: &str,
+        flags: MsFlags,
+        data: &[u8],
+    ) -> Result<(), nix::Error> {
+        self.calls.borrow_mut().push(MountCall::Mount {
+            source: source.to_string(),
+            target: target.to_path_buf(),
+            fstype: fstype.to_string(),
+            flags,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn bind(&self, source: &Path, target: &Path, read_only: bool) -> Result<(), Error> {
+        self.calls.borrow_mut().push(MountCall::Bind {
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            read_only,
+        });
+        Ok(())
+    }
+
+    fn unmount(&self, target: &Path, flags: MntFlags) -> Result<(), nix::Error> {
+        self.calls.borrow_mut().push(MountCall::Unmount {
+            target: target.to_path_buf(),
+            flags,
+        });
+        Ok(())
+    }
+
+    fn make_private(&self, target: &Path) -> Result<(), nix::Error> {
+        self.calls.borrow_mut().push(MountCall::MakePrivate {
+            target: target.to_path_buf(),
+        });
+        Ok(())
+    }
+}
+
+impl Display for MountCall {
+    /// Displays the equivalent command.
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        let command = match self {
+            MountCall::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data,
+            } => {
+                let mut options: Vec<String> =
+                    flag_options(*flags).iter().map(|x| x.to_string()).collect();
+                options.push(String::from_utf8_lossy(data).into_owned());
+                format!(
+                    "mount -t {} -o {} {} {}",
+                    fstype,
+                    shell_quote(&options.join(",")),
+                    shell_quote(source),
+                    shell_quote(&target.to_string_lossy())
+                )
+            }
+            MountCall::Bind {
+                source,
+                target,
+                read_only,
+            } => format!(
+                "mount --bind{} {} {}",
+                if *read_only { " -o ro" } else { "" },
+                shell_quote(&source.to_string_lossy()),
+                shell_quote(&target.to_string_lossy())
+            ),
+            MountCall::Unmount { target, flags } => format!(
+                "umount{} {}",
+                if flags.contains(MntFlags::MNT_DETACH) {
+                    " --lazy"
+                } else {
+                    ""
+                },
+                shell_quote(&target.to_string_lossy())
+            ),
+            MountCall::MakePrivate { target } => format!(
+                "mount --make-rprivate {}",
+                shell_quote(&target.to_string_lossy())
+            ),
+        };
+        write!(f, "{}", color_cmd(&command))
+    }
+}
+
+/*
+ * Overlayfs feature detection
+ */
+
+/// Whether the kernel's overlayfs supports `redirect_dir`, which allows
+/// directories from lower layers to be renamed. Without it, such renames fail
+/// with `EXDEV`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectDirSupport {
+    /// The overlay module isn't loaded, so support can't be determined.
+    Unknown,
+    /// The overlay module doesn't have the `redirect_dir` parameter.
+    Unsupported,
+    /// Supported, but only enabled when requested by a mount option.
+    DefaultOff,
+    /// Supported and enabled by default.
+    DefaultOn,
+}
+
+/// Determines `redirect_dir` support via the overlay module's parameters.
+pub fn probe_redirect_dir() -> RedirectDirSupport {
+    let module_dir = Path::new("/sys/module/overlay");
+    if !module_dir.is_dir() {
+        return RedirectDirSupport::Unknown;
+    }
+    let mut contents = String::new();
+    match File::open(module_dir.join("parameters/redirect_dir"))
+        .and_then(|mut file| file.read_to_string(&mut contents))
+    {
+        Err(_) => RedirectDirSupport::Unsupported,
+        // Boolean module parameters are "Y" or "N".
+        Ok(_) if contents.trim() == "Y" => RedirectDirSupport::DefaultOn,
+        Ok(_) => RedirectDirSupport::DefaultOff,
+    }
+}
+
+/// Mount option to add in order to enable `redirect_dir`, if any.
+pub fn redirect_dir_option(support: RedirectDirSupport) -> Option<&'static str> {
+    match support {
+        RedirectDirSupport::DefaultOff => Some("redirect_dir=on"),
+        RedirectDirSupport::Unknown
+        | RedirectDirSupport::Unsupported
+        | RedirectDirSupport::DefaultOn => None,
+    }
+}
+
+/// Lists the incompatible features that overlayfs has recorded in a work
+/// directory. Overlayfs creates an entry in `work/incompat` for each feature
+/// used by a mount which makes the layers unsafe to mount without it, such as
+/// `volatile`.
+pub fn incompat_features(work_dir: &OvfsWorkDir) -> Result<Vec<String>, Error> {
+    list_dir_names(work_dir.join("work").join("incompat"))
+}
+
+/// Refuses to mount when the work directory has incompatible features, since
+/// mzr never enables them. They indicate that the zone was mounted by
+/// something else, and mounting with a kernel which doesn't know about the
+/// features could corrupt the zone's changes.
+pub fn check_incompat_features(work_dir: &OvfsWorkDir) -> Result<(), Error> {
+    let features = incompat_features(work_dir)?;
+    if !features.is_empty() {
+        bail!(
+            "Refusing to mount, because overlayfs work directory {} has incompatible features: {}",
+            work_dir,
+            features.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Removes stale contents of the work directory's `work` subdirectory, which
+/// overlayfs uses for staging files, and which can cause mounts to fail when
+/// left behind by a mount that wasn't cleanly unmounted. The `incompat` records
+/// within it are kept, as is the `index` directory, since overlayfs relies on
+/// those persisting between mounts.
+///
+/// Refuses to clean a work directory which is used by an active mount.
+pub fn clean_work_dir(work_dir: &OvfsWorkDir) -> Result<(), Error> {
+    if is_work_dir_in_use(work_dir)? {
+        bail!(
+            "Refusing to clean overlayfs work directory {}, because it is used by an active mount.",
+            work_dir
+        );
+    }
+    let staging_dir = work_dir.join("work");
+    for name in list_dir_names(&staging_dir)? {
+        if name == "incompat" {
+            continue;
+        }
+        let path = staging_dir.join(&name);
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.context(format_err!(
+            "Failed to remove stale overlayfs work file {}",
+            color_file(&path.display())
+        ))?;
+    }
+    Ok(())
+}
+
+/// Whether an overlay mount in the current mount namespace uses the work
+/// directory.
+pub fn is_work_dir_in_use(work_dir: &OvfsWorkDir) -> Result<bool, Error> {
+    Ok(read_mounts()?.iter().any(|entry| {
+        entry.fstype == "overlay"
+            && overlay_dir_option(&entry.options, "workdir").as_ref() == Some(work_dir.as_ref())
+    }))
+}
+
+/// Filesystem type reported by `statfs` for overlayfs, from `linux/magic.h`.
+const OVERLAYFS_SUPER_MAGIC: i64 = 0x794c_7630;
+
+/// Returns `true` if the path is on an overlayfs filesystem.
+pub fn is_on_overlayfs(path: &Path) -> Result<bool, Error> {
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    statfs(path, &mut stat).context(format_err!(
+        "Failed to query filesystem type of {}",
+        color_dir(&path.display())
+    ))?;
+    Ok(stat.f_type as i64 == OVERLAYFS_SUPER_MAGIC)
+}
+
+/// Refuses to create zone directories on overlayfs, such as when mzr is run
+/// from within another zone. Using an overlayfs as the upper directory of
+/// another overlayfs is not supported by the kernel.
+pub fn check_not_on_overlayfs(dir: &Path) -> Result<(), Error> {
+    if is_on_overlayfs(dir)? {
+        bail!(
+            "Refusing to create zone within {}, because it is on an overlayfs filesystem. \
+             A zone's changes directory is used as an overlayfs upper directory, which \
+             can't itself be on overlayfs.",
+            color_dir(&dir.display())
+        );
+    }
+    Ok(())
+}
+
+/*
+ * Extra bind mounts
+ */
+
+/// Additional directory to bind-mount within a zone process, after the zone
+/// has been bound. This is useful for making caches available without them
+/// being part of the snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct BindSpec {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+impl BindSpec {
+    /// Checks that the source and target directories exist.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (role, path) in [("source", &self.source), ("target", &self.target)].iter() {
+            if !path.exists() {
+                bail!(
+                    "Bind mount {} {} does not exist.",
+                    role,
+                    color_dir(&path.display())
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mount(&self, backend: &dyn MountBackend) -> Result<(), Error> {
+        backend.bind(&self.source, &self.target, self.read_only)
+    }
+}
+
+impl FromStr for BindSpec {
+    type Err = Error;
+    /// Parses specs of the form `SOURCE:TARGET`, optionally followed by `:ro`
+    /// or `:rw`. Bind mounts are writable by default. Both paths must be
+    /// absolute, since the daemon which performs the mounts has a different
+    /// current directory, and the source must exist. The target is within the
+    /// zone, so it's checked when mounting.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (source, target, read_only) = match parts.as_slice() {
+            [source, target] => (source, target, false),
+            [source, target, "ro"] => (source, target, true),
+            [source, target, "rw"] => (source, target, false),
+            [_, _, mode] => bail!(
+                "Expected bind mount mode to be ro or rw, but got {:?} in {:?}",
+                mode,
+                spec
+            ),
+            _ => bail!(
+                "Expected bind mount in the form SOURCE:TARGET[:ro|:rw], but got {:?}",
+                spec
+            ),
+        };
+        if source.is_empty() || target.is_empty() {
+            bail!(
+                "Expected bind mount to have a non-empty SOURCE and TARGET, but got {:?}",
+                spec
+            );
+        }
+        for (role, path) in [("source", source), ("target", target)].iter() {
+            if !Path::new(path).is_absolute() {
+                bail!(
+                    "Expected bind mount {} to be an absolute path, but got {:?} in {:?}",
+                    role,
+                    path,
+                    spec
+                );
+            }
+        }
+        let source = PathBuf::from(source);
+        if !source.exists() {
+            bail!(
+                "Bind mount source {} does not exist.",
+                color_dir(&source.display())
+            );
+        }
+        Ok(BindSpec {
+            source,
+            target: PathBuf::from(target),
+            read_only,
+        })
+    }
+}
+
+/// Performs bind mounts in order. If one fails, then the ones which succeeded
+/// are unmounted.
+pub fn mount_binds(backend: &dyn MountBackend, binds: &[BindSpec]) -> Result<(), Error> {
+    for (index, bind) in binds.iter().enumerate() {
+        if let Err(e) = bind.validate().and_then(|()| bind.mount(backend)) {
+            unmount_binds(backend, &binds[..index])?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Makes every mount in the current mount namespace private, so that mounts
+/// made afterwards don't propagate to the namespace it was copied from, and
+/// mounts made there don't propagate into it. Without this, zone mounts can
+/// leak to the host when `/` is a shared mount, as it is on systemd systems.
+pub fn make_mounts_private(backend: &dyn MountBackend) -> Result<(), Error> {
+    backend.make_private(Path::new("/")).context(format_err!(
+        "Failed to make mounts private. This can be disabled by setting {} in the config.",
+        color_cmd(&"private_propagation = false")
+    ))?;
+    Ok(())
+}
+
+/// Unmounts bind mounts in the reverse of the order they were mounted, so that
+/// nested bind mounts get unmounted first.
+pub fn unmount_binds(backend: &dyn MountBackend, binds: &[BindSpec]) -> Result<(), Error> {
+    for bind in binds.iter().rev() {
+        backend
+            .unmount(&bind.target, MntFlags::empty())
+            .context(format_err!(
+                "Failed to unmount {}",
+                color_dir(&bind.target.display())
+            ))?;
+    }
+    Ok(())
+}
+
+/// Configuration of how mounts get retried when they fail with transient
+/// errors.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry. This is doubled for each subsequent retry.
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Whether a mount error might go away if the mount is retried. Under heavy
+/// namespace churn, `EAGAIN` can occur transiently. `EBUSY` is not retried,
+/// since for overlayfs it means that the upper or work directory is in use.
+pub fn is_transient_mount_error(err: &nix::Error) -> bool {
+    matches!(err, nix::Error::Sys(Errno::EAGAIN))
+}
+
+/// Whether a failure to mount an overlay could be due to `redirect_dir` being
+/// refused, which happens for unprivileged mounts, such as those within the
+/// daemon's user namespace. The kernel reports this as `EPERM` or `EINVAL`.
+pub fn is_redirect_dir_refusal(err: &Error) -> bool {
+    matches!(
+        err.find_root_cause().downcast_ref::<nix::Error>(),
+        Some(nix::Error::Sys(Errno::EPERM)) | Some(nix::Error::Sys(Errno::EINVAL))
+    )
+}
+
+/// Explains the likely causes of errors from mounting overlayfs, since the
+/// kernel only reports an errno, and details only go to the kernel log.
+pub fn explain_overlay_mount_error(err: &nix::Error) -> Option<&'static str> {
+    let errno = match err {
+        nix::Error::Sys(errno) => *errno,
+        _ => return None,
+    };
+    Some(match errno {
+        Errno::EINVAL => {
+            "overlayfs rejected the layers or options. Common causes are an upper directory on a \
+             filesystem which can't be an overlayfs upper layer, such as another overlayfs or \
+             NFS, a layer on a filesystem which doesn't allow overlayfs mounts within user \
+             namespaces, or an option which this kernel doesn't support. The kernel log \
+             (dmesg) has details."
+        }
+        Errno::EPERM => {
+            "Mounting overlayfs isn't permitted. Kernels before 5.11 don't allow overlayfs \
+             mounts within user namespaces, unless patched by the distribution."
+        }
+        Errno::ENODEV => {
+            "The kernel doesn't support overlayfs. It may need to be loaded with \
+             \"modprobe overlay\"."
+        }
+        Errno::EBUSY => "The upper or work directory is already in use by another overlayfs mount.",
+        Errno::ELOOP => "overlayfs detected that the layers overlap with each other.",
+        Errno::EXDEV => "The upper and work directories must be on the same filesystem.",
+        Errno::ENOENT | Errno::ENOTDIR => "One of the layer directories doesn't exist.",
+        Errno::EACCES => "One of the layer directories isn't accessible.",
+        Errno::EOPNOTSUPP => {
+            "The filesystem of the upper directory lacks features which overlayfs requires, \
+             such as extended attributes or d_type support."
+        }
+        Errno::ESTALE => {
+            "overlayfs found records in the work directory or extended attributes which refer \
+             to different layers, such as after the zone directory was copied."
+        }
+        _ => return None,
+    })
+}
+
+/// Names used by the `mount` command for the flags that it supports.
+fn flag_options(flags: MsFlags) -> Vec<&'static str> {
+    let mut result = Vec::new();
+    for (flag, name) in [
+        (MsFlags::MS_RDONLY, "ro"),
+        (MsFlags::MS_NOSUID, "nosuid"),
+        (MsFlags::MS_NODEV, "nodev"),
+        (MsFlags::MS_NOEXEC, "noexec"),
+        (MsFlags::MS_NOATIME, "noatime"),
+    ]
+    .iter()
+    {
+        if flags.contains(*flag) {
+            result.push(*name);
+        }
+    }
+    result
+}
+
+/// Refuses to mount an overlay which stacks more lowerdirs than the maximum,
+/// since exceeding the kernel's limit fails with an unhelpful `EINVAL`. Zones
+/// are currently mounted with a single lowerdir, since snapshots are full
+/// copies, but this guards mounts which stack more.
+pub fn check_lowerdir_count(overlay: &OverlayMount, max_lowerdirs: usize) -> Result<(), Error> {
+    let count = overlay.lowerdir_count();
+    if count > max_lowerdirs {
+        bail!(
+            "Refusing to mount overlay with {} lowerdirs, since the maximum is {}. Use {} to \
+             collapse the zone onto a single snapshot, or raise {} in the config.",
+            count,
+            max_lowerdirs,
+            color_cmd(&"mzr compact"),
+            color_cmd(&"max_lowerdirs")
+        );
+    }
+    Ok(())
+}
+
+/// Escapes a path for inclusion in the overlayfs options string. This follows
+/// the same rules as the `libmount` crate, where these were derived
+/// experimentally.
+fn append_escaped(dest: &mut Vec<u8>, path: &Path) {
+    for &byte in path.as_os_str().as_bytes().iter() {
+        match byte {
+            // Escape character.
+            b'\\' => dest.extend(b"\\\\"),
+            // Separates lowerdir paths.
+            b':' => dest.extend(b"\\:"),
+            // Separates options.
+            b',' => dest.extend(b"\\,"),
+            x => dest.push(x),
+        }
+    }
+}
+
+/*
+ * Querying mounts
+ */
+
+/// Entry of `/proc/mounts`, describing a mounted filesystem.
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+    /// Options as escaped in the mount table, since unescaping them would
+    /// make commas within option values indistinguishable from separators.
+    /// See `overlay_dir_option`.
+    pub options: String,
+}
+
+/// Reads the mounts visible in the current process's mount namespace.
+pub fn read_mounts() -> Result<Vec<MountEntry>, Error> {
+    let path = "/proc/self/mounts";
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .context(format_err!("Failed to read {}", color_file(&path)))?;
+    parse_mounts(&contents)
+}
+
+pub fn parse_mounts(contents: &str) -> Result<Vec<MountEntry>, Error> {
+    let mut result = Vec::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            bail!("Unexpected line in mounts file: {:?}", line);
+        }
+        result.push(MountEntry {
+            source: unescape_mount_field(fields[0]),
+            target: PathBuf::from(unescape_mount_field(fields[1])),
+            fstype: unescape_mount_field(fields[2]),
+            options: fields[3].to_string(),
+        });
+    }
+    Ok(result)
+}
+
+/// Entry of `/proc/self/mountinfo`. Unlike `/proc/mounts`, this separates the
+/// per-mount options from the superblock options, which for overlayfs include
+/// the layer directories.
+#[derive(Debug, Clone)]
+pub struct MountInfoEntry {
+    pub mount_point: PathBuf,
+    /// Escaped like `MountEntry::options`.
+    pub mount_options: String,
+    pub fstype: String,
+    pub source: String,
+    /// Escaped like `MountEntry::options`.
+    pub super_options: String,
+}
+
+pub fn read_mountinfo() -> Result<Vec<MountInfoEntry>, Error> {
+    let path = "/proc/self/mountinfo";
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .context(format_err!("Failed to read {}", color_file(&path)))?;
+    parse_mountinfo(&contents)
+}
+
+/// Parses the mountinfo format, described in `proc(5)`. Each line has the
+/// form:
+///
+/// ```text
+/// ID PARENT_ID MAJOR:MINOR ROOT MOUNT_POINT MOUNT_OPTIONS [OPTIONAL...] - FSTYPE SOURCE SUPER_OPTIONS
+/// ```
+pub fn parse_mountinfo(contents: &str) -> Result<Vec<MountInfoEntry>, Error> {
+    let mut result = Vec::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The optional fields are terminated by a lone hyphen.
+        let separator = fields.iter().skip(6).position(|field| *field == "-");
+        match separator.map(|index| (&fields[..6], &fields[index + 7..])) {
+            Some((before, [fstype, source, super_options])) => {
+                result.push(MountInfoEntry {
+                    mount_point: PathBuf::from(unescape_mount_field(before[4])),
+                    mount_options: before[5].to_string(),
+                    fstype: unescape_mount_field(fstype),
+                    source: unescape_mount_field(source),
+                    super_options: super_options.to_string(),
+                });
+            }
+            _ => bail!("Unexpected line in mountinfo file: {:?}", line),
+        }
+    }
+    Ok(result)
+}
+
+/// Finds the mountinfo entry of the filesystem mounted at the target. If
+/// multiple filesystems are mounted there, the last one is the visible one.
+pub fn find_mountinfo(target: &Path) -> Result<Option<MountInfoEntry>, Error> {
+    Ok(read_mountinfo()?
+        .into_iter()
+        .rfind(|entry| entry.mount_point == target))
+}
+
+/// Finds the overlay mounts of mzr zones, for any project, by looking for
+/// upper directories of the form `.../PROJECT.mzr/zone/ZONE/changes`. This
+/// only finds mounts visible in the current mount namespace.
+pub fn find_zone_mounts(mounts: &[MountEntry]) -> Vec<(MzrDir, ZoneName)> {
+    mounts.iter().filter_map(zone_of_mount).collect()
+}
+
+fn zone_of_mount(entry: &MountEntry) -> Option<(MzrDir, ZoneName)> {
+    if entry.fstype != "overlay" {
+        return None;
+    }
+    let upper_dir = overlay_dir_option(&entry.options, "upperdir")?;
+    zone_of_changes_dir(&upper_dir)
+}
+
+/// Determines which of the project's zones is mounted at a work directory in
+/// the current mount namespace, such as within `mzr shell`. Yields `None` if
+/// the directory has no sibling mzr directory, or if no zone of that mzr
+/// directory is mounted there. The directory should be canonical, since
+/// mount points are.
+pub fn zone_at_work_dir(work_dir: &UserWorkDir) -> Result<Option<ZoneName>, Error> {
+    let mzr_dir = MzrDir::new(work_dir);
+    if let Err(e) = MzrDir::open(&mzr_dir) {
+        if e.downcast_ref::<NotMzrDir>().is_some() {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    Ok(find_mountinfo(work_dir)?.and_then(|entry| zone_of_mountinfo(&mzr_dir, &entry)))
+}
+
+/// Determines which of the mzr directory's zones has its overlay mounted by
+/// the mountinfo entry, if any.
+fn zone_of_mountinfo(mzr_dir: &MzrDir, entry: &MountInfoEntry) -> Option<ZoneName> {
+    if entry.fstype != "overlay" {
+        return None;
+    }
+    let upper_dir = overlay_dir_option(&entry.super_options, "upperdir")?;
+    match zone_of_changes_dir(&upper_dir)? {
+        (zone_mzr_dir, zone_name) if zone_mzr_dir.as_path() == mzr_dir.as_path() => Some(zone_name),
+        _ => None,
+    }
+}
+
+/// How a zone's overlay was removed by `unmount_all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unmounted {
+    Unmounted,
+    /// The overlay was busy, so it was detached with `MNT_DETACH`. It
+    /// remains accessible to processes already using it.
+    Detached,
+}
+
+/// Checks that a mount was removed, by comparing the mount table from before
+/// unmounting it with the mount table from afterwards. Mounts can be stacked
+/// at the same target, so this checks that there is one fewer mount at the
+/// target, rather than none.
+pub fn verify_unmounted(
+    target: &Path,
+    before: &[MountEntry],
+    after: &[MountEntry],
+) -> Result<(), Error> {
+    let count =
+        |mounts: &[MountEntry]| mounts.iter().filter(|entry| entry.target == target).count();
+    if count(after) >= count(before) {
+        bail!(
+            "Unmounting {} succeeded, but it is still listed in the mount table.",
+            color_dir(&target.display())
+        );
+    }
+    Ok(())
+}
+
+/// Result of unmounting each zone, from `unmount_all`.
+pub type ZoneUnmountResults = Vec<(ZoneName, Result<Unmounted, Error>)>;
+
+/// Unmounts the overlays of all of the project's zones which are mounted in
+/// the current mount namespace, most recently mounted first. Failure to
+/// unmount one zone does not prevent unmounting the others, so the result
+/// for each zone is returned. When `lazy` is set, busy overlays get detached
+/// instead. When `verify` is set, the mount table is re-read after each
+/// unmount, to check that the overlay is really gone - see
+/// `verify_unmounted`.
+pub fn unmount_all(
+    mzr_dir: &MzrDir,
+    lazy: bool,
+    verify: bool,
+) -> Result<ZoneUnmountResults, Error> {
+    let mut results = Vec::new();
+    let mut mounts = read_mounts()?;
+    for entry in mounts.clone().iter().rev() {
+        let zone_name = match zone_of_mount(entry) {
+            Some((zone_mzr_dir, zone_name)) => {
+                if zone_mzr_dir.as_path() != mzr_dir.as_path() {
+                    continue;
+                }
+                zone_name
+            }
+            None => continue,
+        };
+        let result = match timed_umount2(&entry.target, MntFlags::empty()) {
+            Ok(Err(nix::Error::Sys(Errno::EBUSY))) if lazy => {
+                timed_umount2(&entry.target, MntFlags::MNT_DETACH)
+                    .map(|result| result.map(|()| Unmounted::Detached))
+            }
+            other => other.map(|result| result.map(|()| Unmounted::Unmounted)),
+        }
+        .and_then(|result| {
+            Ok(result.context(format_err!(
+                "Failed to unmount {}",
+                color_dir(&entry.target.display())
+            ))?)
+        });
+        let result = match result {
+            // Failing to re-read the mount table only affects this zone's
+            // result, since the other zones can still be unmounted.
+            Ok(unmounted) if verify => read_mounts().and_then(|remaining| {
+                let verified = verify_unmounted(&entry.target, &mounts, &remaining);
+                mounts = remaining;
+                verified.map(|()| unmounted)
+            }),
+            _ => result,
+        };
+        results.push((zone_name, result));
+    }
+    Ok(results)
+}
+
+fn zone_of_changes_dir(changes_dir: &Path) -> Option<(MzrDir, ZoneName)> {
+    OvfsChangesDir::try_from(changes_dir).ok()?;
+    let zone_dir = ZoneDir::try_from(changes_dir.parent()?).ok()?;
+    let mzr_dir = MzrDir::try_from(zone_dir.parent()?.parent()?).ok()?;
+    Some((mzr_dir, zone_dir.zone_name().ok()?))
+}
+
+/// Finds the directory of an overlayfs option which has a single directory,
+/// such as `upperdir`, within escaped mount options like
+/// `MountEntry::options`. The kernel shows these options as they were passed
+/// when mounting, escaped as by `append_escaped`, and then escapes commas and
+/// backslashes as octal, such as `\054`. So the options are split before
+/// either escaping is undone.
+fn overlay_dir_option(options: &str, key: &str) -> Option<PathBuf> {
+    options.split(',').find_map(|option| {
+        let value = strip_prefix(&format!("{}=", key), option)?;
+        let mut result = Vec::new();
+        let mut escaped = false;
+        for &byte in unescape_mount_field(&value).as_bytes() {
+            match (escaped, byte) {
+                (false, b'\\') => escaped = true,
+                _ => {
+                    result.push(byte);
+                    escaped = false;
+                }
+            }
+        }
+        Some(PathBuf::from(OsString::from_vec(result)))
+    })
+}
+
+/// The kernel escapes spaces, tabs, newlines, and backslashes in mount fields
+/// as octal, such as `\040` for space.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && is_octal_escape(digits));
+        match escape {
+            Some(digits) => {
+                // Octal digits are always valid utf-8 and in range of u8.
+                let digits = std::str::from_utf8(digits).unwrap();
+                result.push(u8::from_str_radix(digits, 8).unwrap());
+                i += 4;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn is_octal_escape(digits: &[u8]) -> bool {
+    digits[0] <= b'3' && digits.iter().all(|d| b'0' <= *d && *d <= b'7')
+}
+
+pub fn is_mounted(target: &Path) -> Result<bool, Error> {
+    Ok(read_mounts()?.iter().any(|entry| entry.target == target))
+}
+
+/// Waits until the zone's overlay is mounted in the current mount namespace,
+/// for use when it is being mounted by another process such as the daemon.
+pub fn wait_until_mounted(zone: &Zone, timeout: Duration) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    let mut interval = Duration::from_millis(10);
+    loop {
+        if is_mounted(&zone.ovfs_mount_dir)? {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            bail!(
+                "Timed out after {:?} waiting for {} zone to be mounted at {}.",
+                timeout,
+                zone.name,
+                zone.ovfs_mount_dir
+            );
+        }
+        thread::sleep(min(interval, deadline - now));
+        interval = min(interval * 2, Duration::from_millis(500));
+    }
+}
+
+pub fn unmount(target: &Path) -> Result<(), Error> {
+    timed_umount2(target, MntFlags::empty())?.context(format_err!(
+        "Failed to unmount {}",
+        color_dir(&target.display())
+    ))?;
+    Ok(())
+}
+
+/// How long mounting or unmounting may take before giving up, since these can
+/// hang, such as when a filesystem beneath the target is unresponsive.
+pub const MOUNT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs `umount2` with `MOUNT_TIMEOUT`. The outer result is the timeout, and
+/// the inner result is that of the syscall.
+fn timed_umount2(target: &Path, flags: MntFlags) -> Result<Result<(), nix::Error>, Error> {
+    let owned_target = target.to_path_buf();
+    with_timeout(
+        &format!("unmounting {}", target.display()),
+        MOUNT_TIMEOUT,
+        move || Ok(umount2(&owned_target, flags)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_report::strip_ansi_escapes;
+    use crate::namespaces;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, TestDir};
+    use crate::utils::{ensure_dir, Timeout};
+    use nix::unistd::Uid;
+
+    #[test]
+    fn redirect_dir_refusal_errors() {
+        let mount_err = |errno| -> Error {
+            nix::Error::Sys(errno)
+                .context(format_err!("Failed to mount overlay"))
+                .into()
+        };
+        assert!(is_redirect_dir_refusal(&mount_err(Errno::EPERM)));
+        assert!(is_redirect_dir_refusal(&mount_err(Errno::EINVAL)));
+        assert!(!is_redirect_dir_refusal(&mount_err(Errno::ENOENT)));
+        assert!(!is_redirect_dir_refusal(&format_err!("Timed out")));
+    }
+
+    #[test]
+    fn only_eagain_is_retried() {
+        let transient = |errno| is_transient_mount_error(&nix::Error::Sys(errno));
+        assert!(transient(Errno::EAGAIN));
+        for errno in &[Errno::EBUSY, Errno::EINVAL, Errno::EPERM, Errno::ENOENT] {
+            assert!(!transient(*errno));
+        }
+        assert!(!is_transient_mount_error(&nix::Error::InvalidPath));
+    }
+
+    #[test]
+    fn parses_bind_specs() {
+        let dir = TestDir::new();
+        let source = dir.path().display().to_string();
+        let bind = |target: &str, read_only| BindSpec {
+            source: dir.path().to_path_buf(),
+            target: PathBuf::from(target),
+            read_only,
+        };
+        let good = [
+            (format!("{}:/cache", source), bind("/cache", false)),
+            (format!("{}:/cache:ro", source), bind("/cache", true)),
+            (format!("{}:/cache:rw", source), bind("/cache", false)),
+        ];
+        for (spec, expected) in good.iter() {
+            assert_eq!(&spec.parse::<BindSpec>().unwrap(), expected, "{}", spec);
+        }
+        let bad = [
+            source.clone(),
+            format!("{}:/cache:rx", source),
+            format!("{}:/cache:ro:rw", source),
+            format!("{}:", source),
+            ":/cache".to_string(),
+            "relative:/cache".to_string(),
+            format!("{}:relative", source),
+            format!("{}/missing:/cache", source),
+        ];
+        for spec in bad.iter() {
+            assert!(spec.parse::<BindSpec>().is_err(), "{}", spec);
+        }
+    }
+
+    fn overlay_at(target: &str) -> MountEntry {
+        MountEntry {
+            source: "overlay".to_string(),
+            target: PathBuf::from(target),
+            fstype: "overlay".to_string(),
+            options: "rw".to_string(),
+        }
+    }
+
+    #[test]
+    fn verify_unmounted_checks_for_one_fewer_mount() {
+        let target = Path::new("/zone/mount");
+        let mounted = vec![overlay_at("/other/mount"), overlay_at("/zone/mount")];
+        let stacked = vec![overlay_at("/zone/mount"), overlay_at("/zone/mount")];
+        assert!(verify_unmounted(target, &mounted, &[overlay_at("/other/mount")]).is_ok());
+        assert!(verify_unmounted(target, &stacked, &[overlay_at("/zone/mount")]).is_ok());
+        assert!(verify_unmounted(target, &mounted, &mounted).is_err());
+        assert!(verify_unmounted(target, &stacked, &stacked).is_err());
+    }
+
+    #[test]
+    fn lowerdir_count_skips_escaped_colons() {
+        let overlay_with = |data: &[u8]| OverlayMount {
+            source: "overlay".to_string(),
+            target: PathBuf::from("/zone/mount"),
+            fstype: "overlay".to_string(),
+            flags: MsFlags::empty(),
+            data: data.to_vec(),
+        };
+        let check = |data: &[u8], count: usize| {
+            let overlay = overlay_with(data);
+            assert_eq!(overlay.lowerdir_count(), count);
+            assert_eq!(check_lowerdir_count(&overlay, 2).is_ok(), count <= 2);
+        };
+        check(b"upperdir=/a:b,workdir=/w", 0);
+        check(b"lowerdir=/s1,upperdir=/a:b:c", 1);
+        check(b"upperdir=/u,lowerdir=/s1:/s\\:2:/s3,workdir=/w", 3);
+        check(b"lowerdir=/s\\:1:/s\\,2,upperdir=/u", 2);
+        check(b"lowerdir=/s1,xlowerdir=/a:b", 1);
+    }
+
+    #[test]
+    fn binds_unmount_in_reverse_order() {
+        let dir = TestDir::new();
+        let bind = |source: &str, target: &str| {
+            ensure_dir(dir.join(source)).unwrap();
+            ensure_dir(dir.join(target)).unwrap();
+            BindSpec {
+                source: dir.join(source),
+                target: dir.join(target),
+                read_only: false,
+            }
+        };
+        let binds = vec![bind("a", "zone/a"), bind("b", "zone/a/b")];
+        let backend = RecordingBackend::default();
+        mount_binds(&backend, &binds).unwrap();
+        unmount_binds(&backend, &binds).unwrap();
+        let bind_call = |bind: &BindSpec| MountCall::Bind {
+            source: bind.source.clone(),
+            target: bind.target.clone(),
+            read_only: false,
+        };
+        let unmount_call = |bind: &BindSpec| MountCall::Unmount {
+            target: bind.target.clone(),
+            flags: MntFlags::empty(),
+        };
+        assert_eq!(
+            backend.calls(),
+            vec![
+                bind_call(&binds[0]),
+                bind_call(&binds[1]),
+                unmount_call(&binds[1]),
+                unmount_call(&binds[0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_bind_unmounts_earlier_binds() {
+        let dir = TestDir::new();
+        ensure_dir(dir.join("source")).unwrap();
+        ensure_dir(dir.join("target")).unwrap();
+        let binds = vec![
+            BindSpec {
+                source: dir.join("source"),
+                target: dir.join("target"),
+                read_only: true,
+            },
+            BindSpec {
+                source: dir.join("missing"),
+                target: dir.join("target"),
+                read_only: false,
+            },
+        ];
+        let backend = RecordingBackend::default();
+        assert!(mount_binds(&backend, &binds).is_err());
+        assert_eq!(
+            backend.calls(),
+            vec![
+                MountCall::Bind {
+                    source: dir.join("source"),
+                    target: dir.join("target"),
+                    read_only: true,
+                },
+                MountCall::Unmount {
+                    target: dir.join("target"),
+                    flags: MntFlags::empty(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn overlay_mounts_via_backend() {
+        let overlay = OverlayMount {
+            source: "overlay".to_string(),
+            target: PathBuf::from("/zone/mount"),
+            fstype: "overlay".to_string(),
+            flags: MsFlags::MS_NODEV,
+            data: b"lowerdir=/snap,upperdir=/changes,workdir=/work".to_vec(),
+        };
+        let backend = RecordingBackend::default();
+        overlay
+            .mount_with_retry(&backend, &RetryPolicy::default())
+            .unwrap();
+        assert_eq!(
+            backend.calls(),
+            vec![MountCall::Mount {
+                source: overlay.source.clone(),
+                target: overlay.target.clone(),
+                fstype: overlay.fstype.clone(),
+                flags: overlay.flags,
+                data: overlay.data.clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn mounts_are_made_private_from_the_root() {
+        let backend = RecordingBackend::default();
+        make_mounts_private(&backend).unwrap();
+        assert_eq!(
+            backend.calls(),
+            vec![MountCall::MakePrivate {
+                target: PathBuf::from("/"),
+            }]
+        );
+        // Private propagation is the default, since it keeps zone mounts from
+        // leaking to the host.
+        assert!(
+            crate::config::Config::default()
+                .mount_options()
+                .private_propagation
+        );
+    }
+
+    /// Backend whose overlay mounts fail with an errno.
+    struct FailingBackend(Errno);
+
+    impl MountBackend for FailingBackend {
+        fn mount(
+            &self,
+            _: &str,
+            _: &Path,
+            _: &str,
+            _: MsFlags,
+            _: &[u8],
+        ) -> Result<(), nix::Error> {
+            Err(nix::Error::Sys(self.0))
+        }
+
+        fn bind(&self, _: &Path, _: &Path, _: bool) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn unmount(&self, _: &Path, _: MntFlags) -> Result<(), nix::Error> {
+            Ok(())
+        }
+
+        fn make_private(&self, _: &Path) -> Result<(), nix::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn overlay_mount_errors_are_explained() {
+        let explain = |errno| explain_overlay_mount_error(&nix::Error::Sys(errno)).unwrap();
+        assert!(explain(Errno::EINVAL).contains("dmesg"));
+        assert!(explain(Errno::EPERM).contains("user namespaces"));
+        assert!(explain(Errno::ENODEV).contains("modprobe overlay"));
+        assert!(explain(Errno::EXDEV).contains("same filesystem"));
+        assert!(explain(Errno::ESTALE).contains("copied"));
+        assert_eq!(explain(Errno::ENOENT), explain(Errno::ENOTDIR));
+        assert!(explain_overlay_mount_error(&nix::Error::Sys(Errno::ENOSPC)).is_none());
+        assert!(explain_overlay_mount_error(&nix::Error::InvalidUtf8).is_none());
+        let overlay = OverlayMount {
+            source: "overlay".to_string(),
+            target: PathBuf::from("/zone/mount"),
+            fstype: "overlay".to_string(),
+            flags: MsFlags::empty(),
+            data: b"lowerdir=/snap,upperdir=/changes,workdir=/work".to_vec(),
+        };
+        let policy = RetryPolicy::default();
+        let message = |errno| {
+            let err = overlay
+                .mount_with_retry(&FailingBackend(errno), &policy)
+                .unwrap_err();
+            strip_ansi_escapes(&err.to_string())
+        };
+        let exdev = message(Errno::EXDEV);
+        assert!(exdev.contains(explain(Errno::EXDEV)), "{}", exdev);
+        assert!(exdev.contains("mount -t overlay"), "{}", exdev);
+        let enospc = message(Errno::ENOSPC);
+        assert!(
+            enospc.starts_with("Failed to mount overlay.\nEquivalent command is:"),
+            "{}",
+            enospc
+        );
+    }
+
+    /// Backend whose operations succeed after a delay, standing in for a
+    /// mount which hangs.
+    struct SlowBackend(Duration);
+
+    impl MountBackend for SlowBackend {
+        fn mount(
+            &self,
+            _: &str,
+            _: &Path,
+            _: &str,
+            _: MsFlags,
+            _: &[u8],
+        ) -> Result<(), nix::Error> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn bind(&self, _: &Path, _: &Path, _: bool) -> Result<(), Error> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn unmount(&self, _: &Path, _: MntFlags) -> Result<(), nix::Error> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn make_private(&self, _: &Path) -> Result<(), nix::Error> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_overlay_mounts_time_out() {
+        let overlay = OverlayMount {
+            source: "overlay".to_string(),
+            target: PathBuf::from("/zone/mount"),
+            fstype: "overlay".to_string(),
+            flags: MsFlags::empty(),
+            data: b"lowerdir=/snap,upperdir=/changes,workdir=/work".to_vec(),
+        };
+        let policy = RetryPolicy::default();
+        let timeout = Duration::from_millis(50);
+        overlay
+            .mount_with_timeout(SlowBackend(Duration::from_millis(0)), &policy, timeout)
+            .unwrap();
+        let err = overlay
+            .mount_with_timeout(SlowBackend(Duration::from_secs(2)), &policy, timeout)
+            .unwrap_err();
+        let Timeout(description, timed_out_after) = err.downcast_ref::<Timeout>().unwrap();
+        assert_eq!(description, "mounting overlay at /zone/mount");
+        assert_eq!(*timed_out_after, timeout);
+    }
+
+    #[test]
+    fn zones_are_found_at_work_dirs_of_their_project() {
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&PathBuf::from("/home/user/proj")));
+        let entry = |fstype: &str, upper_dir: &str| MountInfoEntry {
+            mount_point: PathBuf::from("/home/user/proj"),
+            mount_options: "rw,relatime".to_string(),
+            fstype: fstype.to_string(),
+            source: "overlay".to_string(),
+            super_options: format!(
+                "rw,lowerdir=/home/user/proj.mzr/snap/s1,upperdir={},workdir=/work",
+                upper_dir
+            ),
+        };
+        let zone_name = zone_of_mountinfo(
+            &mzr_dir,
+            &entry("overlay", "/home/user/proj.mzr/zone/mz/changes"),
+        );
+        assert_eq!(zone_name.unwrap().as_str(), "mz");
+        for (fstype, upper_dir) in &[
+            ("ext4", "/home/user/proj.mzr/zone/mz/changes"),
+            ("overlay", "/home/user/other.mzr/zone/mz/changes"),
+            ("overlay", "/home/user/proj.mzr/zone/mz/ovfs-work"),
+            ("overlay", "/upper"),
+        ] {
+            assert!(
+                zone_of_mountinfo(&mzr_dir, &entry(fstype, upper_dir)).is_none(),
+                "{} {}",
+                fstype,
+                upper_dir
+            );
+        }
+        // Directories without a sibling mzr directory, or which aren't mount
+        // points, aren't zones.
+        let dir = TestDir::new();
+        let work_dir = UserWorkDir::new(&dir.join("proj"));
+        ensure_dir(&work_dir).unwrap();
+        assert!(zone_at_work_dir(&work_dir).unwrap().is_none());
+        ensure_dir(MzrDir::new(&work_dir)).unwrap();
+        assert!(zone_at_work_dir(&work_dir).unwrap().is_none());
+    }
+
+    #[test]
+    fn overlay_mount_renders_equivalent_command() {
+        let dir = TestDir::new();
+        // Colons and commas need escaping within the options, and spaces need
+        // quoting for the shell.
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("my proj:v1,2")));
+        ensure_dir(&mzr_dir).unwrap();
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let zone = test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        let options = MountOptions {
+            redirect_dir: false,
+            ..MountOptions::default()
+        };
+        let overlay = OverlayMount::for_zone(&zone, &options);
+        let mzr = format!("{}/my proj\\:v1\\,2.mzr", dir.path().display());
+        assert_eq!(
+            overlay.options(),
+            format!(
+                "nosuid,nodev,lowerdir={0}/snap/s1,upperdir={0}/zone/mz/changes,\
+                 workdir={0}/zone/mz/ovfs-work",
+                mzr
+            )
+        );
+        assert_eq!(
+            strip_ansi_escapes(&overlay.to_string()),
+            format!(
+                "mount -t overlay -o '{}' overlay '{}/my proj:v1,2.mzr/zone/mz/mount'",
+                overlay.options(),
+                dir.path().display()
+            )
+        );
+        let read_only = OverlayMount::for_zone(
+            &zone,
+            &MountOptions {
+                read_only: true,
+                nodev: false,
+                nosuid: false,
+                ..options
+            },
+        );
+        assert!(read_only.options().starts_with("ro,lowerdir="));
+    }
+
+    #[test]
+    fn selinux_context_is_appended_quoted() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let zone = test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        let context: SelinuxContext = "system_u:object_r:container_file_t:s0:c1,c2"
+            .parse()
+            .unwrap();
+        let options = MountOptions {
+            redirect_dir: false,
+            selinux_context: Some(context.clone()),
+            ..MountOptions::default()
+        };
+        let overlay = OverlayMount::for_zone(&zone, &options);
+        assert!(
+            overlay
+                .options()
+                .ends_with("/ovfs-work,context=\"system_u:object_r:container_file_t:s0:c1,c2\""),
+            "{}",
+            overlay.options()
+        );
+        // The context's colons and commas don't affect the lowerdirs.
+        assert_eq!(overlay.lowerdir_count(), 1);
+        let without_context = OverlayMount::for_zone(
+            &zone,
+            &MountOptions {
+                selinux_context: None,
+                ..options
+            },
+        );
+        assert!(!without_context.options().contains("context="));
+        for invalid in &["", "a b", "a\"b", "a\nb"] {
+            assert!(invalid.parse::<SelinuxContext>().is_err(), "{:?}", invalid);
+        }
+        let parsed: SelinuxContext =
+            serde_json::from_str("\"system_u:object_r:container_file_t:s0:c1,c2\"").unwrap();
+        assert_eq!(parsed, context);
+        assert!(serde_json::from_str::<SelinuxContext>("\"a b\"").is_err());
+    }
+
+    #[test]
+    fn mount_options_map_to_flags() {
+        let defaults = MountOptions::default();
+        assert_eq!(defaults.to_flags(), MsFlags::MS_NODEV | MsFlags::MS_NOSUID);
+        let none = MountOptions {
+            read_only: false,
+            nodev: false,
+            nosuid: false,
+            ..MountOptions::default()
+        };
+        assert_eq!(none.to_flags(), MsFlags::empty());
+        let read_only = MountOptions {
+            read_only: true,
+            ..none.clone()
+        };
+        assert_eq!(read_only.to_flags(), MsFlags::MS_RDONLY);
+        let all = MountOptions {
+            read_only: true,
+            ..MountOptions::default()
+        };
+        assert_eq!(
+            all.to_flags(),
+            MsFlags::MS_RDONLY | MsFlags::MS_NODEV | MsFlags::MS_NOSUID
+        );
+        assert_eq!(flag_options(all.to_flags()), vec!["ro", "nosuid", "nodev"]);
+        assert!(flag_options(none.to_flags()).is_empty());
+    }
+
+    #[test]
+    fn incompat_features_prevent_mounting() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "snap", &[]);
+        let zone = test_zone(&mzr_dir, "zone", &snap_name, &[]);
+        let work_dir = &zone.ovfs_work_dir;
+        assert!(incompat_features(work_dir).unwrap().is_empty());
+        check_incompat_features(work_dir).unwrap();
+        let incompat_dir = work_dir.join("work").join("incompat");
+        ensure_dir(incompat_dir.join("volatile")).unwrap();
+        fs::write(incompat_dir.join("future"), "").unwrap();
+        assert_eq!(
+            incompat_features(work_dir).unwrap(),
+            vec!["future".to_string(), "volatile".to_string()]
+        );
+        let message =
+            strip_ansi_escapes(&check_incompat_features(work_dir).unwrap_err().to_string());
+        assert!(
+            message.contains("incompatible features: future, volatile"),
+            "{}",
+            message
+        );
+        // Cleaning the work directory keeps the records.
+        ensure_dir(work_dir.join("work").join("stale")).unwrap();
+        clean_work_dir(work_dir).unwrap();
+        assert_eq!(
+            list_dir_names(work_dir.join("work")).unwrap(),
+            vec!["incompat"]
+        );
+        assert_eq!(incompat_features(work_dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn zone_dirs_are_refused_on_overlayfs() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "snap", &[("file", "")]);
+        let zone = test_zone(&mzr_dir, "zone", &snap_name, &[]);
+        assert!(!is_on_overlayfs(&zone.ovfs_changes_dir).unwrap());
+        check_not_on_overlayfs(&zone.ovfs_changes_dir).unwrap();
+        assert!(is_on_overlayfs(&dir.join("missing")).is_err());
+        // Mounting overlayfs within a user namespace isn't permitted
+        // everywhere, so the zone is only mounted when running as root.
+        if !Uid::current().is_root() {
+            return;
+        }
+        let mount_dir = &zone.ovfs_mount_dir;
+        let child_pid = namespaces::with_unshared_mount(|| {
+            OverlayMount::for_zone(&zone, &MountOptions::default())
+                .mount(&RetryPolicy::default())?;
+            if !is_on_overlayfs(mount_dir)? {
+                bail!("Mounted zone wasn't detected as overlayfs");
+            }
+            if check_not_on_overlayfs(&mount_dir.join("file")).is_ok() {
+                bail!("Zone directory within a mounted zone wasn't refused");
+            }
+            Ok(())
+        })
+        .unwrap();
+        namespaces::wait_for_child(child_pid).unwrap();
+    }
+
+    #[test]
+    fn zone_mounts_are_found_across_projects() {
+        let mounts = parse_mounts(
+            "/dev/sda1 / ext4 rw,relatime 0 0\n\
+             overlay /home/user/a overlay rw,lowerdir=/home/user/a.mzr/snap/s1,upperdir=/home/user/a.mzr/zone/z1/changes,workdir=/home/user/a.mzr/zone/z1/ovfs-work 0 0\n\
+             overlay /home/user/my\\040b overlay rw,lowerdir=/l,upperdir=/home/user/my\\040b.mzr/zone/z2/changes,workdir=/w 0 0\n\
+             overlay /var/lib/docker/merged overlay rw,lowerdir=/l,upperdir=/var/lib/docker/diff,workdir=/w 0 0\n\
+             overlay /home/user/c overlay rw,lowerdir=/l,upperdir=/home/user/c.mzr/zone/z3/ovfs-work,workdir=/w 0 0\n\
+             tmpfs /home/user/d.mzr/zone/z4/changes tmpfs rw 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(mounts.len(), 6);
+        assert_eq!(mounts[2].target, PathBuf::from("/home/user/my b"));
+        let zone_mounts: Vec<(PathBuf, String)> = find_zone_mounts(&mounts)
+            .into_iter()
+            .map(|(mzr_dir, zone_name)| {
+                (
+                    mzr_dir.as_path().to_path_buf(),
+                    zone_name.as_str().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            zone_mounts,
+            vec![
+                (PathBuf::from("/home/user/a.mzr"), "z1".to_string()),
+                (PathBuf::from("/home/user/my b.mzr"), "z2".to_string()),
+            ]
+        );
+        assert!(parse_mounts("overlay /home/user/a overlay\n").is_err());
+    }
+
+    #[test]
+    fn unmount_all_continues_past_busy_zones() {
+        // Overlays are only mounted when running as root, as in
+        // `zone_dirs_are_refused_on_overlayfs`.
+        if !Uid::current().is_root() {
+            return;
+        }
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "snap", &[("file", "")]);
+        let zones: Vec<Zone> = ["z1", "z2", "busy"]
+            .iter()
+            .map(|name| test_zone(&mzr_dir, name, &snap_name, &[]))
+            .collect();
+        let other_mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("other")));
+        ensure_dir(&other_mzr_dir).unwrap();
+        test_snapshot(&other_mzr_dir, "snap", &[]);
+        let other_zone = test_zone(&other_mzr_dir, "other", &snap_name, &[]);
+        let zone_names = |results: &[(ZoneName, Result<Unmounted, Error>)]| {
+            results
+                .iter()
+                .map(|(zone_name, result)| {
+                    let result = result.as_ref().map_err(|_| ());
+                    (zone_name.as_str().to_string(), result.copied())
+                })
+                .collect::<Vec<_>>()
+        };
+        let child_pid = namespaces::with_unshared_mount(|| {
+            for zone in zones.iter().chain(Some(&other_zone)) {
+                OverlayMount::for_zone(zone, &MountOptions::default())
+                    .mount(&RetryPolicy::default())?;
+            }
+            let _busy_file = File::open(zones[2].ovfs_mount_dir.join("file"))?;
+            let results = unmount_all(&mzr_dir, false, true)?;
+            let expected = vec![
+                ("busy".to_string(), Err(())),
+                ("z2".to_string(), Ok(Unmounted::Unmounted)),
+                ("z1".to_string(), Ok(Unmounted::Unmounted)),
+            ];
+            if zone_names(&results) != expected {
+                bail!("Unexpected results when not lazy: {:?}", results);
+            }
+            let results = unmount_all(&mzr_dir, true, true)?;
+            if zone_names(&results) != vec![("busy".to_string(), Ok(Unmounted::Detached))] {
+                bail!("Unexpected results when lazy: {:?}", results);
+            }
+            // Zones of other projects are left mounted.
+            let remaining = find_zone_mounts(&read_mounts()?);
+            match remaining.as_slice() {
+                [(remaining_mzr_dir, zone_name)]
+                    if remaining_mzr_dir.as_path() == other_mzr_dir.as_path()
+                        && zone_name.as_str() == "other" => {}
+                _ => bail!("Unexpected remaining zone mounts: {:?}", remaining),
+            }
+            Ok(())
+        })
+        .unwrap();
+        namespaces::wait_for_child(child_pid).unwrap();
+    }
+
+    #[test]
+    fn waits_until_zone_is_mounted() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "snap", &[]);
+        let zone = test_zone(&mzr_dir, "zone", &snap_name, &[]);
+        let start = Instant::now();
+        let err = wait_until_mounted(&zone, Duration::from_millis(100)).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let message = strip_ansi_escapes(&err.to_string());
+        assert!(
+            message.contains("waiting for zone zone to be mounted"),
+            "{}",
+            message
+        );
+        // Overlays are only mounted when running as root, as in
+        // `zone_dirs_are_refused_on_overlayfs`.
+        if !Uid::current().is_root() {
+            return;
+        }
+        let child_pid = namespaces::with_unshared_mount(|| {
+            let overlay = OverlayMount::for_zone(&zone, &MountOptions::default());
+            let mounter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                overlay.mount(&RetryPolicy::default())
+            });
+            wait_until_mounted(&zone, Duration::from_secs(10))?;
+            mounter.join().unwrap()
+        })
+        .unwrap();
+        namespaces::wait_for_child(child_pid).unwrap();
+    }
+
+    #[test]
+    fn escaped_commas_stay_within_option_values() {
+        let options =
+            "rw,lowerdir=/l\\134\\0541:/l2,upperdir=/my\\040proj\\134\\054v2.mzr/zone/mz/\
+                       changes,workdir=/w\\134\\134,uuid=on";
+        assert_eq!(
+            overlay_dir_option(options, "upperdir").unwrap(),
+            PathBuf::from("/my proj,v2.mzr/zone/mz/changes")
+        );
+        assert_eq!(
+            overlay_dir_option(options, "workdir").unwrap(),
+            PathBuf::from("/w\\")
+        );
+        assert!(overlay_dir_option(options, "dir").is_none());
+        assert!(overlay_dir_option(options, "rw").is_none());
+        let mounts =
+            parse_mounts(&format!("overlay /my\\040proj overlay {} 0 0\n", options)).unwrap();
+        let (mzr_dir, zone_name) = zone_of_mount(&mounts[0]).unwrap();
+        assert_eq!(mzr_dir.as_path(), Path::new("/my proj,v2.mzr"));
+        assert_eq!(zone_name.as_str(), "mz");
+    }
+
+    #[test]
+    fn work_dirs_of_active_mounts_are_not_cleaned() {
+        // Overlays are only mounted when running as root, as in
+        // `zone_dirs_are_refused_on_overlayfs`.
+        if !Uid::current().is_root() {
+            return;
+        }
+        let dir = TestDir::new();
+        // The kernel escapes commas within the work directory's path.
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("my proj,v2")));
+        ensure_dir(&mzr_dir).unwrap();
+        let snap_name = test_snapshot(&mzr_dir, "snap", &[]);
+        let zone = test_zone(&mzr_dir, "zone", &snap_name, &[]);
+        let work_dir = &zone.ovfs_work_dir;
+        let stale_file = work_dir.join("work").join("stale");
+        let child_pid = namespaces::with_unshared_mount(|| {
+            OverlayMount::for_zone(&zone, &MountOptions::default())
+                .mount(&RetryPolicy::default())?;
+            if !is_work_dir_in_use(work_dir)? {
+                bail!("Work directory of mounted zone wasn't detected as in use");
+            }
+            fs::write(&stale_file, "")?;
+            if clean_work_dir(work_dir).is_ok() || !stale_file.exists() {
+                bail!("Work directory of mounted zone was cleaned");
+            }
+            unmount(&zone.ovfs_mount_dir)?;
+            if is_work_dir_in_use(work_dir)? {
+                bail!("Work directory of unmounted zone was detected as in use");
+            }
+            Ok(())
+        })
+        .unwrap();
+        namespaces::wait_for_child(child_pid).unwrap();
+        ensure_dir(work_dir.join("work").join("incompat")).unwrap();
+        clean_work_dir(work_dir).unwrap();
+        assert!(!stale_file.exists());
+        assert_eq!(
+            list_dir_names(work_dir.join("work")).unwrap(),
+            vec!["incompat"]
+        );
+    }
+}
@@ -1,18 +1,23 @@
 use crate::colors::*;
 use crate::paths::*;
-use crate::utils::parse_pid_file;
+use crate::utils::{parse_pid_file, with_timeout};
 use failure::{Error, ResultExt};
 use ipc_channel::ipc::{self, IpcOneShotServer, IpcReceiver, IpcSender};
 use nix::errno::Errno;
 use nix::sched::{setns, unshare, CloneFlags};
-use nix::sys::wait::{waitpid, WaitStatus::*};
-use nix::unistd::{Gid, Pid, Uid};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus, WaitStatus::*};
+use nix::unistd::{close, Gid, Pid, Uid};
 use nix::Error::Sys;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
-use std::fs::{File, OpenOptions};
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::os::unix::io::IntoRawFd;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::{thread, time};
 use yansi::Paint;
 
@@ -31,17 +36,7 @@ where
     let clone_flags = CloneFlags::CLONE_NEWNS;
     let child_stack: &mut [u8; STACK_SIZE] = &mut [0; STACK_SIZE];
     let child_pid = ::nix::sched::clone(
-        Box::new(|| {
-            match child_fn() {
-                // Exited successfully.
-                Ok(()) => 0,
-                Err(err) => {
-                    println!();
-                    println!("{} {}", color_err(&"mzr child error:"), err);
-                    1
-                }
-            }
-        }),
+        Box::new(|| exit_child(child_fn())),
         child_stack,
         clone_flags,
         None,
@@ -50,63 +45,348 @@ where
     Ok(child_pid)
 }
 
-pub fn with_unshared_user_and_mount<F, G>(
-    mut write_maps_fn: F,
-    mut child_fn: G,
-) -> Result<Pid, Error>
+/// Exits a cloned child process, reporting its error if it failed. Returning
+/// from the clone callback would only exit the child's main thread, and if the
+/// child has started other threads, such as the ones used by `with_timeout`,
+/// the last of those to exit would exit the process with code 0, hiding the
+/// failure.
+fn exit_child(result: Result<(), Error>) -> isize {
+    match result {
+        // Exited successfully.
+        Ok(()) => process::exit(0),
+        Err(err) => {
+            println!();
+            println!("{} {}", color_err(&"mzr child error:"), err);
+            process::exit(1)
+        }
+    }
+}
+
+pub fn with_unshared_user_and_mount<F, G>(write_maps_fn: F, child_fn: G) -> Result<Pid, Error>
 where
     F: FnMut(Pid) -> Result<(), Error>,
     G: FnMut() -> Result<(), Error>,
 {
-    // clone with unshared mount and user namespaces.
-    let clone_flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER;
-    let child_stack: &mut [u8; STACK_SIZE] = &mut [0; STACK_SIZE];
-    let (parent_server, parent_name) = init_ipc()?;
-    let child_pid = ::nix::sched::clone(
-        Box::new(|| {
-            // Wait for ready message that UID mapping has been setup before
-            // running child_fn. Otherwise, mounting will fail. Also, if the
-            // child process attempts to exec before the UID mapping has been
-            // setup, then the child will lose its capabilities (see
-            // "capabilities(7)" man page).
-            match recv_ready(&parent_name).and(child_fn()) {
-                // Exited successfully.
-                Ok(()) => 0,
-                Err(err) => {
-                    println!();
-                    println!("{} {}", color_err(&"mzr child error:"), err);
-                    1
-                }
-            }
-        }),
-        child_stack,
-        clone_flags,
-        None,
+    UnsharedUserAndMount::new().spawn(write_maps_fn, child_fn)
+}
+
+/// Like `with_unshared_user_and_mount`, but the child function yields a value,
+/// which is sent back to the parent. See
+/// `UnsharedUserAndMount::spawn_with_result`.
+pub fn with_unshared_user_and_mount_result<F, G, T>(
+    write_maps_fn: F,
+    child_fn: G,
+) -> Result<T, Error>
+where
+    F: FnMut(Pid) -> Result<(), Error>,
+    G: FnMut() -> Result<T, Error>,
+    T: Serialize + DeserializeOwned,
+{
+    UnsharedUserAndMount::new().spawn_with_result(write_maps_fn, child_fn)
+}
+
+/// Builder for processes with unshared mount and user namespaces, for when the
+/// defaults used by `with_unshared_user_and_mount` aren't suitable.
+pub struct UnsharedUserAndMount<'a> {
+    stack_size: usize,
+    extra_clone_flags: CloneFlags,
+    handshake_timeout: time::Duration,
+    teardown: Option<Box<dyn FnMut() -> Result<(), Error> + 'a>>,
+}
+
+impl<'a> Default for UnsharedUserAndMount<'a> {
+    fn default() -> Self {
+        UnsharedUserAndMount::new()
+    }
+}
+
+impl<'a> UnsharedUserAndMount<'a> {
+    pub fn new() -> Self {
+        UnsharedUserAndMount {
+            stack_size: STACK_SIZE,
+            extra_clone_flags: CloneFlags::empty(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            teardown: None,
+        }
+    }
+
+    /// Size of the stack allocated for the child process.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// Flags to pass to `clone`, in addition to `CLONE_NEWNS` and
+    /// `CLONE_NEWUSER`.
+    pub fn extra_clone_flags(mut self, flags: CloneFlags) -> Self {
+        self.extra_clone_flags = flags;
+        self
+    }
+
+    /// How long to wait for the child process to connect, after the user
+    /// mapping has been written.
+    pub fn handshake_timeout(mut self, timeout: time::Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Function to run in the child process after the child function returns,
+    /// whether or not it succeeded.
+    pub fn teardown<H>(mut self, teardown_fn: H) -> Self
+    where
+        H: FnMut() -> Result<(), Error> + 'a,
+    {
+        self.teardown = Some(Box::new(teardown_fn));
+        self
+    }
+
+    pub fn spawn<F, G>(self, mut write_maps_fn: F, mut child_fn: G) -> Result<Pid, Error>
+    where
+        F: FnMut(Pid) -> Result<(), Error>,
+        G: FnMut() -> Result<(), Error>,
+    {
+        let UnsharedUserAndMount {
+            stack_size,
+            extra_clone_flags,
+            handshake_timeout,
+            mut teardown,
+        } = self;
+        // clone with unshared mount and user namespaces.
+        let clone_flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER | extra_clone_flags;
+        let mut child_stack = vec![0; stack_size];
+        let (parent_server, parent_name) = init_ipc()?;
+        let child_pid = ::nix::sched::clone(
+            Box::new(|| {
+                // Wait for ready message that UID mapping has been setup before
+                // running child_fn. Otherwise, mounting will fail. Also, if the
+                // child process attempts to exec before the UID mapping has been
+                // setup, then the child will lose its capabilities (see
+                // "capabilities(7)" man page).
+                let result = recv_ready(&parent_name).and_then(|()| {
+                    check_user_mapping_active()?;
+                    let child_result = child_fn();
+                    let teardown_result = match &mut teardown {
+                        None => Ok(()),
+                        Some(teardown_fn) => teardown_fn(),
+                    };
+                    child_result.and(teardown_result)
+                });
+                exit_child(result)
+            }),
+            &mut child_stack,
+            clone_flags,
+            None,
+        )
+        .context("Error while cloning mzr child with unshared user and mount namespaces.")?;
+        write_maps_fn(child_pid)?;
+        send_ready(parent_server, &parent_name, child_pid, handshake_timeout)?;
+        Ok(child_pid)
+    }
+
+    /// Like `spawn`, but the child function yields a value, which is sent back
+    /// to the parent process over IPC. This allows work done within the
+    /// namespaces, such as inspecting a mounted zone, to return data. Waits
+    /// for the child to exit, and yields the value if it succeeded.
+    pub fn spawn_with_result<F, G, T>(self, write_maps_fn: F, mut child_fn: G) -> Result<T, Error>
+    where
+        F: FnMut(Pid) -> Result<(), Error>,
+        G: FnMut() -> Result<T, Error>,
+        T: Serialize + DeserializeOwned,
+    {
+        let (result_tx, result_rx): (IpcSender<T>, IpcReceiver<T>) =
+            wrap_ipc(ipc::channel().map_err(Error::from))?;
+        let child_pid = self.spawn(write_maps_fn, || {
+            let value = child_fn()?;
+            wrap_ipc(result_tx.send(value).map_err(Error::from))
+        })?;
+        // Only the child's copy of the sender remains, so receiving fails
+        // rather than blocking if the child exits without sending. Receiving
+        // happens before waiting, so that a large value can't leave the child
+        // blocked on sending it.
+        drop(result_tx);
+        let received = result_rx.recv();
+        // If the child failed, then its exit status is the more useful error.
+        wait_for_child(child_pid)?;
+        wrap_ipc(received.map_err(Error::from))
+    }
+}
+
+/// The child process exited before it connected to the parent process. This
+/// can happen if it gets killed immediately, such as by the OOM killer.
+#[derive(Fail, Debug)]
+#[fail(display = "mzr child process {} died before initialization.", _0)]
+pub struct ChildDiedBeforeInit(pub Pid);
+
+/// The child process did not connect to the parent process in time. It gets
+/// killed, since it may be partway through initializing.
+#[derive(Fail, Debug)]
+#[fail(
+    display = "Timed out waiting for mzr child process {} to connect to parent.",
+    _0
+)]
+pub struct HandshakeTimeout(pub Pid);
+
+/// Waits for a child process created by `with_unshared_mount` or
+/// `UnsharedUserAndMount::spawn` to exit. Yields an error if it exited with a
+/// non-zero code or was killed by a signal, so that callers can detect
+/// failures.
+pub fn wait_for_child(child_pid: Pid) -> Result<(), Error> {
+    // Note that __WALL is needed, because the child was cloned without
+    // specifying a termination signal.
+    let status = waitpid(child_pid, Some(WaitPidFlag::__WALL)).context(format_err!(
+        "Error while waiting for mzr child process {} to exit.",
+        child_pid
+    ))?;
+    exit_status_result(child_pid, status)
+}
+
+/// Like `wait_for_child`, but yields a `Timeout` error if the child doesn't
+/// exit in time. The child is left running in that case.
+pub fn wait_for_child_timeout(child_pid: Pid, timeout: time::Duration) -> Result<(), Error> {
+    with_timeout(
+        &format!("waiting for mzr child process {} to exit", child_pid),
+        timeout,
+        move || wait_for_child(child_pid),
     )
-    .context("Error while cloning mzr child with unshared user and mount namespaces.")?;
-    write_maps_fn(child_pid)?;
-    send_ready(parent_server)?;
-    Ok(child_pid)
 }
 
+/// Like `wait_for_child`, but doesn't block, so that many children can be
+/// managed at once. Yields `None` if the child is still running. Otherwise,
+/// the child gets reaped and the result is the same as `wait_for_child`.
+pub fn try_wait_for_child(child_pid: Pid) -> Option<Result<(), Error>> {
+    match waitpid(child_pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+        Ok(StillAlive) => None,
+        Ok(status) => Some(exit_status_result(child_pid, status)),
+        Err(err) => Some(
+            Err(err)
+                .context(format_err!(
+                    "Error while checking whether mzr child process {} has exited.",
+                    child_pid
+                ))
+                .map_err(Error::from),
+        ),
+    }
+}
+
+fn exit_status_result(child_pid: Pid, status: WaitStatus) -> Result<(), Error> {
+    match status {
+        Exited(_, 0) => Ok(()),
+        Exited(_, code) => Err(ChildExited(child_pid, code).into()),
+        Signaled(_, signal, core_dumped) => {
+            println!(
+                "mzr child process {} was killed by signal {:?}{}",
+                child_pid,
+                signal,
+                if core_dumped { " (core dumped)" } else { "" }
+            );
+            Err(ChildSignaled(child_pid, signal).into())
+        }
+        _ => bail!(
+            "Unexpected status for mzr child process {}: {:?}",
+            child_pid,
+            status
+        ),
+    }
+}
+
+/// The child process exited with a non-zero exit code.
+#[derive(Fail, Debug)]
+#[fail(display = "mzr child process {} exited with code {}.", _0, _1)]
+pub struct ChildExited(pub Pid, pub i32);
+
+/// The child process was killed by a signal.
+#[derive(Fail, Debug)]
+#[fail(display = "mzr child process {} was killed by signal {:?}.", _0, _1)]
+pub struct ChildSignaled(pub Pid, pub Signal);
+
 // IPC helper functions
 
 fn init_ipc() -> Result<(IpcOneShotServer<IpcSender<Ready>>, String), Error> {
     wrap_ipc(IpcOneShotServer::new().map_err(|x| x.into()))
 }
 
+/// Default for how long to wait for the child process to connect, after the
+/// user mapping has been written.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How often to check whether the child process has died, while waiting for it
+/// to connect.
+const HANDSHAKE_POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+fn send_ready(
+    parent_server: IpcOneShotServer<IpcSender<Ready>>,
+    parent_name: &str,
+    child_pid: Pid,
+    timeout: time::Duration,
+) -> Result<(), Error> {
+    // Accepting blocks until the child connects, so it's done on another
+    // thread. Meanwhile, this thread checks that the child is still alive, so
+    // that a child which dies early doesn't cause a hang.
+    let (result_tx, result_rx) = mpsc::channel();
+    thread::spawn(move || {
+        // Ignore failure to send, which means that the waiting was abandoned.
+        let _ = result_tx.send(accept_and_send_ready(parent_server));
+    });
+    let start_time = time::Instant::now();
+    loop {
+        match result_rx.recv_timeout(HANDSHAKE_POLL_INTERVAL) {
+            Ok(result) => return wrap_ipc(result),
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!("Unexpected error: handshake thread exited without a result.")
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        // Note that __WALL is needed, because the child was cloned without
+        // specifying a termination signal.
+        match waitpid(child_pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+            Ok(Exited(_, _)) | Ok(Signaled(_, _, _)) | Err(Sys(Errno::ECHILD)) => {
+                abandon_handshake(parent_name);
+                return Err(ChildDiedBeforeInit(child_pid).into());
+            }
+            _ => {}
+        }
+        if start_time.elapsed() > timeout {
+            abandon_handshake(parent_name);
+            // The child may be partway through initializing, so it's killed
+            // rather than left running.
+            match kill(child_pid, Signal::SIGKILL) {
+                Ok(()) | Err(Sys(Errno::ESRCH)) => {}
+                Err(e) => Err(e).context(format_err!(
+                    "Failed to kill mzr child process {} after handshake timeout",
+                    child_pid
+                ))?,
+            }
+            // Being killed makes this yield an error, which isn't of interest.
+            let _ = wait_for_child(child_pid);
+            return Err(HandshakeTimeout(child_pid).into());
+        }
+    }
+}
+
+fn accept_and_send_ready(parent_server: IpcOneShotServer<IpcSender<Ready>>) -> Result<(), Error> {
+    let (_, tx1): (_, IpcSender<Ready>) = parent_server.accept()?;
+    tx1.send(Ready)?;
+    Ok(())
+}
+
+/// Connects to the handshake server in place of the child, so that the
+/// thread blocked on accepting exits and the server socket gets closed.
+fn abandon_handshake(parent_name: &str) {
+    // Ignore failure, which means that the server is already gone.
+    let _ = connect_in_place_of_child(parent_name);
+}
+
+fn connect_in_place_of_child(parent_name: &str) -> Result<(), Error> {
+    let (tx1, _rx1): (IpcSender<Ready>, IpcReceiver<Ready>) = ipc::channel()?;
+    let tx0 = IpcSender::connect(parent_name.to_string())?;
+    tx0.send(tx1)?;
+    Ok(())
+}
+
 // TODO(cleanup): Made up this idiom of using an argumentless closure to still
 // use the "?" error plumbing, while having a helper that modifies the error
 // contents.  Is there a cleaner way to do something like this?
 
-fn send_ready(parent_server: IpcOneShotServer<IpcSender<Ready>>) -> Result<(), Error> {
-    wrap_ipc({
-        let (_, tx1): (_, IpcSender<Ready>) = parent_server.accept()?;
-        tx1.send(Ready)?;
-        Ok(())
-    })
-}
-
 fn recv_ready(parent_name: &str) -> Result<(), Error> {
     wrap_ipc({
         // Establish a connection with the parent.
@@ -122,16 +402,95 @@ fn wrap_ipc<T>(x: Result<T, Error>) -> Result<T, Error> {
     Ok(x.context("Error encountered in interprocess communication mechanism.")?)
 }
 
+/// Which user and group the current user is mapped to within user namespaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserMapping {
+    /// Map the current user and group to root, uid and gid 0.
+    Root,
+    /// Map the current user and group to themselves, for builds which
+    /// misbehave when run as root. Note that capabilities within the namespace
+    /// are still retained until exec.
+    Current,
+}
+
+impl UserMapping {
+    pub fn inside_user(self, user: Uid) -> Uid {
+        match self {
+            UserMapping::Root => Uid::from_raw(0),
+            UserMapping::Current => user,
+        }
+    }
+
+    pub fn inside_group(self, group: Gid) -> Gid {
+        match self {
+            UserMapping::Root => Gid::from_raw(0),
+            UserMapping::Current => group,
+        }
+    }
+}
+
+/// Maps the user and group into the child's user namespace, according to the
+/// mapping.
+pub fn map_user_into(
+    child_process: Pid,
+    user: Uid,
+    group: Gid,
+    mapping: UserMapping,
+) -> Result<(), Error> {
+    map_one_user_and_group(
+        child_process,
+        user,
+        mapping.inside_user(user),
+        group,
+        mapping.inside_group(group),
+    )
+}
+
+/// Reverses `map_user_into`, for a child created within a namespace which
+/// uses the mapping, so that the child is back to the original user and group.
+pub fn map_back_to_user(
+    child_process: Pid,
+    user: Uid,
+    group: Gid,
+    mapping: UserMapping,
+) -> Result<(), Error> {
+    map_one_user_and_group(
+        child_process,
+        mapping.inside_user(user),
+        user,
+        mapping.inside_group(group),
+        group,
+    )
+}
+
+/// Checks that the uid and gid mappings of the current process's user namespace
+/// have been written. This guards the invariant that the child function only
+/// runs after the parent has written the mappings - otherwise mounts fail, and
+/// exec drops the child's capabilities.
+fn check_user_mapping_active() -> Result<(), Error> {
+    for file_name in ["uid_map", "gid_map"].iter() {
+        let path = format!("/proc/self/{}", file_name);
+        let contents = fs::read_to_string(&path).context(format_err!(
+            "Failed to read {} to check that the user namespace is mapped",
+            color_file(&path)
+        ))?;
+        if contents.trim().is_empty() {
+            bail!(
+                "Unexpected error: {} is empty, so the user namespace mapping was not written \
+                 before the mzr child process started.",
+                color_file(&path)
+            );
+        }
+    }
+    Ok(())
+}
+
 pub fn map_user_to_root(child_process: Pid, user: Uid, group: Gid) -> Result<(), Error> {
-    let root_user = Uid::from_raw(0);
-    let root_group = Gid::from_raw(0);
-    map_one_user_and_group(child_process, user, root_user, group, root_group)
+    map_user_into(child_process, user, group, UserMapping::Root)
 }
 
 pub fn map_root_to_user(child_process: Pid, user: Uid, group: Gid) -> Result<(), Error> {
-    let root_user = Uid::from_raw(0);
-    let root_group = Gid::from_raw(0);
-    map_one_user_and_group(child_process, root_user, user, root_group, group)
+    map_back_to_user(child_process, user, group, UserMapping::Root)
 }
 
 pub fn map_one_user_and_group(
@@ -143,25 +502,54 @@ pub fn map_one_user_and_group(
 ) -> Result<(), Error> {
     let result: Result<(), Error> = try {
         // Map current user to root within the user namespace.
-        let uid_map_path = format!("/proc/{}/uid_map", child_process);
-        let mut uid_map_file = OpenOptions::new().write(true).open(uid_map_path)?;
-        uid_map_file.write_all(format!("{} {} 1\n", target_user, source_user).as_bytes())?;
+        write_proc_file(
+            child_process,
+            "uid_map",
+            id_map_line(target_user, source_user).as_bytes(),
+        )?;
 
         // Disable usage of setgroups system call, allowing gid_map to
         // be written.
-        let set_groups_path = format!("/proc/{}/setgroups", child_process);
-        let mut set_groups_file = OpenOptions::new().write(true).open(set_groups_path)?;
-        set_groups_file.write_all(b"deny")?;
+        write_proc_file(child_process, "setgroups", b"deny")?;
 
         // Map current group to root within the user namespace.
-        let gid_map_path = format!("/proc/{}/gid_map", child_process);
-        let mut gid_map_file = OpenOptions::new().write(true).open(gid_map_path)?;
-        gid_map_file.write_all(format!("{} {} 1\n", target_group, source_group).as_bytes())?;
+        write_proc_file(
+            child_process,
+            "gid_map",
+            id_map_line(target_group, source_group).as_bytes(),
+        )?;
     };
     result.context("Error encountered while setting up child process user namespace.")?;
     Ok(())
 }
 
+/// Writes one of the process's `/proc` files which configure its user
+/// namespace. The kernel validates these when they are written, so errors
+/// from each step are reported along with the file's path. The file is closed
+/// explicitly, since dropping it would ignore errors.
+fn write_proc_file(child_process: Pid, file_name: &str, contents: &[u8]) -> Result<(), Error> {
+    let path = format!("/proc/{}/{}", child_process, file_name);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .context(format_err!("Failed to open {}", path))?;
+    file.write_all(contents).context(format_err!(
+        "Failed to write {:?} to {}",
+        String::from_utf8_lossy(contents),
+        path
+    ))?;
+    file.flush()
+        .context(format_err!("Failed to flush {}", path))?;
+    close(file.into_raw_fd()).context(format_err!("Failed to close {}", path))?;
+    Ok(())
+}
+
+/// Line of a `uid_map` or `gid_map` file which maps a single id. See
+/// `user_namespaces(7)`.
+pub fn id_map_line<T: Display>(inside_id: T, outside_id: T) -> String {
+    format!("{} {} 1\n", inside_id, outside_id)
+}
+
 /*
 // TODO(cleanup)
 fn wrap_user_mapping<T>(x: Result<T, Error>) -> Result<T, Error> {
@@ -171,7 +559,7 @@ fn wrap_user_mapping<T>(x: Result<T, Error>) -> Result<T, Error> {
 
 pub fn enter_daemon_space(mzr_dir: &MzrDir) -> Result<(), Error> {
     enter_user_and_mount(parse_pid_file(DaemonPidFile::new(&DaemonDir::new(
-        &mzr_dir,
+        mzr_dir,
     )))?)
 }
 
@@ -180,6 +568,15 @@ pub fn unshare_mount() -> Result<(), Error> {
     Ok(())
 }
 
+/// Unshares the user namespace of the current process, mapping its current
+/// user and group to the specified user and group within the new namespace.
+pub fn unshare_user_as(user: Uid, group: Gid) -> Result<(), Error> {
+    let current_user = Uid::current();
+    let current_group = Gid::current();
+    unshare(CloneFlags::CLONE_NEWUSER)?;
+    map_one_user_and_group(Pid::this(), current_user, user, current_group, group)
+}
+
 pub fn enter_mount(pid: Pid) -> Result<(), Error> {
     let proc_dir = ProcDir::new(pid);
     enter_ns(
@@ -204,7 +601,7 @@ fn enter_ns(ns_path: &ProcNamespaceFile, flags: CloneFlags) -> Result<(), Error>
     // TODO(cleanup): make daemon_cmd a constant.
     let daemon_cmd_str = String::from("mzr daemon");
     let daemon_cmd = color_cmd(&daemon_cmd_str);
-    let ns_file = File::open(&ns_path).context(format_err!(
+    let ns_file = File::open(ns_path).context(format_err!(
         "Is {} running? Encountered unexpected error opening {}.",
         daemon_cmd,
         &ns_path
@@ -212,3 +609,236 @@ fn enter_ns(ns_path: &ProcNamespaceFile, flags: CloneFlags) -> Result<(), Error>
     setns(ns_file.into_raw_fd(), flags)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use crate::utils::Timeout;
+    use std::io;
+    use std::process::Command;
+
+    #[test]
+    fn abandon_handshake_unblocks_accept() {
+        let (parent_server, parent_name) = init_ipc().unwrap();
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = result_tx.send(accept_and_send_ready(parent_server));
+        });
+        abandon_handshake(&parent_name);
+        assert!(result_rx
+            .recv_timeout(time::Duration::from_secs(10))
+            .is_ok());
+    }
+    #[test]
+    fn write_proc_file_errors_name_the_file() {
+        // Pids are limited to less than 2^22, so this process doesn't exist.
+        let missing = Pid::from_raw(i32::MAX);
+        let err = write_proc_file(missing, "uid_map", b"0 1000 1\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Failed to open /proc/{}/uid_map", missing)
+        );
+        let cause = err.find_root_cause().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(cause.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Starts a child process which runs a shell command. Tests reap it via
+    /// its pid, such as with `wait_for_child`.
+    #[allow(clippy::zombie_processes)]
+    fn spawn_shell(cmd: &str) -> Pid {
+        let child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
+        Pid::from_raw(child.id() as i32)
+    }
+
+    /// Starts a child process which never connects to the handshake server.
+    fn spawn_unconnected_child() -> Pid {
+        spawn_shell("exec sleep 10")
+    }
+
+    #[test]
+    fn wait_for_child_maps_exit_status() {
+        assert!(wait_for_child(spawn_shell("exit 0")).is_ok());
+        let child_pid = spawn_shell("exit 3");
+        let err = wait_for_child(child_pid).unwrap_err();
+        match err.downcast_ref::<ChildExited>() {
+            Some(ChildExited(pid, 3)) => assert_eq!(*pid, child_pid),
+            _ => panic!("unexpected error: {}", err),
+        }
+        let child_pid = spawn_shell("kill -TERM $$");
+        let err = wait_for_child(child_pid).unwrap_err();
+        match err.downcast_ref::<ChildSignaled>() {
+            Some(ChildSignaled(pid, Signal::SIGTERM)) => assert_eq!(*pid, child_pid),
+            _ => panic!("unexpected error: {}", err),
+        }
+        // The child has already been reaped.
+        assert!(wait_for_child(child_pid).is_err());
+    }
+
+    #[test]
+    fn exit_status_result_maps_statuses() {
+        let pid = Pid::from_raw(1234);
+        assert!(exit_status_result(pid, Exited(pid, 0)).is_ok());
+        let err = exit_status_result(pid, Exited(pid, 1)).unwrap_err();
+        assert!(err.downcast_ref::<ChildExited>().is_some());
+        let err = exit_status_result(pid, Signaled(pid, Signal::SIGSEGV, true)).unwrap_err();
+        assert!(err.downcast_ref::<ChildSignaled>().is_some());
+        let err = exit_status_result(pid, Stopped(pid, Signal::SIGSTOP)).unwrap_err();
+        assert!(err.downcast_ref::<ChildExited>().is_none());
+        assert!(err.downcast_ref::<ChildSignaled>().is_none());
+    }
+
+    #[test]
+    fn try_wait_for_child_polls_without_blocking() {
+        let child_pid = spawn_unconnected_child();
+        assert!(try_wait_for_child(child_pid).is_none());
+        kill(child_pid, Signal::SIGKILL).unwrap();
+        let result = loop {
+            if let Some(result) = try_wait_for_child(child_pid) {
+                break result;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        };
+        let err = result.unwrap_err();
+        assert!(err.downcast_ref::<ChildSignaled>().is_some());
+        // The child has been reaped, so polling it again fails.
+        assert!(try_wait_for_child(child_pid).unwrap().is_err());
+    }
+
+    #[test]
+    fn wait_for_child_timeout_leaves_child_running() {
+        let child_pid = spawn_unconnected_child();
+        let err = wait_for_child_timeout(child_pid, time::Duration::from_millis(100)).unwrap_err();
+        assert!(err.downcast_ref::<Timeout>().is_some());
+        assert_eq!(kill(child_pid, None), Ok(()));
+        kill(child_pid, Signal::SIGKILL).unwrap();
+    }
+
+    #[test]
+    fn child_killed_immediately_died_before_init() {
+        let (parent_server, parent_name) = init_ipc().unwrap();
+        let child_pid = spawn_unconnected_child();
+        kill(child_pid, Signal::SIGKILL).unwrap();
+        let err =
+            send_ready(parent_server, &parent_name, child_pid, HANDSHAKE_TIMEOUT).unwrap_err();
+        assert!(err.downcast_ref::<ChildDiedBeforeInit>().is_some());
+    }
+
+    #[test]
+    fn child_is_killed_after_handshake_timeout() {
+        let (parent_server, parent_name) = init_ipc().unwrap();
+        let child_pid = spawn_unconnected_child();
+        let timeout = time::Duration::from_millis(100);
+        let err = send_ready(parent_server, &parent_name, child_pid, timeout).unwrap_err();
+        assert!(err.downcast_ref::<HandshakeTimeout>().is_some());
+        // The child has been killed and reaped, so it no longer exists.
+        assert_eq!(kill(child_pid, None), Err(Sys(Errno::ESRCH)));
+    }
+
+    #[test]
+    fn builder_configures_child() {
+        let dir = TestDir::new();
+        let marker = dir.join("torn-down");
+        let parent_uts = fs::read_link("/proc/self/ns/uts").unwrap();
+        let user = Uid::current();
+        let group = Gid::current();
+        let spawn = |fail: bool| {
+            let child_pid = UnsharedUserAndMount::new()
+                .stack_size(2 * STACK_SIZE)
+                .extra_clone_flags(CloneFlags::CLONE_NEWUTS)
+                .handshake_timeout(time::Duration::from_secs(30))
+                .teardown(|| Ok(fs::write(&marker, "")?))
+                .spawn(
+                    |child_process| map_user_to_root(child_process, user, group),
+                    || {
+                        if fs::read_link("/proc/self/ns/uts")? == parent_uts {
+                            bail!("Expected child to have its own UTS namespace.");
+                        }
+                        if fail {
+                            bail!("failed");
+                        }
+                        Ok(())
+                    },
+                )
+                .unwrap();
+            wait_for_child(child_pid)
+        };
+        assert!(spawn(false).is_ok());
+        assert!(marker.exists());
+        fs::remove_file(&marker).unwrap();
+        // Teardown happens even when the child function fails.
+        assert!(spawn(true).is_err());
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn id_map_lines_map_one_id() {
+        assert_eq!(id_map_line(0, 1000), "0 1000 1\n");
+        let user = Uid::from_raw(1000);
+        let group = Gid::from_raw(100);
+        let lines = |mapping: UserMapping| {
+            (
+                id_map_line(mapping.inside_user(user), user),
+                id_map_line(mapping.inside_group(group), group),
+            )
+        };
+        assert_eq!(
+            lines(UserMapping::Root),
+            ("0 1000 1\n".to_string(), "0 100 1\n".to_string())
+        );
+        assert_eq!(
+            lines(UserMapping::Current),
+            ("1000 1000 1\n".to_string(), "100 100 1\n".to_string())
+        );
+    }
+
+    #[test]
+    fn current_user_mapping_keeps_ids() {
+        let user = Uid::current();
+        let group = Gid::current();
+        let ids = with_unshared_user_and_mount_result(
+            |child_process| map_user_into(child_process, user, group, UserMapping::Current),
+            || Ok((Uid::current().to_string(), Gid::current().to_string())),
+        )
+        .unwrap();
+        assert_eq!(ids, (user.to_string(), group.to_string()));
+    }
+
+    #[test]
+    fn user_mapping_check_requires_written_maps() {
+        // This process's namespace is mapped, as is a properly set up child's.
+        assert!(check_user_mapping_active().is_ok());
+        let user = Uid::current();
+        let group = Gid::current();
+        let child_pid = with_unshared_user_and_mount(
+            |child_process| map_user_to_root(child_process, user, group),
+            check_user_mapping_active,
+        )
+        .unwrap();
+        assert!(wait_for_child(child_pid).is_ok());
+        // Without the maps, the child function doesn't run.
+        let dir = TestDir::new();
+        let marker = dir.join("ran");
+        let child_pid =
+            with_unshared_user_and_mount(|_| Ok(()), || Ok(fs::write(&marker, "")?)).unwrap();
+        let err = wait_for_child(child_pid).unwrap_err();
+        assert!(err.downcast_ref::<ChildExited>().is_some());
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn spawn_with_result_sends_value_to_parent() {
+        let user = Uid::current();
+        let group = Gid::current();
+        let write_maps = |child_process| map_user_to_root(child_process, user, group);
+        let value = UnsharedUserAndMount::new()
+            .spawn_with_result(write_maps, || {
+                Ok((Uid::current().is_root(), vec!["mapped".to_string(); 3]))
+            })
+            .unwrap();
+        assert_eq!(value, (true, vec!["mapped".to_string(); 3]));
+        let result = UnsharedUserAndMount::new()
+            .spawn_with_result(write_maps, || -> Result<u32, Error> { bail!("failed") });
+        assert!(result.is_err());
+    }
+}
@@ -1,14 +1,17 @@
 use crate::colors::*;
 use crate::utils::add_suffix_to_path;
-use failure::Error;
+use failure::{Error, ResultExt};
 use nix::libc::pid_t;
 use nix::unistd::Pid;
 use serde::{Deserialize, Serialize};
 use shrinkwraprs::Shrinkwrap;
-use std::convert::AsRef;
-use std::ffi::OsStr;
+use std::convert::{AsRef, TryFrom};
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
 
 /// Path to the mzr directory - typically something like `.../PROJECT.mzr`, a
@@ -21,6 +24,22 @@ pub struct MzrDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct UserWorkDir(PathBuf);
 
+/// Path to the optional mzr config file - typically something like
+/// `.../PROJECT/mzr.toml`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ConfigFile(PathBuf);
+
+/// Path to a directory that a zone gets bind-mounted to instead of the user's
+/// work directory. This allows inspecting a zone at a scratch location, without
+/// shadowing the user's work directory.
+#[derive(Debug, Clone, Shrinkwrap, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct ZoneTargetDir(PathBuf);
+
+/// Path to the directory containing all zone directories - typically
+/// something like `.../PROJECT.mzr/zone`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct ZonesDir(PathBuf);
+
 /// Path to the zone directory within the mzr directory - typically something
 /// like `.../PROJECT.mzr/zone/ZONE`.
 #[derive(Debug, Clone, Shrinkwrap)]
@@ -31,11 +50,32 @@ pub struct ZoneDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct ZoneInfoFile(PathBuf);
 
+/// Path to the directory containing all snapshot directories - typically
+/// something like `.../PROJECT.mzr/snap`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapsDir(PathBuf);
+
+/// Path to the directory that deleted zones get moved to - typically something
+/// like `.../PROJECT.mzr/.trash`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct TrashDir(PathBuf);
+
+/// Path to the directory for temporary files and directories - typically
+/// something like `.../PROJECT.mzr/.tmp`.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct TmpDir(PathBuf);
+
 /// Path to snapshot directory - typically something like
 /// `.../PROJECT.mzr/snap/SNAP`.
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct SnapDir(PathBuf);
 
+/// Path to the snapshot info file - typically something like
+/// `.../PROJECT.mzr/snap-info/SNAP.json`. This is stored outside of the
+/// snapshot directory so that it doesn't become part of the snapshot.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct SnapInfoFile(PathBuf);
+
 /// Path to the zone changes directory - typically something like
 /// `.../PROJECT.mzr/zone/ZONE/changes`. This is used as the "upper"
 /// dir of the overlayfs mount, and so changes that overlay the
@@ -77,6 +117,12 @@ pub struct DaemonDir(PathBuf);
 #[derive(Debug, Clone, Shrinkwrap)]
 pub struct DaemonPidFile(PathBuf);
 
+/// Path to the file recording which zones the daemon has mounted - typically
+/// something like `.../PROJECT.mzr/daemon/mounts.json`. This allows cleaning
+/// up lingering mounts after the daemon dies.
+#[derive(Debug, Clone, Shrinkwrap)]
+pub struct DaemonMountsFile(PathBuf);
+
 /// Path to the daemon log file for stdout - typically something like
 /// `.../PROJECT.mzr/daemon/log.stdout`.
 #[derive(Debug, Clone, Shrinkwrap)]
@@ -111,18 +157,63 @@ pub struct ZoneName(String);
 /// Name of a snapshot.
 ///
 /// TODO(name-validation): document validation once it has that.
-#[derive(Debug, Clone, Shrinkwrap, Serialize, Deserialize)]
+#[derive(Debug, Clone, Shrinkwrap, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct SnapName(String);
 
 impl MzrDir {
     pub fn new(work_dir: &UserWorkDir) -> Self {
         MzrDir(add_suffix_to_path(work_dir, ".mzr"))
     }
-}
+
+    /// Checks that an existing directory looks like a mzr directory. Yields
+    /// `NotMzrDir` if nothing exists at the path, and other errors if it
+    /// exists but is not a valid mzr directory.
+    pub fn open(path: &Path) -> Result<MzrDir, Error> {
+        match fs::metadata(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                return Err(NotMzrDir(path.to_path_buf()).into());
+            }
+            Err(e) => Err(e).context(format_err!(
+                "Failed to access mzr directory {}",
+                color_dir(&path.display())
+            ))?,
+            Ok(metadata) => {
+                if !metadata.is_dir() {
+                    bail!(
+                        "Expected {} to be a mzr directory, but it is not a directory.",
+                        color_file(&path.display())
+                    );
+                }
+            }
+        }
+        let mzr_dir = MzrDir(path.to_path_buf());
+        // These get created when first needed, so it's fine for them to not
+        // exist yet.
+        let subdirs = [
+            ZonesDir::new(&mzr_dir).to_path_buf(),
+            SnapsDir::new(&mzr_dir).to_path_buf(),
+        ];
+        for subdir in &subdirs {
+            if fs::metadata(subdir).is_ok_and(|metadata| !metadata.is_dir()) {
+                bail!(
+                    "mzr directory {} is corrupt: expected {} to be a directory.",
+                    mzr_dir,
+                    color_file(&subdir.display())
+                );
+            }
+        }
+        Ok(mzr_dir)
+    }
+}
+
+/// Nothing exists at the path of a mzr directory.
+#[derive(Fail, Debug)]
+#[fail(display = "{:?} is not a mzr directory.", _0)]
+pub struct NotMzrDir(pub PathBuf);
 
 impl UserWorkDir {
-    pub fn new(work_dir: &PathBuf) -> Self {
-        UserWorkDir(work_dir.clone())
+    pub fn new(work_dir: &Path) -> Self {
+        UserWorkDir(work_dir.to_path_buf())
     }
 
     #[allow(dead_code)]
@@ -131,6 +222,39 @@ impl UserWorkDir {
     }
 }
 
+impl ConfigFile {
+    pub fn new(user_work_dir: &UserWorkDir) -> Self {
+        ConfigFile(user_work_dir.join("mzr.toml"))
+    }
+}
+
+impl ZoneTargetDir {
+    pub fn new(target_dir: &Path) -> Result<Self, Error> {
+        if !target_dir.is_absolute() {
+            bail!(
+                "Zone target directory {} must be an absolute path.",
+                color_dir(&target_dir.display())
+            );
+        }
+        if !target_dir.is_dir() {
+            bail!(
+                "Zone target directory {} must be an existing directory.",
+                color_dir(&target_dir.display())
+            );
+        }
+        Ok(ZoneTargetDir(target_dir.to_path_buf()))
+    }
+}
+
+impl ZonesDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
+        let mut result = mzr_dir_buf.clone();
+        result.push("zone");
+        ZonesDir(result)
+    }
+}
+
 impl ZoneDir {
     pub fn new(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Self {
         let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
@@ -139,6 +263,41 @@ impl ZoneDir {
         result.push(zone_name);
         ZoneDir(result)
     }
+
+    /// Recovers the zone's name from the last component of its directory.
+    pub fn zone_name(&self) -> Result<ZoneName, Error> {
+        ZoneName::new(last_component_name(&self.0)?)
+    }
+
+    pub fn info_file(&self) -> ZoneInfoFile {
+        ZoneInfoFile::new(self)
+    }
+
+    pub fn changes_dir(&self) -> OvfsChangesDir {
+        OvfsChangesDir::new(self)
+    }
+
+    pub fn ovfs_work_dir(&self) -> OvfsWorkDir {
+        OvfsWorkDir::new(self)
+    }
+
+    pub fn ovfs_mount_dir(&self) -> OvfsMountDir {
+        OvfsMountDir::new(self)
+    }
+}
+
+fn last_component_name(path: &Path) -> Result<String, Error> {
+    match path.file_name().map(|name| name.to_str()) {
+        Some(Some(name)) => Ok(name.to_string()),
+        Some(None) => bail!(
+            "Expected last component of {} to be valid unicode.",
+            color_dir(&path.display())
+        ),
+        None => bail!(
+            "Expected {} to end with a name.",
+            color_dir(&path.display())
+        ),
+    }
 }
 
 impl ZoneInfoFile {
@@ -150,6 +309,33 @@ impl ZoneInfoFile {
     }
 }
 
+impl SnapsDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
+        let mut result = mzr_dir_buf.clone();
+        result.push("snap");
+        SnapsDir(result)
+    }
+}
+
+impl TrashDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
+        let mut result = mzr_dir_buf.clone();
+        result.push(".trash");
+        TrashDir(result)
+    }
+}
+
+impl TmpDir {
+    pub fn new(mzr_dir: &MzrDir) -> Self {
+        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
+        let mut result = mzr_dir_buf.clone();
+        result.push(".tmp");
+        TmpDir(result)
+    }
+}
+
 impl SnapDir {
     pub fn new(mzr_dir: &MzrDir, snap_name: &SnapName) -> Self {
         let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
@@ -159,17 +345,64 @@ impl SnapDir {
         SnapDir(result)
     }
 
+    /// Recovers the snapshot's name from the last component of its directory.
+    pub fn snap_name(&self) -> Result<SnapName, Error> {
+        SnapName::new(last_component_name(&self.0)?)
+    }
+
+    /// Directory that the snapshot is copied to before being renamed into
+    /// place - typically something like `.../PROJECT.mzr/snap/.SNAP.tmp-PID`.
+    /// It's a sibling of the snapshot directory, so that they're on the same
+    /// filesystem and the rename is atomic.
+    pub fn staging_dir(&self) -> PathBuf {
+        let mut name = OsString::from(".");
+        name.push(self.0.file_name().unwrap_or_default());
+        name.push(format!(".tmp-{}", process::id()));
+        self.0.with_file_name(name)
+    }
+
     pub fn to_arg(&self) -> &OsStr {
         self.0.as_ref()
     }
 }
 
+impl SnapInfoFile {
+    pub fn new(mzr_dir: &MzrDir, snap_name: &SnapName) -> Self {
+        let mzr_dir_buf: &PathBuf = mzr_dir.as_ref();
+        let mut result = mzr_dir_buf.clone();
+        result.push("snap-info");
+        result.push(format!("{}.json", snap_name.as_str()));
+        SnapInfoFile(result)
+    }
+}
+
 impl OvfsChangesDir {
     pub fn new(zone_dir: &ZoneDir) -> Self {
         let mut ovfs_changes_dir = zone_dir.0.clone();
         ovfs_changes_dir.push("changes");
         OvfsChangesDir(ovfs_changes_dir)
     }
+
+    /// Changes directory which is not part of a zone, such as changes staged
+    /// from an archive.
+    pub fn staged(dir: &Path) -> Self {
+        OvfsChangesDir(dir.to_path_buf())
+    }
+
+    /// Given a path within the changes directory, returns the path relative to
+    /// the root of the zone, which is also its path relative to the user's
+    /// work directory when the zone is mounted. The changes directory itself
+    /// yields an empty path.
+    pub fn work_relative_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        match path.strip_prefix(&self.0) {
+            Ok(rel_path) => Ok(rel_path.to_path_buf()),
+            Err(_) => bail!(
+                "Expected {} to be within changes directory {}",
+                color_file(&path.display()),
+                self
+            ),
+        }
+    }
 }
 
 impl OvfsWorkDir {
@@ -223,6 +456,15 @@ impl DaemonPidFile {
     }
 }
 
+impl DaemonMountsFile {
+    pub fn new(daemon_dir: &DaemonDir) -> Self {
+        let dir_buf: &PathBuf = daemon_dir.as_ref();
+        let mut result = dir_buf.clone();
+        result.push("mounts.json");
+        DaemonMountsFile(result)
+    }
+}
+
 impl DaemonLogStdoutFile {
     pub fn new(daemon_dir: &DaemonDir) -> Self {
         let dir_buf: &PathBuf = daemon_dir.as_ref();
@@ -286,7 +528,21 @@ impl ZoneName {
 impl FromStr for ZoneName {
     type Err = Error;
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        Ok(ZoneName::new(name.to_string())?)
+        ZoneName::new(name.to_string())
+    }
+}
+
+impl TryFrom<String> for ZoneName {
+    type Error = Error;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        ZoneName::new(name)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for ZoneName {
+    type Error = Error;
+    fn try_from(name: &'a str) -> Result<Self, Self::Error> {
+        name.parse()
     }
 }
 
@@ -300,7 +556,99 @@ impl SnapName {
 impl FromStr for SnapName {
     type Err = Error;
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        Ok(SnapName::new(name.to_string())?)
+        SnapName::new(name.to_string())
+    }
+}
+
+impl TryFrom<String> for SnapName {
+    type Error = Error;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        SnapName::new(name)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for SnapName {
+    type Error = Error;
+    fn try_from(name: &'a str) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+/*
+ * Conversions from paths discovered on the filesystem, which check that the
+ * path has the expected layout.
+ */
+
+impl<'a> TryFrom<&'a Path> for MzrDir {
+    type Error = Error;
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        if path.extension().is_none_or(|extension| extension != "mzr") {
+            bail!(
+                "Expected {} to be a mzr directory, with a name ending in \".mzr\".",
+                color_dir(&path.display())
+            );
+        }
+        Ok(MzrDir(path.to_path_buf()))
+    }
+}
+
+impl<'a> TryFrom<&'a Path> for ZoneDir {
+    type Error = Error;
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        let mzr_dir = mzr_dir_of_child(path, "zone")?;
+        Ok(ZoneDir::new(
+            &mzr_dir,
+            &ZoneName::new(last_component_name(path)?)?,
+        ))
+    }
+}
+
+impl<'a> TryFrom<&'a Path> for SnapDir {
+    type Error = Error;
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        let mzr_dir = mzr_dir_of_child(path, "snap")?;
+        Ok(SnapDir::new(
+            &mzr_dir,
+            &SnapName::new(last_component_name(path)?)?,
+        ))
+    }
+}
+
+impl<'a> TryFrom<&'a Path> for OvfsChangesDir {
+    type Error = Error;
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        match (path.file_name(), path.parent()) {
+            (Some(name), Some(zone_dir)) if name == "changes" => {
+                Ok(ZoneDir::try_from(zone_dir)?.changes_dir())
+            }
+            _ => bail!(
+                "Expected {} to be a zone's changes directory, like \
+                 .../PROJECT.mzr/zone/ZONE/changes",
+                color_dir(&path.display())
+            ),
+        }
+    }
+}
+
+/// Finds the mzr directory of a path of the form `.../PROJECT.mzr/DIR/NAME`.
+fn mzr_dir_of_child(path: &Path, dir_name: &str) -> Result<MzrDir, Error> {
+    match path.parent() {
+        Some(parent) if parent.file_name().is_some_and(|name| name == dir_name) => {
+            match parent.parent() {
+                Some(mzr_dir) => MzrDir::try_from(mzr_dir),
+                None => bail!(
+                    "Expected {} to be within a mzr directory.",
+                    color_dir(&path.display())
+                ),
+            }
+        }
+        _ => bail!(
+            "Expected {} to be within a {:?} directory of a mzr directory, like \
+             .../PROJECT.mzr/{}/NAME",
+            color_dir(&path.display()),
+            dir_name,
+            dir_name
+        ),
     }
 }
 
@@ -316,6 +664,24 @@ impl AsRef<Path> for UserWorkDir {
     }
 }
 
+impl AsRef<Path> for ConfigFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ZoneTargetDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for ZonesDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<Path> for ZoneDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
@@ -328,12 +694,36 @@ impl AsRef<Path> for ZoneInfoFile {
     }
 }
 
+impl AsRef<Path> for SnapsDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for TrashDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<Path> for TmpDir {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<Path> for SnapDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
     }
 }
 
+impl AsRef<Path> for SnapInfoFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<Path> for OvfsChangesDir {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
@@ -376,6 +766,12 @@ impl AsRef<Path> for DaemonPidFile {
     }
 }
 
+impl AsRef<Path> for DaemonMountsFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<Path> for DaemonLogStdoutFile {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
@@ -430,6 +826,24 @@ impl AsRef<OsStr> for UserWorkDir {
     }
 }
 
+impl AsRef<OsStr> for ConfigFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZoneTargetDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for ZonesDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<OsStr> for ZoneDir {
     fn as_ref(&self) -> &OsStr {
         self.0.as_ref()
@@ -442,12 +856,36 @@ impl AsRef<OsStr> for ZoneInfoFile {
     }
 }
 
+impl AsRef<OsStr> for SnapsDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for TrashDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<OsStr> for TmpDir {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<OsStr> for SnapDir {
     fn as_ref(&self) -> &OsStr {
         self.0.as_ref()
     }
 }
 
+impl AsRef<OsStr> for SnapInfoFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<OsStr> for OvfsChangesDir {
     fn as_ref(&self) -> &OsStr {
         self.0.as_ref()
@@ -490,6 +928,12 @@ impl AsRef<OsStr> for DaemonPidFile {
     }
 }
 
+impl AsRef<OsStr> for DaemonMountsFile {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
 impl AsRef<OsStr> for DaemonLogStdoutFile {
     fn as_ref(&self) -> &OsStr {
         self.0.as_ref()
@@ -544,6 +988,24 @@ impl Display for UserWorkDir {
     }
 }
 
+impl Display for ConfigFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZoneTargetDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for ZonesDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for ZoneDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -556,12 +1018,36 @@ impl Display for ZoneInfoFile {
     }
 }
 
+impl Display for SnapsDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for TrashDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
+impl Display for TmpDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_dir(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for SnapDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
     }
 }
 
+impl Display for SnapInfoFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for OvfsChangesDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_dir(&self.0.display()).fmt(f)
@@ -604,6 +1090,12 @@ impl Display for DaemonPidFile {
     }
 }
 
+impl Display for DaemonMountsFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        color_file(&self.0.display()).fmt(f)
+    }
+}
+
 impl Display for DaemonLogStdoutFile {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         color_file(&self.0.display()).fmt(f)
@@ -645,3 +1137,179 @@ impl Display for SnapName {
         color_snap_name(&self.0).fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use std::convert::TryInto;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn zone_target_dir_accepts_existing_dir() {
+        let dir = TestDir::new();
+        let target_dir = ZoneTargetDir::new(dir.path()).unwrap();
+        assert_eq!(target_dir.as_path(), dir.path());
+    }
+
+    #[test]
+    fn zone_target_dir_rejects_relative_path() {
+        assert!(ZoneTargetDir::new(&PathBuf::from("relative/dir")).is_err());
+    }
+
+    #[test]
+    fn zone_target_dir_rejects_missing_or_file() {
+        let dir = TestDir::new();
+        assert!(ZoneTargetDir::new(&dir.join("missing")).is_err());
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(ZoneTargetDir::new(&dir.join("file")).is_err());
+    }
+
+    #[test]
+    fn names_convert_from_strings() {
+        let zone_name = ZoneName::try_from("mz").unwrap();
+        assert_eq!(zone_name, ZoneName::new("mz".to_string()).unwrap());
+        assert_eq!(ZoneName::try_from("mz".to_string()).unwrap(), zone_name);
+        let snap_name = SnapName::try_from("s1").unwrap();
+        assert_eq!(snap_name, SnapName::new("s1".to_string()).unwrap());
+        assert_eq!(SnapName::try_from("s1".to_string()).unwrap(), snap_name);
+        fn zone_dir_of<T: TryInto<ZoneName, Error = Error>>(name: T) -> Result<ZoneDir, Error> {
+            let mzr_dir = MzrDir::new(&UserWorkDir::new(&PathBuf::from("/proj")));
+            Ok(ZoneDir::new(&mzr_dir, &name.try_into()?))
+        }
+        assert_eq!(
+            zone_dir_of("mz").unwrap().as_path(),
+            Path::new("/proj.mzr/zone/mz")
+        );
+    }
+
+    #[test]
+    fn names_are_recovered_from_dirs() {
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&PathBuf::from("/proj")));
+        let zone_name = ZoneName::new("mz".to_string()).unwrap();
+        let snap_name = SnapName::new("s1".to_string()).unwrap();
+        assert_eq!(
+            ZoneDir::new(&mzr_dir, &zone_name).zone_name().unwrap(),
+            zone_name
+        );
+        assert_eq!(
+            SnapDir::new(&mzr_dir, &snap_name).snap_name().unwrap(),
+            snap_name
+        );
+        // Paths which don't end in a valid unicode name.
+        let non_unicode = PathBuf::from(OsStr::from_bytes(b"/proj.mzr/zone/\xff"));
+        assert!(ZoneDir(non_unicode.clone()).zone_name().is_err());
+        assert!(SnapDir(non_unicode).snap_name().is_err());
+        assert!(ZoneDir(PathBuf::from("/")).zone_name().is_err());
+        assert!(SnapDir(PathBuf::from("/proj.mzr/snap/.."))
+            .snap_name()
+            .is_err());
+    }
+
+    #[test]
+    fn zone_dir_accessors_match_constructors() {
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&PathBuf::from("/proj")));
+        let zone_dir = ZoneDir::new(&mzr_dir, &ZoneName::new("mz".to_string()).unwrap());
+        assert_eq!(
+            zone_dir.info_file().as_path(),
+            ZoneInfoFile::new(&zone_dir).as_path()
+        );
+        assert_eq!(
+            zone_dir.changes_dir().as_path(),
+            OvfsChangesDir::new(&zone_dir).as_path()
+        );
+        assert_eq!(
+            zone_dir.ovfs_work_dir().as_path(),
+            OvfsWorkDir::new(&zone_dir).as_path()
+        );
+        assert_eq!(
+            zone_dir.ovfs_mount_dir().as_path(),
+            OvfsMountDir::new(&zone_dir).as_path()
+        );
+        assert!(zone_dir.info_file().starts_with(zone_dir.as_path()));
+    }
+
+    #[test]
+    fn open_checks_mzr_dir_layout() {
+        let dir = TestDir::new();
+        let path = dir.join("proj.mzr");
+        // Nothing there is not a mzr directory, rather than a corrupt one.
+        let err = MzrDir::open(&path).unwrap_err();
+        assert!(err.downcast_ref::<NotMzrDir>().is_some());
+        fs::write(&path, "").unwrap();
+        let err = MzrDir::open(&path).unwrap_err();
+        assert!(err.downcast_ref::<NotMzrDir>().is_none());
+        fs::remove_file(&path).unwrap();
+        // The subdirectories get created when needed.
+        fs::create_dir(&path).unwrap();
+        assert_eq!(MzrDir::open(&path).unwrap().as_path(), path.as_path());
+        fs::create_dir(path.join("zone")).unwrap();
+        fs::create_dir(path.join("snap")).unwrap();
+        assert!(MzrDir::open(&path).is_ok());
+        fs::remove_dir(path.join("snap")).unwrap();
+        fs::write(path.join("snap"), "").unwrap();
+        let err = MzrDir::open(&path).unwrap_err();
+        assert!(err.to_string().contains("corrupt"));
+    }
+
+    #[test]
+    fn dirs_convert_from_paths_with_expected_layout() {
+        let mzr_dir = MzrDir::try_from(Path::new("/p/proj.mzr")).unwrap();
+        assert_eq!(mzr_dir.as_path(), Path::new("/p/proj.mzr"));
+        assert!(MzrDir::try_from(Path::new("/p/proj")).is_err());
+        let zone_dir = ZoneDir::try_from(Path::new("/p/proj.mzr/zone/mz")).unwrap();
+        assert_eq!(zone_dir.zone_name().unwrap().as_str(), "mz");
+        let snap_dir = SnapDir::try_from(Path::new("/p/proj.mzr/snap/s1")).unwrap();
+        assert_eq!(snap_dir.snap_name().unwrap().as_str(), "s1");
+        let changes_dir =
+            OvfsChangesDir::try_from(Path::new("/p/proj.mzr/zone/mz/changes")).unwrap();
+        assert_eq!(changes_dir.as_path(), zone_dir.changes_dir().as_path());
+        for path in &[
+            "/p/proj.mzr/snap/mz",
+            "/p/proj/zone/mz",
+            "/zone/mz",
+            "/p/proj.mzr/zone",
+            "mz",
+        ] {
+            assert!(ZoneDir::try_from(Path::new(path)).is_err(), "{}", path);
+        }
+        assert!(SnapDir::try_from(Path::new("/p/proj.mzr/zone/s1")).is_err());
+        for path in &["/p/proj.mzr/zone/mz/work", "/p/proj.mzr/snap/mz/changes"] {
+            assert!(
+                OvfsChangesDir::try_from(Path::new(path)).is_err(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn changes_paths_are_relative_to_work_dir() {
+        let work_dir = UserWorkDir::new(&PathBuf::from("/proj"));
+        let mzr_dir = MzrDir::new(&work_dir);
+        let zone_dir = ZoneDir::new(&mzr_dir, &ZoneName::new("mz".to_string()).unwrap());
+        let changes_dir = zone_dir.changes_dir();
+        let rel = |path: &str| changes_dir.work_relative_path(Path::new(path));
+        assert_eq!(
+            rel("/proj.mzr/zone/mz/changes/src/lib.rs").unwrap(),
+            Path::new("src/lib.rs")
+        );
+        assert_eq!(
+            work_dir.join(rel("/proj.mzr/zone/mz/changes/a/b/c").unwrap()),
+            Path::new("/proj/a/b/c")
+        );
+        assert_eq!(rel("/proj.mzr/zone/mz/changes").unwrap(), Path::new(""));
+        assert_eq!(rel("/proj.mzr/zone/mz/changes/").unwrap(), Path::new(""));
+        assert!(rel("/proj.mzr/zone/mz/changes2/file").is_err());
+        assert!(rel("/proj.mzr/zone/mz").is_err());
+        assert!(rel("/proj/src/lib.rs").is_err());
+        let staged = OvfsChangesDir::staged(Path::new("/tmp/stage/files"));
+        assert_eq!(
+            staged
+                .work_relative_path(Path::new("/tmp/stage/files/a"))
+                .unwrap(),
+            Path::new("a")
+        );
+    }
+}
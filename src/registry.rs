@@ -0,0 +1,255 @@
+use crate::colors::*;
+use crate::utils::{ensure_dir, is_process_alive};
+use failure::{Error, ResultExt};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{self, Pid};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/*
+ * Registry of active operations
+ *
+ * mzr invocations for any project record the operations they're performing
+ * on shared resources in a per-user registry directory, holding an exclusive
+ * `flock` on the resource's lock file while they do so. Since the kernel
+ * releases these locks when a process exits, entries left behind by processes
+ * which died are never mistaken for active operations.
+ */
+
+/// Directory containing the lock files of the registry - `mzr` within
+/// `$XDG_RUNTIME_DIR`, or a per-user directory in `/tmp` if that's unset.
+pub fn registry_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("mzr"),
+        None => env::temp_dir().join(format!("mzr-{}", unistd::getuid())),
+    }
+}
+
+/// Exclusive lock on a resource, held until this is dropped.
+#[derive(Debug)]
+pub struct OperationLock {
+    file: File,
+}
+
+/// Operation recorded in the registry, which may no longer be active.
+#[derive(Debug)]
+pub struct Operation {
+    pub resource: String,
+    pub pid: Pid,
+    pub description: String,
+}
+
+/// Acquires an exclusive lock on a resource, waiting for any other mzr process
+/// which holds it. The description is shown to other processes which wait for
+/// the lock.
+///
+/// The registry is optional - if it can't be used, then a warning is printed
+/// and `None` is returned, so that the operation can proceed without it.
+pub fn lock(resource: &str, description: &str) -> Option<OperationLock> {
+    match try_lock(&registry_dir(), resource, description) {
+        Ok(lock) => Some(lock),
+        Err(e) => {
+            println!(
+                "{} Proceeding without registering operation in {}: {}",
+                color_warn(&"Warning:"),
+                color_dir(&registry_dir().display()),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn try_lock(dir: &Path, resource: &str, description: &str) -> Result<OperationLock, Error> {
+    ensure_dir(dir)?;
+    let path = dir.join(lock_file_name(resource));
+    loop {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format_err!(
+                "Failed to open lock file {}",
+                color_file(&path.display())
+            ))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                match read_operation(&mut file)? {
+                    Some(operation) => println!(
+                        "Waiting for another mzr process (PID {}) which is {}",
+                        operation.pid, operation.description
+                    ),
+                    None => println!("Waiting for another mzr process using {}", resource),
+                }
+                flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+            }
+            Err(e) => Err(e)?,
+        }
+        // `active_operations` removes lock files which aren't locked, so the
+        // file may have been removed between opening and locking it.
+        let locked_ino = file.metadata()?.ino();
+        if fs::metadata(&path).map(|metadata| metadata.ino()).ok() != Some(locked_ino) {
+            continue;
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}\n{}\n{}\n", process::id(), resource, description)?;
+        file.sync_all()?;
+        return Ok(OperationLock { file });
+    }
+}
+
+impl Drop for OperationLock {
+    fn drop(&mut self) {
+        // Clearing the file marks the operation as finished. Errors are
+        // ignored, since the lock is released when the file is closed anyway.
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn lock_file_name(resource: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    resource.hash(&mut hasher);
+    format!("{:016x}.lock", hasher.finish())
+}
+
+fn read_operation(file: &mut File) -> Result<Option<Operation>, Error> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    let mut lines = contents.lines();
+    match (lines.next().map(str::parse), lines.next(), lines.next()) {
+        (Some(Ok(pid)), Some(resource), Some(description)) => Ok(Some(Operation {
+            resource: resource.to_string(),
+            pid: Pid::from_raw(pid),
+            description: description.to_string(),
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Lists the operations which are currently holding locks. Lock files of
+/// operations which are no longer active are removed.
+pub fn active_operations() -> Result<Vec<Operation>, Error> {
+    active_operations_in(&registry_dir())
+}
+
+fn active_operations_in(dir: &Path) -> Result<Vec<Operation>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir).context(format_err!(
+        "Failed to read registry directory {}",
+        color_dir(&dir.display())
+    ))? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "lock") {
+            continue;
+        }
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            // Removed by another process.
+            Err(_) => continue,
+        };
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            // Nothing holds the lock, so the entry is stale.
+            Ok(()) => {
+                let _ = fs::remove_file(&path);
+            }
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                if let Some(operation) = read_operation(&mut file)? {
+                    if is_process_alive(operation.pid) {
+                        result.push(operation);
+                    }
+                }
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn locks_are_exclusive_and_registered() {
+        let dir = TestDir::new();
+        let registry = dir.join("registry");
+        assert!(active_operations_in(&registry).unwrap().is_empty());
+        let lock = try_lock(&registry, "snapshot cache", "copying files").unwrap();
+        let operations = active_operations_in(&registry).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].pid, unistd::getpid());
+        assert_eq!(operations[0].resource, "snapshot cache");
+        assert_eq!(operations[0].description, "copying files");
+        // Another lock on a different resource doesn't wait.
+        let other_lock = try_lock(&registry, "other", "testing").unwrap();
+        assert_eq!(active_operations_in(&registry).unwrap().len(), 2);
+        drop(other_lock);
+        // flock locks are per open file, so another thread has to wait too.
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let waiter_registry = registry.clone();
+        let waiter = thread::spawn(move || {
+            let lock = try_lock(&waiter_registry, "snapshot cache", "waiting").unwrap();
+            locked_tx.send(()).unwrap();
+            lock
+        });
+        let timeout = Duration::from_millis(200);
+        assert!(locked_rx.recv_timeout(timeout).is_err());
+        drop(lock);
+        locked_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let lock = waiter.join().unwrap();
+        let operations = active_operations_in(&registry).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].description, "waiting");
+        drop(lock);
+        assert!(active_operations_in(&registry).unwrap().is_empty());
+    }
+
+    #[test]
+    fn stale_entries_are_removed() {
+        let dir = TestDir::new();
+        let registry = dir.join("registry");
+        ensure_dir(&registry).unwrap();
+        // Left behind by a process which died while holding the lock.
+        let stale = registry.join(lock_file_name("stale"));
+        fs::write(&stale, format!("{}\nstale\ncrashed\n", process::id())).unwrap();
+        let garbage = registry.join(lock_file_name("garbage"));
+        fs::write(&garbage, "garbage").unwrap();
+        let unrelated = registry.join("unrelated");
+        fs::write(&unrelated, "").unwrap();
+        let lock = try_lock(&registry, "active", "testing").unwrap();
+        let operations = active_operations_in(&registry).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].resource, "active");
+        assert!(!stale.exists());
+        assert!(!garbage.exists());
+        assert!(unrelated.exists());
+        // A stale entry doesn't prevent locking the resource again.
+        fs::write(&stale, format!("{}\nstale\ncrashed\n", process::id())).unwrap();
+        let stale_lock = try_lock(&registry, "stale", "recovering").unwrap();
+        assert_eq!(active_operations_in(&registry).unwrap().len(), 2);
+        drop(stale_lock);
+        drop(lock);
+        assert!(active_operations_in(&registry).unwrap().is_empty());
+        assert_eq!(fs::read_dir(&registry).unwrap().count(), 1);
+    }
+}
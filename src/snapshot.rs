@@ -1,18 +1,663 @@
+use crate::archive::{self, Compression, SshDestination};
+use crate::changes::{self, PathChange};
 use crate::colors::*;
+use crate::config::DirMode;
+use crate::json::{self, Versioned};
+use crate::mount::is_mounted;
 use crate::paths::*;
+use crate::registry;
 use crate::top_dirs::TopDirs;
-use crate::utils::run_process;
+use crate::utils::{
+    check_contained_rel_path, check_no_symlinked_parents, closest_match, ensure_dir,
+    ensure_dir_with_mode, format_bytes, list_subdir_names, rename_dir_into_place, run_process,
+    set_dir_mode,
+};
+use crate::vfs::RealFs;
+use crate::zone::Zone;
+use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
-use std::fs::create_dir_all;
-use std::path::PathBuf;
+use nix::sys::stat::{mknod, Mode, SFlag};
+use nix::sys::statvfs::statvfs;
+use nix::unistd::{chown, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{
+    self, create_dir, read_link, remove_dir_all, remove_file, set_permissions, symlink_metadata,
+    FileType,
+};
+use std::io;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
 
-pub fn of_workdir(top_dirs: &TopDirs, snap_name: &SnapName) -> Result<SnapDir, Error> {
-    create(&top_dirs.user_work_dir, &top_dirs.mzr_dir, snap_name)
+/// Information about a snapshot, stored in its `SnapInfoFile`. Snapshots
+/// taken by older versions of mzr do not have this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapInfo {
+    /// Version of the format of this info, see `json::Versioned`.
+    pub version: u32,
+    /// Snapshot that this snapshot was derived from, such as the snapshot of
+    /// the zone which was snapshotted.
+    pub parent: Option<SnapName>,
+    pub creation_time: DateTime<Utc>,
+    /// Arbitrary key / value metadata, such as `build=ci-1234`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
-fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapDir, Error> {
+impl Versioned for SnapInfo {
+    const VERSION: u32 = 1;
+
+    fn migrate(version: u32, contents: Value) -> Result<Value, Error> {
+        match version {
+            // Version 0 is info from before versioning, which has the same
+            // format.
+            0 => Ok(contents),
+            _ => bail!(
+                "Unexpected error: no migration from snapshot info version {}",
+                version
+            ),
+        }
+    }
+}
+
+/// How extended attributes are handled when copying files into a snapshot.
+/// These are needed for things like SELinux labels and file capabilities, but
+/// not all filesystems support them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XattrPolicy {
+    /// Preserve extended attributes, silently skipping them when the target
+    /// filesystem doesn't support them (`EOPNOTSUPP`).
+    #[default]
+    PreserveIfPossible,
+    /// Preserve extended attributes, failing if they can't be preserved.
+    Preserve,
+    /// Don't copy extended attributes.
+    Strip,
+}
+
+impl FromStr for XattrPolicy {
+    type Err = Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "preserve-if-possible" => Ok(XattrPolicy::PreserveIfPossible),
+            "preserve" => Ok(XattrPolicy::Preserve),
+            "strip" => Ok(XattrPolicy::Strip),
+            _ => bail!(
+                "Expected xattr policy to be one of preserve-if-possible, preserve, or strip, \
+                 but got {:?}",
+                input
+            ),
+        }
+    }
+}
+
+impl XattrPolicy {
+    /// `cp` argument that implements this policy, in addition to `--archive`.
+    fn cp_arg(self) -> Option<&'static str> {
+        match self {
+            // `--archive` preserves xattrs, but doesn't report failures to do
+            // so.
+            XattrPolicy::PreserveIfPossible => None,
+            // Explicitly requesting xattrs makes `cp` fail when they can't be
+            // preserved.
+            XattrPolicy::Preserve => Some("--preserve=xattr"),
+            XattrPolicy::Strip => Some("--no-preserve=xattr"),
+        }
+    }
+}
+
+/// Options for how files get copied into a snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    pub xattrs: XattrPolicy,
+    /// Number of worker threads to copy files with. When `None`, the whole tree
+    /// is copied by a single `cp` invocation.
+    pub jobs: Option<usize>,
+    /// Fail when a special file can't be copied, rather than skipping it with
+    /// a warning. See `copy_special_file`.
+    pub strict_special_files: bool,
+    /// Called periodically with the progress of the copy. Reporting progress
+    /// requires copying with worker threads, so when `jobs` is `None`, one
+    /// worker is used.
+    pub progress: Option<fn(&CopyProgress)>,
+    /// Whether to stat every file before copying, so that progress can be
+    /// measured in bytes rather than files. This adds latency for huge trees.
+    pub measure_sizes: bool,
+    /// Mode for the snapshot's root directory, and the snapshots directory if
+    /// it gets created. See the `dir_mode` config setting. When `None`, the
+    /// root directory keeps the mode of the source directory.
+    pub dir_mode: Option<DirMode>,
+}
+
+/// Progress of a snapshot copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyProgress {
+    pub files_done: u64,
+    pub files_total: u64,
+    /// Bytes copied and total bytes, only known when sizes are measured.
+    pub bytes: Option<(u64, u64)>,
+    pub elapsed: Duration,
+}
+
+impl CopyProgress {
+    /// Fraction of the copy which is done, from 0 to 1. This is by bytes when
+    /// known, and otherwise by files.
+    pub fn fraction(&self) -> f64 {
+        let (done, total) = self.bytes.unwrap_or((self.files_done, self.files_total));
+        if total == 0 {
+            1.0
+        } else {
+            done as f64 / total as f64
+        }
+    }
+
+    /// Estimated time remaining, assuming the rate so far continues. `None`
+    /// when nothing has been copied yet, since there's no rate to go by.
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction();
+        if fraction <= 0.0 {
+            return None;
+        }
+        let elapsed = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        let remaining = elapsed * (1.0 - fraction) / fraction;
+        Some(Duration::from_millis((remaining * 1000.0) as u64))
+    }
+}
+
+impl Display for CopyProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{:.0}% copied ({} of {} files",
+            self.fraction() * 100.0,
+            self.files_done,
+            self.files_total
+        )?;
+        if let Some((done, total)) = self.bytes {
+            write!(f, ", {} of {}", format_bytes(done), format_bytes(total))?;
+        }
+        write!(f, ")")?;
+        if let Some(eta) = self.eta() {
+            write!(f, ", about {}s remaining", eta.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// How often the progress callback is called.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of worker threads for parallel copies - the number of online
+/// CPUs.
+pub fn default_jobs() -> usize {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus < 1 {
+        1
+    } else {
+        cpus as usize
+    }
+}
+
+/// Expands a snapshot naming template like `{zone}-{date}-{seq}`. The
+/// supported variables are:
+///
+/// * `{zone}` - name of the zone being snapshotted.
+/// * `{date}` - current UTC date, like `20190527`.
+/// * `{seq}` - the smallest number, starting from 1, for which the expanded
+///   name doesn't refer to an existing snapshot.
+///
+/// `{{` and `}}` stand for literal braces. Templates which expand to names
+/// which can't be used as snapshot directory names are rejected.
+pub fn expand_name_template(
+    mzr_dir: &MzrDir,
+    template: &str,
+    zone: Option<&ZoneName>,
+) -> Result<SnapName, Error> {
+    let date = Utc::now().format("%Y%m%d").to_string();
+    let expand = |seq: usize| -> Result<String, Error> {
+        let mut result = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    result.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    result.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => bail!("Unclosed '{{' in snapshot name template {:?}", template),
+                    };
+                    match &rest[..end] {
+                        "zone" => match zone {
+                            Some(zone) => result.push_str(zone.as_str()),
+                            None => bail!(
+                                "Snapshot name template {:?} uses {{zone}}, \
+                                 but the snapshot is not being taken of a zone.",
+                                template
+                            ),
+                        },
+                        "date" => result.push_str(&date),
+                        "seq" => result.push_str(&seq.to_string()),
+                        variable => bail!(
+                            "Unknown variable {{{}}} in snapshot name template {:?}. \
+                             Supported variables are {{zone}}, {{date}}, and {{seq}}.",
+                            variable,
+                            template
+                        ),
+                    }
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => bail!("Unmatched '}}' in snapshot name template {:?}", template),
+                _ => result.push(c),
+            }
+        }
+        Ok(result)
+    };
+    let uses_seq = expand(1)? != expand(2)?;
+    let mut seq = 1;
+    loop {
+        let name = expand(seq)?;
+        if let Err(e) = validate_snap_name(&name) {
+            bail!(
+                "Snapshot name template {:?} expanded to an invalid name. {}",
+                template,
+                e
+            );
+        }
+        let snap_name = SnapName::new(name)?;
+        if !uses_seq || !SnapDir::new(mzr_dir, &snap_name).exists() {
+            return Ok(snap_name);
+        }
+        seq += 1;
+    }
+}
+
+/// Checks that a name can be used as the name of a snapshot directory.
+fn validate_snap_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        bail!("Snapshot name must not be empty.");
+    }
+    if name == "." || name == ".." {
+        bail!("Snapshot name must not be {:?}.", name);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| c == '/' || c.is_whitespace() || c.is_control())
+    {
+        bail!("Snapshot name {:?} must not contain {:?}.", name, c);
+    }
+    Ok(())
+}
+
+pub fn of_workdir(
+    top_dirs: &TopDirs,
+    snap_name: &SnapName,
+    options: &CopyOptions,
+) -> Result<SnapDir, Error> {
+    create(
+        &top_dirs.user_work_dir,
+        &top_dirs.mzr_dir,
+        snap_name,
+        None,
+        options,
+    )
+}
+
+/// Creates a snapshot of the zone's merged state, by copying the zone's
+/// snapshot and applying its changes. Unlike snapshotting the overlay mount,
+/// this does not require the zone to be mounted.
+pub fn of_zone(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    snap_name: &SnapName,
+    dir_mode: Option<DirMode>,
+) -> Result<SnapDir, Error> {
+    let snap_dir = create(
+        &zone.snap_dir,
+        mzr_dir,
+        snap_name,
+        Some(&zone.info.snapshot),
+        &CopyOptions {
+            dir_mode,
+            ..CopyOptions::default()
+        },
+    )?;
+    changes::apply_to_tree(&zone.ovfs_changes_dir, &snap_dir)?;
+    Ok(snap_dir)
+}
+
+/// Like `of_zone`, but only snapshots the directory at the relative path
+/// within the zone's merged state, so that the new snapshot is rooted at that
+/// directory.
+pub fn of_zone_subdir(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    rel_path: &Path,
+    snap_name: &SnapName,
+    dir_mode: Option<DirMode>,
+) -> Result<SnapDir, Error> {
+    let options = CopyOptions {
+        dir_mode,
+        ..CopyOptions::default()
+    };
+    let state = changes::path_state(&zone.ovfs_changes_dir, &zone.snap_dir, rel_path)?;
+    // Symlinks would be followed when copying, rather than resolved within
+    // the zone.
+    check_no_symlinked_parents(&zone.ovfs_changes_dir, rel_path)?;
+    check_no_symlinked_parents(&zone.snap_dir, rel_path)?;
+    let changes_entry = zone.ovfs_changes_dir.join(rel_path);
+    let snap_subdir = zone.snap_dir.join(rel_path);
+    let is_dir = |path: &Path| symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir());
+    // Whether the snapshot's version of the directory is part of the merged
+    // state, rather than being absent or hidden by the changes.
+    let (exists_as_dir, snapshot_visible) = match state.change {
+        PathChange::Absent | PathChange::Deleted => bail!(
+            "{} does not exist in {} zone.",
+            color_file(&rel_path.display()),
+            zone.name
+        ),
+        PathChange::Unchanged => (is_dir(&snap_subdir), true),
+        PathChange::ContainsChanges => (true, true),
+        PathChange::Added | PathChange::Modified => (is_dir(&changes_entry), false),
+    };
+    if !exists_as_dir {
+        bail!(
+            "{} is not a directory in {} zone, so it can't be snapshotted on its own.",
+            color_file(&rel_path.display()),
+            zone.name
+        );
+    }
+    let snap_dir = if snapshot_visible {
+        create(
+            &snap_subdir,
+            mzr_dir,
+            snap_name,
+            Some(&zone.info.snapshot),
+            &options,
+        )?
+    } else {
+        changes::with_staging_dir(mzr_dir, "empty-snap", |empty_dir| {
+            create(
+                empty_dir,
+                mzr_dir,
+                snap_name,
+                Some(&zone.info.snapshot),
+                &options,
+            )
+        })?
+    };
+    if is_dir(&changes_entry) {
+        changes::apply_to_tree_in(&RealFs, &changes_entry, &snap_dir)?;
+        match dir_mode {
+            None => set_permissions(&snap_dir, symlink_metadata(&changes_entry)?.permissions())?,
+            Some(mode) => set_dir_mode(&snap_dir, mode)?,
+        }
+    }
+    Ok(snap_dir)
+}
+
+/// Estimate of how much of a snapshot of a zone's merged state would be
+/// identical to the zone's snapshot. Only regular file contents are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedBytesEstimate {
+    /// Bytes of the zone's snapshot which are not modified or deleted by the
+    /// zone's changes.
+    pub shared: u64,
+    /// Bytes of files added or modified by the zone's changes.
+    pub changed: u64,
+}
+
+impl SharedBytesEstimate {
+    /// Fraction of the new snapshot which could be shared with the zone's
+    /// snapshot.
+    pub fn shared_fraction(&self) -> f64 {
+        let total = self.shared + self.changed;
+        if total == 0 {
+            1.0
+        } else {
+            self.shared as f64 / total as f64
+        }
+    }
+}
+
+/// Estimates how much of a snapshot of the zone's merged state could be
+/// shared with the zone's snapshot, by comparing the zone's changes against
+/// it. When most of it could be shared, reflinks or an incremental snapshot
+/// would save a lot of space.
+pub fn estimate_shared_bytes(zone: &Zone) -> Result<SharedBytesEstimate, Error> {
+    let mut superseded = 0;
+    let mut hidden_dirs = Vec::new();
+    for whiteout in changes::list_whiteouts(&zone.ovfs_changes_dir)? {
+        superseded += regular_file_bytes(&zone.snap_dir.join(&whiteout.rel_path))?;
+        hidden_dirs.push(whiteout.rel_path);
+    }
+    let mut changed = 0;
+    for walk_result in WalkDir::new(&zone.ovfs_changes_dir)
+        .min_depth(1)
+        .same_file_system(true)
+    {
+        let entry = walk_result?;
+        let metadata = entry.metadata()?;
+        if !metadata.file_type().is_file() {
+            continue;
+        }
+        changed += metadata.len();
+        let rel_path = zone.ovfs_changes_dir.work_relative_path(entry.path())?;
+        // Files within deleted or opaque directories have already been
+        // counted as superseding the snapshot's contents.
+        if !hidden_dirs.iter().any(|dir| rel_path.starts_with(dir)) {
+            superseded += regular_file_bytes(&zone.snap_dir.join(&rel_path))?;
+        }
+    }
+    let shared = regular_file_bytes(&zone.snap_dir)?.saturating_sub(superseded);
+    Ok(SharedBytesEstimate { shared, changed })
+}
+
+/// Sums the sizes of the regular files at or within the path. Symlinks are
+/// not followed, and a missing path has no size.
+fn regular_file_bytes(path: &Path) -> Result<u64, Error> {
+    match symlink_metadata(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+        Ok(_) => {}
+    }
+    let mut total = 0;
+    for walk_result in WalkDir::new(path).same_file_system(true) {
+        let metadata = walk_result?.metadata()?;
+        if metadata.file_type().is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Creates a snapshot of a mounted zone by copying its overlay mount, so that
+/// the zone can continue to be used. Unlike `of_zone`, this is not
+/// consistent: files which are written during the copy may be captured
+/// partially written, or not at all. The overlay must be mounted within the
+/// current mount namespace.
+///
+/// When a relative path is specified, only the directory at that path within
+/// the zone is snapshotted.
+pub fn of_mounted_zone(
+    mzr_dir: &MzrDir,
+    zone: &Zone,
+    rel_path: Option<&Path>,
+    snap_name: &SnapName,
+    options: &CopyOptions,
+) -> Result<SnapDir, Error> {
+    if !is_mounted(&zone.ovfs_mount_dir)? {
+        bail!(
+            "{} zone is not mounted at {}, so a live snapshot can't be taken of it.",
+            zone.name,
+            zone.ovfs_mount_dir
+        );
+    }
+    let source_dir = match rel_path {
+        None => zone.ovfs_mount_dir.to_path_buf(),
+        Some(rel_path) => {
+            check_contained_rel_path(rel_path)?;
+            check_no_symlinked_parents(&zone.ovfs_mount_dir, rel_path)?;
+            let source_dir = zone.ovfs_mount_dir.join(rel_path);
+            match symlink_metadata(&source_dir) {
+                Ok(ref metadata) if metadata.is_dir() => {}
+                Ok(_) => bail!(
+                    "{} is not a directory in {} zone, so it can't be snapshotted on its own.",
+                    color_file(&rel_path.display()),
+                    zone.name
+                ),
+                Err(_) => bail!(
+                    "{} does not exist in {} zone.",
+                    color_file(&rel_path.display()),
+                    zone.name
+                ),
+            }
+            source_dir
+        }
+    };
+    create(
+        &source_dir,
+        mzr_dir,
+        snap_name,
+        Some(&zone.info.snapshot),
+        options,
+    )
+}
+
+/// Warns if the snapshot directory, or the directory containing it, is
+/// writable by other users. Zones use snapshots as their read-only lower
+/// directory, so this could allow others to modify every zone based on it.
+pub fn warn_if_writable_by_others(snap_dir: &SnapDir) -> Result<(), Error> {
+    let dirs = iter::once(snap_dir.as_path()).chain(snap_dir.parent());
+    for dir in dirs {
+        let mode = symlink_metadata(dir)
+            .context(format_err!(
+                "Failed to read metadata of {}",
+                color_dir(&dir.display())
+            ))?
+            .mode();
+        if mode & 0o002 != 0 {
+            println!(
+                "{} {} is writable by other users, who could modify the snapshot. \
+                 Consider running {}",
+                color_warn(&"Warning:"),
+                color_dir(&dir.display()),
+                color_cmd(&format!("chmod o-w {}", dir.display()))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Creates a copy of a snapshot, so that it can be experimented with without
+/// affecting the original. Uses reflinks when the filesystem supports them.
+/// The original is recorded as the parent of the copy.
+pub fn duplicate(
+    mzr_dir: &MzrDir,
+    source: &SnapName,
+    target: &SnapName,
+    dir_mode: Option<DirMode>,
+) -> Result<SnapDir, Error> {
+    let source_dir = SnapDir::new(mzr_dir, source);
+    if !source_dir.is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            source,
+            did_you_mean_snap(mzr_dir, source)?
+        );
+    }
+    create(
+        &source_dir,
+        mzr_dir,
+        target,
+        Some(source),
+        &CopyOptions {
+            dir_mode,
+            ..CopyOptions::default()
+        },
+    )
+}
+
+fn create(
+    source_dir: &Path,
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    parent: Option<&SnapName>,
+    options: &CopyOptions,
+) -> Result<SnapDir, Error> {
+    create_with(
+        mzr_dir,
+        snap_name,
+        parent,
+        options.dir_mode,
+        |staging_dir| match options.jobs {
+            None if options.progress.is_none() => {
+                match copy_tree(source_dir, staging_dir, options.xattrs) {
+                    Ok(()) => Ok(()),
+                    // `cp` fails on special files that it can't create, such
+                    // as device nodes when unprivileged, so then the tree is
+                    // copied again in a way that handles them separately.
+                    // Only checking for them after failure avoids walking the
+                    // tree when they aren't present.
+                    Err(e) => {
+                        if !has_special_files(source_dir)? {
+                            return Err(e);
+                        }
+                        if staging_dir.exists() {
+                            remove_dir_all(staging_dir).context(format_err!(
+                                "Failed to remove partial snapshot directory {} before \
+                                 copying it again.",
+                                color_dir(&staging_dir.display())
+                            ))?;
+                        }
+                        copy_tree_parallel(source_dir, staging_dir, options, 1)
+                    }
+                }
+            }
+            jobs => copy_tree_parallel(source_dir, staging_dir, options, jobs.unwrap_or(1)),
+        },
+    )
+}
+
+/// Like `create`, but the contents of the snapshot are copied into its
+/// staging directory by the provided function. This allows tests to simulate
+/// copies which fail partway through.
+fn create_with<F>(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    parent: Option<&SnapName>,
+    dir_mode: Option<DirMode>,
+    copy: F,
+) -> Result<SnapDir, Error>
+where
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
     let snap_dir = &SnapDir::new(mzr_dir, snap_name);
+    // Serializes creation of the snapshot with other mzr processes, so that
+    // they don't both decide that it doesn't exist yet.
+    let _lock = registry::lock(
+        &format!("snapshot {}", snap_dir.display()),
+        &format!("taking snapshot {}", snap_dir.display()),
+    );
     if snap_dir.exists() {
         // TODO(friendliness): Should suggest "mzr rm" feature once it exists.
         bail!("A snapshot named {} already exists.", snap_name);
@@ -20,10 +665,67 @@ fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Resul
     let snap_parent = snap_dir
         .parent()
         .ok_or_else(|| format_err!("Unexpected error: snapshot directory must have a parent."))?;
-    create_dir_all(snap_parent).context(format_err!(
-        "Unexpected error while creating snapshot parent directory {}",
-        color_dir(&snap_parent.display())
-    ))?;
+    ensure_dir_with_mode(snap_parent, dir_mode)?;
+    if is_filesystem_full(snap_parent) {
+        bail!(
+            "Not taking snapshot {}, because the filesystem containing {} is full.",
+            snap_name,
+            color_dir(&snap_parent.display())
+        );
+    }
+    // The snapshot is copied to a staging directory and then renamed into
+    // place, so that an interrupted copy doesn't leave behind a snapshot which
+    // looks complete.
+    let staging_dir = snap_dir.staging_dir();
+    if staging_dir.exists() {
+        remove_dir_all(&staging_dir).context(format_err!(
+            "Failed to remove stale staging directory {}",
+            color_dir(&staging_dir.display())
+        ))?;
+    }
+    let copy_result = copy(&staging_dir)
+        .and_then(|()| match dir_mode {
+            None => Ok(()),
+            Some(mode) => set_dir_mode(&staging_dir, mode),
+        })
+        .and_then(|()| rename_dir_into_place(&staging_dir, snap_dir));
+    if let Err(e) = copy_result {
+        // This is checked before removing the partial snapshot, since
+        // removing it frees up space. Failures of `cp` are only reported via
+        // its exit code, so a full filesystem is taken to be the cause.
+        let out_of_space = is_out_of_space_error(&e) || is_filesystem_full(snap_parent);
+        // Only the staging directory is removed, since if the rename failed,
+        // then the snapshot directory belongs to another process.
+        if staging_dir.exists() {
+            remove_dir_all(&staging_dir).context(format_err!(
+                "Failed to remove partial snapshot directory {} after failing to copy it.",
+                color_dir(&staging_dir.display())
+            ))?;
+        }
+        if out_of_space {
+            bail!(
+                "Ran out of disk space while taking snapshot {}, so the partial snapshot \
+                 was removed.",
+                snap_name
+            );
+        }
+        return Err(e);
+    }
+    write_info(
+        mzr_dir,
+        snap_name,
+        &SnapInfo {
+            version: SnapInfo::VERSION,
+            parent: parent.cloned(),
+            creation_time: Utc::now(),
+            tags: BTreeMap::new(),
+        },
+    )?;
+    // TODO(cleanup): Can this clone be avoided?
+    Ok(snap_dir.clone())
+}
+
+fn copy_tree(source_dir: &Path, snap_dir: &Path, xattrs: XattrPolicy) -> Result<(), Error> {
     let mut cmd_base = Command::new("cp");
     let cmd = cmd_base
         .stdin(Stdio::null())
@@ -34,6 +736,9 @@ fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Resul
         // When using reflinks to make a snapshot, it's pretty comparable to
         // creating a tree of hardlinks, which tends to be much faster.
         .arg("--reflink=auto")
+        // Implied by `--archive`, but made explicit since following symlinks
+        // could copy files from outside the tree.
+        .arg("--no-dereference")
         // Don't clobber files. Shouldn't happen, since we check for destination
         // of the target. But if it does happen, then something funky is
         // happening and we should exit.
@@ -43,10 +748,1851 @@ fn create(source_dir: &PathBuf, mzr_dir: &MzrDir, snap_name: &SnapName) -> Resul
         // This makes it so that `cp` doesn't use its default behavior of
         // copying into the target directory if the destination is a directory.
         .arg("--no-target-directory")
+        .args(xattrs.cp_arg())
         // Source directory
         .arg(source_dir)
-        .arg(snap_dir.to_arg());
-    run_process(cmd)?;
-    // TODO(cleanup): Can this clone be avoided?
-    Ok(snap_dir.clone())
+        .arg(snap_dir);
+    run_process(cmd)
+}
+
+fn is_out_of_space_error(err: &Error) -> bool {
+    err.iter_chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .and_then(|io_err| io_err.raw_os_error())
+            == Some(libc::ENOSPC)
+    })
+}
+
+/// Returns `true` if there are no blocks or inodes available to unprivileged
+/// users on the filesystem containing the path.
+fn is_filesystem_full(path: &Path) -> bool {
+    match statvfs(path) {
+        Ok(stats) => is_full(
+            stats.blocks_available(),
+            stats.files(),
+            stats.files_available(),
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Filesystems which don't have a fixed number of inodes, such as btrfs and
+/// tmpfs, report zero inodes in total, so running out is only checked for
+/// when there is a total.
+fn is_full(blocks_available: u64, files: u64, files_available: u64) -> bool {
+    blocks_available == 0 || (files > 0 && files_available == 0)
+}
+
+/// Number of files copied by each `cp` invocation in parallel copies.
+const COPY_CHUNK_SIZE: usize = 256;
+
+/// Copies a tree using multiple worker threads, each of which runs `cp` on
+/// chunks of the file list. Directories are created up front so that files can
+/// be copied into them in any order. Their attributes are copied afterwards,
+/// deepest first, so that read-only directories and modification times are
+/// not disturbed by the copying of their contents.
+fn copy_tree_parallel(
+    source_dir: &Path,
+    snap_dir: &Path,
+    options: &CopyOptions,
+    jobs: usize,
+) -> Result<(), Error> {
+    let xattrs = options.xattrs;
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut special_files = Vec::new();
+    let mut sizes = Vec::new();
+    // Directories are yielded before their contents.
+    for walk_result in WalkDir::new(source_dir) {
+        let entry = walk_result?;
+        let rel_path = entry.path().strip_prefix(source_dir)?.to_path_buf();
+        if entry.file_type().is_dir() {
+            let target = snap_dir.join(&rel_path);
+            create_dir(&target).context(format_err!(
+                "Unexpected error while creating snapshot directory {}",
+                color_dir(&target.display())
+            ))?;
+            dirs.push(rel_path);
+        } else if is_special_file(entry.file_type()) {
+            special_files.push(rel_path);
+        } else {
+            if options.measure_sizes {
+                sizes.push(entry.metadata()?.len());
+            }
+            files.push(rel_path);
+        }
+    }
+    for rel_path in &special_files {
+        copy_special_file(
+            &source_dir.join(rel_path),
+            &snap_dir.join(rel_path),
+            options.strict_special_files,
+        )?;
+    }
+    let files = Arc::new(files);
+    let sizes = Arc::new(sizes);
+    let next_chunk = Arc::new(AtomicUsize::new(0));
+    let failed = Arc::new(AtomicBool::new(false));
+    let files_done = Arc::new(AtomicUsize::new(0));
+    let bytes_done = Arc::new(AtomicUsize::new(0));
+    let reporter = options.progress.map(|progress| {
+        let files_total = files.len() as u64;
+        let bytes_total = if options.measure_sizes {
+            Some(sizes.iter().sum::<u64>())
+        } else {
+            None
+        };
+        let files_done = files_done.clone();
+        let bytes_done = bytes_done.clone();
+        let failed = failed.clone();
+        let start = Instant::now();
+        thread::spawn(move || {
+            let mut last_report = start;
+            loop {
+                let done = files_done.load(Ordering::SeqCst) as u64;
+                if done >= files_total || failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    last_report = Instant::now();
+                    progress(&CopyProgress {
+                        files_done: done,
+                        files_total,
+                        bytes: bytes_total
+                            .map(|total| (bytes_done.load(Ordering::SeqCst) as u64, total)),
+                        elapsed: start.elapsed(),
+                    });
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        })
+    });
+    let mut workers = Vec::new();
+    for _ in 0..max(jobs, 1) {
+        let files = files.clone();
+        let sizes = sizes.clone();
+        let next_chunk = next_chunk.clone();
+        let failed = failed.clone();
+        let files_done = files_done.clone();
+        let bytes_done = bytes_done.clone();
+        let source_dir = source_dir.to_path_buf();
+        let snap_dir = snap_dir.to_path_buf();
+        workers.push(thread::spawn(move || -> Result<(), Error> {
+            while !failed.load(Ordering::SeqCst) {
+                let start = next_chunk.fetch_add(1, Ordering::SeqCst) * COPY_CHUNK_SIZE;
+                if start >= files.len() {
+                    break;
+                }
+                let end = min(start + COPY_CHUNK_SIZE, files.len());
+                let result = run_process(
+                    Command::new("cp")
+                        .current_dir(&source_dir)
+                        .stdin(Stdio::null())
+                        .arg("--archive")
+                        .arg("--reflink=auto")
+                        .arg("--no-dereference")
+                        .arg("--no-clobber")
+                        .args(xattrs.cp_arg())
+                        // Paths are relative to the source directory, and
+                        // this copies them to the same relative path within
+                        // the snapshot.
+                        .arg("--parents")
+                        .arg("--target-directory")
+                        .arg(&snap_dir)
+                        .arg("--")
+                        .args(&files[start..end]),
+                );
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                    return result;
+                }
+                files_done.fetch_add(end - start, Ordering::SeqCst);
+                if !sizes.is_empty() {
+                    let chunk_bytes: u64 = sizes[start..end].iter().sum();
+                    bytes_done.fetch_add(chunk_bytes as usize, Ordering::SeqCst);
+                }
+            }
+            Ok(())
+        }));
+    }
+    let mut worker_results = Vec::new();
+    for worker in workers {
+        worker_results.push(
+            worker
+                .join()
+                .map_err(|_| format_err!("Unexpected error: snapshot copy worker panicked.")),
+        );
+    }
+    // Workers which panic don't mark the copy as failed, so this makes sure
+    // that the reporter stops.
+    failed.store(true, Ordering::SeqCst);
+    if let Some(reporter) = reporter {
+        reporter
+            .join()
+            .map_err(|_| format_err!("Unexpected error: snapshot progress reporter panicked."))?;
+    }
+    for result in worker_results {
+        result??;
+    }
+    for rel_path in dirs.iter().rev() {
+        let (source, target) = (source_dir.join(rel_path), snap_dir.join(rel_path));
+        // Extended attributes are copied first, since setting them requires
+        // write access to the directory.
+        copy_dir_xattrs(&source, &target, xattrs)?;
+        copy_dir_attributes(&source, &target)?;
+    }
+    Ok(())
+}
+
+fn is_special_file(file_type: FileType) -> bool {
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_char_device()
+        || file_type.is_block_device()
+}
+
+fn has_special_files(dir: &Path) -> Result<bool, Error> {
+    for walk_result in WalkDir::new(dir) {
+        if is_special_file(walk_result?.file_type()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Recreates a fifo or device node. Sockets are skipped, since they are only
+/// meaningful to the process listening on them, and creating device nodes is
+/// usually not permitted for unprivileged users. Unless `strict` is set,
+/// files which can't be recreated are skipped with a warning.
+fn copy_special_file(source: &Path, target: &Path, strict: bool) -> Result<(), Error> {
+    let metadata = symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+    let mode = Mode::from_bits_truncate(metadata.mode());
+    let result = if file_type.is_socket() {
+        Err(format_err!("sockets can't be copied"))
+    } else {
+        let kind = if file_type.is_fifo() {
+            SFlag::S_IFIFO
+        } else if file_type.is_char_device() {
+            SFlag::S_IFCHR
+        } else {
+            SFlag::S_IFBLK
+        };
+        mknod(target, kind, mode, metadata.rdev())
+            .map_err(Error::from)
+            .and_then(|()| copy_dir_attributes(source, target))
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if strict => Err(e
+            .context(format_err!(
+                "Failed to copy special file {} into snapshot",
+                color_file(&source.display())
+            ))
+            .into()),
+        Err(e) => {
+            println!(
+                "{} Skipping special file {}: {}",
+                color_warn(&"Warning:"),
+                color_file(&source.display()),
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Copies the permissions, ownership, and timestamps of a directory or special
+/// file.
+fn copy_dir_attributes(source: &Path, target: &Path) -> Result<(), Error> {
+    // Setting attributes follows symlinks, so this refuses to modify whatever
+    // a symlink which replaced the target points to.
+    if symlink_metadata(target)?.file_type().is_symlink() {
+        bail!(
+            "Unexpected symlink at {}, while copying attributes of {}",
+            color_file(&target.display()),
+            color_file(&source.display())
+        );
+    }
+    let metadata = symlink_metadata(source)?;
+    set_permissions(target, metadata.permissions())?;
+    // Like `cp --archive`, failure to preserve ownership is ignored, since
+    // it's expected when not running as root.
+    let _ = chown(
+        target,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+    );
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let c_path = CString::new(target.as_os_str().as_bytes())?;
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        Err(io::Error::last_os_error()).context(format_err!(
+            "Unexpected error while setting timestamps of {}",
+            color_dir(&target.display())
+        ))?;
+    }
+    Ok(())
+}
+
+/// Copies the extended attributes of a directory, according to the policy.
+/// Like `cp --archive`, failures are ignored with `PreserveIfPossible`.
+fn copy_dir_xattrs(source: &Path, target: &Path, xattrs: XattrPolicy) -> Result<(), Error> {
+    let result = match xattrs {
+        XattrPolicy::Strip => return Ok(()),
+        XattrPolicy::PreserveIfPossible | XattrPolicy::Preserve => {
+            copy_xattrs_unchecked(source, target)
+        }
+    };
+    match result {
+        Err(_) if xattrs == XattrPolicy::PreserveIfPossible => Ok(()),
+        result => Ok(result.context(format_err!(
+            "Failed to copy extended attributes of {} into snapshot",
+            color_dir(&source.display())
+        ))?),
+    }
+}
+
+fn copy_xattrs_unchecked(source: &Path, target: &Path) -> Result<(), io::Error> {
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+    let names = read_xattr_buffer(|buffer, size| unsafe {
+        libc::llistxattr(source.as_ptr(), buffer as *mut libc::c_char, size)
+    })?;
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let name = CString::new(name)?;
+        let value = read_xattr_buffer(|buffer, size| unsafe {
+            libc::lgetxattr(source.as_ptr(), name.as_ptr(), buffer, size)
+        })?;
+        let result = unsafe {
+            libc::lsetxattr(
+                target.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Calls an xattr syscall which reads into a buffer, first calling it with a
+/// size of zero to find out how large the buffer needs to be. This is retried
+/// if the value grows in between.
+fn read_xattr_buffer<F>(read: F) -> Result<Vec<u8>, io::Error>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let len = read(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if len >= 0 {
+            buffer.truncate(len as usize);
+            return Ok(buffer);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// Makes the mzr directory's snapshots directory a symlink to the directory
+/// configured via `snapshot_dir`, creating it if necessary. Snapshots which
+/// are already stored elsewhere are not moved, since they may be large.
+pub fn link_snaps_dir(mzr_dir: &MzrDir, snapshot_dir: &Path) -> Result<(), Error> {
+    if !snapshot_dir.is_absolute() {
+        bail!(
+            "The snapshot_dir setting must be an absolute path, but it is {}.",
+            color_dir(&snapshot_dir.display())
+        );
+    }
+    let snaps_dir = SnapsDir::new(mzr_dir);
+    match symlink_metadata(&snaps_dir) {
+        Ok(ref metadata) if metadata.file_type().is_symlink() => {
+            let link_target = read_link(&snaps_dir).context(format_err!(
+                "Failed to read symlink {}",
+                color_dir(&snaps_dir.display())
+            ))?;
+            if link_target != snapshot_dir {
+                bail!(
+                    "{} is a symlink to {}, but snapshot_dir is {}. Change or remove the \
+                     symlink to use snapshot_dir.",
+                    color_dir(&snaps_dir.display()),
+                    color_dir(&link_target.display()),
+                    color_dir(&snapshot_dir.display())
+                );
+            }
+            return Ok(());
+        }
+        Ok(ref metadata) if metadata.is_dir() => {
+            if !list_subdir_names(&snaps_dir)?.is_empty() {
+                bail!(
+                    "Snapshots are stored in {}, but snapshot_dir is {}. Move them there \
+                     and remove {} to use snapshot_dir.",
+                    color_dir(&snaps_dir.display()),
+                    color_dir(&snapshot_dir.display()),
+                    color_dir(&snaps_dir.display())
+                );
+            }
+            fs::remove_dir(&snaps_dir).context(format_err!(
+                "Failed to remove empty snapshots directory {}",
+                color_dir(&snaps_dir.display())
+            ))?;
+        }
+        _ => {}
+    }
+    ensure_dir(snapshot_dir)?;
+    symlink(snapshot_dir, &snaps_dir).context(format_err!(
+        "Failed to create symlink from {} to snapshot_dir {}",
+        color_dir(&snaps_dir.display()),
+        color_dir(&snapshot_dir.display())
+    ))?;
+    Ok(())
+}
+
+/// Lists the names of all snapshots. The staging directories of snapshots
+/// being taken are skipped.
+pub fn list_snaps(mzr_dir: &MzrDir) -> Result<Vec<SnapName>, Error> {
+    list_subdir_names(SnapsDir::new(mzr_dir))?
+        .into_iter()
+        .filter(|name| !is_staging_dir_name(name))
+        .map(SnapName::new)
+        .collect()
+}
+
+/// Whether a directory name has the form of a snapshot's staging directory,
+/// `.SNAP.tmp-PID` - see `SnapDir::staging_dir`.
+fn is_staging_dir_name(name: &str) -> bool {
+    let (snap_name, pid) = match name.rfind(".tmp-") {
+        Some(index) => (&name[..index], &name[index + ".tmp-".len()..]),
+        None => return false,
+    };
+    snap_name.len() > 1
+        && snap_name.starts_with('.')
+        && !pid.is_empty()
+        && pid.chars().all(|c| c.is_ascii_digit())
+}
+
+/// When a snapshot doesn't exist, this yields a hint suggesting a snapshot
+/// with a similar name, or an empty string if there isn't one.
+pub fn did_you_mean_snap(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<String, Error> {
+    let snap_names = list_snaps(mzr_dir)?;
+    Ok(
+        match closest_match(snap_name, snap_names.iter().map(|x| x.as_str())) {
+            None => String::new(),
+            Some(suggestion) => format!(" Did you mean {}?", color_snap_name(&suggestion)),
+        },
+    )
+}
+
+/*
+ * Exporting and importing snapshots
+ */
+
+/// Writes the contents of a snapshot to a tar archive.
+pub fn export(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    archive_path: &Path,
+    compression: Compression,
+) -> Result<(), Error> {
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            snap_name,
+            did_you_mean_snap(mzr_dir, snap_name)?
+        );
+    }
+    archive::create(&snap_dir, archive_path, compression)
+}
+
+/// Streams a snapshot as a tar archive to a file on a remote host, via ssh.
+pub fn export_ssh(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    destination: &SshDestination,
+    compression: Compression,
+) -> Result<(), Error> {
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            snap_name,
+            did_you_mean_snap(mzr_dir, snap_name)?
+        );
+    }
+    archive::create_streamed(&snap_dir, &mut destination.command(), compression).context(
+        format_err!("Failed to export snapshot to {}", color_file(destination)),
+    )?;
+    Ok(())
+}
+
+/// Creates a snapshot from a tar archive, which may be compressed. Like
+/// `create`, the archive is extracted into a staging directory which is then
+/// renamed into place, so that a failed or interrupted import doesn't leave
+/// behind a snapshot which looks complete.
+pub fn import(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    archive_path: &Path,
+    allow_absolute_symlinks: bool,
+    dir_mode: Option<DirMode>,
+) -> Result<SnapDir, Error> {
+    // The archive's root entry sets the mode of the snapshot directory, so
+    // the configured mode gets applied after extraction.
+    create_with(mzr_dir, snap_name, None, dir_mode, |staging_dir| {
+        create_dir(staging_dir).context(format_err!(
+            "Failed to create staging directory {}",
+            color_dir(&staging_dir.display())
+        ))?;
+        archive::extract(archive_path, staging_dir, allow_absolute_symlinks)
+    })
+}
+
+/// Permanently deletes a snapshot along with its info file. Zones based on the
+/// snapshot would be left broken, so this is only for snapshots which are
+/// known to be unused, such as temporary ones.
+pub fn delete(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    remove_dir_all(&snap_dir).context(format_err!(
+        "Unexpected error while removing snapshot directory {}",
+        snap_dir
+    ))?;
+    let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+    if info_file.exists() {
+        remove_file(&info_file).context(format_err!(
+            "Unexpected error while removing snapshot info file {}",
+            info_file
+        ))?;
+    }
+    Ok(())
+}
+
+/*
+ * Snapshot info
+ */
+
+fn write_info(mzr_dir: &MzrDir, snap_name: &SnapName, info: &SnapInfo) -> Result<(), Error> {
+    let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+    if let Some(parent) = info_file.parent() {
+        ensure_dir(parent)?;
+    }
+    json::write(&info_file, info)
+}
+
+/// Reads the snapshot's info, or `None` if it has no info file.
+pub fn read_info(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<Option<SnapInfo>, Error> {
+    let info_file = SnapInfoFile::new(mzr_dir, snap_name);
+    if info_file.exists() {
+        Ok(Some(json::read_versioned(&info_file)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Like `read_info`, but for snapshots without an info file, yields info
+/// based on the snapshot directory's modification time.
+fn read_or_default_info(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapInfo, Error> {
+    match read_info(mzr_dir, snap_name)? {
+        Some(info) => Ok(info),
+        None => {
+            let snap_dir = SnapDir::new(mzr_dir, snap_name);
+            let modified = snap_dir
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .context(format_err!(
+                    "Failed to get modification time of snapshot directory {}",
+                    snap_dir
+                ))?;
+            Ok(SnapInfo {
+                version: SnapInfo::VERSION,
+                parent: None,
+                creation_time: DateTime::from(modified),
+                tags: BTreeMap::new(),
+            })
+        }
+    }
+}
+
+/*
+ * Snapshot tags
+ */
+
+/// Tag keys can't be empty, or contain `=` or control characters, so that
+/// they can be specified and printed as `KEY=VALUE`.
+pub fn validate_tag_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() {
+        bail!("Snapshot tag keys can't be empty.");
+    }
+    if key.chars().any(|c| c == '=' || c.is_control()) {
+        bail!(
+            "Snapshot tag key {:?} is invalid, since it contains '=' or control characters.",
+            key
+        );
+    }
+    Ok(())
+}
+
+fn check_snap_exists(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+    if !SnapDir::new(mzr_dir, snap_name).is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            snap_name,
+            did_you_mean_snap(mzr_dir, snap_name)?
+        );
+    }
+    Ok(())
+}
+
+pub fn get_tags(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<BTreeMap<String, String>, Error> {
+    check_snap_exists(mzr_dir, snap_name)?;
+    Ok(read_info(mzr_dir, snap_name)?
+        .map(|info| info.tags)
+        .unwrap_or_default())
+}
+
+pub fn set_tag(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    key: &str,
+    value: &str,
+) -> Result<(), Error> {
+    validate_tag_key(key)?;
+    check_snap_exists(mzr_dir, snap_name)?;
+    let mut info = read_or_default_info(mzr_dir, snap_name)?;
+    info.tags.insert(key.to_string(), value.to_string());
+    write_info(mzr_dir, snap_name, &info)
+}
+
+/// Removes a tag from a snapshot, yielding `false` if it didn't have the tag.
+pub fn remove_tag(mzr_dir: &MzrDir, snap_name: &SnapName, key: &str) -> Result<bool, Error> {
+    check_snap_exists(mzr_dir, snap_name)?;
+    match read_info(mzr_dir, snap_name)? {
+        None => Ok(false),
+        Some(mut info) => {
+            if info.tags.remove(key).is_none() {
+                return Ok(false);
+            }
+            write_info(mzr_dir, snap_name, &info)?;
+            Ok(true)
+        }
+    }
+}
+
+/// Lists the snapshots which have a tag with the specified key, and if
+/// specified, value.
+pub fn list_snaps_with_tag(
+    mzr_dir: &MzrDir,
+    key: &str,
+    value: Option<&str>,
+) -> Result<Vec<SnapName>, Error> {
+    let mut result = Vec::new();
+    for snap_name in list_snaps(mzr_dir)? {
+        let tags = read_info(mzr_dir, &snap_name)?
+            .map(|info| info.tags)
+            .unwrap_or_default();
+        let matches = match (tags.get(key), value) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(actual), Some(expected)) => actual == expected,
+        };
+        if matches {
+            result.push(snap_name);
+        }
+    }
+    Ok(result)
+}
+
+/*
+ * Snapshot lineage
+ */
+
+/// Node of the tree of snapshots, where children are snapshots derived from
+/// their parent.
+#[derive(Debug, Serialize)]
+pub struct LineageNode {
+    pub name: SnapName,
+    pub children: Vec<LineageNode>,
+}
+
+/// Builds the lineage trees of all snapshots. Snapshots without a recorded
+/// parent, or whose parent no longer exists, are the roots.
+pub fn lineage(mzr_dir: &MzrDir) -> Result<Vec<LineageNode>, Error> {
+    let snap_names = list_snaps(mzr_dir)?;
+    let mut parents = HashMap::new();
+    for snap_name in &snap_names {
+        if let Some(SnapInfo {
+            parent: Some(parent),
+            ..
+        }) = read_info(mzr_dir, snap_name)?
+        {
+            if snap_names.contains(&parent) {
+                parents.insert(snap_name.clone(), parent);
+            }
+        }
+    }
+    check_lineage_acyclic(&parents)?;
+    let mut children: HashMap<&SnapName, Vec<&SnapName>> = HashMap::new();
+    let mut roots = Vec::new();
+    for snap_name in &snap_names {
+        match parents.get(snap_name) {
+            None => roots.push(snap_name),
+            Some(parent) => children.entry(parent).or_default().push(snap_name),
+        }
+    }
+    Ok(roots
+        .into_iter()
+        .map(|root| lineage_node(root, &children))
+        .collect())
+}
+
+fn check_lineage_acyclic(parents: &HashMap<SnapName, SnapName>) -> Result<(), Error> {
+    for start in parents.keys() {
+        let mut visited = HashSet::new();
+        let mut current = start;
+        while let Some(parent) = parents.get(current) {
+            if !visited.insert(current) {
+                bail!(
+                    "Snapshot parents form a cycle involving the {} snapshot.",
+                    current
+                );
+            }
+            current = parent;
+        }
+    }
+    Ok(())
+}
+
+fn lineage_node(
+    snap_name: &SnapName,
+    children: &HashMap<&SnapName, Vec<&SnapName>>,
+) -> LineageNode {
+    LineageNode {
+        name: snap_name.clone(),
+        children: children
+            .get(snap_name)
+            .map(|names| {
+                names
+                    .iter()
+                    .map(|child| lineage_node(child, children))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// The snapshot a zone is based on, along with the snapshots it was derived
+/// from.
+#[derive(Debug, Serialize)]
+pub struct SnapChain {
+    /// The zone's snapshot followed by its ancestors, nearest first. Since
+    /// snapshots are full copies, only the first is needed to mount the zone.
+    pub snaps: Vec<SnapName>,
+    /// Snapshot which is recorded as the parent of the last snapshot in the
+    /// chain, or as the zone's snapshot if the chain is empty, but which no
+    /// longer exists.
+    pub missing: Option<SnapName>,
+}
+
+/// Follows the recorded parents of the zone's snapshot, stopping at a
+/// snapshot without a recorded parent or at a parent which no longer exists.
+pub fn snapshot_chain(mzr_dir: &MzrDir, zone: &Zone) -> Result<SnapChain, Error> {
+    let mut snaps: Vec<SnapName> = Vec::new();
+    let mut next = Some(zone.info.snapshot.clone());
+    while let Some(snap_name) = next {
+        if !SnapDir::new(mzr_dir, &snap_name).is_dir() {
+            return Ok(SnapChain {
+                snaps,
+                missing: Some(snap_name),
+            });
+        }
+        if snaps.contains(&snap_name) {
+            bail!(
+                "Snapshot parents form a cycle involving the {} snapshot.",
+                snap_name
+            );
+        }
+        next = read_info(mzr_dir, &snap_name)?.and_then(|info| info.parent);
+        snaps.push(snap_name);
+    }
+    Ok(SnapChain {
+        snaps,
+        missing: None,
+    })
+}
+
+/// Renders lineage trees in a style similar to the `tree` command.
+pub fn render_lineage(roots: &[LineageNode]) -> String {
+    let mut result = String::new();
+    for root in roots {
+        result.push_str(&format!("{}\n", root.name));
+        render_lineage_children(&root.children, "", &mut result);
+    }
+    result
+}
+
+fn render_lineage_children(nodes: &[LineageNode], prefix: &str, result: &mut String) {
+    for (index, node) in nodes.iter().enumerate() {
+        let is_last = index + 1 == nodes.len();
+        let (branch, continuation) = if is_last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        result.push_str(&format!("{}{}{}\n", prefix, branch, node.name));
+        render_lineage_children(
+            &node.children,
+            &format!("{}{}", prefix, continuation),
+            result,
+        );
+    }
+}
+
+/*
+ * Listing snapshot contents
+ */
+
+/// Entry in the tree of a snapshot's files.
+#[derive(Debug, Serialize)]
+pub struct FileNode {
+    pub name: String,
+    pub kind: FileKind,
+    /// Size of regular files, in bytes.
+    pub size: Option<u64>,
+    /// Entries within a directory. Empty when the directory is beyond the
+    /// depth limit.
+    pub children: Vec<FileNode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Dir,
+    File,
+    Symlink,
+    Other,
+}
+
+/// Names of directories whose contents are metadata of other tools, and so
+/// are not listed by `list_snapshot_tree`.
+const METADATA_DIR_NAMES: [&str; 1] = [".git"];
+
+/// Lists the files within a snapshot, without needing to mount it. Entries
+/// deeper than `max_depth` are omitted, where entries at the root of the
+/// snapshot have depth 1. The contents of metadata directories like `.git`
+/// are omitted.
+pub fn list_snapshot_tree(
+    mzr_dir: &MzrDir,
+    snap_name: &SnapName,
+    max_depth: Option<usize>,
+) -> Result<Vec<FileNode>, Error> {
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            snap_name,
+            did_you_mean_snap(mzr_dir, snap_name)?
+        );
+    }
+    list_file_nodes(&snap_dir, 1, max_depth)
+}
+
+fn list_file_nodes(
+    dir: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+) -> Result<Vec<FileNode>, Error> {
+    if max_depth.is_some_and(|max_depth| depth > max_depth) {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for entry in dir.read_dir().context(format_err!(
+        "Failed to read directory {}",
+        color_dir(&dir.display())
+    ))? {
+        entries.push(entry?);
+    }
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut result = Vec::new();
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        let node = if file_type.is_dir() {
+            let children = if METADATA_DIR_NAMES.contains(&name.as_str()) {
+                Vec::new()
+            } else {
+                list_file_nodes(&entry.path(), depth + 1, max_depth)?
+            };
+            FileNode {
+                name,
+                kind: FileKind::Dir,
+                size: None,
+                children,
+            }
+        } else {
+            let (kind, size) = if file_type.is_file() {
+                (FileKind::File, Some(entry.metadata()?.len()))
+            } else if file_type.is_symlink() {
+                (FileKind::Symlink, None)
+            } else {
+                (FileKind::Other, None)
+            };
+            FileNode {
+                name,
+                kind,
+                size,
+                children: Vec::new(),
+            }
+        };
+        result.push(node);
+    }
+    Ok(result)
+}
+
+/// Renders a snapshot's file tree in a style similar to the `tree` command.
+pub fn render_file_tree(nodes: &[FileNode], sizes: bool) -> String {
+    let mut result = String::new();
+    render_file_tree_children(nodes, "", sizes, &mut result);
+    result
+}
+
+fn render_file_tree_children(nodes: &[FileNode], prefix: &str, sizes: bool, result: &mut String) {
+    for (index, node) in nodes.iter().enumerate() {
+        let is_last = index + 1 == nodes.len();
+        let (branch, continuation) = if is_last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        let suffix = match (node.kind, node.size) {
+            (FileKind::Dir, _) => "/".to_string(),
+            (_, Some(size)) if sizes => format!(" ({})", format_bytes(size)),
+            _ => String::new(),
+        };
+        result.push_str(&format!("{}{}{}{}\n", prefix, branch, node.name, suffix));
+        render_file_tree_children(
+            &node.children,
+            &format!("{}{}", prefix, continuation),
+            sizes,
+            result,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{diff_trees, Comparison};
+    use crate::error_report::strip_ansi_escapes;
+    use crate::namespaces;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, write_files, TestDir};
+    use libmount::BindMount;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn snap_name(name: &str) -> SnapName {
+        SnapName::new(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn create_applies_dir_mode_to_snapshot_root() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let source_dir = dir.join("proj");
+        ensure_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("file"), "contents").unwrap();
+        set_permissions(&source_dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let mode_of = |path: &Path| symlink_metadata(path).unwrap().mode() & 0o7777;
+        let snap_name = |name: &str| SnapName::new(name.to_string()).unwrap();
+        let options = CopyOptions {
+            dir_mode: Some(DirMode(0o755)),
+            ..CopyOptions::default()
+        };
+        let snap_dir = create(&source_dir, &mzr_dir, &snap_name("s1"), None, &options).unwrap();
+        assert_eq!(mode_of(&snap_dir), 0o755);
+        assert_eq!(mode_of(&SnapsDir::new(&mzr_dir)), 0o755);
+        assert!(snap_dir.join("file").is_file());
+        let snap_dir = create(
+            &source_dir,
+            &mzr_dir,
+            &snap_name("s2"),
+            None,
+            &CopyOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(mode_of(&snap_dir), 0o700);
+    }
+
+    #[test]
+    fn out_of_space_during_copy_removes_partial_snapshot() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = SnapName::new("s1".to_string()).unwrap();
+        let result = create_with(&mzr_dir, &snap_name, None, None, |staging_dir| {
+            ensure_dir(staging_dir.join("subdir"))?;
+            fs::write(staging_dir.join("subdir/file"), "partial")?;
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+                .context("Failed to copy file")
+                .map_err(Error::from)
+        });
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("Ran out of disk space"));
+        let snap_dir = SnapDir::new(&mzr_dir, &snap_name);
+        assert!(!snap_dir.exists());
+        assert!(!snap_dir.staging_dir().exists());
+        assert!(read_info(&mzr_dir, &snap_name).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_copy_keeps_original_error() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = SnapName::new("s1".to_string()).unwrap();
+        let result = create_with(&mzr_dir, &snap_name, None, None, |_| {
+            Err(format_err!("cp failed"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "cp failed");
+        assert!(!SnapDir::new(&mzr_dir, &snap_name).exists());
+    }
+
+    #[test]
+    fn filesystem_full_checks_inodes_only_when_limited() {
+        assert!(is_full(0, 1000, 10));
+        assert!(is_full(10, 1000, 0));
+        assert!(!is_full(10, 1000, 10));
+        // btrfs and tmpfs report no inodes at all.
+        assert!(!is_full(10, 0, 0));
+    }
+
+    #[test]
+    fn link_snaps_dir_creates_symlink() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        ensure_dir(SnapsDir::new(&mzr_dir)).unwrap();
+        let snapshot_dir = dir.join("snaps");
+        link_snaps_dir(&mzr_dir, &snapshot_dir).unwrap();
+        assert_eq!(read_link(SnapsDir::new(&mzr_dir)).unwrap(), snapshot_dir);
+        assert!(snapshot_dir.is_dir());
+        // Linking again is fine, but linking elsewhere is not.
+        link_snaps_dir(&mzr_dir, &snapshot_dir).unwrap();
+        assert!(link_snaps_dir(&mzr_dir, &dir.join("other")).is_err());
+    }
+
+    #[test]
+    fn link_snaps_dir_keeps_existing_snapshots() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_dir = SnapDir::new(&mzr_dir, &SnapName::new("s1".to_string()).unwrap());
+        ensure_dir(&snap_dir).unwrap();
+        assert!(link_snaps_dir(&mzr_dir, &dir.join("snaps")).is_err());
+        assert!(snap_dir.is_dir());
+    }
+
+    #[test]
+    fn link_snaps_dir_requires_absolute_path() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        assert!(link_snaps_dir(&mzr_dir, Path::new("snaps")).is_err());
+    }
+
+    #[test]
+    fn parallel_copy_applies_xattr_policy_to_directories() {
+        let dir = TestDir::new();
+        let source_dir = dir.join("source");
+        ensure_dir(source_dir.join("sub")).unwrap();
+        let c_path = CString::new(source_dir.join("sub").as_os_str().as_bytes()).unwrap();
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                CString::new("user.mzr-test").unwrap().as_ptr(),
+                b"value".as_ptr() as *const libc::c_void,
+                5,
+                0,
+            )
+        };
+        if result < 0 {
+            // The temporary directory's filesystem doesn't support user
+            // extended attributes.
+            return;
+        }
+        for (name, xattrs, expected) in &[
+            ("preserved", XattrPolicy::Preserve, Some(b"value".to_vec())),
+            ("stripped", XattrPolicy::Strip, None),
+        ] {
+            let snap_dir = dir.join(name);
+            let options = CopyOptions {
+                xattrs: *xattrs,
+                ..CopyOptions::default()
+            };
+            copy_tree_parallel(&source_dir, &snap_dir, &options, 1).unwrap();
+            let value = changes::get_xattr(&snap_dir.join("sub"), "user.mzr-test").unwrap();
+            assert_eq!(&value, expected);
+        }
+    }
+
+    #[test]
+    fn parallel_copy_copies_all_files_with_many_workers() {
+        let dir = TestDir::new();
+        let source_dir = dir.join("source");
+        let files: Vec<(String, String)> = (0..60)
+            .map(|i| {
+                (
+                    format!("d{}/sub{}/f{}", i % 3, i % 5, i),
+                    format!("contents {}", i),
+                )
+            })
+            .collect();
+        let file_refs: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(path, contents)| (path.as_str(), contents.as_str()))
+            .collect();
+        write_files(&source_dir, &file_refs);
+        write_files(
+            &source_dir,
+            &[("read-only/file", "kept"), ("script", "#!/bin/sh")],
+        );
+        symlink("d0/sub0/f0", source_dir.join("link")).unwrap();
+        set_permissions(source_dir.join("script"), fs::Permissions::from_mode(0o755)).unwrap();
+        set_permissions(
+            source_dir.join("read-only"),
+            fs::Permissions::from_mode(0o555),
+        )
+        .unwrap();
+        let snap_dir = dir.join("snap");
+        copy_tree_parallel(&source_dir, &snap_dir, &CopyOptions::default(), 4).unwrap();
+        for comparison in &[Comparison::Metadata, Comparison::Contents] {
+            let diff = diff_trees(&source_dir, &snap_dir, *comparison).unwrap();
+            assert!(diff.added.is_empty(), "{:?}", diff);
+            assert!(diff.removed.is_empty(), "{:?}", diff);
+            assert!(diff.modified.is_empty(), "{:?}", diff);
+        }
+        assert_eq!(
+            fs::read_to_string(snap_dir.join("d2/sub4/f59")).unwrap(),
+            "contents 59"
+        );
+        assert_eq!(
+            read_link(snap_dir.join("link")).unwrap(),
+            PathBuf::from("d0/sub0/f0")
+        );
+        let mode_of = |path: &Path| symlink_metadata(path).unwrap().mode() & 0o7777;
+        assert_eq!(mode_of(&snap_dir.join("script")), 0o755);
+        assert_eq!(mode_of(&snap_dir.join("read-only")), 0o555);
+        // Allow the test directory to be removed.
+        set_permissions(
+            source_dir.join("read-only"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        set_permissions(
+            snap_dir.join("read-only"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn unsupported_xattrs_are_only_skipped_if_possible() {
+        let dir = TestDir::new();
+        let source_dir = dir.join("source");
+        ensure_dir(&source_dir).unwrap();
+        let c_path = CString::new(source_dir.as_os_str().as_bytes()).unwrap();
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                CString::new("user.mzr-test").unwrap().as_ptr(),
+                b"value".as_ptr() as *const libc::c_void,
+                5,
+                0,
+            )
+        };
+        if result < 0 {
+            // The temporary directory's filesystem doesn't support user
+            // extended attributes.
+            return;
+        }
+        // Symlinks can't have user extended attributes, so they stand in for a
+        // target filesystem which doesn't support them.
+        let target = dir.join("target");
+        symlink(&source_dir, &target).unwrap();
+        assert!(copy_dir_xattrs(&source_dir, &target, XattrPolicy::Preserve).is_err());
+        copy_dir_xattrs(&source_dir, &target, XattrPolicy::PreserveIfPossible).unwrap();
+        copy_dir_xattrs(&source_dir, &target, XattrPolicy::Strip).unwrap();
+        assert_eq!(XattrPolicy::default(), XattrPolicy::PreserveIfPossible);
+        assert_eq!(XattrPolicy::PreserveIfPossible.cp_arg(), None);
+        assert_eq!(XattrPolicy::Strip.cp_arg(), Some("--no-preserve=xattr"));
+        assert_eq!("strip".parse::<XattrPolicy>().unwrap(), XattrPolicy::Strip);
+        assert!("keep".parse::<XattrPolicy>().is_err());
+    }
+
+    #[test]
+    fn only_staging_dirs_are_hidden_from_snapshot_list() {
+        assert!(is_staging_dir_name(".s1.tmp-123"));
+        assert!(is_staging_dir_name(".s1.tmp-2.tmp-45"));
+        assert!(!is_staging_dir_name(".s1"));
+        assert!(!is_staging_dir_name(".tmp-123"));
+        assert!(!is_staging_dir_name(".s1.tmp-"));
+        assert!(!is_staging_dir_name(".s1.tmp-12a"));
+        assert!(!is_staging_dir_name("s1.tmp-123"));
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snaps_dir = SnapsDir::new(&mzr_dir);
+        for name in &["s1", ".hidden", ".s2.tmp-123"] {
+            ensure_dir(snaps_dir.join(name)).unwrap();
+        }
+        let mut snap_names: Vec<String> = list_snaps(&mzr_dir)
+            .unwrap()
+            .into_iter()
+            .map(|name| (*name).clone())
+            .collect();
+        snap_names.sort();
+        assert_eq!(snap_names, vec![".hidden", "s1"]);
+    }
+
+    #[test]
+    fn name_templates_expand_variables() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let zone_name = ZoneName::new("mz".to_string()).unwrap();
+        let expand = |template: &str, zone: Option<&ZoneName>| {
+            expand_name_template(&mzr_dir, template, zone).map(|name| (*name).clone())
+        };
+        let date = Utc::now().format("%Y%m%d").to_string();
+        assert_eq!(
+            expand("{zone}-{date}", Some(&zone_name)).unwrap(),
+            format!("mz-{}", date)
+        );
+        assert_eq!(expand("{{seq}}-{seq}", None).unwrap(), "{seq}-1");
+        for seq in 1..3 {
+            let name = SnapName::new(format!("run-{}", seq)).unwrap();
+            ensure_dir(SnapDir::new(&mzr_dir, &name)).unwrap();
+        }
+        assert_eq!(expand("run-{seq}", None).unwrap(), "run-3");
+        // Without {seq}, existing snapshots are left for the caller to report.
+        assert_eq!(expand("run-1", None).unwrap(), "run-1");
+        assert!(expand("{zone}", None).is_err());
+        assert!(expand("{time}", None).is_err());
+        assert!(expand("run-{seq", None).is_err());
+        assert!(expand("run}", None).is_err());
+        assert!(expand("a/{seq}", None).is_err());
+    }
+
+    #[test]
+    fn import_stages_snapshot_before_renaming_it_into_place() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let source = test_snapshot(&mzr_dir, "source", &[("dir/file", "contents")]);
+        let archive_path = dir.join("source.tar");
+        export(&mzr_dir, &source, &archive_path, Compression::None).unwrap();
+        let imported = snap_name("imported");
+        let snap_dir = import(&mzr_dir, &imported, &archive_path, false, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(snap_dir.join("dir/file")).unwrap(),
+            "contents"
+        );
+        assert!(!snap_dir.staging_dir().exists());
+        assert!(read_info(&mzr_dir, &imported).unwrap().is_some());
+        assert!(import(&mzr_dir, &imported, &archive_path, false, None).is_err());
+        // A malicious archive is refused without leaving anything behind.
+        let malicious_dir = dir.join("malicious");
+        write_files(&malicious_dir, &[("file", "")]);
+        symlink("../../outside", malicious_dir.join("link")).unwrap();
+        let malicious_path = dir.join("malicious.tar");
+        archive::create(&malicious_dir, &malicious_path, Compression::None).unwrap();
+        let refused = snap_name("refused");
+        assert!(import(&mzr_dir, &refused, &malicious_path, false, None).is_err());
+        let refused_dir = SnapDir::new(&mzr_dir, &refused);
+        assert!(!refused_dir.exists());
+        assert!(!refused_dir.staging_dir().exists());
+        assert!(read_info(&mzr_dir, &refused).unwrap().is_none());
+    }
+
+    #[test]
+    fn zstd_exports_round_trip() {
+        if Command::new("zstd").arg("--version").output().is_err() {
+            // zstd isn't installed.
+            return;
+        }
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let contents = "compressible ".repeat(1000);
+        let source = test_snapshot(
+            &mzr_dir,
+            "source",
+            &[("dir/file", &contents), ("empty", "")],
+        );
+        let archive_path = dir.join("source.tar.zst");
+        let compression = Compression::zstd(19).unwrap();
+        export(&mzr_dir, &source, &archive_path, compression).unwrap();
+        assert!(fs::metadata(&archive_path).unwrap().len() < contents.len() as u64);
+        match Compression::detect(&archive_path).unwrap() {
+            Compression::Zstd { .. } => {}
+            other => panic!("Expected zstd compression, but detected {:?}", other),
+        }
+        let imported =
+            import(&mzr_dir, &snap_name("imported"), &archive_path, false, None).unwrap();
+        let diff = diff_trees(
+            SnapDir::new(&mzr_dir, &source),
+            &imported,
+            Comparison::Contents,
+        )
+        .unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+        assert!(Compression::zstd(0).is_err());
+        assert!(Compression::zstd(archive::MAX_ZSTD_LEVEL + 1).is_err());
+        let short = dir.join("short");
+        fs::write(&short, [0x28, 0xb5]).unwrap();
+        match Compression::detect(&short).unwrap() {
+            Compression::None => {}
+            other => panic!("Expected no compression, but detected {:?}", other),
+        }
+    }
+
+    /// Records the parent of a test snapshot.
+    fn set_parent(mzr_dir: &MzrDir, child: &SnapName, parent: &SnapName) {
+        write_info(
+            mzr_dir,
+            child,
+            &SnapInfo {
+                version: SnapInfo::VERSION,
+                parent: Some(parent.clone()),
+                creation_time: Utc::now(),
+                tags: BTreeMap::new(),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn lineage_builds_tree_of_snapshots() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let names: Vec<SnapName> = ["a", "b", "c", "d", "orphan"]
+            .iter()
+            .map(|name| test_snapshot(&mzr_dir, name, &[]))
+            .collect();
+        set_parent(&mzr_dir, &names[1], &names[0]);
+        set_parent(&mzr_dir, &names[2], &names[0]);
+        set_parent(&mzr_dir, &names[3], &names[1]);
+        // Snapshots whose parent no longer exists are roots.
+        set_parent(&mzr_dir, &names[4], &snap_name("deleted"));
+        let mut roots = lineage(&mzr_dir).unwrap();
+        roots.sort_by(|x, y| x.name.cmp(&y.name));
+        for root in &mut roots {
+            root.children.sort_by(|x, y| x.name.cmp(&y.name));
+        }
+        let rendered = strip_ansi_escapes(&render_lineage(&roots));
+        assert_eq!(rendered, "a\n├── b\n│   └── d\n└── c\norphan\n");
+    }
+
+    #[test]
+    fn lineage_detects_cycles() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let a = test_snapshot(&mzr_dir, "a", &[]);
+        let b = test_snapshot(&mzr_dir, "b", &[]);
+        let c = test_snapshot(&mzr_dir, "c", &[]);
+        set_parent(&mzr_dir, &a, &b);
+        set_parent(&mzr_dir, &b, &c);
+        assert!(lineage(&mzr_dir).is_ok());
+        set_parent(&mzr_dir, &c, &a);
+        let err = lineage(&mzr_dir).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+
+    #[test]
+    fn duplicate_copies_snapshot_and_records_parent() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let source = test_snapshot(&mzr_dir, "source", &[("dir/file", "contents")]);
+        let source_dir = SnapDir::new(&mzr_dir, &source);
+        symlink("dir/file", source_dir.join("link")).unwrap();
+        mknod(
+            &source_dir.join("fifo"),
+            SFlag::S_IFIFO,
+            Mode::from_bits_truncate(0o644),
+            0,
+        )
+        .unwrap();
+        let copy = snap_name("copy");
+        let copy_dir = duplicate(&mzr_dir, &source, &copy, None).unwrap();
+        let diff = diff_trees(&source_dir, &copy_dir, Comparison::Contents).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+        assert_eq!(
+            read_link(copy_dir.join("link")).unwrap(),
+            Path::new("dir/file")
+        );
+        let info = read_info(&mzr_dir, &copy).unwrap().unwrap();
+        assert_eq!(info.parent, Some(source.clone()));
+        // Modifying the copy leaves the original alone.
+        fs::write(copy_dir.join("dir/file"), "modified").unwrap();
+        assert_eq!(
+            fs::read_to_string(source_dir.join("dir/file")).unwrap(),
+            "contents"
+        );
+        // The fallback used when `cp` can't copy special files yields the same
+        // tree.
+        let fallback_dir = dir.join("fallback");
+        copy_tree_parallel(&source_dir, &fallback_dir, &CopyOptions::default(), 2).unwrap();
+        let diff = diff_trees(&source_dir, &fallback_dir, Comparison::Contents).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+    }
+
+    #[test]
+    fn duplicate_refuses_existing_target_and_missing_source() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let source = test_snapshot(&mzr_dir, "source", &[("file", "source")]);
+        let existing = test_snapshot(&mzr_dir, "existing", &[("file", "existing")]);
+        let err = duplicate(&mzr_dir, &source, &existing, None).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        let existing_dir = SnapDir::new(&mzr_dir, &existing);
+        assert_eq!(
+            fs::read_to_string(existing_dir.join("file")).unwrap(),
+            "existing"
+        );
+        let err = duplicate(&mzr_dir, &snap_name("sorce"), &snap_name("copy"), None).unwrap_err();
+        assert!(strip_ansi_escapes(&err.to_string()).contains("Did you mean source?"));
+        assert!(!SnapDir::new(&mzr_dir, &snap_name("copy")).exists());
+    }
+
+    #[test]
+    fn snapshot_tags_can_be_set_listed_and_removed() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let s1 = test_snapshot(&mzr_dir, "s1", &[]);
+        let s2 = test_snapshot(&mzr_dir, "s2", &[]);
+        test_snapshot(&mzr_dir, "untagged", &[]);
+        // Snapshots without info files get one when tagged.
+        assert!(read_info(&mzr_dir, &s1).unwrap().is_none());
+        set_tag(&mzr_dir, &s1, "build", "ci-1").unwrap();
+        set_tag(&mzr_dir, &s1, "owner", "alice").unwrap();
+        set_tag(&mzr_dir, &s2, "build", "ci-2").unwrap();
+        set_tag(&mzr_dir, &s2, "build", "ci-3").unwrap();
+        let tags = get_tags(&mzr_dir, &s1).unwrap();
+        assert_eq!(tags.get("build").map(String::as_str), Some("ci-1"));
+        assert_eq!(tags.get("owner").map(String::as_str), Some("alice"));
+        assert_eq!(
+            get_tags(&mzr_dir, &s2)
+                .unwrap()
+                .get("build")
+                .map(String::as_str),
+            Some("ci-3")
+        );
+        let with_tag = |key: &str, value: Option<&str>| {
+            let mut names: Vec<String> = list_snaps_with_tag(&mzr_dir, key, value)
+                .unwrap()
+                .into_iter()
+                .map(|name| (*name).clone())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(with_tag("build", None), vec!["s1", "s2"]);
+        assert_eq!(with_tag("build", Some("ci-3")), vec!["s2"]);
+        assert!(with_tag("build", Some("ci-2")).is_empty());
+        assert!(remove_tag(&mzr_dir, &s1, "build").unwrap());
+        assert!(!remove_tag(&mzr_dir, &s1, "build").unwrap());
+        assert_eq!(with_tag("build", None), vec!["s2"]);
+        assert_eq!(with_tag("owner", None), vec!["s1"]);
+    }
+
+    #[test]
+    fn snapshot_tags_are_validated() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let s1 = test_snapshot(&mzr_dir, "s1", &[]);
+        for key in &["", "a=b", "tab\t", "nul\0", "esc\u{1b}"] {
+            assert!(validate_tag_key(key).is_err());
+            assert!(set_tag(&mzr_dir, &s1, key, "value").is_err());
+        }
+        assert!(validate_tag_key("build.id-2").is_ok());
+        assert!(get_tags(&mzr_dir, &s1).unwrap().is_empty());
+        let missing = snap_name("missing");
+        assert!(set_tag(&mzr_dir, &missing, "build", "ci-1").is_err());
+        assert!(get_tags(&mzr_dir, &missing).is_err());
+        assert!(remove_tag(&mzr_dir, &missing, "build").is_err());
+    }
+
+    #[test]
+    fn estimates_bytes_shared_with_zone_snapshot() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let bytes = |count: usize| "x".repeat(count);
+        let (kept, modified, a, b, old, deleted) = (
+            bytes(100),
+            bytes(300),
+            bytes(200),
+            bytes(400),
+            bytes(500),
+            bytes(50),
+        );
+        let snap = test_snapshot(
+            &mzr_dir,
+            "s1",
+            &[
+                ("kept", &kept),
+                ("modified", &modified),
+                ("deleted-dir/a", &a),
+                ("deleted-dir/b", &b),
+                ("opaque/old", &old),
+                ("deleted", &deleted),
+            ],
+        );
+        let unchanged = test_zone(&mzr_dir, "unchanged", &snap, &[]);
+        let estimate = estimate_shared_bytes(&unchanged).unwrap();
+        assert_eq!(
+            estimate,
+            SharedBytesEstimate {
+                shared: 1550,
+                changed: 0
+            }
+        );
+        assert_eq!(estimate.shared_fraction(), 1.0);
+        let (modified, new, added) = (bytes(60), bytes(70), bytes(30));
+        let zone = test_zone(
+            &mzr_dir,
+            "changed",
+            &snap,
+            &[
+                ("modified", &modified),
+                ("opaque/.wh..wh..opq", ""),
+                ("opaque/new", &new),
+                (".wh.deleted", ""),
+                ("added", &added),
+            ],
+        );
+        changes::create_whiteout(&zone.ovfs_changes_dir.join("deleted-dir")).unwrap();
+        let estimate = estimate_shared_bytes(&zone).unwrap();
+        assert_eq!(
+            estimate,
+            SharedBytesEstimate {
+                shared: 100,
+                changed: 160
+            }
+        );
+        assert!((estimate.shared_fraction() - 100.0 / 260.0).abs() < 1e-9);
+        let empty = SharedBytesEstimate {
+            shared: 0,
+            changed: 0,
+        };
+        assert_eq!(empty.shared_fraction(), 1.0);
+    }
+
+    #[test]
+    fn live_snapshot_copies_mounted_zone() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap = test_snapshot(&mzr_dir, "s1", &[("dir/file", "base")]);
+        let zone = test_zone(&mzr_dir, "mz", &snap, &[]);
+        let options = CopyOptions::default();
+        let err = of_mounted_zone(&mzr_dir, &zone, None, &snap_name("live"), &options).unwrap_err();
+        assert!(err.to_string().contains("not mounted"));
+        // The zone's overlay is simulated by bind-mounting a directory with
+        // its merged contents, within a private mount namespace.
+        let merged_dir = dir.join("merged");
+        write_files(&merged_dir, &[("dir/file", "merged"), ("other", "")]);
+        let user = Uid::current();
+        let group = Gid::current();
+        let refused = namespaces::with_unshared_user_and_mount_result(
+            |child_process| namespaces::map_user_to_root(child_process, user, group),
+            || {
+                BindMount::new(&merged_dir, &zone.ovfs_mount_dir)
+                    .mount()
+                    .map_err(|e| format_err!("{}", e))?;
+                of_mounted_zone(&mzr_dir, &zone, None, &snap_name("live"), &options)?;
+                let rel_path = Some(Path::new("dir"));
+                of_mounted_zone(&mzr_dir, &zone, rel_path, &snap_name("live-dir"), &options)?;
+                let refused = |rel_path: &str| {
+                    of_mounted_zone(
+                        &mzr_dir,
+                        &zone,
+                        Some(Path::new(rel_path)),
+                        &snap_name("refused"),
+                        &options,
+                    )
+                    .is_err()
+                };
+                Ok(vec![refused("other"), refused("missing"), refused("..")])
+            },
+        )
+        .unwrap();
+        assert_eq!(refused, vec![true, true, true]);
+        let live_dir = SnapDir::new(&mzr_dir, &snap_name("live"));
+        assert_eq!(
+            fs::read_to_string(live_dir.join("dir/file")).unwrap(),
+            "merged"
+        );
+        assert!(live_dir.join("other").is_file());
+        let info = read_info(&mzr_dir, &snap_name("live")).unwrap().unwrap();
+        assert_eq!(info.parent, Some(snap));
+        let live_subdir = SnapDir::new(&mzr_dir, &snap_name("live-dir"));
+        assert_eq!(
+            fs::read_to_string(live_subdir.join("file")).unwrap(),
+            "merged"
+        );
+        assert!(!SnapDir::new(&mzr_dir, &snap_name("refused")).exists());
+    }
+
+    #[test]
+    fn lists_snapshot_tree_to_depth() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap = test_snapshot(
+            &mzr_dir,
+            "s1",
+            &[
+                ("a/b/c/deep", ""),
+                ("a/file", "1234"),
+                (".git/HEAD", "ref"),
+                ("top", "12"),
+            ],
+        );
+        symlink("top", SnapDir::new(&mzr_dir, &snap).join("link")).unwrap();
+        let render = |max_depth: Option<usize>, sizes: bool| {
+            let nodes = list_snapshot_tree(&mzr_dir, &snap, max_depth).unwrap();
+            strip_ansi_escapes(&render_file_tree(&nodes, sizes))
+        };
+        assert_eq!(
+            render(None, false),
+            [
+                "├── .git/",
+                "├── a/",
+                "│   ├── b/",
+                "│   │   └── c/",
+                "│   │       └── deep",
+                "│   └── file",
+                "├── link",
+                "└── top\n",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            render(Some(1), false),
+            "├── .git/\n├── a/\n├── link\n└── top\n"
+        );
+        assert_eq!(
+            render(Some(2), true),
+            "├── .git/\n├── a/\n│   ├── b/\n│   └── file (4 bytes)\n├── link\n└── top (2 bytes)\n"
+        );
+        let nodes = list_snapshot_tree(&mzr_dir, &snap, None).unwrap();
+        let kinds: Vec<(&str, FileKind, Option<u64>)> = nodes
+            .iter()
+            .map(|node| (node.name.as_str(), node.kind, node.size))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (".git", FileKind::Dir, None),
+                ("a", FileKind::Dir, None),
+                ("link", FileKind::Symlink, None),
+                ("top", FileKind::File, Some(2)),
+            ]
+        );
+        // The contents of metadata directories are skipped.
+        assert!(nodes[0].children.is_empty());
+        assert!(list_snapshot_tree(&mzr_dir, &snap_name("missing"), None).is_err());
+    }
+
+    #[test]
+    fn snapshot_chain_follows_parents() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let names: Vec<SnapName> = ["s1", "s2", "s3"]
+            .iter()
+            .map(|name| test_snapshot(&mzr_dir, name, &[]))
+            .collect();
+        set_parent(&mzr_dir, &names[1], &names[0]);
+        set_parent(&mzr_dir, &names[2], &names[1]);
+        let zone = test_zone(&mzr_dir, "mz", &names[2], &[]);
+        let chain = snapshot_chain(&mzr_dir, &zone).unwrap();
+        assert_eq!(
+            chain.snaps,
+            vec![names[2].clone(), names[1].clone(), names[0].clone()]
+        );
+        assert_eq!(chain.missing, None);
+        // A deleted ancestor breaks the chain there.
+        delete(&mzr_dir, &names[0]).unwrap();
+        let chain = snapshot_chain(&mzr_dir, &zone).unwrap();
+        assert_eq!(chain.snaps, vec![names[2].clone(), names[1].clone()]);
+        assert_eq!(chain.missing, Some(names[0].clone()));
+        // As does a deleted snapshot of the zone itself.
+        delete(&mzr_dir, &names[2]).unwrap();
+        let chain = snapshot_chain(&mzr_dir, &zone).unwrap();
+        assert!(chain.snaps.is_empty());
+        assert_eq!(chain.missing, Some(names[2].clone()));
+    }
+
+    #[test]
+    fn snapshot_chain_detects_cycles() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let a = test_snapshot(&mzr_dir, "a", &[]);
+        let b = test_snapshot(&mzr_dir, "b", &[]);
+        set_parent(&mzr_dir, &a, &b);
+        set_parent(&mzr_dir, &b, &a);
+        let zone = test_zone(&mzr_dir, "mz", &a, &[]);
+        let err = snapshot_chain(&mzr_dir, &zone).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+
+    #[test]
+    fn copy_progress_estimates_remaining_time() {
+        let progress = |files_done, bytes, elapsed_ms| CopyProgress {
+            files_done,
+            files_total: 100,
+            bytes,
+            elapsed: Duration::from_millis(elapsed_ms),
+        };
+        // Nothing copied yet, so there's no rate to estimate from.
+        let start = progress(0, None, 500);
+        assert_eq!(start.fraction(), 0.0);
+        assert_eq!(start.eta(), None);
+        // A quarter of the files in 10s leaves 30s.
+        let quarter = progress(25, None, 10_000);
+        assert_eq!(quarter.fraction(), 0.25);
+        assert_eq!(quarter.eta(), Some(Duration::from_secs(30)));
+        // Bytes take precedence over files when they're known.
+        let by_bytes = progress(25, Some((750, 1000)), 3_000);
+        assert_eq!(by_bytes.fraction(), 0.75);
+        assert_eq!(by_bytes.eta(), Some(Duration::from_secs(1)));
+        let done = progress(100, Some((1000, 1000)), 4_000);
+        assert_eq!(done.eta(), Some(Duration::from_secs(0)));
+        // Empty copies are complete.
+        let empty = CopyProgress {
+            files_done: 0,
+            files_total: 0,
+            bytes: None,
+            elapsed: Duration::from_millis(10),
+        };
+        assert_eq!(empty.fraction(), 1.0);
+        assert_eq!(empty.eta(), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn copy_progress_displays_estimate() {
+        let progress = CopyProgress {
+            files_done: 25,
+            files_total: 100,
+            bytes: None,
+            elapsed: Duration::from_secs(10),
+        };
+        assert_eq!(
+            progress.to_string(),
+            "25% copied (25 of 100 files), about 30s remaining"
+        );
+        let progress = CopyProgress {
+            files_done: 0,
+            bytes: Some((0, 2048)),
+            ..progress
+        };
+        assert_eq!(
+            strip_ansi_escapes(&progress.to_string()),
+            format!(
+                "0% copied (0 of 100 files, 0 bytes of {})",
+                format_bytes(2048)
+            )
+        );
+    }
+
+    #[test]
+    fn missing_snapshots_suggest_near_misses() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        test_snapshot(&mzr_dir, "release-1", &[]);
+        test_snapshot(&mzr_dir, "nightly", &[]);
+        let suggestion = |name: &str| {
+            strip_ansi_escapes(&did_you_mean_snap(&mzr_dir, &snap_name(name)).unwrap())
+        };
+        assert_eq!(suggestion("release-2"), " Did you mean release-1?");
+        assert_eq!(suggestion("nightlyy"), " Did you mean nightly?");
+        assert_eq!(suggestion("unrelated"), "");
+    }
+
+    /// Writes a script which stands in for ssh, by running the remote command
+    /// locally.
+    fn fake_ssh(dir: &TestDir, exit_code: i32) -> String {
+        let path = dir.join(format!("fake-ssh-{}", exit_code));
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = -- ] || exit 100\nsh -c \"$3\"\nexit {}\n",
+                exit_code
+            ),
+        )
+        .unwrap();
+        set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn export_ssh_streams_archive_to_remote_command() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let source = test_snapshot(&mzr_dir, "source", &[("dir/file", "contents")]);
+        let remote_path = dir.join("remote.tar");
+        let remote = |ssh_command: &str| {
+            SshDestination::new(ssh_command, "host", &remote_path.display().to_string()).unwrap()
+        };
+        export_ssh(
+            &mzr_dir,
+            &source,
+            &remote(&fake_ssh(&dir, 0)),
+            Compression::None,
+        )
+        .unwrap();
+        let imported = import(&mzr_dir, &snap_name("imported"), &remote_path, false, None).unwrap();
+        let diff = diff_trees(
+            SnapDir::new(&mzr_dir, &source),
+            &imported,
+            Comparison::Contents,
+        )
+        .unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+        // The exit status of the remote command is surfaced.
+        let err = export_ssh(
+            &mzr_dir,
+            &source,
+            &remote(&fake_ssh(&dir, 3)),
+            Compression::None,
+        )
+        .unwrap_err();
+        let message = strip_ansi_escapes(&format!("{}", err.iter_chain().last().unwrap()));
+        assert!(message.contains("exit status: 3"), "{}", message);
+        let missing = export_ssh(
+            &mzr_dir,
+            &snap_name("missing"),
+            &remote(&fake_ssh(&dir, 0)),
+            Compression::None,
+        );
+        assert!(missing.is_err());
+    }
 }
@@ -0,0 +1,83 @@
+use crate::paths::{MzrDir, SnapDir, SnapName, UserWorkDir, ZoneName};
+use crate::utils::ensure_dir;
+use crate::zone::Zone;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Helpers for tests
+ */
+
+static NEXT_TEST_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary directory which is removed when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        let dir = env::temp_dir().join(format!(
+            "mzr-test-{}-{}",
+            process::id(),
+            NEXT_TEST_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Creates an mzr directory within the test directory.
+pub fn test_mzr_dir(dir: &TestDir) -> MzrDir {
+    let mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("proj")));
+    ensure_dir(&mzr_dir).unwrap();
+    mzr_dir
+}
+
+/// Creates a snapshot containing the specified files, which are given as paths
+/// relative to the snapshot along with their contents.
+pub fn test_snapshot(mzr_dir: &MzrDir, name: &str, files: &[(&str, &str)]) -> SnapName {
+    let snap_name = SnapName::new(name.to_string()).unwrap();
+    let snap_dir = SnapDir::new(mzr_dir, &snap_name);
+    ensure_dir(&snap_dir).unwrap();
+    write_files(&snap_dir, files);
+    snap_name
+}
+
+/// Creates a zone based on the snapshot, with the specified files written to
+/// its changes directory.
+pub fn test_zone(
+    mzr_dir: &MzrDir,
+    name: &str,
+    snap_name: &SnapName,
+    files: &[(&str, &str)],
+) -> Zone {
+    let zone_name = ZoneName::new(name.to_string()).unwrap();
+    let zone = Zone::create(mzr_dir, &zone_name, snap_name, None).unwrap();
+    write_files(&zone.ovfs_changes_dir, files);
+    zone
+}
+
+/// Writes files, creating their parent directories.
+pub fn write_files(dir: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = dir.join(path);
+        ensure_dir(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
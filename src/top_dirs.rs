@@ -1,21 +1,24 @@
 use crate::colors::*;
-use crate::paths::{MzrDir, UserWorkDir};
-use crate::utils::{confirm, Confirmed};
+use crate::config::Config;
+use crate::paths::{MzrDir, NotMzrDir, UserWorkDir};
+use crate::snapshot;
+use crate::utils::{confirm, ensure_dir_with_mode, Confirmed};
 use failure::{Error, ResultExt};
 use std::env;
-use std::fs::create_dir_all;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct TopDirs {
     pub mzr_dir: MzrDir,
     pub user_work_dir: UserWorkDir,
+    /// Settings from the config file, loaded once the directories are found.
+    pub config: Config,
 }
 
 impl TopDirs {
     pub fn find(action: &str) -> Result<TopDirs, Error> {
         match TopDirs::find_impl(&current_dir()?) {
-            Ok(top_dirs) => Ok(top_dirs),
+            Ok(top_dirs) => top_dirs.load_config(),
             Err(err) => match err.downcast() {
                 Ok(MzrDirNotFound) => Err(format_err!(
                     "Couldn't find mzr directory, and can't {} without one.",
@@ -26,12 +29,17 @@ impl TopDirs {
         }
     }
 
-    fn find_impl(start_dir: &PathBuf) -> Result<TopDirs, Error> {
-        let mut dir = start_dir.clone();
+    fn find_impl(start_dir: &Path) -> Result<TopDirs, Error> {
+        let mut dir = start_dir.to_path_buf();
         loop {
             let candidate = TopDirs::from_user_work(UserWorkDir::new(&dir));
-            if candidate.mzr_dir.is_dir() {
-                return Ok(candidate);
+            match MzrDir::open(&candidate.mzr_dir) {
+                Ok(_) => return Ok(candidate),
+                Err(err) => {
+                    if err.downcast_ref::<NotMzrDir>().is_none() {
+                        return Err(err);
+                    }
+                }
             }
             dir.pop();
             if dir.file_name().is_none() {
@@ -45,38 +53,33 @@ impl TopDirs {
             .map(|v| v.into())
             .unwrap_or(current_dir()?);
         match TopDirs::find_impl(&start_dir) {
-            Ok(top_dirs) => Ok(top_dirs),
-            Err(err) => {
-                match err.downcast() {
-                    Ok(MzrDirNotFound) => {
-                        println!("Couldn't find a mzr directory sibling to any parent directory, but one is needed in order to {}.", action);
-                        let dirs = match find_git_repo(&start_dir) {
-                            None => TopDirs::from_user_work(UserWorkDir::new(&start_dir)),
-                            Some(git_dir) => {
-                                println!("There's a git repository at {}", git_dir);
-                                TopDirs::from_user_work(git_dir)
-                            }
-                        };
-                        match confirm(&format!("Init a new mzr directory at {}", dirs.mzr_dir))? {
-                            Confirmed::Yes => {
-                                //TODO(cleanup): can this clone be avoided? (same on other
-                                // create_dir_all usages)
-                                create_dir_all(dirs.mzr_dir.clone())?;
-                                println!(
-                                    "{} mzr directory initialized.",
-                                    color_success(&"Success:")
-                                );
-                                //TODO(cleanup): can this clone be avoided?
-                                Ok(dirs.clone())
-                            }
-                            Confirmed::No => {
-                                Err(format_err!("Can't {} without a mzr directory", action))
-                            }
+            Ok(top_dirs) => top_dirs.load_config(),
+            Err(err) => match err.downcast() {
+                Ok(MzrDirNotFound) => {
+                    println!("Couldn't find a mzr directory sibling to any parent directory, but one is needed in order to {}.", action);
+                    let dirs = match find_git_repo(&start_dir) {
+                        None => TopDirs::from_user_work(UserWorkDir::new(&start_dir)),
+                        Some(git_dir) => {
+                            println!("There's a git repository at {}", git_dir);
+                            TopDirs::from_user_work(git_dir)
+                        }
+                    };
+                    check_not_nested(&dirs)?;
+                    let dirs = dirs.load_config()?;
+                    match confirm(&format!("Init a new mzr directory at {}", dirs.mzr_dir))? {
+                        Confirmed::Yes => {
+                            ensure_dir_with_mode(&dirs.mzr_dir, dirs.config.dir_mode)?;
+                            dirs.apply_snapshot_dir()?;
+                            println!("{} mzr directory initialized.", color_success(&"Success:"));
+                            Ok(dirs)
+                        }
+                        Confirmed::No => {
+                            Err(format_err!("Can't {} without a mzr directory", action))
                         }
                     }
-                    Err(other_err) => Err(other_err),
                 }
-            }
+                Err(other_err) => Err(other_err),
+            },
         }
     }
 
@@ -84,6 +87,24 @@ impl TopDirs {
         TopDirs {
             mzr_dir: MzrDir::new(&user_work_dir),
             user_work_dir,
+            config: Config::default(),
+        }
+    }
+
+    fn load_config(mut self) -> Result<TopDirs, Error> {
+        self.config = Config::load(&self.user_work_dir)?;
+        self.config.apply_color();
+        if self.mzr_dir.is_dir() {
+            self.apply_snapshot_dir()?;
+        }
+        Ok(self)
+    }
+
+    /// Links the snapshots directory to the configured `snapshot_dir`, if any.
+    fn apply_snapshot_dir(&self) -> Result<(), Error> {
+        match &self.config.snapshot_dir {
+            None => Ok(()),
+            Some(snapshot_dir) => snapshot::link_snaps_dir(&self.mzr_dir, snapshot_dir),
         }
     }
 }
@@ -92,13 +113,33 @@ impl TopDirs {
 #[fail(display = "Did not find mzr directory for any parent directories.")]
 pub struct MzrDirNotFound;
 
+/// Refuses to create a mzr directory for a work directory which is within
+/// another mzr directory, such as a zone's changes directory. This would lead
+/// to confusing overlay-in-overlay situations.
+fn check_not_nested(dirs: &TopDirs) -> Result<(), Error> {
+    for ancestor in dirs.user_work_dir.ancestors() {
+        let is_mzr_dir = ancestor
+            .extension()
+            .is_some_and(|extension| extension == "mzr");
+        if is_mzr_dir && ancestor.is_dir() {
+            bail!(
+                "Refusing to init a mzr directory at {}, because it would be nested within \
+                 the mzr directory {}.",
+                dirs.mzr_dir,
+                color_dir(&ancestor.display())
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Like `env::current_dir`, but gives a decent error.
 fn current_dir() -> Result<PathBuf, Error> {
     Ok(env::current_dir().context("Error getting current directory - does it still exist?")?)
 }
 
-fn find_git_repo(start_dir: &PathBuf) -> Option<UserWorkDir> {
-    let mut cur = start_dir.clone();
+fn find_git_repo(start_dir: &Path) -> Option<UserWorkDir> {
+    let mut cur = start_dir.to_path_buf();
     loop {
         // Exit if there is no file name.
         cur.file_name()?;
@@ -113,3 +154,32 @@ fn find_git_repo(start_dir: &PathBuf) -> Option<UserWorkDir> {
         cur.pop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_mzr_dir, TestDir};
+    use crate::utils::ensure_dir;
+    use std::fs;
+
+    fn dirs_for(work_dir: PathBuf) -> TopDirs {
+        ensure_dir(&work_dir).unwrap();
+        TopDirs::from_user_work(UserWorkDir::new(&work_dir))
+    }
+
+    #[test]
+    fn nested_mzr_dirs_are_rejected() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let nested = dirs_for(mzr_dir.join("zone/mz/changes/sub"));
+        let err = check_not_nested(&nested).unwrap_err();
+        assert!(err.to_string().contains("would be nested"));
+        assert!(check_not_nested(&dirs_for(mzr_dir.join("snap"))).is_err());
+        // Sibling projects, and files which merely look like mzr directories,
+        // are fine.
+        assert!(check_not_nested(&dirs_for(dir.join("other/sub"))).is_ok());
+        fs::write(dir.join("file.mzr"), "").unwrap();
+        let beside_file = TopDirs::from_user_work(UserWorkDir::new(&dir.join("file.mzr/sub")));
+        assert!(check_not_nested(&beside_file).is_ok());
+    }
+}
@@ -1,16 +1,25 @@
 use crate::colors::*;
+use crate::config::DirMode;
 use failure::{Error, Fail, ResultExt};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
 use nix::unistd;
+use std::cmp::{max, min};
 use std::ffi::CString;
 use std::ffi::OsStr;
 use std::fmt::Display;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::iter;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::ExitStatusExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::{exit, ExitStatus};
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 use void::Void;
 
 /*
@@ -44,10 +53,10 @@ struct UnexpectedConfirmInput(String);
  * Path utilities
  */
 
-pub fn add_suffix_to_path(path: &PathBuf, suffix: &str) -> PathBuf {
+pub fn add_suffix_to_path(path: &Path, suffix: &str) -> PathBuf {
     match path.file_name().and_then(|x| x.to_str()) {
         Some(name) => {
-            let mut result = path.clone();
+            let mut result = path.to_path_buf();
             result.set_file_name(OsStr::new(&[name, suffix].concat()));
             result
         }
@@ -55,8 +64,8 @@ pub fn add_suffix_to_path(path: &PathBuf, suffix: &str) -> PathBuf {
     }
 }
 
-pub fn find_existent_parent_dir(path: &PathBuf) -> Option<PathBuf> {
-    let mut dir = path.clone();
+pub fn find_existent_parent_dir(path: &Path) -> Option<PathBuf> {
+    let mut dir = path.to_path_buf();
     while !dir.is_dir() {
         match dir.parent() {
             None => {
@@ -70,20 +79,311 @@ pub fn find_existent_parent_dir(path: &PathBuf) -> Option<PathBuf> {
     Some(dir)
 }
 
-pub fn maybe_strip_prefix(prefix: &PathBuf, path: &PathBuf) -> PathBuf {
+/// Creates a directory and any missing parents. Unlike `create_dir_all`, it
+/// gives a clear error when a non-directory is in the way, so that partially
+/// created directories (such as from an interrupted `mzr create`) are accepted
+/// but other conflicts are not.
+pub fn ensure_dir<P: AsRef<Path>>(dir: P) -> Result<(), Error> {
+    ensure_dir_with_mode(dir, None)
+}
+
+/// Like `ensure_dir`, but if a mode is specified, then it is applied to each
+/// newly created directory, instead of the mode implied by the umask.
+pub fn ensure_dir_with_mode<P: AsRef<Path>>(dir: P, mode: Option<DirMode>) -> Result<(), Error> {
+    let dir = dir.as_ref();
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|path| fs::symlink_metadata(path).is_err())
+        .collect();
+    if let Err(e) = fs::create_dir_all(dir) {
+        let obstruction = dir
+            .ancestors()
+            .find(|path| fs::metadata(path).is_ok_and(|metadata| !metadata.is_dir()));
+        match obstruction {
+            Some(path) => bail!(
+                "Failed to create directory {}, because {} exists and is not a directory.",
+                color_dir(&dir.display()),
+                color_file(&path.display())
+            ),
+            None => Err(e).context(format_err!(
+                "Failed to create directory {}",
+                color_dir(&dir.display())
+            ))?,
+        }
+    }
+    if let Some(mode) = mode {
+        for path in missing.iter().rev() {
+            set_dir_mode(path, mode)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a directory whose parent must already exist, yielding `false` if
+/// it already exists. Since creating a directory is atomic, at most one of
+/// several concurrent callers gets `true`. The mode is applied as in
+/// `ensure_dir_with_mode`.
+pub fn create_new_dir<P: AsRef<Path>>(dir: P, mode: Option<DirMode>) -> Result<bool, Error> {
+    let dir = dir.as_ref();
+    match fs::create_dir(dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => Err(e).context(format_err!(
+            "Failed to create directory {}",
+            color_dir(&dir.display())
+        ))?,
+    }
+    if let Some(mode) = mode {
+        set_dir_mode(dir, mode)?;
+    }
+    Ok(true)
+}
+
+/// Sets the mode of a directory, such as one which was created by copying,
+/// to the mode configured via `dir_mode`.
+pub fn set_dir_mode(dir: &Path, DirMode(mode): DirMode) -> Result<(), Error> {
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode)).context(format_err!(
+        "Failed to set mode of directory {}",
+        color_dir(&dir.display())
+    ))?;
+    Ok(())
+}
+
+/// Determines whether names within a directory are case-insensitive, by
+/// creating a probe file and checking whether it can be found with a
+/// differently cased name.
+pub fn is_case_insensitive_dir<P: AsRef<Path>>(dir: P) -> Result<bool, Error> {
+    let dir = dir.as_ref();
+    let probe = dir.join(format!(".mzr-case-probe-{}", std::process::id()));
+    let probe_upper = dir.join(format!(".MZR-CASE-PROBE-{}", std::process::id()));
+    File::create(&probe).context(format_err!(
+        "Failed to create file {} to check case sensitivity",
+        color_file(&probe.display())
+    ))?;
+    let result = fs::symlink_metadata(&probe_upper).is_ok();
+    fs::remove_file(&probe).context(format_err!(
+        "Failed to remove file {}",
+        color_file(&probe.display())
+    ))?;
+    Ok(result)
+}
+
+/// Returns `true` if the directory has no entries. Stops reading the directory
+/// as soon as an entry is found.
+pub fn is_empty_dir<P: AsRef<Path>>(dir: P) -> Result<bool, Error> {
+    let mut entries = fs::read_dir(&dir).context(format_err!(
+        "Failed to read directory {}",
+        color_dir(&dir.as_ref().display())
+    ))?;
+    Ok(entries.next().is_none())
+}
+
+/// Lists the names of the subdirectories of a directory, sorted. Yields an empty
+/// list if the directory does not exist.
+pub fn list_subdir_names<P: AsRef<Path>>(dir: P) -> Result<Vec<String>, Error> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir).context(format_err!(
+        "Failed to read directory {}",
+        color_dir(&dir.display())
+    ))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            match entry.file_name().into_string() {
+                Ok(name) => result.push(name),
+                Err(name) => println!(
+                    "{} Ignoring {:?} in {}, since its name is not valid unicode.",
+                    color_warn(&"Warning:"),
+                    name,
+                    color_dir(&dir.display())
+                ),
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Lists the names of all entries of a directory, sorted. Yields an empty list
+/// if the directory does not exist. Names which aren't valid unicode are
+/// converted lossily.
+pub fn list_dir_names<P: AsRef<Path>>(dir: P) -> Result<Vec<String>, Error> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir).context(format_err!(
+        "Failed to read directory {}",
+        color_dir(&dir.display())
+    ))? {
+        result.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Renames a directory to a target path which must not exist. This is atomic
+/// when both are on the same filesystem, which callers should arrange by
+/// putting the source directory next to the target. Otherwise, `rename` fails
+/// with `EXDEV`, and so the directory is copied and then removed instead. In
+/// that case, if the copy fails, the partial target is left for the caller to
+/// clean up, and the source is left intact.
+pub fn rename_dir_into_place(source: &Path, target: &Path) -> Result<(), Error> {
+    match fs::rename(source, target) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            println!(
+                "{} {} and {} are on different filesystems, so copying instead of renaming.",
+                color_warn(&"Warning:"),
+                color_dir(&source.display()),
+                color_dir(&target.display())
+            );
+            run_process(
+                Command::new("cp")
+                    .stdin(Stdio::null())
+                    .arg("--archive")
+                    .arg("--reflink=auto")
+                    .arg("--no-target-directory")
+                    .arg(source)
+                    .arg(target),
+            )?;
+            fs::remove_dir_all(source).context(format_err!(
+                "Failed to remove {} after copying it to {}",
+                color_dir(&source.display()),
+                color_dir(&target.display())
+            ))?;
+            Ok(())
+        }
+        Err(e) => Err(e).context(format_err!(
+            "Failed to rename {} to {}",
+            color_dir(&source.display()),
+            color_dir(&target.display())
+        ))?,
+    }
+}
+
+pub fn maybe_strip_prefix(prefix: &Path, path: &Path) -> PathBuf {
     path.strip_prefix(prefix).unwrap_or(path).to_path_buf()
 }
 
+/// Checks that none of the directories are within each other, since mounting
+/// an overlay or bind mount within its own source leads to recursion. Paths
+/// which exist have symlinks resolved first, so that aliases are detected.
+pub fn check_dirs_disjoint(dirs: &[(&str, &Path)]) -> Result<(), Error> {
+    let resolved: Vec<PathBuf> = dirs
+        .iter()
+        .map(|(_, dir)| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()))
+        .collect();
+    for (i, (a_desc, a)) in dirs.iter().enumerate() {
+        for (j, (b_desc, b)) in dirs.iter().enumerate().skip(i + 1) {
+            if resolved[i].starts_with(&resolved[j]) || resolved[j].starts_with(&resolved[i]) {
+                bail!(
+                    "The {} {} and the {} {} must not be within each other.",
+                    a_desc,
+                    color_dir(&a.display()),
+                    b_desc,
+                    color_dir(&b.display())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a path, such as an archive entry, is relative and doesn't use
+/// `..` to escape the directory it's relative to.
+pub fn check_contained_rel_path(path: &Path) -> Result<(), Error> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir => bail!(
+                "Path {} is not allowed to contain \"..\"",
+                color_file(&path.display())
+            ),
+            Component::RootDir | Component::Prefix(_) => bail!(
+                "Path {} is not allowed to be absolute",
+                color_file(&path.display())
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that none of the directories containing a relative path within a
+/// root are symlinks, since following them could reach outside of the root.
+/// Unlike `check_contained_rel_path`, this checks the filesystem rather than
+/// just the path.
+pub fn check_no_symlinked_parents(root: &Path, rel_path: &Path) -> Result<(), Error> {
+    let mut ancestor = root.to_path_buf();
+    for component in rel_path.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        match fs::symlink_metadata(&ancestor) {
+            Ok(ref metadata) if metadata.file_type().is_symlink() => bail!(
+                "Path {} is not allowed to be within a symlink, but {} is a symlink",
+                color_file(&rel_path.display()),
+                color_file(&ancestor.display())
+            ),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 /*
  * String utilities
  */
 
 pub fn strip_prefix(prefix: &str, input: &str) -> Option<String> {
-    if input.starts_with(prefix) {
-        Some(input[prefix.len()..].to_string())
-    } else {
-        None
+    input.strip_prefix(prefix).map(String::from)
+}
+
+/// Formats a number of bytes for display, using binary units.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
     }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Computes the Levenshtein edit distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut prev_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = prev_row[j] + if a_char == *b_char { 0 } else { 1 };
+            row.push(min(substitution, min(prev_row[j + 1], row[j]) + 1));
+        }
+        prev_row = row;
+    }
+    prev_row[b_chars.len()]
+}
+
+/// Finds the candidate closest to the input, for "did you mean" suggestions.
+/// Candidates which are too different from the input are not considered.
+pub fn closest_match<'a, I>(input: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let max_distance = max(1, input.chars().count() / 3);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(input, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
 }
 
 /*
@@ -110,6 +410,38 @@ pub fn run_process(cmd: &mut Command) -> Result<(), Error> {
     Ok(())
 }
 
+/// Quotes a string for pasting into a POSIX shell.
+pub fn shell_quote(input: &str) -> String {
+    if !input.is_empty()
+        && input
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,".contains(c))
+    {
+        input.to_string()
+    } else {
+        format!("'{}'", input.replace('\'', "'\\''"))
+    }
+}
+
+/// Runs a process and yields its stdout, or an error if encountered.
+pub fn collect_process_output(cmd: &mut Command) -> Result<String, Error> {
+    let output = cmd.stderr(Stdio::inherit()).output().context(format_err!(
+        "Error encountered while running {:?}",
+        color_cmd(cmd)
+    ))?;
+    if !output.status.success() {
+        bail!(
+            "{:?} exited with failure status {}",
+            color_cmd(cmd),
+            color_err(&output.status)
+        );
+    }
+    Ok(String::from_utf8(output.stdout).context(format_err!(
+        "Output of {:?} was not valid UTF-8",
+        color_cmd(cmd)
+    ))?)
+}
+
 // TODO: should handle args, will probably need that.
 pub fn execvp(cmd: &str) -> Result<Void, Error> {
     let cmd_cstring = CString::new(cmd).context(format!(
@@ -124,7 +456,7 @@ pub fn execvp(cmd: &str) -> Result<Void, Error> {
 
 /// Given an `ExitStatus`, probably yielded by an invoked process,
 /// exits the current process with the same code.
-pub fn exit_with_status(status: ExitStatus) -> Void {
+pub fn exit_with_status(status: ExitStatus) -> ! {
     if status.success() {
         exit(0);
     } else {
@@ -138,6 +470,48 @@ pub fn exit_with_status(status: ExitStatus) -> Void {
     }
 }
 
+/*
+ * Timeout utilities
+ */
+
+/// A blocking operation didn't finish within its time limit.
+#[derive(Fail, Debug)]
+#[fail(display = "Timed out after {:?} while {}.", _1, _0)]
+pub struct Timeout(pub String, pub Duration);
+
+/// Runs a blocking operation, such as a syscall which can hang, on another
+/// thread, yielding a `Timeout` error if it doesn't finish in time. The
+/// description says what the operation is doing, such as "unmounting /foo".
+///
+/// There's no way to interrupt a blocked syscall, so on timeout the thread is
+/// detached rather than joined. It keeps running until the operation finishes
+/// or mzr exits, so the thread and whatever the operation holds, such as file
+/// descriptors, are leaked until then. Its eventual result is discarded. This
+/// is acceptable for a CLI which usually exits after a timeout, but callers
+/// shouldn't retry timed out operations in a loop.
+pub fn with_timeout<T, F>(description: &str, timeout: Duration, operation: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let (result_tx, result_rx) = mpsc::channel();
+    thread::Builder::new()
+        .name(String::from("mzr-timeout"))
+        .spawn(move || {
+            // Ignore failure to send, which means that the waiting timed out.
+            let _ = result_tx.send(operation());
+        })
+        .context(format_err!("Failed to start thread for {}", description))?;
+    match result_rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(Timeout(description.to_string(), timeout).into()),
+        Err(RecvTimeoutError::Disconnected) => bail!(
+            "Unexpected error: thread exited without a result while {}.",
+            description
+        ),
+    }
+}
+
 /*
  * File reading utilities
  */
@@ -153,6 +527,242 @@ where
     Ok(contents.parse()?)
 }
 
+/// Checks that procfs is mounted at `/proc`. mzr relies on it for setting up
+/// user namespace mappings, entering namespaces, and finding mounted zones,
+/// and without it these fail with obscure errors about missing files.
+pub fn check_proc_mounted() -> Result<(), Error> {
+    check_proc_mounted_at(Path::new("/proc"))
+}
+
+/// Like `check_proc_mounted`, but checking the specified directory. An empty
+/// directory, which is what's typically left when procfs isn't mounted, does
+/// not count.
+pub fn check_proc_mounted_at(proc_dir: &Path) -> Result<(), Error> {
+    if !proc_dir.join("self").join("mountinfo").exists() {
+        bail!(
+            "procfs does not appear to be mounted at {}, but mzr requires it in order to \
+             set up namespaces and find mounted zones. It can be mounted with {}",
+            color_dir(&proc_dir.display()),
+            color_cmd(&format!("mount -t proc proc {}", proc_dir.display()))
+        );
+    }
+    Ok(())
+}
+
+/// Checks whether a process exists, by sending it the null signal.
+pub fn is_process_alive(pid: unistd::Pid) -> bool {
+    match kill(pid, None) {
+        Ok(()) => true,
+        // Process exists, but belongs to another user.
+        Err(nix::Error::Sys(Errno::EPERM)) => true,
+        Err(_) => false,
+    }
+}
+
+/// Reads a pid file. Older versions of mzr write just the pid, whereas newer
+/// versions may write `KEY=VALUE` lines, one of which is `pid=PID`. Lines
+/// with unknown keys are ignored.
 pub fn parse_pid_file<P: AsRef<Path> + Display>(path: P) -> Result<unistd::Pid, Error> {
-    parse_file(path).map(unistd::Pid::from_raw)
+    let contents = fs::read_to_string(&path).context(format_err!("Failed to read {}", &path))?;
+    parse_pid_file_contents(&contents).ok_or_else(|| {
+        format_err!(
+            "Expected {} to contain a pid, but got {:?}",
+            &path,
+            contents
+        )
+    })
+}
+
+fn parse_pid_file_contents(contents: &str) -> Option<unistd::Pid> {
+    let mut lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let first_line = lines.next()?;
+    let pid_str = if first_line.contains('=') {
+        iter::once(first_line)
+            .chain(lines)
+            .find_map(|line| strip_prefix("pid=", line))?
+    } else {
+        first_line.to_string()
+    };
+    match pid_str.trim().parse() {
+        Ok(pid) if pid > 0 => Some(unistd::Pid::from_raw(pid)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::{DaemonDir, DaemonPidFile};
+    use crate::test_utils::{test_mzr_dir, TestDir};
+
+    #[test]
+    fn ensure_dir_creates_missing_dirs() {
+        let dir = TestDir::new();
+        let nested = dir.join("a/b/c");
+        ensure_dir(&nested).unwrap();
+        assert!(nested.is_dir());
+    }
+
+    #[test]
+    fn ensure_dir_accepts_existing_dir() {
+        let dir = TestDir::new();
+        ensure_dir(dir.join("a")).unwrap();
+        fs::write(dir.join("a/file"), "contents").unwrap();
+        ensure_dir(dir.join("a")).unwrap();
+        assert!(dir.join("a/file").is_file());
+    }
+
+    #[test]
+    fn ensure_dir_with_mode_sets_mode_of_created_dirs() {
+        let dir = TestDir::new();
+        let mode_of = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        ensure_dir(dir.join("a")).unwrap();
+        fs::set_permissions(dir.join("a"), fs::Permissions::from_mode(0o700)).unwrap();
+        ensure_dir_with_mode(dir.join("a/b/c"), Some(DirMode(0o751))).unwrap();
+        assert_eq!(mode_of(&dir.join("a")), 0o700);
+        assert_eq!(mode_of(&dir.join("a/b")), 0o751);
+        assert_eq!(mode_of(&dir.join("a/b/c")), 0o751);
+        assert!(create_new_dir(dir.join("d"), Some(DirMode(0o705))).unwrap());
+        assert!(!create_new_dir(dir.join("d"), Some(DirMode(0o705))).unwrap());
+        assert_eq!(mode_of(&dir.join("d")), 0o705);
+    }
+
+    #[test]
+    fn ensure_dir_rejects_existing_file() {
+        let dir = TestDir::new();
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(ensure_dir(dir.join("file")).is_err());
+        assert!(ensure_dir(dir.join("file/sub")).is_err());
+    }
+
+    #[test]
+    fn symlinked_parents_are_rejected() {
+        let dir = TestDir::new();
+        ensure_dir(dir.join("real/sub")).unwrap();
+        std::os::unix::fs::symlink("real", dir.join("link")).unwrap();
+        assert!(check_no_symlinked_parents(dir.path(), Path::new("real/sub")).is_ok());
+        assert!(check_no_symlinked_parents(dir.path(), Path::new("link")).is_ok());
+        assert!(check_no_symlinked_parents(dir.path(), Path::new("missing/sub")).is_ok());
+        assert!(check_no_symlinked_parents(dir.path(), Path::new("link/sub")).is_err());
+        assert!(check_no_symlinked_parents(dir.path(), Path::new("real/../link/sub")).is_err());
+    }
+
+    #[test]
+    fn nested_dirs_are_not_disjoint() {
+        let dir = TestDir::new();
+        let zone_dir = dir.join("proj.mzr/zone/z");
+        ensure_dir(zone_dir.join("changes")).unwrap();
+        ensure_dir(zone_dir.join("changes-old")).unwrap();
+        let disjoint = |paths: &[&Path]| {
+            let dirs: Vec<(&str, &Path)> = paths.iter().map(|path| ("directory", *path)).collect();
+            check_dirs_disjoint(&dirs).is_ok()
+        };
+        let changes_dir = zone_dir.join("changes");
+        let work_dir = dir.join("proj");
+        assert!(disjoint(&[
+            &changes_dir,
+            &work_dir,
+            &zone_dir.join("changes-old")
+        ]));
+        assert!(disjoint(&[&changes_dir]));
+        assert!(!disjoint(&[&changes_dir.join("proj"), &changes_dir]));
+        assert!(!disjoint(&[&changes_dir, &work_dir, &zone_dir]));
+        assert!(!disjoint(&[&changes_dir, &changes_dir]));
+        // Aliases via symlinks are resolved.
+        std::os::unix::fs::symlink(&changes_dir, dir.join("link")).unwrap();
+        assert!(!disjoint(&[&dir.join("link"), &changes_dir]));
+        let err = check_dirs_disjoint(&[("work directory", &work_dir), ("parent", dir.path())])
+            .unwrap_err();
+        assert!(err.to_string().contains("must not be within each other"));
+    }
+
+    #[test]
+    fn proc_must_have_mountinfo() {
+        let dir = TestDir::new();
+        assert!(check_proc_mounted_at(dir.path()).is_err());
+        ensure_dir(dir.join("self")).unwrap();
+        fs::write(dir.join("self/mountinfo"), "").unwrap();
+        assert!(check_proc_mounted_at(dir.path()).is_ok());
+        assert!(check_proc_mounted().is_ok());
+    }
+
+    #[test]
+    fn closest_match_suggests_near_misses() {
+        assert_eq!(edit_distance("feature", "featrue"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+        let candidates = ["feature", "features", "main"];
+        assert_eq!(
+            closest_match("featur", candidates.iter().cloned()),
+            Some("feature")
+        );
+        assert_eq!(closest_match("mian", candidates.iter().cloned()), None);
+        assert_eq!(
+            closest_match("man", candidates.iter().cloned()),
+            Some("main")
+        );
+        assert_eq!(closest_match("unrelated", candidates.iter().cloned()), None);
+        assert_eq!(closest_match("main", Vec::new()), None);
+    }
+
+    #[test]
+    fn case_sensitivity_probe_cleans_up() {
+        let dir = TestDir::new();
+        fs::write(dir.join("existing"), "").unwrap();
+        // The test directory is on a case-sensitive filesystem.
+        assert!(!is_case_insensitive_dir(dir.path()).unwrap());
+        let names = || {
+            let mut names: Vec<_> = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(), vec!["existing"]);
+        // An upper case entry which is found when looking up the probe stands
+        // in for a case-insensitive filesystem.
+        let upper_probe = format!(".MZR-CASE-PROBE-{}", std::process::id());
+        fs::write(dir.join(&upper_probe), "").unwrap();
+        assert!(is_case_insensitive_dir(dir.path()).unwrap());
+        assert_eq!(names(), vec![upper_probe.as_str(), "existing"]);
+        assert!(is_case_insensitive_dir(dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn pid_files_parse_old_and_new_formats() {
+        let pid = |contents: &str| parse_pid_file_contents(contents).map(i32::from);
+        // Format written by older versions.
+        assert_eq!(pid("123\n"), Some(123));
+        assert_eq!(pid("  123  "), Some(123));
+        assert_eq!(pid("\n123\nsocket=/run/mzr.sock\n"), Some(123));
+        // Newer format, with unknown keys ignored.
+        assert_eq!(pid("pid=123\n"), Some(123));
+        assert_eq!(
+            pid("start_time=2018-10-01T00:00:00Z\npid=123\nfuture=value\n"),
+            Some(123)
+        );
+        // Garbage.
+        assert_eq!(pid(""), None);
+        assert_eq!(pid("not a pid"), None);
+        assert_eq!(pid("0"), None);
+        assert_eq!(pid("-5"), None);
+        assert_eq!(pid("socket=/run/mzr.sock\n"), None);
+        assert_eq!(pid("pid=abc\n"), None);
+        let dir = TestDir::new();
+        let daemon_dir = DaemonDir::new(&test_mzr_dir(&dir));
+        let pid_file = DaemonPidFile::new(&daemon_dir);
+        assert!(parse_pid_file(&pid_file).is_err());
+        ensure_dir(&daemon_dir).unwrap();
+        fs::write(&pid_file, "pid=42\n").unwrap();
+        assert_eq!(
+            parse_pid_file(&pid_file).unwrap(),
+            unistd::Pid::from_raw(42)
+        );
+        fs::write(&pid_file, "\u{0}\u{1}garbage").unwrap();
+        assert!(parse_pid_file(&pid_file).is_err());
+    }
 }
@@ -0,0 +1,421 @@
+use crate::changes::get_xattr;
+use crate::colors::*;
+use crate::merge::copy_file;
+use failure::{Error, ResultExt};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/*
+ * Filesystem abstraction
+ *
+ * Logic which manipulates snapshot and zone trees, such as applying overlayfs
+ * changes, goes through the `Filesystem` trait. `RealFs` passes operations
+ * through to the actual filesystem, while `MemoryFs` keeps an in-memory tree,
+ * so that the logic can be exercised deterministically.
+ */
+
+/// Type of a filesystem entry. Symlinks are not followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    CharDevice { rdev: u64 },
+    Other,
+}
+
+/// Metadata of a filesystem entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryStat {
+    pub kind: EntryKind,
+    /// Permission bits.
+    pub mode: u32,
+}
+
+impl EntryStat {
+    /// overlayfs represents deletions as character devices with device
+    /// number 0/0.
+    pub fn is_whiteout(&self) -> bool {
+        self.kind == EntryKind::CharDevice { rdev: 0 }
+    }
+}
+
+pub trait Filesystem {
+    /// Metadata of the entry at the path, without following symlinks, or
+    /// `None` if it doesn't exist.
+    fn stat(&self, path: &Path) -> Result<Option<EntryStat>, Error>;
+
+    /// Reads an extended attribute without following symlinks, yielding
+    /// `None` if it is not set.
+    fn get_xattr(&self, path: &Path, attr: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Names of the entries in a directory, in sorted order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>, Error>;
+
+    /// Creates a directory with the specified permission bits.
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<(), Error>;
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error>;
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error>;
+
+    /// Copies a non-directory entry, preserving its properties.
+    fn copy_file(&self, source: &Path, target: &Path) -> Result<(), Error>;
+}
+
+/// Filesystem which passes operations through to the actual filesystem.
+#[derive(Debug, Clone, Copy)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn stat(&self, path: &Path) -> Result<Option<EntryStat>, Error> {
+        let metadata = match fs::symlink_metadata(path) {
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => return Ok(None),
+                _ => Err(e).context(format_err!(
+                    "Unexpected error while reading metadata of {}",
+                    color_file(&path.display())
+                ))?,
+            },
+            Ok(metadata) => metadata,
+        };
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_char_device() {
+            EntryKind::CharDevice {
+                rdev: metadata.rdev(),
+            }
+        } else {
+            EntryKind::Other
+        };
+        Ok(Some(EntryStat {
+            kind,
+            mode: metadata.mode() & 0o7777,
+        }))
+    }
+
+    fn get_xattr(&self, path: &Path, attr: &str) -> Result<Option<Vec<u8>>, Error> {
+        get_xattr(path, attr)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path).context(format_err!(
+            "Unexpected error while reading directory {}",
+            color_dir(&path.display())
+        ))? {
+            names.push(entry?.file_name());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        fs::create_dir(path).context(format_err!(
+            "Unexpected error while creating directory {}",
+            color_dir(&path.display())
+        ))?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_file(path).context(format_err!(
+            "Unexpected error while removing {}",
+            color_file(&path.display())
+        ))?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_dir_all(path).context(format_err!(
+            "Unexpected error while removing {}",
+            color_dir(&path.display())
+        ))?;
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &Path, target: &Path) -> Result<(), Error> {
+        copy_file(&source.to_path_buf(), &target.to_path_buf())
+    }
+}
+
+/// In-memory filesystem, where paths are keys of a map. Parent directories
+/// must be added before their contents.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    entries: RefCell<BTreeMap<PathBuf, MemoryEntry>>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryEntry {
+    stat: EntryStat,
+    contents: Vec<u8>,
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    pub fn add_dir(&self, path: &Path) -> Result<(), Error> {
+        self.add(path, EntryKind::Dir, 0o755, Vec::new())
+    }
+
+    pub fn add_file(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        self.add(path, EntryKind::File, 0o644, contents.to_vec())
+    }
+
+    /// Adds an overlayfs whiteout, marking the path as deleted.
+    pub fn add_whiteout(&self, path: &Path) -> Result<(), Error> {
+        self.add(path, EntryKind::CharDevice { rdev: 0 }, 0, Vec::new())
+    }
+
+    pub fn set_xattr(&self, path: &Path, attr: &str, value: &[u8]) -> Result<(), Error> {
+        match self.entries.borrow_mut().get_mut(path) {
+            Some(entry) => {
+                entry.xattrs.insert(attr.to_string(), value.to_vec());
+                Ok(())
+            }
+            None => bail!("{} does not exist", path.display()),
+        }
+    }
+
+    /// Contents of the file at the path, or `None` if it isn't a file.
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.entries.borrow().get(path) {
+            Some(entry) if entry.stat.kind == EntryKind::File => Some(entry.contents.clone()),
+            _ => None,
+        }
+    }
+
+    /// All paths in the filesystem, in sorted order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.borrow().keys().cloned().collect()
+    }
+
+    fn add(&self, path: &Path, kind: EntryKind, mode: u32, contents: Vec<u8>) -> Result<(), Error> {
+        self.check_parent_is_dir(path)?;
+        let mut entries = self.entries.borrow_mut();
+        if entries.contains_key(path) {
+            bail!("{} already exists", path.display());
+        }
+        entries.insert(
+            path.to_path_buf(),
+            MemoryEntry {
+                stat: EntryStat { kind, mode },
+                contents,
+                xattrs: BTreeMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    fn check_parent_is_dir(&self, path: &Path) -> Result<(), Error> {
+        match path.parent() {
+            // Root directory.
+            Some(parent) if parent == Path::new("") || parent == Path::new("/") => Ok(()),
+            None => Ok(()),
+            Some(parent) => match self.stat(parent)? {
+                Some(EntryStat {
+                    kind: EntryKind::Dir,
+                    ..
+                }) => Ok(()),
+                _ => bail!("{} is not a directory", parent.display()),
+            },
+        }
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn stat(&self, path: &Path) -> Result<Option<EntryStat>, Error> {
+        Ok(self.entries.borrow().get(path).map(|entry| entry.stat))
+    }
+
+    fn get_xattr(&self, path: &Path, attr: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.entries.borrow().get(path) {
+            Some(entry) => Ok(entry.xattrs.get(attr).cloned()),
+            None => bail!("{} does not exist", path.display()),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        match self.stat(path)? {
+            Some(EntryStat {
+                kind: EntryKind::Dir,
+                ..
+            }) => {}
+            _ => bail!("{} is not a directory", path.display()),
+        }
+        Ok(self
+            .entries
+            .borrow()
+            .keys()
+            .filter(|child| child.parent() == Some(path))
+            .filter_map(|child| child.file_name().map(|name| name.to_os_string()))
+            .collect())
+    }
+
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.add(path, EntryKind::Dir, mode, Vec::new())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        let mut entries = self.entries.borrow_mut();
+        match entries.get(path).map(|entry| entry.stat.kind) {
+            None => bail!("{} does not exist", path.display()),
+            Some(EntryKind::Dir) => bail!("{} is a directory", path.display()),
+            Some(_) => {
+                entries.remove(path);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error> {
+        let mut entries = self.entries.borrow_mut();
+        if !entries.contains_key(path) {
+            bail!("{} does not exist", path.display());
+        }
+        let removed: Vec<PathBuf> = entries
+            .keys()
+            .filter(|other| other.starts_with(path))
+            .cloned()
+            .collect();
+        for other in removed {
+            entries.remove(&other);
+        }
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &Path, target: &Path) -> Result<(), Error> {
+        let entry = match self.entries.borrow().get(source) {
+            Some(entry) if entry.stat.kind != EntryKind::Dir => entry.clone(),
+            Some(_) => bail!("{} is a directory", source.display()),
+            None => bail!("{} does not exist", source.display()),
+        };
+        self.check_parent_is_dir(target)?;
+        self.entries
+            .borrow_mut()
+            .insert(target.to_path_buf(), entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changes::apply_to_tree_in;
+
+    fn paths(fs: &MemoryFs) -> Vec<String> {
+        fs.paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn memory_fs_requires_parent_dirs() {
+        let fs = MemoryFs::new();
+        assert!(fs.add_file(Path::new("/a/f"), b"").is_err());
+        fs.add_dir(Path::new("/a")).unwrap();
+        fs.add_file(Path::new("/a/f"), b"contents").unwrap();
+        assert!(fs.add_file(Path::new("/a/f/g"), b"").is_err());
+        assert!(fs.add_file(Path::new("/a/f"), b"").is_err());
+        assert_eq!(fs.contents(Path::new("/a/f")), Some(b"contents".to_vec()));
+        assert_eq!(fs.contents(Path::new("/a")), None);
+    }
+
+    #[test]
+    fn memory_fs_reads_and_removes_only_children() {
+        let fs = MemoryFs::new();
+        for dir in &["/a", "/a/b", "/ab"] {
+            fs.add_dir(Path::new(dir)).unwrap();
+        }
+        fs.add_file(Path::new("/a/b/f"), b"").unwrap();
+        fs.add_file(Path::new("/a/f"), b"").unwrap();
+        assert_eq!(
+            fs.read_dir(Path::new("/a")).unwrap(),
+            vec![OsString::from("b"), OsString::from("f")]
+        );
+        assert!(fs.read_dir(Path::new("/a/f")).is_err());
+        assert!(fs.remove_file(Path::new("/a/b")).is_err());
+        fs.remove_dir_all(Path::new("/a")).unwrap();
+        assert_eq!(paths(&fs), vec!["/ab"]);
+    }
+
+    #[test]
+    fn memory_fs_copies_files_with_xattrs() {
+        let fs = MemoryFs::new();
+        fs.add_dir(Path::new("/d")).unwrap();
+        fs.add_file(Path::new("/f"), b"contents").unwrap();
+        fs.set_xattr(Path::new("/f"), "user.a", b"1").unwrap();
+        fs.copy_file(Path::new("/f"), Path::new("/d/g")).unwrap();
+        assert_eq!(fs.contents(Path::new("/d/g")), Some(b"contents".to_vec()));
+        assert_eq!(
+            fs.get_xattr(Path::new("/d/g"), "user.a").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert!(fs.copy_file(Path::new("/d"), Path::new("/e")).is_err());
+        assert!(fs
+            .copy_file(Path::new("/f"), Path::new("/missing/g"))
+            .is_err());
+    }
+
+    #[test]
+    fn applies_changes_to_memory_tree() {
+        let fs = MemoryFs::new();
+        for dir in &["/changes", "/changes/opaque", "/changes/merged", "/tree"] {
+            fs.add_dir(Path::new(dir)).unwrap();
+        }
+        fs.set_xattr(Path::new("/changes/opaque"), "user.overlay.opaque", b"y")
+            .unwrap();
+        fs.add_file(Path::new("/changes/opaque/new"), b"new")
+            .unwrap();
+        fs.add_file(Path::new("/changes/merged/added"), b"added")
+            .unwrap();
+        fs.add_file(Path::new("/changes/modified"), b"modified")
+            .unwrap();
+        fs.add_whiteout(Path::new("/changes/deleted")).unwrap();
+        for dir in &["/tree/opaque", "/tree/merged"] {
+            fs.add_dir(Path::new(dir)).unwrap();
+        }
+        fs.add_file(Path::new("/tree/opaque/old"), b"old").unwrap();
+        fs.add_file(Path::new("/tree/merged/kept"), b"kept")
+            .unwrap();
+        fs.add_file(Path::new("/tree/modified"), b"original")
+            .unwrap();
+        fs.add_file(Path::new("/tree/deleted"), b"deleted").unwrap();
+        apply_to_tree_in(&fs, Path::new("/changes"), Path::new("/tree")).unwrap();
+        let tree_paths: Vec<String> = paths(&fs)
+            .into_iter()
+            .filter(|path| path.starts_with("/tree/"))
+            .collect();
+        assert_eq!(
+            tree_paths,
+            vec![
+                "/tree/merged",
+                "/tree/merged/added",
+                "/tree/merged/kept",
+                "/tree/modified",
+                "/tree/opaque",
+                "/tree/opaque/new",
+            ]
+        );
+        assert_eq!(
+            fs.contents(Path::new("/tree/modified")),
+            Some(b"modified".to_vec())
+        );
+    }
+}
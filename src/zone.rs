@@ -1,12 +1,33 @@
-use crate::colors::color_dir;
-use crate::json;
+use crate::changes;
+use crate::colors::{color_cmd, color_dir, color_warn, color_zone_name};
+use crate::config::DirMode;
+use crate::diff::{diff_trees, Comparison};
+use crate::json::{self, Versioned};
+use crate::mount::{
+    check_incompat_features, check_lowerdir_count, check_not_on_overlayfs, clean_work_dir,
+    is_mounted, is_redirect_dir_refusal, selinux_enforcing, unmount, MountOptions, OverlayMount,
+};
 use crate::paths::*;
+use crate::registry;
+use crate::snapshot;
+use crate::utils::{
+    check_dirs_disjoint, closest_match, create_new_dir, ensure_dir, ensure_dir_with_mode,
+    is_case_insensitive_dir, list_subdir_names,
+};
 use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
-use libmount::{BindMount, Overlay};
+use libmount::BindMount;
+use nix::sys::utsname::uname;
 use serde::{Deserialize, Serialize};
-use std::fs::{create_dir, create_dir_all};
-use std::iter;
+use serde_json::Value;
+use std::env;
+use std::fs::{self, remove_dir_all, rename};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
 
 #[derive(Debug)]
 pub struct Zone {
@@ -19,54 +40,163 @@ pub struct Zone {
     pub info: ZoneInfo,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZoneInfo {
+    /// Version of the format of this info, see `json::Versioned`.
+    pub version: u32,
     pub snapshot: SnapName,
     pub creation_time: DateTime<Utc>,
+    /// Directory or archive that the changes directory was populated from
+    /// when the zone was created.
+    #[serde(default)]
+    pub changes_seed: Option<PathBuf>,
+    /// How the zone's overlay was most recently mounted.
+    #[serde(default)]
+    pub last_mount: Option<MountRecord>,
+    /// Pinned zones can't be deleted without forcing it.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Note about what the zone is for. See `clean_description`.
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountRecord {
+    pub time: DateTime<Utc>,
+    /// Release of the kernel that the overlay was mounted with.
+    pub kernel_release: String,
+    /// Options passed to the mount, in the form accepted by `mount -o`.
+    pub options: String,
+}
+
+/// The paths used by a zone, as plain paths so that they serialize without
+/// color codes.
+#[derive(Debug, Serialize)]
+pub struct ZonePaths {
+    pub zone_dir: PathBuf,
+    pub changes_dir: PathBuf,
+    pub ovfs_work_dir: PathBuf,
+    pub info_file: PathBuf,
+    pub snap_dir: PathBuf,
+}
+
+impl Versioned for ZoneInfo {
+    const VERSION: u32 = 1;
+
+    fn migrate(version: u32, contents: Value) -> Result<Value, Error> {
+        match version {
+            // Version 0 is info from before versioning, which has the same
+            // format.
+            0 => Ok(contents),
+            _ => bail!(
+                "Unexpected error: no migration from zone info version {}",
+                version
+            ),
+        }
+    }
 }
 
 impl Zone {
+    /// Creates a zone based on the snapshot. The zone's directory is given
+    /// the mode, if specified. See the `dir_mode` config setting.
     pub fn create(
         mzr_dir: &MzrDir,
         zone_name: &ZoneName,
         snap_name: &SnapName,
+        dir_mode: Option<DirMode>,
     ) -> Result<Zone, Error> {
-        let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
-        Zone::create_impl(mzr_dir, &zone_dir, zone_name, snap_name)
+        let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+        Zone::create_impl(mzr_dir, &zone_dir, zone_name, snap_name, None, dir_mode)
+    }
+
+    /// Creates a zone whose changes directory is initially populated from a
+    /// directory or tar archive of changes. If seeding fails, then the zone is
+    /// removed.
+    pub fn create_seeded(
+        mzr_dir: &MzrDir,
+        zone_name: &ZoneName,
+        snap_name: &SnapName,
+        changes_seed: &Path,
+        dir_mode: Option<DirMode>,
+    ) -> Result<Zone, Error> {
+        let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+        Zone::create_impl(
+            mzr_dir,
+            &zone_dir,
+            zone_name,
+            snap_name,
+            Some(changes_seed),
+            dir_mode,
+        )
+    }
+
+    /// Like `create`, but if a zone with the same name and snapshot already
+    /// exists, then it is loaded instead. This allows scripts to be re-run. It
+    /// is an error for the zone to exist with a different snapshot.
+    pub fn create_idempotent(
+        mzr_dir: &MzrDir,
+        zone_name: &ZoneName,
+        snap_name: &SnapName,
+        dir_mode: Option<DirMode>,
+    ) -> Result<Zone, Error> {
+        match Zone::load_if_exists(mzr_dir, zone_name)? {
+            None => Zone::create(mzr_dir, zone_name, snap_name, dir_mode),
+            Some(zone) => {
+                if &zone.info.snapshot == snap_name {
+                    Ok(zone)
+                } else {
+                    bail!(
+                        "{} zone already exists, but is based on the {} snapshot rather than {}.",
+                        zone_name,
+                        zone.info.snapshot,
+                        snap_name
+                    )
+                }
+            }
+        }
     }
 
     pub fn load(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Zone, Error> {
-        let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
-        Zone::load_impl(mzr_dir, &zone_dir, &zone_name)
+        let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+        if !zone_dir.is_dir() {
+            bail!(
+                "{} zone does not exist.{}",
+                zone_name,
+                did_you_mean_zone(mzr_dir, zone_name)?
+            );
+        }
+        Zone::load_impl(mzr_dir, &zone_dir, zone_name)
     }
 
     pub fn load_if_exists(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<Option<Zone>, Error> {
-        let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
+        let zone_dir = ZoneDir::new(mzr_dir, zone_name);
         if zone_dir.is_dir() {
-            Ok(Some(Zone::load_impl(mzr_dir, &zone_dir, &zone_name)?))
+            Ok(Some(Zone::load_impl(mzr_dir, &zone_dir, zone_name)?))
         } else {
             Ok(None)
         }
     }
 
     pub fn exists(mzr_dir: &MzrDir, zone_name: &ZoneName) -> bool {
-        ZoneDir::new(mzr_dir, &zone_name).is_dir()
+        ZoneDir::new(mzr_dir, zone_name).is_dir()
     }
 
     pub fn load_or_create<F>(
         mzr_dir: &MzrDir,
         zone_name: &ZoneName,
         get_snap_name: F,
+        dir_mode: Option<DirMode>,
     ) -> Result<Zone, Error>
     where
         F: FnOnce() -> Result<SnapName, Error>,
     {
-        let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
+        let zone_dir = ZoneDir::new(mzr_dir, zone_name);
         if zone_dir.is_dir() {
-            Zone::load_impl(mzr_dir, &zone_dir, &zone_name)
+            Zone::load_impl(mzr_dir, &zone_dir, zone_name)
         } else {
             let snap_name = get_snap_name()?;
-            Zone::create_impl(mzr_dir, &zone_dir, zone_name, &snap_name)
+            Zone::create_impl(mzr_dir, &zone_dir, zone_name, &snap_name, None, dir_mode)
         }
     }
 
@@ -75,68 +205,66 @@ impl Zone {
         zone_dir: &ZoneDir,
         zone_name: &ZoneName,
         snap_name: &SnapName,
+        changes_seed: Option<&Path>,
+        dir_mode: Option<DirMode>,
     ) -> Result<Zone, Error> {
-        let snap_dir = SnapDir::new(mzr_dir, &snap_name);
+        let snap_dir = SnapDir::new(mzr_dir, snap_name);
         if !snap_dir.is_dir() {
             bail!(
-                "Expected that the {} snapshot would exist at {}",
+                "Expected that the {} snapshot would exist at {}.{}",
                 snap_name,
-                snap_dir
+                snap_dir,
+                snapshot::did_you_mean_snap(mzr_dir, snap_name)?
             );
         }
+        check_zone_limit(mzr_dir, max_zones()?)?;
+        check_case_collision(mzr_dir, zone_name)?;
         let zone_parent = zone_dir
             .parent()
             .ok_or_else(|| format_err!("Unexpected error: zone directory must have a parent."))?;
-        create_dir_all(zone_parent).context(format_err!(
-            "Unexpected error while creating zone parent directory {}",
-            color_dir(&zone_parent.display())
-        ))?;
-        match create_dir(zone_dir.clone()) {
-            Err(e) => {
-                if zone_dir.exists() {
-                    Err(e).context(format_err!(
-                        "{} zone already exists at {}",
-                        zone_name,
-                        zone_dir
-                    ))?
-                } else {
-                    Err(e).context(format_err!(
-                        "Unexpected error while creating zone directory {}",
-                        zone_dir
-                    ))?
-                }
-            }
-            Ok(()) => {
-                let ovfs_changes_dir = OvfsChangesDir::new(&zone_dir);
-                let ovfs_work_dir = OvfsWorkDir::new(&zone_dir);
-                let ovfs_mount_dir = OvfsMountDir::new(&zone_dir);
-                create_dir_all(ovfs_changes_dir.clone()).context(format_err!(
-                    "Unexpected error while creating zone changes directory for overlayfs: {}",
-                    ovfs_changes_dir
-                ))?;
-                create_dir_all(ovfs_work_dir.clone()).context(format_err!(
-                    "Unexpected error while creating zone work directory for overlayfs: {}",
-                    ovfs_work_dir
-                ))?;
-                create_dir_all(ovfs_mount_dir.clone()).context(format_err!(
-                    "Unexpected error while creating zone mount directory for overlayfs: {}",
-                    ovfs_mount_dir
-                ))?;
-                let info = ZoneInfo {
-                    snapshot: snap_name.clone(),
-                    creation_time: Utc::now(),
-                };
-                json::write(&ZoneInfoFile::new(&zone_dir), &info)?;
-                Ok(Zone {
-                    name: zone_name.clone(),
-                    zone_dir: zone_dir.clone(),
-                    snap_dir,
-                    ovfs_changes_dir,
-                    ovfs_work_dir,
-                    ovfs_mount_dir,
-                    info,
-                })
-            }
+        ensure_dir_with_mode(zone_parent, dir_mode)?;
+        check_not_on_overlayfs(zone_parent)?;
+        let reservation = ZoneReservation::new(mzr_dir, zone_dir, zone_name, dir_mode)?;
+        let ovfs_changes_dir = zone_dir.changes_dir();
+        let ovfs_work_dir = zone_dir.ovfs_work_dir();
+        let ovfs_mount_dir = zone_dir.ovfs_mount_dir();
+        ensure_dir(&ovfs_changes_dir)?;
+        ensure_dir(&ovfs_work_dir)?;
+        ensure_dir(&ovfs_mount_dir)?;
+        if let Some(seed) = changes_seed {
+            changes::seed(&ovfs_changes_dir, seed)?;
+        }
+        let info = ZoneInfo {
+            version: ZoneInfo::VERSION,
+            snapshot: snap_name.clone(),
+            creation_time: Utc::now(),
+            changes_seed: changes_seed.map(PathBuf::from),
+            last_mount: None,
+            pinned: false,
+            description: None,
+        };
+        // Writing the info file is what makes the zone exist, so after this it
+        // no longer gets removed on failure.
+        json::write(&zone_dir.info_file(), &info)?;
+        reservation.complete();
+        Ok(Zone {
+            name: zone_name.clone(),
+            zone_dir: zone_dir.clone(),
+            snap_dir,
+            ovfs_changes_dir,
+            ovfs_work_dir,
+            ovfs_mount_dir,
+            info,
+        })
+    }
+
+    pub fn paths(&self) -> ZonePaths {
+        ZonePaths {
+            zone_dir: self.zone_dir.to_path_buf(),
+            changes_dir: self.ovfs_changes_dir.to_path_buf(),
+            ovfs_work_dir: self.ovfs_work_dir.to_path_buf(),
+            info_file: self.zone_dir.info_file().to_path_buf(),
+            snap_dir: self.snap_dir.to_path_buf(),
         }
     }
 
@@ -145,11 +273,11 @@ impl Zone {
         zone_dir: &ZoneDir,
         zone_name: &ZoneName,
     ) -> Result<Zone, Error> {
-        let info: ZoneInfo = json::read(&ZoneInfoFile::new(&zone_dir))?.contents;
+        let info: ZoneInfo = json::read_versioned(&zone_dir.info_file())?;
         let snap_dir = SnapDir::new(mzr_dir, &info.snapshot);
-        let ovfs_changes_dir = OvfsChangesDir::new(zone_dir);
-        let ovfs_work_dir = OvfsWorkDir::new(zone_dir);
-        let ovfs_mount_dir = OvfsMountDir::new(zone_dir);
+        let ovfs_changes_dir = zone_dir.changes_dir();
+        let ovfs_work_dir = zone_dir.ovfs_work_dir();
+        let ovfs_mount_dir = zone_dir.ovfs_mount_dir();
         Ok(Zone {
             name: zone_name.clone(),
             zone_dir: zone_dir.clone(),
@@ -161,23 +289,1201 @@ impl Zone {
         })
     }
 
-    pub fn mount(&self) -> Result<(), Error> {
-        Overlay::writable(
-            iter::once(self.snap_dir.as_ref()),
-            &self.ovfs_changes_dir,
-            &self.ovfs_work_dir,
-            &self.ovfs_mount_dir,
-        )
-        .mount()
-        // TODO(cleanup): Should make it so that '?' can be used,
-        // by making libmount Error implement Sync. Same pattern
-        // repeated below for bind mount.
-        .map_err(|e| format_err!("{}", e))
+    /// Replaces the zone's snapshot with a snapshot of its merged state, and
+    /// clears its changes. This reclaims space used by changes which have been
+    /// superseded, such as files which were created and then deleted.
+    ///
+    /// The zone must not be mounted while this happens.
+    pub fn compact(
+        &mut self,
+        mzr_dir: &MzrDir,
+        snap_name: &SnapName,
+        dir_mode: Option<DirMode>,
+    ) -> Result<(), Error> {
+        let snap_dir = snapshot::of_zone(mzr_dir, self, snap_name, dir_mode)?;
+        self.info = update_info(&self.zone_dir, |info| info.snapshot = snap_name.clone())?;
+        self.snap_dir = snap_dir;
+        recreate_dir(&self.ovfs_changes_dir)?;
+        recreate_dir(&self.ovfs_work_dir)?;
+        Ok(())
+    }
+
+    /// Changes the snapshot that the zone is based on, keeping its changes.
+    /// Changes to paths which differ between the old and new snapshots may
+    /// no longer make sense, see `rebase_conflicts`.
+    ///
+    /// The zone must not be mounted while this happens, including within the
+    /// namespaces of zone processes, since its work directory gets recreated.
+    pub fn rebase(&mut self, mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<(), Error> {
+        let snap_dir = existing_snap_dir(mzr_dir, snap_name)?;
+        self.info = update_info(&self.zone_dir, |info| info.snapshot = snap_name.clone())?;
+        self.snap_dir = snap_dir;
+        // The overlay's work directory may refer to the old snapshot, such
+        // as in its index.
+        recreate_dir(&self.ovfs_work_dir)?;
+        Ok(())
     }
 
-    pub fn bind_to(&self, user_work_dir: &UserWorkDir) -> Result<(), Error> {
-        BindMount::new(&self.ovfs_mount_dir, &user_work_dir)
+    /// Finds the zone's changes which would conflict with rebasing it onto
+    /// another snapshot - changes to paths which differ between the zone's
+    /// snapshot and the other snapshot, or which are within or contain such
+    /// paths. Directories which only merge with the snapshot's directories
+    /// are not considered changes.
+    pub fn rebase_conflicts(
+        &self,
+        mzr_dir: &MzrDir,
+        snap_name: &SnapName,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let snap_dir = existing_snap_dir(mzr_dir, snap_name)?;
+        let diff = diff_trees(&self.snap_dir, &snap_dir, Comparison::Metadata)?;
+        let differing: Vec<&PathBuf> = diff
+            .added
+            .iter()
+            .chain(diff.removed.iter())
+            .chain(diff.modified.iter())
+            .collect();
+        let mut changed: Vec<PathBuf> = changes::list_whiteouts(&self.ovfs_changes_dir)?
+            .into_iter()
+            .map(|whiteout| whiteout.rel_path)
+            .collect();
+        for walk_result in WalkDir::new(&self.ovfs_changes_dir).min_depth(1) {
+            let entry = walk_result?;
+            if changes::ChangeKind::of(entry.path())? == Some(changes::ChangeKind::File) {
+                changed.push(self.ovfs_changes_dir.work_relative_path(entry.path())?);
+            }
+        }
+        changed.sort();
+        changed.dedup();
+        Ok(changed
+            .into_iter()
+            .filter(|path| {
+                differing
+                    .iter()
+                    .any(|other| path.starts_with(other) || other.starts_with(path))
+            })
+            .collect())
+    }
+
+    pub fn mount(&self, options: &MountOptions) -> Result<(), Error> {
+        check_incompat_features(&self.ovfs_work_dir)?;
+        if options.clean_work_dir {
+            clean_work_dir(&self.ovfs_work_dir)?;
+        }
+        check_dirs_disjoint(&[
+            ("snapshot directory", &self.snap_dir),
+            ("changes directory", &self.ovfs_changes_dir),
+            ("overlay work directory", &self.ovfs_work_dir),
+            ("overlay mount directory", &self.ovfs_mount_dir),
+        ])?;
+        snapshot::warn_if_writable_by_others(&self.snap_dir)?;
+        if options.selinux_context.is_none() && selinux_enforcing() {
+            println!(
+                "{} SELinux is enforcing, but no selinux_context is configured for zone mounts, \
+                 so processes may be denied access to files in the zone.",
+                color_warn(&"Warning:")
+            );
+        }
+        let mut overlay = OverlayMount::for_zone(self, options);
+        check_lowerdir_count(&overlay, options.max_lowerdirs)?;
+        if let Err(err) = overlay.mount(&options.retry) {
+            if !overlay.enables_redirect_dir() || !is_redirect_dir_refusal(&err) {
+                return Err(err);
+            }
+            println!(
+                "{} Failed to mount with redirect_dir enabled, so mounting without it. \
+                 Renaming directories from the snapshot will fail with EXDEV.",
+                color_warn(&"Warning:")
+            );
+            overlay = OverlayMount::for_zone(
+                self,
+                &MountOptions {
+                    redirect_dir: false,
+                    ..options.clone()
+                },
+            );
+            overlay.mount(&options.retry)?;
+        }
+        if options.record_mount {
+            self.record_mount(&overlay);
+        }
+        Ok(())
+    }
+
+    /// Records the mount in the zone's info file, for debugging. Failure to
+    /// do so is only a warning, since the zone is already mounted.
+    fn record_mount(&self, overlay: &OverlayMount) {
+        let record = MountRecord {
+            time: Utc::now(),
+            kernel_release: uname().release().to_string(),
+            options: overlay.options(),
+        };
+        if let Err(e) = update_info(&self.zone_dir, |info| info.last_mount = Some(record)) {
+            println!(
+                "{} Failed to record mount of {} zone in {}: {}",
+                color_warn(&"Warning:"),
+                self.name,
+                self.zone_dir.info_file(),
+                e
+            );
+        }
+    }
+
+    /// Bind-mounts the zone's overlay to a target directory - typically the
+    /// `UserWorkDir`, but can also be a `ZoneTargetDir`.
+    pub fn bind_to<T: AsRef<Path>>(&self, target_dir: &T) -> Result<(), Error> {
+        check_dirs_disjoint(&[
+            ("zone target directory", target_dir.as_ref()),
+            ("zone directory", &self.zone_dir),
+            ("snapshot directory", &self.snap_dir),
+        ])?;
+        BindMount::new(&self.ovfs_mount_dir, target_dir.as_ref())
             .mount()
             .map_err(|e| format_err!("{}", e))
     }
 }
+
+fn existing_snap_dir(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<SnapDir, Error> {
+    let snap_dir = SnapDir::new(mzr_dir, snap_name);
+    if !snap_dir.is_dir() {
+        bail!(
+            "{} snapshot does not exist.{}",
+            snap_name,
+            snapshot::did_you_mean_snap(mzr_dir, snap_name)?
+        );
+    }
+    Ok(snap_dir)
+}
+
+/// Lists the names of all zones.
+pub fn list_zones(mzr_dir: &MzrDir) -> Result<Vec<ZoneName>, Error> {
+    list_subdir_names(ZonesDir::new(mzr_dir))?
+        .into_iter()
+        .map(ZoneName::new)
+        .collect()
+}
+
+/// Lists the zones based on the snapshot. Zones whose info can't be read are
+/// skipped with a warning, so that one broken zone doesn't prevent finding the
+/// others.
+pub fn zones_based_on(mzr_dir: &MzrDir, snap_name: &SnapName) -> Result<Vec<ZoneName>, Error> {
+    let mut result = Vec::new();
+    for zone_name in list_zones(mzr_dir)? {
+        let info_file = ZoneDir::new(mzr_dir, &zone_name).info_file();
+        match json::read_versioned::<ZoneInfo>(&info_file) {
+            Ok(info) => {
+                if &info.snapshot == snap_name {
+                    result.push(zone_name);
+                }
+            }
+            Err(e) => println!(
+                "{} Skipping {} zone, since its info can't be read: {}",
+                color_warn(&"Warning:"),
+                zone_name,
+                e
+            ),
+        }
+    }
+    Ok(result)
+}
+
+/// Just the creation time of zone info, so that reading it skips the rest.
+#[derive(Debug, Deserialize)]
+struct ZoneCreationTime {
+    #[serde(default)]
+    version: u32,
+    creation_time: DateTime<Utc>,
+}
+
+/// When the zone was created, for cheaply sorting many zones. Only the
+/// creation time is read from the zone's info. If the info is missing,
+/// unreadable, or from a newer version of mzr, then the zone directory's birth
+/// time is used instead. Where the filesystem doesn't record birth times, its
+/// status change time is used, which is only an approximation, since it
+/// changes along with the directory's metadata or entries.
+pub fn zone_created_at(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<SystemTime, Error> {
+    let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+    if !zone_dir.is_dir() {
+        bail!(
+            "{} zone does not exist.{}",
+            zone_name,
+            did_you_mean_zone(mzr_dir, zone_name)?
+        );
+    }
+    match json::read::<ZoneCreationTime>(&zone_dir.info_file()) {
+        Ok(file) if file.contents.version <= ZoneInfo::VERSION => {
+            Ok(SystemTime::from(file.contents.creation_time))
+        }
+        _ => {
+            let metadata = fs::metadata(&zone_dir).context(format_err!(
+                "Unexpected error while reading metadata of zone directory {}",
+                zone_dir
+            ))?;
+            Ok(metadata.created().unwrap_or_else(|_| {
+                UNIX_EPOCH
+                    + Duration::new(
+                        metadata.ctime().max(0) as u64,
+                        metadata.ctime_nsec().max(0) as u32,
+                    )
+            }))
+        }
+    }
+}
+
+/// Zone directory containing a `changes` or `ovfs-work` directory, but no
+/// valid zone info, such as one left over from an interrupted create.
+#[derive(Debug)]
+pub struct OrphanedZoneDir {
+    pub zone_dir: ZoneDir,
+    /// Why the zone info isn't valid.
+    pub reason: String,
+    /// Whether the directory was modified recently, and so might belong to a
+    /// create which is still in progress.
+    pub in_progress: bool,
+}
+
+/// Zone directories modified more recently than this are considered to
+/// possibly be in the middle of being created.
+const IN_PROGRESS_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Finds zone directories whose `changes` or `ovfs-work` directories are not
+/// associated with a valid zone, so that they can be reported for cleanup.
+pub fn find_orphaned_dirs(mzr_dir: &MzrDir) -> Result<Vec<OrphanedZoneDir>, Error> {
+    let mut result = Vec::new();
+    for zone_name in list_zones(mzr_dir)? {
+        let zone_dir = ZoneDir::new(mzr_dir, &zone_name);
+        let changes_dir = zone_dir.changes_dir();
+        let ovfs_work_dir = zone_dir.ovfs_work_dir();
+        if !changes_dir.exists() && !ovfs_work_dir.exists() {
+            continue;
+        }
+        let info_file = zone_dir.info_file();
+        let reason = if !info_file.exists() {
+            format!("{} is missing", info_file)
+        } else {
+            match json::read_versioned::<ZoneInfo>(&info_file) {
+                Ok(_) => continue,
+                Err(e) => format!("{} is invalid: {}", info_file, e),
+            }
+        };
+        let in_progress = may_be_in_progress(&zone_dir);
+        result.push(OrphanedZoneDir {
+            zone_dir,
+            reason,
+            in_progress,
+        });
+    }
+    Ok(result)
+}
+
+/// Whether the zone directory or any of its standard contents were modified
+/// within `IN_PROGRESS_AGE`.
+fn may_be_in_progress(zone_dir: &ZoneDir) -> bool {
+    let paths: [&Path; 4] = [
+        zone_dir,
+        &zone_dir.changes_dir(),
+        &zone_dir.ovfs_work_dir(),
+        &zone_dir.info_file(),
+    ];
+    paths.iter().any(|path| {
+        fs::symlink_metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age < IN_PROGRESS_AGE)
+    })
+}
+
+/// Reservation of a zone's name, made by creating its directory, so that
+/// concurrent creates of the same zone can't both succeed. Unless `complete`
+/// is called, the directory is removed when this is dropped, which also
+/// happens when unwinding.
+struct ZoneReservation {
+    zone_dir: ZoneDir,
+    completed: bool,
+}
+
+impl ZoneReservation {
+    fn new(
+        mzr_dir: &MzrDir,
+        zone_dir: &ZoneDir,
+        zone_name: &ZoneName,
+        dir_mode: Option<DirMode>,
+    ) -> Result<Self, Error> {
+        loop {
+            if create_new_dir(zone_dir, dir_mode)? {
+                return Ok(ZoneReservation {
+                    zone_dir: zone_dir.clone(),
+                    completed: false,
+                });
+            }
+            if zone_dir.info_file().exists() {
+                bail!("{} zone already exists at {}", zone_name, zone_dir);
+            }
+            if may_be_in_progress(zone_dir) {
+                bail!(
+                    "{} zone is being created by another process. If that's not the case, \
+                     remove {} and try again.",
+                    zone_name,
+                    zone_dir
+                );
+            }
+            // A stale zone directory without an info file is left over from
+            // an interrupted create, so it gets replaced. Moving it aside
+            // first means that only one process can claim it.
+            let tmp_dir = TmpDir::new(mzr_dir);
+            ensure_dir(&tmp_dir)?;
+            let leftover_dir = tmp_dir.join(format!("{}-{}", **zone_name, process::id()));
+            match rename(zone_dir, &leftover_dir) {
+                Ok(()) => remove_dir_all(&leftover_dir).context(format_err!(
+                    "Failed to remove {}, left over from an interrupted create.",
+                    color_dir(&leftover_dir.display())
+                ))?,
+                // Claimed by another process.
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => Err(e).context(format_err!(
+                    "Unexpected error while moving {} to {}",
+                    zone_dir,
+                    color_dir(&leftover_dir.display())
+                ))?,
+            }
+        }
+    }
+
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for ZoneReservation {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        if let Err(e) = remove_dir_all(&self.zone_dir) {
+            println!(
+                "{} Failed to remove zone directory {} after failing to create zone: {}",
+                color_warn(&"Warning:"),
+                self.zone_dir,
+                e
+            );
+        }
+    }
+}
+
+/// Environment variable which limits the number of zones that can exist,
+/// which protects against runaway scripts creating many zones. By default,
+/// there is no limit.
+const MAX_ZONES_VAR: &str = "MZR_MAX_ZONES";
+
+/// Reads the limit on the number of zones from `MAX_ZONES_VAR`.
+fn max_zones() -> Result<Option<usize>, Error> {
+    parse_max_zones(env::var(MAX_ZONES_VAR))
+}
+
+fn parse_max_zones(var: Result<String, env::VarError>) -> Result<Option<usize>, Error> {
+    match var {
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).context(format_err!("Failed to read {}", MAX_ZONES_VAR))?,
+        Ok(value) => Ok(Some(value.parse::<usize>().context(format_err!(
+            "Expected {} to be a number, but it is {:?}",
+            MAX_ZONES_VAR,
+            value
+        ))?)),
+    }
+}
+
+fn check_zone_limit(mzr_dir: &MzrDir, max_zones: Option<usize>) -> Result<(), Error> {
+    let max_zones = match max_zones {
+        None => return Ok(()),
+        Some(max_zones) => max_zones,
+    };
+    let zone_count = list_zones(mzr_dir)?.len();
+    if zone_count >= max_zones {
+        bail!(
+            "Can't create zone, because there are already {} zones, and {} is set to {}.",
+            zone_count,
+            MAX_ZONES_VAR,
+            max_zones
+        );
+    }
+    Ok(())
+}
+
+/// Refuses to create a zone whose name only differs in case from an existing
+/// zone, when the filesystem is case-insensitive, since their directories would
+/// be the same.
+fn check_case_collision(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    let lower_name = zone_name.to_lowercase();
+    let colliding = list_zones(mzr_dir)?
+        .into_iter()
+        .find(|other| **other != **zone_name && other.to_lowercase() == lower_name);
+    if let Some(other) = colliding {
+        // Only probe when needed, since it involves creating a file.
+        if is_case_insensitive_dir(ZonesDir::new(mzr_dir))? {
+            bail!(
+                "Can't create zone {}, because its name would be the same as the existing {} \
+                 zone on this case-insensitive filesystem.",
+                zone_name,
+                other
+            );
+        }
+    }
+    Ok(())
+}
+
+/// When a zone doesn't exist, this yields a hint suggesting a zone with a
+/// similar name, or an empty string if there isn't one.
+pub fn did_you_mean_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<String, Error> {
+    let zone_names = list_zones(mzr_dir)?;
+    Ok(
+        match closest_match(zone_name, zone_names.iter().map(|x| x.as_str())) {
+            None => String::new(),
+            Some(suggestion) => format!(" Did you mean {}?", color_zone_name(&suggestion)),
+        },
+    )
+}
+
+/*
+ * Deleting zones
+ */
+
+/// Moves a zone's directory into the trash directory, so that it can be
+/// recovered if it was deleted by accident. The zone's name is suffixed with
+/// the time of deletion, so that zones with the same name don't collide.
+pub fn move_to_trash(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    force: bool,
+) -> Result<PathBuf, Error> {
+    check_not_pinned(mzr_dir, zone_name, force)?;
+    let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+    let trash_dir = TrashDir::new(mzr_dir);
+    ensure_dir(&trash_dir)?;
+    let trashed_dir = trash_dir.join(format!(
+        "{}-{}",
+        **zone_name,
+        Utc::now().format("%Y%m%d-%H%M%S")
+    ));
+    if trashed_dir.exists() {
+        bail!(
+            "Can't move zone to trash, because {} already exists.",
+            color_dir(&trashed_dir.display())
+        );
+    }
+    rename(&zone_dir, &trashed_dir).context(format_err!(
+        "Unexpected error while moving {} to {}",
+        zone_dir,
+        color_dir(&trashed_dir.display())
+    ))?;
+    Ok(trashed_dir)
+}
+
+/// Permanently deletes a zone's directory.
+pub fn delete(mzr_dir: &MzrDir, zone_name: &ZoneName, force: bool) -> Result<(), Error> {
+    check_not_pinned(mzr_dir, zone_name, force)?;
+    let zone_dir = ZoneDir::new(mzr_dir, zone_name);
+    remove_dir_all(&zone_dir).context(format_err!(
+        "Unexpected error while removing zone directory {}",
+        zone_dir
+    ))?;
+    Ok(())
+}
+
+/// Discards all of the zone's changes, including deletions, so that it matches
+/// its snapshot again.
+///
+/// The zone must not be mounted while this happens.
+pub fn reset_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    recreate_dir(&zone.ovfs_changes_dir)?;
+    // The overlay's work directory may refer to the discarded changes, such
+    // as in its index.
+    recreate_dir(&zone.ovfs_work_dir)?;
+    Ok(())
+}
+
+/// Refuses to delete a pinned zone, unless forced.
+pub fn check_not_pinned(mzr_dir: &MzrDir, zone_name: &ZoneName, force: bool) -> Result<(), Error> {
+    if !force && Zone::load(mzr_dir, zone_name)?.info.pinned {
+        bail!(
+            "Refusing to delete {} zone, because it is pinned. Use {} to unpin it, or {} to \
+             delete it anyway.",
+            zone_name,
+            color_cmd(&"mzr unpin"),
+            color_cmd(&"--force")
+        );
+    }
+    Ok(())
+}
+
+/// Pins a zone, so that deleting it requires forcing it.
+pub fn pin_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    set_pinned(mzr_dir, zone_name, true)
+}
+
+pub fn unpin_zone(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    set_pinned(mzr_dir, zone_name, false)
+}
+
+fn set_pinned(mzr_dir: &MzrDir, zone_name: &ZoneName, pinned: bool) -> Result<(), Error> {
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    update_info(&zone.zone_dir, |info| info.pinned = pinned)?;
+    Ok(())
+}
+
+/// Maximum length of a zone description, in characters.
+const MAX_DESCRIPTION_CHARS: usize = 200;
+
+/// Sets the zone's description, yielding the description as stored, after
+/// `clean_description`.
+pub fn set_description(
+    mzr_dir: &MzrDir,
+    zone_name: &ZoneName,
+    description: &str,
+) -> Result<String, Error> {
+    let description = clean_description(description)?;
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    update_info(&zone.zone_dir, |info| {
+        info.description = Some(description.clone())
+    })?;
+    Ok(description)
+}
+
+pub fn clear_description(mzr_dir: &MzrDir, zone_name: &ZoneName) -> Result<(), Error> {
+    let zone = Zone::load(mzr_dir, zone_name)?;
+    update_info(&zone.zone_dir, |info| info.description = None)?;
+    Ok(())
+}
+
+/// Updates a zone's info file, yielding the updated info. The info is re-read
+/// while holding a lock on the file, so that concurrent updates to other
+/// fields, such as by `mzr pin` during a mount, don't get overwritten.
+fn update_info<F: FnOnce(&mut ZoneInfo)>(zone_dir: &ZoneDir, update: F) -> Result<ZoneInfo, Error> {
+    let info_file = zone_dir.info_file();
+    let _lock = registry::lock(
+        &format!("zone info {}", info_file.display()),
+        &format!("updating {}", info_file.display()),
+    );
+    let mut info: ZoneInfo = json::read_versioned(&info_file)?;
+    update(&mut info);
+    json::write(&info_file, &info)?;
+    Ok(info)
+}
+
+/// Prepares a description for storage, so that it can be displayed on one
+/// line. Tabs and newlines become spaces, other control characters are
+/// removed, and surrounding whitespace is trimmed. Empty and overly long
+/// descriptions are rejected.
+pub fn clean_description(description: &str) -> Result<String, Error> {
+    let cleaned: String = description
+        .chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        bail!("Zone description must not be empty.");
+    }
+    let len = cleaned.chars().count();
+    if len > MAX_DESCRIPTION_CHARS {
+        bail!(
+            "Zone description is {} characters long, but must be at most {}.",
+            len,
+            MAX_DESCRIPTION_CHARS
+        );
+    }
+    Ok(cleaned.to_string())
+}
+
+/// Creates a uniquely named zone based on the snapshot, and runs the function
+/// with it. The zone and its changes are deleted afterwards, whether the
+/// function succeeds, fails, or panics. If the zone is mounted in the current
+/// mount namespace, it gets unmounted first.
+pub fn with_temp_zone<F, T>(mzr_dir: &MzrDir, snap_name: &SnapName, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&Zone) -> Result<T, Error>,
+{
+    let pid = std::process::id();
+    let mut suffix = 0;
+    let zone_name = loop {
+        let candidate = ZoneName::new(format!("tmp-{}-{}", pid, suffix))?;
+        if !Zone::exists(mzr_dir, &candidate) {
+            break candidate;
+        }
+        suffix += 1;
+    };
+    let temp_zone = TempZone {
+        mzr_dir,
+        zone: Zone::create(mzr_dir, &zone_name, snap_name, None)?,
+    };
+    f(&temp_zone.zone)
+}
+
+/// Deletes its zone when dropped, which also happens when unwinding.
+struct TempZone<'a> {
+    mzr_dir: &'a MzrDir,
+    zone: Zone,
+}
+
+impl<'a> TempZone<'a> {
+    fn remove(&self) -> Result<(), Error> {
+        if is_mounted(&self.zone.ovfs_mount_dir)? {
+            unmount(&self.zone.ovfs_mount_dir)?;
+        }
+        // Temporary zones aren't pinned, but nothing should stop cleanup.
+        delete(self.mzr_dir, &self.zone.name, true)
+    }
+}
+
+impl<'a> Drop for TempZone<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.remove() {
+            println!(
+                "{} Failed to remove temporary zone {}: {}",
+                color_warn(&"Warning:"),
+                self.zone.name,
+                e
+            );
+        }
+    }
+}
+
+/// Lists the paths of the zones in the trash directory.
+pub fn list_trash(mzr_dir: &MzrDir) -> Result<Vec<PathBuf>, Error> {
+    let trash_dir = TrashDir::new(mzr_dir);
+    Ok(list_subdir_names(&trash_dir)?
+        .into_iter()
+        .map(|name| trash_dir.join(name))
+        .collect())
+}
+
+/// Permanently deletes the zones in the trash directory.
+pub fn empty_trash(mzr_dir: &MzrDir) -> Result<(), Error> {
+    for trashed_dir in list_trash(mzr_dir)? {
+        remove_dir_all(&trashed_dir).context(format_err!(
+            "Unexpected error while removing {}",
+            color_dir(&trashed_dir.display())
+        ))?;
+    }
+    Ok(())
+}
+
+/// Removes a directory along with its contents, and then creates it again
+/// empty.
+fn recreate_dir(dir: &Path) -> Result<(), Error> {
+    remove_dir_all(dir).context(format_err!(
+        "Unexpected error while removing directory {}",
+        color_dir(&dir.display())
+    ))?;
+    ensure_dir(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{self, Compression};
+    use crate::error_report::strip_ansi_escapes;
+    use crate::test_utils::{test_mzr_dir, test_snapshot, test_zone, write_files, TestDir};
+    use std::os::unix::fs::symlink;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn descriptions_are_cleaned_to_one_line() {
+        assert_eq!(
+            clean_description("  fix\tthe\r\nbuild\u{7}  ").unwrap(),
+            "fix the  build"
+        );
+        assert!(clean_description(" \n\t").is_err());
+        assert!(clean_description("\u{1b}").is_err());
+        let longest: String = "é".repeat(MAX_DESCRIPTION_CHARS);
+        assert_eq!(clean_description(&longest).unwrap(), longest);
+        assert!(clean_description(&format!("{}x", longest)).is_err());
+    }
+
+    #[test]
+    fn temp_zones_are_deleted_after_failure_or_panic() {
+        let dir = TestDir::new();
+        let mzr_dir = MzrDir::new(&UserWorkDir::new(&dir.join("proj")));
+        let snap_name = SnapName::new("s1".to_string()).unwrap();
+        ensure_dir(SnapDir::new(&mzr_dir, &snap_name)).unwrap();
+        let zone_dirs = || list_zones(&mzr_dir).unwrap().len();
+        let result: Result<(), Error> = with_temp_zone(&mzr_dir, &snap_name, |zone| {
+            assert!(zone.zone_dir.info_file().is_file());
+            bail!("failed")
+        });
+        assert!(result.is_err());
+        assert_eq!(zone_dirs(), 0);
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            with_temp_zone(&mzr_dir, &snap_name, |_| -> Result<(), Error> {
+                panic!("panicked")
+            })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(zone_dirs(), 0);
+    }
+
+    #[test]
+    fn recording_a_mount_keeps_concurrent_updates() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let zone = test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        assert!(zone.info.last_mount.is_none());
+        // Pinning the zone after it was loaded stands in for a concurrent
+        // update of its info.
+        pin_zone(&mzr_dir, &zone.name).unwrap();
+        let overlay = OverlayMount::for_zone(&zone, &MountOptions::default());
+        let mount_times = (0..2)
+            .map(|_| {
+                let before = Utc::now();
+                zone.record_mount(&overlay);
+                let info = Zone::load(&mzr_dir, &zone.name).unwrap().info;
+                assert!(info.pinned);
+                let record = info.last_mount.unwrap();
+                assert!(before <= record.time && record.time <= Utc::now());
+                assert_eq!(record.options, overlay.options());
+                record.time
+            })
+            .collect::<Vec<_>>();
+        assert!(mount_times[0] <= mount_times[1]);
+    }
+
+    /// Creates a directory of changes along with a tar archive of it, yielding
+    /// their paths.
+    fn seed_dir_and_tar(dir: &TestDir, name: &str, link_target: &str) -> (PathBuf, PathBuf) {
+        let seed_dir = dir.join(name);
+        write_files(&seed_dir, &[("added", "new"), ("dir/nested", "nested")]);
+        symlink(link_target, seed_dir.join("link")).unwrap();
+        let seed_tar = dir.join(format!("{}.tar", name));
+        archive::create(&seed_dir, &seed_tar, Compression::None).unwrap();
+        (seed_dir, seed_tar)
+    }
+
+    #[test]
+    fn creates_zones_seeded_with_changes() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let (seed_dir, seed_tar) = seed_dir_and_tar(&dir, "seed", "dir/nested");
+        for (name, seed) in &[("from-dir", seed_dir), ("from-tar", seed_tar)] {
+            let zone_name = ZoneName::new(name.to_string()).unwrap();
+            let zone = Zone::create_seeded(&mzr_dir, &zone_name, &snap_name, seed, None).unwrap();
+            assert_eq!(zone.info.changes_seed.as_ref(), Some(seed));
+            let changes_dir = &zone.ovfs_changes_dir;
+            assert_eq!(
+                fs::read_to_string(changes_dir.join("added")).unwrap(),
+                "new"
+            );
+            assert_eq!(
+                fs::read_to_string(changes_dir.join("dir/nested")).unwrap(),
+                "nested"
+            );
+            assert_eq!(
+                fs::read_link(changes_dir.join("link")).unwrap(),
+                PathBuf::from("dir/nested")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_seeds_with_escaping_entries() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        for (name, link_target) in &[("escaping", "../../outside"), ("absolute", "/etc")] {
+            let (seed_dir, seed_tar) = seed_dir_and_tar(&dir, name, link_target);
+            for seed in &[seed_dir, seed_tar] {
+                let zone_name = ZoneName::new(format!("{}-zone", name)).unwrap();
+                assert!(Zone::create_seeded(&mzr_dir, &zone_name, &snap_name, seed, None).is_err());
+                assert!(!Zone::exists(&mzr_dir, &zone_name));
+            }
+        }
+    }
+
+    #[test]
+    fn created_at_reads_zone_info() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let mut zone = test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        zone.info.creation_time = "2019-05-27T12:00:00Z".parse().unwrap();
+        json::write(&zone.zone_dir.info_file(), &zone.info).unwrap();
+        assert_eq!(
+            zone_created_at(&mzr_dir, &zone.name).unwrap(),
+            SystemTime::from(zone.info.creation_time)
+        );
+        let missing = ZoneName::new("missing".to_string()).unwrap();
+        assert!(zone_created_at(&mzr_dir, &missing).is_err());
+    }
+
+    #[test]
+    fn created_at_falls_back_on_zone_dir_metadata() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let zone = test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        fs::remove_file(zone.zone_dir.info_file()).unwrap();
+        let created_at = zone_created_at(&mzr_dir, &zone.name).unwrap();
+        assert!(before <= created_at);
+        assert!(created_at <= SystemTime::now());
+    }
+
+    #[test]
+    fn trashed_zones_are_kept_until_trash_is_emptied() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let zone = test_zone(&mzr_dir, "mz", &snap_name, &[("file", "changed")]);
+        assert!(list_trash(&mzr_dir).unwrap().is_empty());
+        let trashed_dir = move_to_trash(&mzr_dir, &zone.name, false).unwrap();
+        assert!(!Zone::exists(&mzr_dir, &zone.name));
+        assert_eq!(list_trash(&mzr_dir).unwrap(), vec![trashed_dir.clone()]);
+        assert!(trashed_dir.starts_with(TrashDir::new(&mzr_dir).as_path()));
+        assert_eq!(
+            fs::read_to_string(trashed_dir.join("changes/file")).unwrap(),
+            "changed"
+        );
+        // A zone with the same name can be created again.
+        test_zone(&mzr_dir, "mz", &snap_name, &[]);
+        empty_trash(&mzr_dir).unwrap();
+        assert!(list_trash(&mzr_dir).unwrap().is_empty());
+        assert!(!trashed_dir.exists());
+        assert!(Zone::exists(&mzr_dir, &zone.name));
+    }
+
+    #[test]
+    fn idempotent_create_only_accepts_the_same_snapshot() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let other_snap_name = test_snapshot(&mzr_dir, "s2", &[]);
+        let zone_name = ZoneName::new("mz".to_string()).unwrap();
+        let created = Zone::create_idempotent(&mzr_dir, &zone_name, &snap_name, None).unwrap();
+        assert_eq!(created.info.snapshot, snap_name);
+        let loaded = Zone::create_idempotent(&mzr_dir, &zone_name, &snap_name, None).unwrap();
+        assert_eq!(loaded.info.creation_time, created.info.creation_time);
+        assert!(Zone::create(&mzr_dir, &zone_name, &snap_name, None).is_err());
+        let err = Zone::create_idempotent(&mzr_dir, &zone_name, &other_snap_name, None)
+            .err()
+            .unwrap();
+        assert!(strip_ansi_escapes(&err.to_string()).contains("rather than s2"));
+        let info = Zone::load(&mzr_dir, &zone_name).unwrap().info;
+        assert_eq!(info.snapshot, snap_name);
+        assert_eq!(info.creation_time, created.info.creation_time);
+    }
+
+    #[test]
+    fn rebase_updates_snapshot_and_keeps_changes() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let other_snap_name = test_snapshot(&mzr_dir, "s2", &[]);
+        let mut zone = test_zone(&mzr_dir, "mz", &snap_name, &[("file", "changed")]);
+        write_files(&zone.ovfs_work_dir, &[("index/stale", "")]);
+        let missing_snap_name = SnapName::new("missing".to_string()).unwrap();
+        assert!(zone.rebase(&mzr_dir, &missing_snap_name).is_err());
+        assert_eq!(
+            Zone::load(&mzr_dir, &zone.name).unwrap().info.snapshot,
+            snap_name
+        );
+        zone.rebase(&mzr_dir, &other_snap_name).unwrap();
+        assert_eq!(zone.info.snapshot, other_snap_name);
+        assert_eq!(
+            zone.snap_dir.as_path(),
+            SnapDir::new(&mzr_dir, &other_snap_name).as_path()
+        );
+        let loaded = Zone::load(&mzr_dir, &zone.name).unwrap();
+        assert_eq!(loaded.info.snapshot, other_snap_name);
+        assert_eq!(
+            fs::read_to_string(loaded.ovfs_changes_dir.join("file")).unwrap(),
+            "changed"
+        );
+        assert!(fs::read_dir(&loaded.ovfs_work_dir)
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn rebase_conflicts_are_changes_to_differing_paths() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(
+            &mzr_dir,
+            "s1",
+            &[("modified", "1"), ("same", "same"), ("removed", "")],
+        );
+        let other_snap_name = test_snapshot(
+            &mzr_dir,
+            "s2",
+            &[("modified", "2"), ("same", "same"), ("added", "")],
+        );
+        // Snapshots preserve timestamps, so the unchanged file only matches if
+        // its modification times match too.
+        let same_modified = fs::metadata(SnapDir::new(&mzr_dir, &snap_name).join("same"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(SnapDir::new(&mzr_dir, &other_snap_name).join("same"))
+            .unwrap()
+            .set_modified(same_modified)
+            .unwrap();
+        let zone = test_zone(
+            &mzr_dir,
+            "mz",
+            &snap_name,
+            &[
+                ("modified", "changed"),
+                ("same", "changed"),
+                ("added/file", "new"),
+                ("new/file", "new"),
+            ],
+        );
+        changes::create_whiteout(&zone.ovfs_changes_dir.join("removed")).unwrap();
+        let conflicts = zone.rebase_conflicts(&mzr_dir, &other_snap_name).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![
+                PathBuf::from("added/file"),
+                PathBuf::from("modified"),
+                PathBuf::from("removed"),
+            ]
+        );
+        assert!(zone
+            .rebase_conflicts(&mzr_dir, &snap_name)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn pinned_zones_are_only_deleted_when_forced() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        let pinned = test_zone(&mzr_dir, "pinned", &snap_name, &[]).name;
+        let trashed = test_zone(&mzr_dir, "trashed", &snap_name, &[]).name;
+        for zone_name in &[&pinned, &trashed] {
+            pin_zone(&mzr_dir, zone_name).unwrap();
+            assert!(Zone::load(&mzr_dir, zone_name).unwrap().info.pinned);
+            assert!(check_not_pinned(&mzr_dir, zone_name, false).is_err());
+            assert!(check_not_pinned(&mzr_dir, zone_name, true).is_ok());
+        }
+        assert!(delete(&mzr_dir, &pinned, false).is_err());
+        assert!(move_to_trash(&mzr_dir, &trashed, false).is_err());
+        assert!(Zone::exists(&mzr_dir, &pinned));
+        assert!(Zone::exists(&mzr_dir, &trashed));
+        delete(&mzr_dir, &pinned, true).unwrap();
+        move_to_trash(&mzr_dir, &trashed, true).unwrap();
+        assert!(!Zone::exists(&mzr_dir, &pinned));
+        assert!(!Zone::exists(&mzr_dir, &trashed));
+        let unpinned = test_zone(&mzr_dir, "unpinned", &snap_name, &[]).name;
+        pin_zone(&mzr_dir, &unpinned).unwrap();
+        unpin_zone(&mzr_dir, &unpinned).unwrap();
+        assert!(!Zone::load(&mzr_dir, &unpinned).unwrap().info.pinned);
+        delete(&mzr_dir, &unpinned, false).unwrap();
+    }
+
+    #[test]
+    fn only_one_concurrent_reservation_proceeds() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        ensure_dir(ZonesDir::new(&mzr_dir)).unwrap();
+        let zone_name = ZoneName::new("mz".to_string()).unwrap();
+        let zone_dir = ZoneDir::new(&mzr_dir, &zone_name);
+        let barrier = Barrier::new(2);
+        let results: Vec<bool> = thread::scope(|scope| {
+            let reservers: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        let reservation =
+                            ZoneReservation::new(&mzr_dir, &zone_dir, &zone_name, None);
+                        // Wait for both attempts before the winning one gets
+                        // dropped, which removes the directory.
+                        barrier.wait();
+                        reservation.is_ok()
+                    })
+                })
+                .collect();
+            reservers.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|&&ok| ok).count(), 1);
+        // The reservation wasn't completed, so its directory got removed.
+        assert!(!zone_dir.exists());
+        let reservation = ZoneReservation::new(&mzr_dir, &zone_dir, &zone_name, None).unwrap();
+        reservation.complete();
+        assert!(zone_dir.is_dir());
+    }
+
+    #[test]
+    fn reset_discards_changes_and_whiteouts() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[("file", "base"), ("dir/deleted", "")]);
+        let zone = test_zone(
+            &mzr_dir,
+            "mz",
+            &snap_name,
+            &[("file", "changed"), ("added", "new")],
+        );
+        changes::create_whiteout(&zone.ovfs_changes_dir.join("dir/deleted")).unwrap();
+        write_files(&zone.ovfs_work_dir, &[("index/stale", "")]);
+        reset_zone(&mzr_dir, &zone.name).unwrap();
+        assert!(fs::read_dir(&zone.ovfs_changes_dir)
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(fs::read_dir(&zone.ovfs_work_dir).unwrap().next().is_none());
+        assert!(changes::list_whiteouts(&zone.ovfs_changes_dir)
+            .unwrap()
+            .is_empty());
+        // Applying the remaining changes to a copy of the snapshot yields the
+        // snapshot, so the zone's merged view matches its base.
+        let merged = dir.join("merged");
+        write_files(&merged, &[("file", "base"), ("dir/deleted", "")]);
+        changes::apply_to_tree(&zone.ovfs_changes_dir, &merged).unwrap();
+        let diff = diff_trees(&zone.snap_dir, &merged, Comparison::Contents).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty());
+        assert_eq!(
+            Zone::load(&mzr_dir, &zone.name).unwrap().info.snapshot,
+            snap_name
+        );
+    }
+
+    #[test]
+    fn missing_zones_suggest_near_misses() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        test_zone(&mzr_dir, "feature", &snap_name, &[]);
+        test_zone(&mzr_dir, "main", &snap_name, &[]);
+        let suggestion = |name: &str| {
+            let zone_name = ZoneName::new(name.to_string()).unwrap();
+            strip_ansi_escapes(&did_you_mean_zone(&mzr_dir, &zone_name).unwrap())
+        };
+        assert_eq!(suggestion("featur"), " Did you mean feature?");
+        assert_eq!(suggestion("unrelated"), "");
+        let err = Zone::load(&mzr_dir, &ZoneName::new("mai".to_string()).unwrap())
+            .err()
+            .unwrap();
+        assert!(strip_ansi_escapes(&err.to_string()).ends_with("Did you mean main?"));
+    }
+
+    #[test]
+    fn zone_limit_counts_existing_zones() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        assert!(check_zone_limit(&mzr_dir, Some(1)).is_ok());
+        test_zone(&mzr_dir, "a", &snap_name, &[]);
+        test_zone(&mzr_dir, "b", &snap_name, &[]);
+        assert!(check_zone_limit(&mzr_dir, None).is_ok());
+        assert!(check_zone_limit(&mzr_dir, Some(3)).is_ok());
+        let err = check_zone_limit(&mzr_dir, Some(2)).unwrap_err();
+        assert!(err.to_string().contains("already 2 zones"));
+        assert!(check_zone_limit(&mzr_dir, Some(0)).is_err());
+        assert_eq!(
+            parse_max_zones(Err(env::VarError::NotPresent)).unwrap(),
+            None
+        );
+        assert_eq!(parse_max_zones(Ok("2".to_string())).unwrap(), Some(2));
+        assert!(parse_max_zones(Ok("two".to_string())).is_err());
+        assert!(parse_max_zones(Ok("-1".to_string())).is_err());
+    }
+
+    #[test]
+    fn names_differing_in_case_only_collide_when_case_insensitive() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        test_zone(&mzr_dir, "feature", &snap_name, &[]);
+        let upper_name = ZoneName::new("Feature".to_string()).unwrap();
+        assert!(check_case_collision(&mzr_dir, &upper_name).is_ok());
+        fs::write(
+            ZonesDir::new(&mzr_dir).join(format!(".MZR-CASE-PROBE-{}", process::id())),
+            "",
+        )
+        .unwrap();
+        assert!(check_case_collision(&mzr_dir, &upper_name).is_err());
+        let other_name = ZoneName::new("other".to_string()).unwrap();
+        assert!(check_case_collision(&mzr_dir, &other_name).is_ok());
+    }
+
+    #[test]
+    fn orphaned_dirs_are_found_and_classified() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let snap_name = test_snapshot(&mzr_dir, "s1", &[]);
+        test_zone(&mzr_dir, "valid", &snap_name, &[]);
+        let zone_dir =
+            |name: &str| ZoneDir::new(&mzr_dir, &ZoneName::new(name.to_string()).unwrap());
+        let recent = zone_dir("recent");
+        ensure_dir(recent.changes_dir()).unwrap();
+        let stale = zone_dir("stale");
+        ensure_dir(stale.ovfs_work_dir()).unwrap();
+        fs::write(stale.info_file(), "not json").unwrap();
+        // Make the stale zone directory look like it was left behind long ago.
+        let long_ago = SystemTime::now() - IN_PROGRESS_AGE * 2;
+        for path in &[
+            stale.info_file().to_path_buf(),
+            stale.ovfs_work_dir().to_path_buf(),
+            stale.to_path_buf(),
+        ] {
+            fs::File::open(path)
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+        ensure_dir(zone_dir("unrelated").join("other")).unwrap();
+        let mut orphans = find_orphaned_dirs(&mzr_dir).unwrap();
+        orphans.sort_by(|x, y| x.zone_dir.cmp(&y.zone_dir));
+        let found: Vec<(String, bool)> = orphans
+            .iter()
+            .map(|orphan| {
+                let zone_name = orphan.zone_dir.zone_name().unwrap();
+                ((*zone_name).clone(), orphan.in_progress)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![("recent".to_string(), true), ("stale".to_string(), false)]
+        );
+        assert!(orphans[0].reason.contains("missing"));
+        assert!(orphans[1].reason.contains("invalid"));
+    }
+
+    #[test]
+    fn zones_are_filtered_by_snapshot() {
+        let dir = TestDir::new();
+        let mzr_dir = test_mzr_dir(&dir);
+        let s1 = test_snapshot(&mzr_dir, "s1", &[]);
+        let s2 = test_snapshot(&mzr_dir, "s2", &[]);
+        let unused = test_snapshot(&mzr_dir, "unused", &[]);
+        test_zone(&mzr_dir, "a", &s1, &[]);
+        test_zone(&mzr_dir, "b", &s2, &[]);
+        test_zone(&mzr_dir, "c", &s1, &[]);
+        let unreadable = test_zone(&mzr_dir, "d", &s1, &[]);
+        fs::write(unreadable.zone_dir.info_file(), "not json").unwrap();
+        let names = |snap_name: &SnapName| {
+            let mut names: Vec<String> = zones_based_on(&mzr_dir, snap_name)
+                .unwrap()
+                .into_iter()
+                .map(|zone_name| (*zone_name).clone())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&s1), vec!["a", "c"]);
+        assert_eq!(names(&s2), vec!["b"]);
+        assert!(names(&unused).is_empty());
+    }
+}